@@ -57,4 +57,12 @@ pub enum EncodingError {
     /// Error encoding the given flow id, the flow id is too large.
     #[error("The verification flow id length can't be converted into a u16: {0}")]
     FlowId(#[from] std::num::TryFromIntError),
+    /// The rendered QR code would be too large, the quiet zone or module size
+    /// of the render settings are too big.
+    #[error("The rendered QR code is too large")]
+    ImageSize,
+    /// Error encoding the rendered QR code as an image file.
+    #[cfg(feature = "decode_image")]
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
 }
@@ -26,6 +26,9 @@
 )]
 
 mod error;
+mod render;
+#[cfg(feature = "decode_image")]
+mod scanner;
 mod types;
 mod utils;
 
@@ -33,8 +36,11 @@ pub use error::{DecodingError, EncodingError};
 #[cfg(feature = "decode_image")]
 pub use image;
 pub use qrcode;
+pub use render::RenderSettings;
 #[cfg(feature = "decode_image")]
 pub use rqrr;
+#[cfg(feature = "decode_image")]
+pub use scanner::{QrScanner, Rejection};
 pub use types::{
     QrVerificationData, SelfVerificationData, SelfVerificationNoMasterKey, VerificationData,
 };
@@ -45,13 +51,13 @@ mod test {
     use std::{convert::TryFrom, io::Cursor};
 
     #[cfg(feature = "decode_image")]
-    use image::{ImageFormat, Luma};
+    use image::{GenericImageView, ImageFormat, Luma};
     #[cfg(feature = "decode_image")]
-    use qrcode::QrCode;
+    use qrcode::{EcLevel, QrCode};
 
     #[cfg(feature = "decode_image")]
     use crate::utils::decode_qr;
-    use crate::{DecodingError, QrVerificationData};
+    use crate::{DecodingError, EncodingError, QrVerificationData, RenderSettings};
 
     #[cfg(feature = "decode_image")]
     static VERIFICATION: &[u8; 4277] = include_bytes!("../data/verification.png");
@@ -141,6 +147,44 @@ mod test {
         assert_eq!(result, third_result);
     }
 
+    #[test]
+    #[cfg(feature = "decode_image")]
+    fn render_decode_cycle() {
+        let image = Cursor::new(VERIFICATION);
+        let image = image::load(image, ImageFormat::Png).unwrap();
+        let result = QrVerificationData::from_image(image).unwrap();
+
+        let settings = RenderSettings::new().ec_level(EcLevel::H).quiet_zone(1).module_size(3);
+
+        let png = result.to_png(&settings).unwrap();
+        let image = image::load(Cursor::new(png), ImageFormat::Png).unwrap();
+        let qr_width = result.to_qr_code_with_ec_level(EcLevel::H).unwrap().width() as u32;
+
+        assert_eq!(image.width(), (qr_width + 2) * 3);
+        assert_eq!(result, QrVerificationData::from_image(image).unwrap());
+
+        let svg = result.to_svg(&settings).unwrap();
+        assert!(svg.contains("<svg"));
+        assert!(svg.ends_with("</svg>"));
+    }
+
+    #[test]
+    fn render_too_large() {
+        let data = b"MATRIX\
+                     \x02\x02\x00\x07\
+                     FLOW_ID\
+                     AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                     BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                     SHARED_SECRET";
+        let result = QrVerificationData::from_bytes(data).unwrap();
+
+        let settings = RenderSettings::new().quiet_zone(u32::MAX);
+        assert!(matches!(result.to_svg(&settings), Err(EncodingError::ImageSize)));
+
+        let settings = RenderSettings::new().module_size(u32::MAX);
+        assert!(matches!(result.to_svg(&settings), Err(EncodingError::ImageSize)));
+    }
+
     #[test]
     #[cfg(feature = "decode_image")]
     fn decode_invalid_qr() {
@@ -0,0 +1,154 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Write;
+
+#[cfg(feature = "decode_image")]
+use image::{ImageBuffer, Luma};
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::error::EncodingError;
+
+/// Settings controlling how a `QrVerificationData` gets rendered as an image.
+///
+/// # Example
+/// ```
+/// # use matrix_qrcode::{qrcode::EcLevel, RenderSettings};
+/// let settings = RenderSettings::new().ec_level(EcLevel::M).quiet_zone(2).module_size(4);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub(crate) ec_level: EcLevel,
+    quiet_zone: u32,
+    module_size: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self { ec_level: EcLevel::L, quiet_zone: 4, module_size: 8 }
+    }
+}
+
+impl RenderSettings {
+    /// Create new default render settings.
+    ///
+    /// The default settings use the lowest error correction level, a quiet
+    /// zone of 4 modules and 8 pixels per module.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the error correction level of the QR code.
+    ///
+    /// Higher error correction levels make the QR code easier to scan from a
+    /// damaged or badly lit display but also make the QR code larger.
+    pub fn ec_level(mut self, ec_level: EcLevel) -> Self {
+        self.ec_level = ec_level;
+        self
+    }
+
+    /// Set the width of the quiet zone, the empty border around the QR code,
+    /// in modules.
+    ///
+    /// The QR code specification requires a quiet zone of 4 modules, but many
+    /// scanners are able to cope with a smaller one.
+    pub fn quiet_zone(mut self, modules: u32) -> Self {
+        self.quiet_zone = modules;
+        self
+    }
+
+    /// Set the size of a single module of the QR code in pixels.
+    ///
+    /// The size is clamped to be at least a single pixel.
+    pub fn module_size(mut self, pixels: u32) -> Self {
+        self.module_size = pixels.max(1);
+        self
+    }
+
+    /// Get the size of the rendered image in pixels for the given QR code.
+    ///
+    /// Returns an error if the size doesn't fit into a `u32`.
+    fn image_size(&self, code: &QrCode) -> Result<u32, EncodingError> {
+        self.quiet_zone
+            .checked_mul(2)
+            .and_then(|q| q.checked_add(code.width() as u32))
+            .and_then(|w| w.checked_mul(self.module_size))
+            .ok_or(EncodingError::ImageSize)
+    }
+
+    /// Get an iterator over the positions, in modules, of all the dark modules
+    /// of the given QR code, the positions include the quiet zone.
+    fn dark_modules(&self, code: &QrCode) -> impl Iterator<Item = (u32, u32)> {
+        let width = code.width() as u32;
+        let quiet_zone = self.quiet_zone;
+
+        code.to_colors().into_iter().enumerate().filter(|(_, c)| *c == Color::Dark).map(
+            move |(i, _)| {
+                let i = i as u32;
+                (i % width + quiet_zone, i / width + quiet_zone)
+            },
+        )
+    }
+}
+
+pub(crate) fn render_svg(
+    code: &QrCode,
+    settings: &RenderSettings,
+) -> Result<String, EncodingError> {
+    let size = settings.image_size(code)?;
+    let module_size = settings.module_size;
+
+    let mut svg = String::new();
+
+    // Writing into a `String` can't fail, so we ignore the results here.
+    let _ = write!(
+        svg,
+        "<?xml version=\"1.0\" standalone=\"yes\"?>\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"{0}\" height=\"{0}\" \
+         viewBox=\"0 0 {0} {0}\" shape-rendering=\"crispEdges\">\
+         <rect x=\"0\" y=\"0\" width=\"{0}\" height=\"{0}\" fill=\"#fff\"/>\
+         <path fill=\"#000\" d=\"",
+        size
+    );
+
+    for (x, y) in settings.dark_modules(code) {
+        let _ =
+            write!(svg, "M{0} {1}h{2}v{2}h-{2}z", x * module_size, y * module_size, module_size);
+    }
+
+    svg.push_str("\"/></svg>");
+
+    Ok(svg)
+}
+
+#[cfg(feature = "decode_image")]
+pub(crate) fn render_luma(
+    code: &QrCode,
+    settings: &RenderSettings,
+) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, EncodingError> {
+    let size = settings.image_size(code)?;
+    let module_size = settings.module_size;
+
+    let mut image = ImageBuffer::from_pixel(size, size, Luma([255]));
+
+    for (x, y) in settings.dark_modules(code) {
+        for dy in 0..module_size {
+            for dx in 0..module_size {
+                image.put_pixel(x * module_size + dx, y * module_size + dy, Luma([0]));
+            }
+        }
+    }
+
+    Ok(image)
+}
@@ -0,0 +1,251 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+
+use image::{GenericImage, GenericImageView, ImageBuffer, Luma};
+
+use crate::{error::DecodingError, types::QrVerificationData};
+
+/// The maximum number of rejections the scanner remembers, older rejections
+/// will be forgotten.
+const MAX_REJECTIONS: usize = 32;
+
+/// A QR code that was found in a frame but couldn't be used for a
+/// verification.
+#[derive(Debug)]
+pub struct Rejection {
+    /// The number of the frame, counting from 0, that contained the QR code.
+    pub frame: u64,
+    /// Was the QR code found in the inverted version of the frame, i.e. was it
+    /// a light on dark QR code.
+    pub inverted: bool,
+    /// The reason why the QR code was rejected, e.g. because it's missing the
+    /// Matrix header or uses an unsupported version or verification mode.
+    pub error: DecodingError,
+}
+
+/// A streaming QR code scanner.
+///
+/// The scanner accepts successive grey scale frames, for example coming from a
+/// camera, and returns the first valid `QrVerificationData` it finds.
+///
+/// The QR code detection tolerates rotated and perspective distorted QR codes,
+/// by default the scanner will also look for inverted, light on dark, QR codes
+/// if a frame doesn't contain a valid regular QR code.
+///
+/// QR codes that were found but couldn't be decoded are remembered as
+/// [`Rejection`]s, which can be used to tell the user why a scan doesn't
+/// succeed.
+///
+/// # Example
+/// ```no_run
+/// # use matrix_qrcode::{image, QrScanner};
+/// # let frames: Vec<image::GrayImage> = vec![];
+/// let mut scanner = QrScanner::new();
+///
+/// for frame in frames {
+///     if let Some(data) = scanner.scan_frame(frame) {
+///         println!("Found a QR code with the flow id {}", data.flow_id());
+///         break;
+///     }
+/// }
+///
+/// for rejection in scanner.rejections() {
+///     println!("Rejected a QR code in frame {}: {}", rejection.frame, rejection.error);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct QrScanner {
+    try_inverted: bool,
+    frame_count: u64,
+    result: Option<QrVerificationData>,
+    rejections: VecDeque<Rejection>,
+}
+
+impl Default for QrScanner {
+    fn default() -> Self {
+        Self { try_inverted: true, frame_count: 0, result: None, rejections: VecDeque::new() }
+    }
+}
+
+impl QrScanner {
+    /// Create a new `QrScanner`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Should the scanner look for inverted, light on dark, QR codes.
+    ///
+    /// This is enabled by default, disabling it makes scanning a frame cheaper.
+    pub fn try_inverted(mut self, try_inverted: bool) -> Self {
+        self.try_inverted = try_inverted;
+        self
+    }
+
+    /// Scan a single grey scale frame for a QR code.
+    ///
+    /// Returns the `QrVerificationData` of the first valid QR code that was
+    /// found, either in this frame or in a previously scanned frame. Once a
+    /// valid QR code was found no further frames are scanned until the scanner
+    /// is [`reset()`](#method.reset).
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The grey scale frame that should be scanned.
+    pub fn scan_frame<I>(&mut self, frame: I) -> Option<QrVerificationData>
+    where
+        I: GenericImage<Pixel = Luma<u8>> + GenericImageView<Pixel = Luma<u8>>,
+    {
+        if self.result.is_none() {
+            let frame_number = self.frame_count;
+            self.frame_count += 1;
+
+            let inverted = if self.try_inverted {
+                let (width, height) = frame.dimensions();
+                Some(ImageBuffer::from_fn(width, height, |x, y| {
+                    Luma([u8::MAX - frame.get_pixel(x, y)[0]])
+                }))
+            } else {
+                None
+            };
+
+            self.result = self.scan(frame_number, false, frame);
+
+            if let (None, Some(inverted)) = (&self.result, inverted) {
+                self.result = self.scan(frame_number, true, inverted);
+            }
+        }
+
+        self.result.clone()
+    }
+
+    /// Get the `QrVerificationData` that was found, if any.
+    pub fn result(&self) -> Option<&QrVerificationData> {
+        self.result.as_ref()
+    }
+
+    /// Get the number of frames that were scanned.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Get the most recent QR codes that were found but were rejected.
+    pub fn rejections(&self) -> impl Iterator<Item = &Rejection> {
+        self.rejections.iter()
+    }
+
+    /// Reset the scanner so it can be used to scan for a new QR code.
+    pub fn reset(&mut self) {
+        self.frame_count = 0;
+        self.result = None;
+        self.rejections.clear();
+    }
+
+    fn scan<I>(&mut self, frame: u64, inverted: bool, image: I) -> Option<QrVerificationData>
+    where
+        I: GenericImage<Pixel = Luma<u8>> + GenericImageView<Pixel = Luma<u8>>,
+    {
+        let mut image = rqrr::PreparedImage::prepare(image);
+
+        for grid in image.detect_grids() {
+            let mut decoded = Vec::new();
+
+            let result = grid
+                .decode_to(&mut decoded)
+                .map_err(DecodingError::from)
+                .and_then(|_| QrVerificationData::from_bytes(decoded));
+
+            match result {
+                Ok(data) => return Some(data),
+                Err(error) => self.reject(Rejection { frame, inverted, error }),
+            }
+        }
+
+        None
+    }
+
+    fn reject(&mut self, rejection: Rejection) {
+        if self.rejections.len() == MAX_REJECTIONS {
+            self.rejections.pop_front();
+        }
+
+        self.rejections.push_back(rejection);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{imageops, Luma};
+    use qrcode::QrCode;
+
+    use super::QrScanner;
+    use crate::{DecodingError, QrVerificationData, RenderSettings};
+
+    fn verification_data() -> QrVerificationData {
+        let data = b"MATRIX\
+                   \x02\x02\x00\x07\
+                   FLOW_ID\
+                   AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                   BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                   SHARED_SECRET";
+
+        QrVerificationData::from_bytes(data).unwrap()
+    }
+
+    #[test]
+    fn scan_after_rejected_frames() {
+        let data = verification_data();
+        let mut scanner = QrScanner::new();
+
+        let blank = image::GrayImage::from_pixel(200, 200, Luma([255]));
+        assert!(scanner.scan_frame(blank).is_none());
+        assert_eq!(scanner.rejections().count(), 0);
+
+        let qr = QrCode::new(b"NonMatrixCode").unwrap();
+        let image = qr.render::<Luma<u8>>().build();
+        assert!(scanner.scan_frame(image).is_none());
+
+        let rejection = scanner.rejections().next().expect("The QR code should be rejected");
+        assert_eq!(rejection.frame, 1);
+        assert!(matches!(rejection.error, DecodingError::Header));
+
+        let image = data.to_luma(&RenderSettings::new()).unwrap();
+        assert_eq!(scanner.scan_frame(image).as_ref(), Some(&data));
+        assert_eq!(scanner.frame_count(), 3);
+
+        scanner.reset();
+        assert!(scanner.result().is_none());
+        assert_eq!(scanner.rejections().count(), 0);
+    }
+
+    #[test]
+    fn scan_rotated_and_inverted() {
+        let data = verification_data();
+        let image = data.to_luma(&RenderSettings::new()).unwrap();
+
+        let mut scanner = QrScanner::new();
+        let rotated = imageops::rotate90(&image);
+        assert_eq!(scanner.scan_frame(rotated).as_ref(), Some(&data));
+
+        let mut inverted = image;
+        imageops::invert(&mut inverted);
+
+        let mut scanner = QrScanner::new().try_inverted(false);
+        assert!(scanner.scan_frame(inverted.clone()).is_none());
+
+        let mut scanner = QrScanner::new();
+        assert_eq!(scanner.scan_frame(inverted).as_ref(), Some(&data));
+    }
+}
@@ -19,17 +19,21 @@ use std::{
 
 use byteorder::{BigEndian, ReadBytesExt};
 #[cfg(feature = "decode_image")]
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Luma};
-use qrcode::QrCode;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageOutputFormat, Luma};
+use qrcode::{EcLevel, QrCode};
 use ruma_identifiers::EventId;
 use ruma_serde::Base64;
 
-#[cfg(feature = "decode_image")]
-use crate::utils::decode_qr;
 use crate::{
     error::{DecodingError, EncodingError},
-    utils::{base_64_encode, to_bytes, to_qr_code, HEADER, MAX_MODE, MIN_SECRET_LEN, VERSION},
+    render::{render_svg, RenderSettings},
+    utils::{
+        base_64_encode, bytes_to_qr_code, to_bytes, to_qr_code, HEADER, MAX_MODE, MIN_SECRET_LEN,
+        VERSION,
+    },
 };
+#[cfg(feature = "decode_image")]
+use crate::{render::render_luma, utils::decode_qr};
 
 /// An enum representing the different modes a QR verification can be in.
 #[derive(Clone, Debug, PartialEq)]
@@ -227,6 +231,77 @@ impl QrVerificationData {
         }
     }
 
+    /// Encode the `QrVerificationData` into a `QrCode` using the given error
+    /// correction level.
+    ///
+    /// Unlike [`to_qr_code()`](#method.to_qr_code), which always uses the
+    /// lowest error correction level, this will pick a larger QR code version
+    /// if the data doesn't fit otherwise.
+    pub fn to_qr_code_with_ec_level(&self, ec_level: EcLevel) -> Result<QrCode, EncodingError> {
+        bytes_to_qr_code(&self.to_bytes()?, ec_level)
+    }
+
+    /// Render the `QrVerificationData` as a SVG image.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The settings controlling the error correction level,
+    /// quiet zone and size of the rendered QR code.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{QrVerificationData, DecodingError, RenderSettings};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// let data = b"MATRIX\
+    ///              \x02\x02\x00\x07\
+    ///              FLOW_ID\
+    ///              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    ///              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    ///              SHARED_SECRET";
+    ///
+    /// let result = QrVerificationData::from_bytes(data)?;
+    /// let svg = result.to_svg(&RenderSettings::new().quiet_zone(2)).unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_svg(&self, settings: &RenderSettings) -> Result<String, EncodingError> {
+        let code = self.to_qr_code_with_ec_level(settings.ec_level)?;
+        render_svg(&code, settings)
+    }
+
+    /// Render the `QrVerificationData` as a grey scale image.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The settings controlling the error correction level,
+    /// quiet zone and size of the rendered QR code.
+    #[cfg(feature = "decode_image")]
+    pub fn to_luma(
+        &self,
+        settings: &RenderSettings,
+    ) -> Result<ImageBuffer<Luma<u8>, Vec<u8>>, EncodingError> {
+        let code = self.to_qr_code_with_ec_level(settings.ec_level)?;
+        render_luma(&code, settings)
+    }
+
+    /// Render the `QrVerificationData` as a PNG image.
+    ///
+    /// Returns the bytes of the PNG encoded image.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The settings controlling the error correction level,
+    /// quiet zone and size of the rendered QR code.
+    #[cfg(feature = "decode_image")]
+    pub fn to_png(&self, settings: &RenderSettings) -> Result<Vec<u8>, EncodingError> {
+        let image = DynamicImage::ImageLuma8(self.to_luma(settings)?);
+        let mut png = Vec::new();
+
+        image.write_to(&mut png, ImageOutputFormat::Png)?;
+
+        Ok(png)
+    }
+
     /// Decode the byte slice containing the decoded QR code data.
     ///
     /// The format is defined in the [spec].
@@ -17,7 +17,7 @@ use std::convert::TryInto;
 use base64::{decode_config, encode_config, STANDARD_NO_PAD};
 #[cfg(feature = "decode_image")]
 use image::{GenericImage, GenericImageView, Luma};
-use qrcode::{bits::Bits, EcLevel, QrCode, Version};
+use qrcode::{bits::Bits, types::QrError, EcLevel, QrCode, Version};
 use ruma_serde::Base64;
 
 #[cfg(feature = "decode_image")]
@@ -74,6 +74,15 @@ pub(crate) fn to_qr_code(
 ) -> Result<QrCode, EncodingError> {
     let data = to_bytes(mode, flow_id, first_key, second_key, shared_secret)?;
 
+    bytes_to_qr_code(&data, EcLevel::L)
+}
+
+/// The smallest QR code version we're going to use, this is large enough to
+/// hold our payload using the lowest error correction level.
+const MIN_QR_VERSION: i16 = 7;
+const MAX_QR_VERSION: i16 = 40;
+
+pub(crate) fn bytes_to_qr_code(data: &[u8], ec_level: EcLevel) -> Result<QrCode, EncodingError> {
     // Mobile clients seem to have trouble decoding the QR code that gets
     // generated by `QrCode::new()` it seems to add a couple of data segments
     // with different data modes/types. The parsers seem to assume a single
@@ -83,11 +92,22 @@ pub(crate) fn to_qr_code(
     // We make sure that there isn't an ECI bit set and we just push the bytes,
     // this seems to help since the decoder doesn't assume an encoding and
     // treats everything as raw bytes.
-    let mut bits = Bits::new(Version::Normal(7));
-    bits.push_byte_data(&data)?;
-    bits.push_terminator(EcLevel::L)?;
+    //
+    // Higher error correction levels need more space, so we pick the smallest
+    // version that is able to hold the data.
+    let mut version = MIN_QR_VERSION;
 
-    Ok(QrCode::with_bits(bits, EcLevel::L)?)
+    loop {
+        let mut bits = Bits::new(Version::Normal(version));
+
+        let result = bits.push_byte_data(data).and_then(|_| bits.push_terminator(ec_level));
+
+        match result {
+            Ok(()) => return Ok(QrCode::with_bits(bits, ec_level)?),
+            Err(QrError::DataTooLong) if version < MAX_QR_VERSION => version += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
 }
 
 #[cfg(feature = "decode_image")]
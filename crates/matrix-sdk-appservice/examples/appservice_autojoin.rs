@@ -18,7 +18,7 @@ pub async fn handle_room_member(
     room: Room,
     event: SyncRoomMemberEvent,
 ) -> Result<()> {
-    if !appservice.user_id_is_in_namespace(&event.state_key)? {
+    if !appservice.is_user_id_in_namespace(&event.state_key) {
         trace!("not an appservice user: {}", event.state_key);
     } else if let MembershipState::Invite = event.content.membership {
         let user_id = Box::<UserId>::try_from(event.state_key.as_str())?;
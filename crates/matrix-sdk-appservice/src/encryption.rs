@@ -0,0 +1,68 @@
+// Copyright 2022 Famedly GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    api::client::r0::sync::sync_events::DeviceLists, events::AnyToDeviceEvent, serde::Raw,
+    DeviceId, DeviceKeyAlgorithm, UInt, UserId,
+};
+use serde::Deserialize;
+
+/// The end-to-end encryption related data a homeserver pushes to an
+/// application service as part of a transaction.
+///
+/// The fields are defined in [MSC2409] and [MSC3202] and are only used if the
+/// `encryption` feature is enabled. Custom webservers can deserialize this
+/// from the JSON body of a transaction and pass it to
+/// [`AppService::receive_transaction_with_encryption_data()`].
+///
+/// [MSC2409]: https://github.com/matrix-org/matrix-doc/pull/2409
+/// [MSC3202]: https://github.com/matrix-org/matrix-doc/pull/3202
+/// [`AppService::receive_transaction_with_encryption_data()`]: crate::AppService::receive_transaction_with_encryption_data
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EncryptionData {
+    /// The to-device events for the users of the application service, every
+    /// event contains the user and device it was sent to.
+    #[serde(default, rename = "de.sorunome.msc2409.to_device")]
+    pub to_device: Vec<Raw<AnyToDeviceEvent>>,
+
+    /// The users whose devices changed.
+    #[serde(default, rename = "org.matrix.msc3202.device_lists")]
+    pub device_lists: DeviceLists,
+
+    /// The number of unclaimed one-time keys of the devices of the users of
+    /// the application service.
+    #[serde(
+        default,
+        rename = "org.matrix.msc3202.device_one_time_keys_count",
+        alias = "org.matrix.msc3202.device_one_time_key_counts"
+    )]
+    pub device_one_time_keys_count:
+        BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, BTreeMap<DeviceKeyAlgorithm, UInt>>>,
+
+    /// The unused fallback key types of the devices of the users of the
+    /// application service.
+    #[serde(default, rename = "org.matrix.msc3202.device_unused_fallback_key_types")]
+    pub device_unused_fallback_key_types:
+        BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, Vec<DeviceKeyAlgorithm>>>,
+}
+
+/// The recipient of a to-device event pushed by the homeserver.
+#[cfg(feature = "encryption")]
+#[derive(Deserialize)]
+pub(crate) struct ToDeviceRecipient {
+    pub to_user_id: Box<UserId>,
+    pub to_device_id: Box<DeviceId>,
+}
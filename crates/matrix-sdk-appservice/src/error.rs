@@ -41,6 +41,18 @@ pub enum Error {
     #[error("uri path is unknown")]
     UriPathUnknown,
 
+    #[error("user {0} is not in the namespaces of the registration")]
+    UserNotInNamespace(Box<ruma::UserId>),
+
+    #[error("no client for device {1} of user {0} found, and it can't be restored")]
+    NoClientForDevice(Box<ruma::UserId>, Box<ruma::DeviceId>),
+
+    #[error("user {0} is not in an exclusive namespace of the registration")]
+    UserNotInExclusiveNamespace(Box<ruma::UserId>),
+
+    #[error("alias {0} is not in an exclusive namespace of the registration")]
+    AliasNotInExclusiveNamespace(Box<ruma::RoomAliasId>),
+
     #[error(transparent)]
     HttpRequest(#[from] ruma::api::error::FromHttpRequestError),
 
@@ -3,8 +3,18 @@ use std::{future::Future, pin::Pin, sync::Arc};
 use matrix_sdk::locks::Mutex;
 
 use crate::{
-    ruma::api::appservice::query::{
-        query_room_alias::v1 as query_room, query_user_id::v1 as query_user,
+    ruma::{
+        api::appservice::{
+            query::{query_room_alias::v1 as query_room, query_user_id::v1 as query_user},
+            thirdparty::{
+                get_location_for_protocol::v1 as get_location_for_protocol,
+                get_location_for_room_alias::v1 as get_location_for_room_alias,
+                get_protocol::v1 as get_protocol,
+                get_user_for_protocol::v1 as get_user_for_protocol,
+                get_user_for_user_id::v1 as get_user_for_user_id,
+            },
+        },
+        thirdparty::{Location, Protocol, User},
     },
     AppService,
 };
@@ -13,10 +23,41 @@ pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 pub(crate) type AppserviceFn<A, R> =
     Box<dyn FnMut(AppService, A) -> BoxFuture<'static, R> + Send + Sync + 'static>;
 
+/// A query for third-party users, received on one of the
+/// [`/thirdparty/user`] endpoints.
+///
+/// [`/thirdparty/user`]: https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartyuserprotocol
+#[derive(Debug)]
+pub enum ThirdPartyUserQuery {
+    /// Query users of the given protocol matching the given fields.
+    Protocol(get_user_for_protocol::IncomingRequest),
+
+    /// Query the third-party users behind the given Matrix user ID.
+    UserId(get_user_for_user_id::IncomingRequest),
+}
+
+/// A query for third-party locations, received on one of the
+/// [`/thirdparty/location`] endpoints.
+///
+/// [`/thirdparty/location`]: https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartylocationprotocol
+#[derive(Debug)]
+pub enum ThirdPartyLocationQuery {
+    /// Query locations of the given protocol matching the given fields.
+    Protocol(get_location_for_protocol::IncomingRequest),
+
+    /// Query the third-party locations behind the given room alias.
+    Alias(get_location_for_room_alias::IncomingRequest),
+}
+
 #[derive(Default, Clone)]
 pub struct EventHandler {
     pub users: Arc<Mutex<Option<AppserviceFn<query_user::IncomingRequest, bool>>>>,
     pub rooms: Arc<Mutex<Option<AppserviceFn<query_room::IncomingRequest, bool>>>>,
+    pub protocols:
+        Arc<Mutex<Option<AppserviceFn<get_protocol::IncomingRequest, Option<Protocol>>>>>,
+    pub thirdparty_users: Arc<Mutex<Option<AppserviceFn<ThirdPartyUserQuery, Vec<User>>>>>,
+    pub thirdparty_locations:
+        Arc<Mutex<Option<AppserviceFn<ThirdPartyLocationQuery, Vec<Location>>>>>,
 }
 
 impl std::fmt::Debug for EventHandler {
@@ -30,6 +71,18 @@ impl std::fmt::Debug for EventHandler {
             Ok(lock) => debug.field("rooms", &lock.is_some()),
             Err(_) => debug.field("rooms", &format_args!("<locked>")),
         };
+        match self.protocols.try_lock() {
+            Ok(lock) => debug.field("protocols", &lock.is_some()),
+            Err(_) => debug.field("protocols", &format_args!("<locked>")),
+        };
+        match self.thirdparty_users.try_lock() {
+            Ok(lock) => debug.field("thirdparty_users", &lock.is_some()),
+            Err(_) => debug.field("thirdparty_users", &format_args!("<locked>")),
+        };
+        match self.thirdparty_locations.try_lock() {
+            Ok(lock) => debug.field("thirdparty_locations", &lock.is_some()),
+            Err(_) => debug.field("thirdparty_locations", &format_args!("<locked>")),
+        };
         debug.finish()
     }
 }
@@ -66,16 +66,22 @@
 //! # };
 //! ```
 //!
+//! Instead of running the bundled webserver, [`AppService::service()`] returns
+//! a [`tower::Service`] that can be mounted into any hyper-based webserver,
+//! e.g. an axum router.
+//!
 //! Check the [examples directory] for fully working examples.
 //!
+//! [`tower::Service`]: tower_service::Service
+//!
 //! [Application Service]: https://matrix.org/docs/spec/application_service/r0.1.2
 //! [matrix-org/matrix-rust-sdk#228]: https://github.com/matrix-org/matrix-rust-sdk/issues/228
 //! [examples directory]: https://github.com/matrix-org/matrix-rust-sdk/tree/main/crates/matrix-sdk-appservice/examples
 
-#[cfg(not(any(feature = "warp")))]
-compile_error!("one webserver feature must be enabled. available ones: `warp`");
-
+#[cfg(feature = "encryption")]
+use std::collections::BTreeMap;
 use std::{
+    collections::BTreeSet,
     convert::{TryFrom, TryInto},
     fs::File,
     future::Future,
@@ -85,8 +91,12 @@ use std::{
 };
 
 use dashmap::DashMap;
+pub use encryption::EncryptionData;
+#[cfg(feature = "encryption")]
+use encryption::ToDeviceRecipient;
 pub use error::Error;
-use event_handler::AppserviceFn;
+use event_handler::{AppserviceFn, ThirdPartyLocationQuery, ThirdPartyUserQuery};
+use futures_util::stream::{self, StreamExt};
 use http::Uri;
 pub use matrix_sdk;
 #[doc(no_inline)]
@@ -95,25 +105,44 @@ use matrix_sdk::{
     bytes::Bytes,
     config::ClientConfig,
     event_handler::{EventHandler, EventHandlerResult, SyncEvent},
+    locks::Mutex,
     reqwest::Url,
-    Client, Session,
+    util::milli_seconds_since_unix_epoch,
+    Client, KeyValueStore, Session,
 };
-use regex::Regex;
+use namespaces::{CompiledNamespaces, NamespacedEventHandler};
+pub use provisioning::{ProvisioningConfig, VirtualUserProfile};
 use ruma::{
     api::{
         appservice::{
+            event::push_events,
             query::{query_room_alias::v1 as query_room, query_user_id::v1 as query_user},
+            thirdparty::get_protocol::v1 as get_protocol,
             Registration,
         },
-        client::r0::account::register,
+        client::{
+            error::ErrorKind,
+            r0::{account::register, alias::create_alias},
+        },
     },
-    assign, identifiers, DeviceId, ServerName, UserId,
+    assign, identifiers,
+    thirdparty::{Location, Protocol, User},
+    DeviceId, MilliSecondsSinceUnixEpoch, MxcUri, RoomAliasId, RoomId, ServerName, UserId,
 };
-use serde::de::DeserializeOwned;
-use tracing::info;
+#[cfg(feature = "encryption")]
+use ruma::{
+    events::{room::member::MembershipState, AnyRoomEvent},
+    serde::Raw,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, warn};
+pub use webserver::HttpService;
 
+mod encryption;
 mod error;
 pub mod event_handler;
+mod namespaces;
+mod provisioning;
 mod webserver;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -121,6 +150,67 @@ pub type Host = String;
 pub type Port = u16;
 
 const USER_KEY: &[u8] = b"appservice.users.";
+const DEVICE_ID_KEY: &[u8] = b"appservice.device_id";
+const VIRTUAL_USER_DEVICE_ID_KEY: &[u8] = b"appservice.device_ids.";
+const TRANSACTIONS_NAMESPACE: &str = "appservice.transactions";
+
+/// The number of completed transactions that are remembered to ignore retries
+/// of them, the homeserver only retries the most recent transaction so this
+/// doesn't need to be large.
+const MAX_COMPLETED_TRANSACTIONS: usize = 100;
+
+/// The processing state of a transaction, as persisted in the state store.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TransactionState {
+    /// We started to process the transaction but didn't finish yet, or the
+    /// process crashed while doing so.
+    Started,
+    /// The transaction was fully processed.
+    Completed,
+}
+
+/// The persisted record of a transaction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TransactionRecord {
+    state: TransactionState,
+    /// When the state of the transaction last changed.
+    updated_at: MilliSecondsSinceUnixEpoch,
+    /// The users whose clients already processed the transaction, they are
+    /// skipped if the transaction is processed again.
+    #[serde(default)]
+    processed_by: BTreeSet<Box<UserId>>,
+}
+
+impl TransactionRecord {
+    fn new() -> Self {
+        Self {
+            state: TransactionState::Started,
+            updated_at: milli_seconds_since_unix_epoch(),
+            processed_by: BTreeSet::new(),
+        }
+    }
+
+    fn set_state(&mut self, state: TransactionState) {
+        self.state = state;
+        self.updated_at = milli_seconds_since_unix_epoch();
+    }
+}
+
+/// Removes the lock of a transaction from [`AppService::transactions`] when
+/// it's dropped, unless other retries of the transaction are still waiting for
+/// it.
+struct TransactionLockCleanup<'a> {
+    transactions: &'a DashMap<String, Arc<Mutex<()>>>,
+    txn_id: &'a str,
+}
+
+impl Drop for TransactionLockCleanup<'_> {
+    fn drop(&mut self) {
+        // One reference is held by the map and one by the caller that is
+        // about to release the lock.
+        self.transactions.remove_if(self.txn_id, |_, lock| Arc::strong_count(lock) <= 2);
+    }
+}
 
 /// AppService Registration
 ///
@@ -207,6 +297,8 @@ pub struct AppService {
     registration: Arc<AppServiceRegistration>,
     clients: Arc<DashMap<Localpart, Client>>,
     event_handler: event_handler::EventHandler,
+    namespaces: Arc<CompiledNamespaces>,
+    transactions: Arc<DashMap<String, Arc<Mutex<()>>>>,
 }
 
 impl AppService {
@@ -251,13 +343,22 @@ impl AppService {
     ) -> Result<Self> {
         let homeserver_url = homeserver_url.try_into()?;
         let server_name = server_name.try_into()?;
+        let namespaces = Arc::new(CompiledNamespaces::new(&registration.namespaces)?);
         let registration = Arc::new(registration);
         let clients = Arc::new(DashMap::new());
         let sender_localpart = registration.sender_localpart.clone();
         let event_handler = event_handler::EventHandler::default();
+        let transactions = Arc::new(DashMap::new());
 
-        let appservice =
-            AppService { homeserver_url, server_name, registration, clients, event_handler };
+        let appservice = AppService {
+            homeserver_url,
+            server_name,
+            registration,
+            clients,
+            event_handler,
+            namespaces,
+            transactions,
+        };
 
         // we create and cache the [`MainUser`] by default
         appservice.create_and_cache_client(&sender_localpart, client_config).await?;
@@ -278,7 +379,9 @@ impl AppService {
     ///
     /// Note that if you want to do actions like joining rooms with a virtual
     /// user it needs to be registered first. `Self::register_virtual_user()`
-    /// can be used for that purpose.
+    /// can be used for that purpose. If the `encryption` feature is enabled,
+    /// a device is created for the user the first time its client is created,
+    /// so the user needs to be registered before that.
     ///
     /// # Arguments
     ///
@@ -298,17 +401,26 @@ impl AppService {
     ///
     /// Since this method is a singleton follow-up calls with different
     /// [`ClientConfig`]s will be ignored.
+    ///
+    /// Returns [`Error::UserNotInNamespace`] if the user isn't covered by the
+    /// `users` namespaces of the [`AppServiceRegistration`].
     pub async fn virtual_user_client_with_config(
         &self,
         localpart: impl AsRef<str>,
         config: ClientConfig,
     ) -> Result<Client> {
-        // TODO: check if localpart is covered by namespace?
         let localpart = localpart.as_ref();
 
         let client = if let Some(client) = self.clients.get(localpart) {
             client.clone()
         } else {
+            if localpart != self.registration.sender_localpart {
+                let user_id = UserId::parse_with_server_name(localpart, &self.server_name)?;
+                if !self.is_user_id_in_namespace(user_id.as_str()) {
+                    return Err(Error::UserNotInNamespace(user_id));
+                }
+            }
+
             self.create_and_cache_client(localpart, config).await?
         };
 
@@ -322,9 +434,19 @@ impl AppService {
     ) -> Result<Client> {
         let user_id = UserId::parse_with_server_name(localpart, &self.server_name)?;
 
+        // Every user needs its own device for end-to-end encryption, so the
+        // identity and the device need to be asserted for all users, including
+        // the [`MainUser`]
+        #[cfg(feature = "encryption")]
+        let config = {
+            let request_config = config.get_request_config().assert_identity().assert_device();
+            config.request_config(request_config)
+        };
+
         // The `as_token` in the `Session` maps to the [`MainUser`]
         // (`sender_localpart`) by default, so we don't need to assert identity
         // in that case
+        #[cfg(not(feature = "encryption"))]
         let config = if localpart != self.registration.sender_localpart {
             let request_config = config.get_request_config().assert_identity();
             config.request_config(request_config)
@@ -338,8 +460,7 @@ impl AppService {
         let session = Session {
             access_token: self.registration.as_token.clone(),
             user_id: user_id.clone(),
-            // TODO: expose & proper E2EE
-            device_id: DeviceId::new(),
+            device_id: self.client_device_id(&client, &user_id).await?,
         };
 
         client.restore_login(session).await?;
@@ -348,6 +469,89 @@ impl AppService {
         Ok(client)
     }
 
+    /// Get the device ID the given [`Client`] of the given user should use.
+    ///
+    /// If the `encryption` feature is enabled, the device is created on the
+    /// homeserver first, since only existing devices can be asserted and
+    /// receive to-device events. The device ID is persisted in the state store
+    /// of the client, so the client keeps its device, and thus its end-to-end
+    /// encryption keys, as long as a persistent store is used.
+    ///
+    /// The device IDs of [`VirtualUser`]s are additionally persisted in the
+    /// state store of the [`MainUser`]'s [`Client`], this lets us restore their
+    /// clients when we receive to-device events for them, see
+    /// [`Self::receive_transaction_with_encryption_data()`].
+    async fn client_device_id(&self, client: &Client, user_id: &UserId) -> Result<Box<DeviceId>> {
+        let is_main_user = user_id.localpart() == self.registration.sender_localpart;
+
+        let device_id = match client.store().get_custom_value(DEVICE_ID_KEY).await? {
+            Some(device_id) => String::from_utf8_lossy(&device_id).as_ref().into(),
+            None => {
+                let persisted_device_id = if is_main_user {
+                    None
+                } else {
+                    self.virtual_user_device_id(user_id.localpart()).await?
+                };
+
+                let device_id = match persisted_device_id {
+                    Some(device_id) => device_id,
+                    None => self.create_device(client, user_id).await?,
+                };
+
+                client
+                    .store()
+                    .set_custom_value(DEVICE_ID_KEY, device_id.as_str().as_bytes().to_vec())
+                    .await?;
+
+                device_id
+            }
+        };
+
+        if !is_main_user {
+            self.get_cached_client(None)?
+                .store()
+                .set_custom_value(
+                    &[VIRTUAL_USER_DEVICE_ID_KEY, user_id.localpart().as_bytes()].concat(),
+                    device_id.as_str().as_bytes().to_vec(),
+                )
+                .await?;
+        }
+
+        Ok(device_id)
+    }
+
+    /// Create a new device for the given user.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    async fn create_device(&self, client: &Client, user_id: &UserId) -> Result<Box<DeviceId>> {
+        #[cfg(feature = "encryption")]
+        let device_id = client
+            .create_appservice_device(&self.registration.as_token, user_id, None)
+            .await?
+            .device_id;
+
+        #[cfg(not(feature = "encryption"))]
+        let device_id = DeviceId::new();
+
+        Ok(device_id)
+    }
+
+    /// Get the device ID of the given [`VirtualUser`] as persisted in the state
+    /// store of the [`MainUser`]'s [`Client`].
+    ///
+    /// Returns `None` if the [`MainUser`]'s client doesn't exist yet or if we
+    /// never created a client for the user.
+    async fn virtual_user_device_id(&self, localpart: &str) -> Result<Option<Box<DeviceId>>> {
+        let client = match self.get_cached_client(None) {
+            Ok(client) => client,
+            Err(_) => return Ok(None),
+        };
+
+        let key = [VIRTUAL_USER_DEVICE_ID_KEY, localpart.as_bytes()].concat();
+        let device_id = client.store().get_custom_value(&key).await?;
+
+        Ok(device_id.map(|d| String::from_utf8_lossy(&d).as_ref().into()))
+    }
+
     /// Get cached [`Client`]
     ///
     /// Will return the client for the given `localpart` if previously
@@ -392,6 +596,23 @@ impl AppService {
         Ok(self)
     }
 
+    /// Same as [`Self::register_event_handler()`] but the event handler is
+    /// only called for events that touch the namespaces of the
+    /// [`AppServiceRegistration`].
+    ///
+    /// An event touches the namespaces if its `sender` or `state_key` is in
+    /// the `users` namespaces, or if the room it was sent in is in the `rooms`
+    /// namespaces or has a canonical alias in the `aliases` namespaces.
+    pub async fn register_namespaced_event_handler<Ev, Ctx, H>(&self, handler: H) -> Result<&Self>
+    where
+        Ev: SyncEvent + DeserializeOwned + Send + 'static,
+        H: EventHandler<Ev, Ctx>,
+        <H::Future as Future>::Output: EventHandlerResult,
+    {
+        let handler = NamespacedEventHandler { handler, namespaces: self.namespaces.clone() };
+        self.register_event_handler(handler).await
+    }
+
     /// Convenience wrapper around [`Client::register_event_handler_context`]
     /// attaches the event handler context to the [`MainUser`]'s [`Client`].
     pub fn register_event_handler_context<T>(&self, ctx: T) -> Result<&Self>
@@ -448,18 +669,106 @@ impl AppService {
         *self.event_handler.rooms.lock().await = Some(handler);
     }
 
+    /// Register a responder for queries about the third-party protocols the
+    /// application service bridges to.
+    ///
+    /// Return `None` if the protocol isn't supported.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/protocol/{protocol}](https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartyprotocolprotocol).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_protocol_query(Box::new(|appservice, req| Box::pin(async move {
+    ///     println!("Got request for {}", req.protocol);
+    ///     None
+    /// })));
+    /// # }
+    /// ```
+    pub async fn register_protocol_query(
+        &self,
+        handler: AppserviceFn<get_protocol::IncomingRequest, Option<Protocol>>,
+    ) {
+        *self.event_handler.protocols.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about third-party users, either by
+    /// protocol and fields or by Matrix user ID.
+    ///
+    /// Returning no users answers the query with `404 Not Found`.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/user/{protocol}](https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartyuserprotocol)
+    /// and [GET /_matrix/app/v1/thirdparty/user](https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartyuser).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::{AppService, event_handler::ThirdPartyUserQuery};
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_thirdparty_user_query(Box::new(|appservice, query| Box::pin(async move {
+    ///     match query {
+    ///         ThirdPartyUserQuery::Protocol(req) => println!("Got request for {}", req.protocol),
+    ///         ThirdPartyUserQuery::UserId(req) => println!("Got request for {}", req.userid),
+    ///     }
+    ///     Vec::new()
+    /// })));
+    /// # }
+    /// ```
+    pub async fn register_thirdparty_user_query(
+        &self,
+        handler: AppserviceFn<ThirdPartyUserQuery, Vec<User>>,
+    ) {
+        *self.event_handler.thirdparty_users.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about third-party locations, either by
+    /// protocol and fields or by room alias.
+    ///
+    /// Returning no locations answers the query with `404 Not Found`.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/location/{protocol}](https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartylocationprotocol)
+    /// and [GET /_matrix/app/v1/thirdparty/location](https://spec.matrix.org/unstable/application-service-api/#get_matrixappv1thirdpartylocation).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::{AppService, event_handler::ThirdPartyLocationQuery};
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_thirdparty_location_query(Box::new(|appservice, query| {
+    ///     Box::pin(async move {
+    ///         match query {
+    ///             ThirdPartyLocationQuery::Protocol(req) => println!("Protocol {}", req.protocol),
+    ///             ThirdPartyLocationQuery::Alias(req) => println!("Alias {}", req.alias),
+    ///         }
+    ///         Vec::new()
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_thirdparty_location_query(
+        &self,
+        handler: AppserviceFn<ThirdPartyLocationQuery, Vec<Location>>,
+    ) {
+        *self.event_handler.thirdparty_locations.lock().await = Some(handler);
+    }
+
     /// Register a virtual user by sending a [`register::Request`] to the
     /// homeserver
     ///
     /// # Arguments
     ///
     /// * `localpart` - The localpart of the user to register. Must be covered
-    ///   by the namespaces in the [`Registration`] in order to succeed.
+    ///   by an exclusive `users` namespace in the [`Registration`], otherwise
+    ///   [`Error::UserNotInExclusiveNamespace`] is returned.
     ///
     /// # Returns
     /// This function may return a UIAA response, which should be checked for
     /// with [`Error::uiaa_response()`].
     pub async fn register_virtual_user(&self, localpart: impl AsRef<str>) -> Result<()> {
+        let user_id = UserId::parse_with_server_name(localpart.as_ref(), &self.server_name)?;
+        if !self.namespaces.user_id_matches(user_id.as_str(), true) {
+            return Err(Error::UserNotInExclusiveNamespace(user_id));
+        }
+
         if self.is_user_registered(localpart.as_ref()).await? {
             return Ok(());
         }
@@ -469,12 +778,107 @@ impl AppService {
         });
 
         let client = self.get_cached_client(None)?;
-        client.register(request).await?;
+        match client.register(request).await {
+            Ok(_) => {}
+            // the user was registered before, e.g. with a different store
+            Err(e) if e.client_api_error_kind() == Some(&ErrorKind::UserInUse) => {
+                debug!("Virtual user {} is already registered", localpart.as_ref());
+            }
+            Err(e) => return Err(e.into()),
+        }
         self.set_user_registered(localpart.as_ref()).await?;
 
         Ok(())
     }
 
+    /// Provision a batch of virtual users
+    ///
+    /// Registers the users, sets their profiles and joins them to the rooms of
+    /// the [`ProvisioningConfig`]. Up to [`ProvisioningConfig::concurrency()`]
+    /// users are provisioned concurrently. The avatar of the config is only
+    /// uploaded once and the resulting MXC URI is reused for all users.
+    ///
+    /// Provisioning is idempotent, users that are already registered are
+    /// skipped during registration but still get their profile and rooms
+    /// updated.
+    ///
+    /// # Returns
+    ///
+    /// The localparts and [`Client`]s of the provisioned users, or the error
+    /// that occurred while provisioning the respective user. An error is only
+    /// returned if uploading the avatar failed.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk_appservice::{AppService, ProvisioningConfig, VirtualUserProfile};
+    /// # async fn run(appservice: AppService) -> matrix_sdk_appservice::Result<()> {
+    /// let users = (0..1000).map(|i| {
+    ///     VirtualUserProfile::new(format!("_appservice_ghost_{}", i))
+    ///         .displayname(format!("Ghost {}", i))
+    /// });
+    ///
+    /// for (localpart, result) in
+    ///     appservice.provision_virtual_users(users, ProvisioningConfig::new()).await?
+    /// {
+    ///     if let Err(e) = result {
+    ///         println!("Failed to provision {}: {}", localpart, e);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn provision_virtual_users(
+        &self,
+        users: impl IntoIterator<Item = VirtualUserProfile>,
+        config: ProvisioningConfig,
+    ) -> Result<Vec<(String, Result<Client>)>> {
+        let avatar_url = match &config.avatar {
+            Some((content_type, data)) => {
+                let client = self.get_cached_client(None)?;
+                Some(client.upload(content_type, &mut data.as_slice()).await?.content_uri)
+            }
+            None => None,
+        };
+
+        let results = stream::iter(users)
+            .map(|user| self.provision_virtual_user(user, avatar_url.as_deref(), &config.rooms))
+            .buffer_unordered(config.concurrency)
+            .collect()
+            .await;
+
+        Ok(results)
+    }
+
+    async fn provision_virtual_user(
+        &self,
+        user: VirtualUserProfile,
+        avatar_url: Option<&MxcUri>,
+        rooms: &[Box<RoomId>],
+    ) -> (String, Result<Client>) {
+        let result: Result<Client> = async {
+            self.register_virtual_user(&user.localpart).await?;
+            let client = self.virtual_user_client(&user.localpart).await?;
+
+            if let Some(displayname) = &user.displayname {
+                client.set_display_name(Some(displayname.as_str())).await?;
+            }
+
+            if let Some(avatar_url) = avatar_url {
+                client.set_avatar_url(Some(avatar_url)).await?;
+            }
+
+            for room_id in rooms {
+                client.join_room_by_id(room_id).await?;
+            }
+
+            Ok(client)
+        }
+        .await;
+
+        (user.localpart, result)
+    }
+
     /// Add the given localpart to the database of registered localparts.
     async fn set_user_registered(&self, localpart: impl AsRef<str>) -> Result<()> {
         let client = self.get_cached_client(None)?;
@@ -498,6 +902,368 @@ impl AppService {
         Ok(registered)
     }
 
+    /// Process a [transaction] received from the homeserver
+    ///
+    /// Homeservers retry transactions until they get a successful response,
+    /// so the same transaction might be received multiple times. The ids of
+    /// processed transactions are persisted in the state store of the
+    /// [`MainUser`]'s [`Client`] and replayed transactions are ignored, this
+    /// makes sure that event handlers see the events of a transaction only
+    /// once.
+    ///
+    /// If the process crashed while a transaction was being processed, the
+    /// transaction is processed again when the homeserver retries it. Event
+    /// handlers are called again in that case, so they might see some events
+    /// of an interrupted transaction twice.
+    ///
+    /// Custom webservers should pass transactions to this method instead of
+    /// using [`Client::receive_transaction()`] directly.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction` - The incoming transaction received from the homeserver.
+    ///
+    /// [transaction]: https://matrix.org/docs/spec/application_service/r0.1.2#put-matrix-app-v1-transactions-txnid
+    pub async fn receive_transaction(
+        &self,
+        transaction: push_events::v1::IncomingRequest,
+    ) -> Result<()> {
+        self.receive_transaction_with_encryption_data(transaction, EncryptionData::default()).await
+    }
+
+    /// Same as [`Self::receive_transaction()`] but also processes the
+    /// end-to-end encryption related data the homeserver pushed as part of
+    /// the transaction.
+    ///
+    /// If the `encryption` feature is enabled, the to-device events, one-time
+    /// key counts and unused fallback key types are routed to the cached
+    /// [`Client`] of the user and device they belong to. Clients of
+    /// [`VirtualUser`]s that receive to-device events but aren't cached, e.g.
+    /// after a restart, are restored with the default [`ClientConfig`] and
+    /// their persisted device ID. If that isn't possible the transaction isn't
+    /// completed, so the homeserver retries it. The room events are
+    /// passed to the [`MainUser`]'s client and to the clients of the users
+    /// that are members of the room, and the device list changes are passed to
+    /// all cached clients. This lets every [`VirtualUser`] decrypt room events
+    /// and send encrypted messages using [`room::Joined::send()`] with its own
+    /// device.
+    ///
+    /// If processing the transaction fails for one of the clients, the clients
+    /// that already processed it are skipped when the homeserver retries the
+    /// transaction.
+    ///
+    /// The data is ignored if the `encryption` feature isn't enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction` - The incoming transaction received from the homeserver.
+    ///
+    /// * `encryption_data` - The end-to-end encryption related data, it needs
+    ///   to be deserialized from the JSON body of the transaction.
+    ///
+    /// [`room::Joined::send()`]: matrix_sdk::room::Joined::send
+    pub async fn receive_transaction_with_encryption_data(
+        &self,
+        transaction: push_events::v1::IncomingRequest,
+        encryption_data: EncryptionData,
+    ) -> Result<()> {
+        let txn_id = transaction.txn_id.to_string();
+
+        // Retries of a transaction can arrive while we're still processing
+        // the original one, make sure they wait for it to finish.
+        let lock = self.transactions.entry(txn_id.clone()).or_default().clone();
+        let _cleanup = TransactionLockCleanup { transactions: &self.transactions, txn_id: &txn_id };
+        let _guard = lock.lock().await;
+
+        let records = self.transaction_records()?;
+
+        let mut record = match records.get(&txn_id).await? {
+            Some(record) if record.state == TransactionState::Completed => {
+                debug!(txn_id = txn_id.as_str(), "Ignoring an already processed transaction");
+                return Ok(());
+            }
+            Some(record) => {
+                // The changes of the transaction might have been saved before
+                // the event handlers were called, so it's processed again by
+                // all the clients that didn't finish processing it.
+                warn!(txn_id = txn_id.as_str(), "Processing an interrupted transaction again");
+                record
+            }
+            None => {
+                let record = TransactionRecord::new();
+                records.set(&txn_id, &record).await?;
+                record
+            }
+        };
+
+        self.process_transaction(&txn_id, &records, &mut record, transaction, encryption_data)
+            .await?;
+
+        record.set_state(TransactionState::Completed);
+        records.set(&txn_id, &record).await?;
+
+        self.prune_transaction_records(&records).await?;
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    async fn process_transaction(
+        &self,
+        _: &str,
+        _: &KeyValueStore<String, TransactionRecord>,
+        _: &mut TransactionRecord,
+        transaction: push_events::v1::IncomingRequest,
+        _: EncryptionData,
+    ) -> Result<()> {
+        let client = self.get_cached_client(None)?;
+        client.receive_transaction(transaction).await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    async fn process_transaction(
+        &self,
+        txn_id: &str,
+        records: &KeyValueStore<String, TransactionRecord>,
+        record: &mut TransactionRecord,
+        transaction: push_events::v1::IncomingRequest,
+        encryption_data: EncryptionData,
+    ) -> Result<()> {
+        let EncryptionData {
+            to_device,
+            device_lists,
+            mut device_one_time_keys_count,
+            mut device_unused_fallback_key_types,
+        } = encryption_data;
+
+        let mut to_device_events: BTreeMap<_, Vec<_>> = BTreeMap::new();
+
+        for event in to_device {
+            match event.deserialize_as::<ToDeviceRecipient>() {
+                Ok(recipient) => to_device_events
+                    .entry((recipient.to_user_id, recipient.to_device_id))
+                    .or_default()
+                    .push(event),
+                Err(e) => warn!(error =? e, "Ignoring a to-device event without a recipient"),
+            }
+        }
+
+        self.restore_virtual_user_clients(to_device_events.keys()).await?;
+
+        // The [`MainUser`] gets all the events of the transaction and processes
+        // them first, so its room state can be used to find out which rooms the
+        // [`VirtualUser`]s are in.
+        let main_client = self.get_cached_client(None)?;
+        let virtual_clients: Vec<Client> = self
+            .clients
+            .iter()
+            .filter(|c| c.key() != &self.registration.sender_localpart)
+            .map(|c| c.value().clone())
+            .collect();
+        let clients = std::iter::once((main_client.clone(), true))
+            .chain(virtual_clients.into_iter().map(|client| (client, false)));
+
+        for (client, is_main_client) in clients {
+            let (user_id, device_id) = match client.session().await {
+                Some(session) => (session.user_id, session.device_id),
+                None => continue,
+            };
+
+            let to_device =
+                to_device_events.remove(&(user_id.clone(), device_id.clone())).unwrap_or_default();
+            let one_time_keys_count = device_one_time_keys_count
+                .get_mut(&user_id)
+                .and_then(|devices| devices.remove(&device_id))
+                .unwrap_or_default();
+            let unused_fallback_key_types = device_unused_fallback_key_types
+                .get_mut(&user_id)
+                .and_then(|devices| devices.remove(&device_id));
+
+            if record.processed_by.contains(&user_id) {
+                continue;
+            }
+
+            let events = if is_main_client {
+                transaction.events.clone()
+            } else {
+                Self::room_events_of_member(&main_client, &user_id, &transaction.events).await?
+            };
+
+            let nothing_to_process = events.is_empty()
+                && to_device.is_empty()
+                && device_lists.changed.is_empty()
+                && device_lists.left.is_empty()
+                && one_time_keys_count.is_empty()
+                && unused_fallback_key_types.is_none();
+
+            if !nothing_to_process {
+                client
+                    .receive_transaction_with_encryption(
+                        txn_id,
+                        events,
+                        to_device,
+                        device_lists.clone(),
+                        one_time_keys_count,
+                        unused_fallback_key_types,
+                    )
+                    .await?;
+            }
+
+            record.processed_by.insert(user_id);
+            records.set(&txn_id.to_owned(), record).await?;
+        }
+
+        for ((user_id, device_id), events) in to_device_events {
+            warn!(
+                user_id = user_id.as_str(),
+                device_id = device_id.as_str(),
+                count = events.len(),
+                "Dropping to-device events for a device that doesn't belong to us"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Restore the [`Client`]s of the [`VirtualUser`]s that should receive
+    /// to-device events but aren't cached, e.g. because the appservice was
+    /// restarted.
+    ///
+    /// The clients are restored with the default [`ClientConfig`] and the
+    /// device ID that was persisted when they were first created. Recipients
+    /// outside of our `users` namespaces and devices other than the persisted
+    /// one are skipped, their events are dropped. If the device of a user in
+    /// our namespaces is unknown, an error is returned so the transaction is
+    /// retried by the homeserver instead of losing the events.
+    #[cfg(feature = "encryption")]
+    async fn restore_virtual_user_clients(
+        &self,
+        recipients: impl Iterator<Item = &(Box<UserId>, Box<DeviceId>)>,
+    ) -> Result<()> {
+        for (user_id, device_id) in recipients {
+            let localpart = user_id.localpart();
+
+            if self.clients.contains_key(localpart)
+                || user_id.server_name() != &*self.server_name
+                || !self.is_user_id_in_namespace(user_id.as_str())
+            {
+                continue;
+            }
+
+            match self.virtual_user_device_id(localpart).await? {
+                Some(persisted) if persisted == *device_id => {
+                    debug!(
+                        user_id = user_id.as_str(),
+                        device_id = device_id.as_str(),
+                        "Restoring the client of a virtual user to receive to-device events"
+                    );
+                    self.virtual_user_client(localpart).await?;
+                }
+                Some(_) => {}
+                None => return Err(Error::NoClientForDevice(user_id.clone(), device_id.clone())),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the events of the rooms the given user is a member of.
+    ///
+    /// The user is considered to be a member of a room if the given events
+    /// contain a membership change of the user in the room, or if the user
+    /// joined or was invited to the room according to the room state of the
+    /// [`MainUser`]'s [`Client`].
+    #[cfg(feature = "encryption")]
+    async fn room_events_of_member(
+        main_client: &Client,
+        user_id: &UserId,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Result<Vec<Raw<AnyRoomEvent>>> {
+        #[derive(Deserialize)]
+        struct EventInfo {
+            room_id: Box<RoomId>,
+            #[serde(rename = "type")]
+            event_type: String,
+            state_key: Option<String>,
+        }
+
+        let infos: Vec<_> = events.iter().map(|e| e.deserialize_as::<EventInfo>().ok()).collect();
+
+        let mut is_member: BTreeMap<Box<RoomId>, bool> = infos
+            .iter()
+            .flatten()
+            .filter(|info| {
+                info.event_type == "m.room.member"
+                    && info.state_key.as_deref() == Some(user_id.as_str())
+            })
+            .map(|info| (info.room_id.clone(), true))
+            .collect();
+
+        let mut member_events = Vec::new();
+
+        for (event, info) in events.iter().zip(infos) {
+            let room_id = match info {
+                Some(info) => info.room_id,
+                None => continue,
+            };
+
+            let member = match is_member.get(&room_id) {
+                Some(member) => *member,
+                None => {
+                    let member = match main_client.get_room(&room_id) {
+                        Some(room) => room.get_member_no_sync(user_id).await?,
+                        None => None,
+                    };
+                    let member = member.map_or(false, |m| {
+                        matches!(m.membership(), MembershipState::Join | MembershipState::Invite)
+                    });
+                    is_member.insert(room_id, member);
+                    member
+                }
+            };
+
+            if member {
+                member_events.push(event.clone());
+            }
+        }
+
+        Ok(member_events)
+    }
+
+    /// The records of the received transactions, persisted in the state store
+    /// of the [`MainUser`]'s [`Client`].
+    fn transaction_records(&self) -> Result<KeyValueStore<String, TransactionRecord>> {
+        let client = self.get_cached_client(None)?;
+        Ok(client.store().key_value_store(TRANSACTIONS_NAMESPACE))
+    }
+
+    /// Forget the oldest completed transactions if more than
+    /// [`MAX_COMPLETED_TRANSACTIONS`] are remembered.
+    async fn prune_transaction_records(
+        &self,
+        records: &KeyValueStore<String, TransactionRecord>,
+    ) -> Result<()> {
+        let mut completed: Vec<_> = records
+            .entries()
+            .await?
+            .into_iter()
+            .filter(|(_, record)| record.state == TransactionState::Completed)
+            .collect();
+
+        if completed.len() > MAX_COMPLETED_TRANSACTIONS {
+            completed.sort_by_key(|(_, record)| record.updated_at);
+
+            let excess = completed.len() - MAX_COMPLETED_TRANSACTIONS;
+            for (txn_id, _) in &completed[..excess] {
+                records.remove(txn_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get the AppService [registration]
     ///
     /// [registration]: https://matrix.org/docs/spec/application_service/r0.1.2#registration
@@ -514,16 +1280,63 @@ impl AppService {
 
     /// Check if given `user_id` is in any of the [`AppServiceRegistration`]'s
     /// `users` namespaces
+    pub fn is_user_id_in_namespace(&self, user_id: impl AsRef<str>) -> bool {
+        self.namespaces.user_id_matches(user_id.as_ref(), false)
+    }
+
+    /// Check if given `user_id` is in any of the [`AppServiceRegistration`]'s
+    /// `users` namespaces
+    ///
+    /// The namespaces are compiled when the [`AppService`] is created, so this
+    /// never fails.
+    #[deprecated(since = "0.2.0", note = "use is_user_id_in_namespace() instead")]
     pub fn user_id_is_in_namespace(&self, user_id: impl AsRef<str>) -> Result<bool> {
-        for user in &self.registration.namespaces.users {
-            // TODO: precompile on AppService construction
-            let re = Regex::new(&user.regex)?;
-            if re.is_match(user_id.as_ref()) {
-                return Ok(true);
-            }
+        Ok(self.is_user_id_in_namespace(user_id))
+    }
+
+    /// Check if given `alias` is in any of the [`AppServiceRegistration`]'s
+    /// `aliases` namespaces
+    pub fn alias_is_in_namespace(&self, alias: impl AsRef<str>) -> bool {
+        self.namespaces.alias_matches(alias.as_ref(), false)
+    }
+
+    /// Check if given `room_id` is in any of the [`AppServiceRegistration`]'s
+    /// `rooms` namespaces
+    pub fn room_id_is_in_namespace(&self, room_id: impl AsRef<str>) -> bool {
+        self.namespaces.room_id_matches(room_id.as_ref(), false)
+    }
+
+    /// Create the given room alias with the [`MainUser`]'s [`Client`]
+    ///
+    /// The alias must be covered by an exclusive `aliases` namespace of the
+    /// [`AppServiceRegistration`], otherwise
+    /// [`Error::AliasNotInExclusiveNamespace`] is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The room alias to create.
+    /// * `room_id` - The room the alias should point to.
+    pub async fn create_room_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
+        if !self.namespaces.alias_matches(alias.as_str(), true) {
+            return Err(Error::AliasNotInExclusiveNamespace(alias.to_owned()));
         }
 
-        Ok(false)
+        let client = self.get_cached_client(None)?;
+        client.send(create_alias::Request::new(alias, room_id), None).await?;
+
+        Ok(())
+    }
+
+    /// Returns a [`HttpService`] implementing the appservice API
+    ///
+    /// The service can be mounted into any hyper-based webserver, e.g. as the
+    /// fallback service of an axum router. Requests to paths that aren't part
+    /// of the [application-service-specific routes] are answered with
+    /// `404 Not Found`.
+    ///
+    /// [application-service-specific routes]: https://spec.matrix.org/unstable/application-service-api/#legacy-routes
+    pub fn service(&self) -> HttpService {
+        HttpService::new(self.clone())
     }
 
     /// Returns a [`warp::Filter`] to be used as [`warp::serve()`] route
@@ -544,19 +1357,15 @@ impl AppService {
     ///
     /// This is a blocking call that tries to listen on the provided host and
     /// port
+    #[cfg(feature = "warp")]
+    #[cfg_attr(docs, doc(cfg(feature = "warp")))]
     pub async fn run(&self, host: impl Into<String>, port: impl Into<u16>) -> Result<()> {
         let host = host.into();
         let port = port.into();
-        info!("Starting AppService on {}:{}", &host, &port);
+        tracing::info!("Starting AppService on {}:{}", &host, &port);
 
-        #[cfg(feature = "warp")]
-        {
-            webserver::warp::run_server(self.clone(), host, port).await?;
-            Ok(())
-        }
-
-        #[cfg(not(any(feature = "warp",)))]
-        unreachable!()
+        webserver::warp::run_server(self.clone(), host, port).await?;
+        Ok(())
     }
 }
 
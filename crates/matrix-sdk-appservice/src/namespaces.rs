@@ -0,0 +1,151 @@
+// Copyright 2022 Famedly GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{future::Future, sync::Arc};
+
+use futures_util::future::{FutureExt, Map, OptionFuture};
+use matrix_sdk::event_handler::{EventHandler, EventHandlerData, EventHandlerResult, EventKind};
+use regex::Regex;
+use ruma::api::appservice::{Namespace, Namespaces};
+use serde::Deserialize;
+
+use crate::Result;
+
+/// A namespace of the registration with a precompiled regex.
+#[derive(Debug, Clone)]
+struct CompiledNamespace {
+    exclusive: bool,
+    regex: Regex,
+}
+
+impl CompiledNamespace {
+    fn new(namespace: &Namespace) -> Result<Self> {
+        Ok(Self { exclusive: namespace.exclusive, regex: Regex::new(&namespace.regex)? })
+    }
+}
+
+/// The user, alias and room namespaces of the registration, compiled once when
+/// the [`AppService`](crate::AppService) is constructed.
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledNamespaces {
+    users: Vec<CompiledNamespace>,
+    aliases: Vec<CompiledNamespace>,
+    rooms: Vec<CompiledNamespace>,
+}
+
+impl CompiledNamespaces {
+    pub(crate) fn new(namespaces: &Namespaces) -> Result<Self> {
+        let compile = |namespaces: &[Namespace]| {
+            namespaces.iter().map(CompiledNamespace::new).collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            users: compile(&namespaces.users)?,
+            aliases: compile(&namespaces.aliases)?,
+            rooms: compile(&namespaces.rooms)?,
+        })
+    }
+
+    fn matches(namespaces: &[CompiledNamespace], id: &str, exclusive_only: bool) -> bool {
+        namespaces.iter().any(|n| (n.exclusive || !exclusive_only) && n.regex.is_match(id))
+    }
+
+    pub(crate) fn user_id_matches(&self, user_id: &str, exclusive_only: bool) -> bool {
+        Self::matches(&self.users, user_id, exclusive_only)
+    }
+
+    pub(crate) fn alias_matches(&self, alias: &str, exclusive_only: bool) -> bool {
+        Self::matches(&self.aliases, alias, exclusive_only)
+    }
+
+    pub(crate) fn room_id_matches(&self, room_id: &str, exclusive_only: bool) -> bool {
+        Self::matches(&self.rooms, room_id, exclusive_only)
+    }
+
+    /// Does the given event touch our namespaces, i.e. was it sent by or to a
+    /// user of our namespaces, or was it sent in a room of our namespaces.
+    fn event_matches(&self, data: &EventHandlerData<'_>) -> bool {
+        #[derive(Deserialize)]
+        struct EventIds {
+            sender: Option<String>,
+            state_key: Option<String>,
+        }
+
+        if let Ok(ids) = serde_json::from_str::<EventIds>(data.raw.get()) {
+            let user_matches = |id: &Option<String>| {
+                id.as_deref().map_or(false, |id| self.user_id_matches(id, false))
+            };
+
+            if user_matches(&ids.sender) || user_matches(&ids.state_key) {
+                return true;
+            }
+        }
+
+        if let Some(room) = &data.room {
+            if self.room_id_matches(room.room_id().as_str(), false) {
+                return true;
+            }
+
+            if let Some(alias) = room.canonical_alias() {
+                return self.alias_matches(alias.as_str(), false);
+            }
+        }
+
+        false
+    }
+}
+
+/// An event handler that is only called for events that touch the namespaces
+/// of the registration.
+#[derive(Clone)]
+pub(crate) struct NamespacedEventHandler<H> {
+    pub handler: H,
+    pub namespaces: Arc<CompiledNamespaces>,
+}
+
+/// The result of a [`NamespacedEventHandler`], `None` if the event didn't
+/// touch our namespaces and the handler wasn't called.
+pub(crate) struct NamespacedResult<T>(Option<T>);
+
+impl<T: EventHandlerResult> EventHandlerResult for NamespacedResult<T> {
+    fn print_error(&self, event_type: &str) {
+        if let Some(result) = &self.0 {
+            result.print_error(event_type)
+        }
+    }
+}
+
+type NamespacedFuture<F> = Map<
+    OptionFuture<F>,
+    fn(Option<<F as Future>::Output>) -> NamespacedResult<<F as Future>::Output>,
+>;
+
+impl<Ev, Ctx, H> EventHandler<Ev, Ctx> for NamespacedEventHandler<H>
+where
+    H: EventHandler<Ev, Ctx>,
+{
+    type Future = NamespacedFuture<H::Future>;
+    const ID: (EventKind, &'static str) = H::ID;
+
+    fn handle_event(&self, ev: Ev, data: EventHandlerData<'_>) -> Option<Self::Future> {
+        let future = if self.namespaces.event_matches(&data) {
+            // Context extractors that failed still need to be reported.
+            Some(self.handler.handle_event(ev, data)?)
+        } else {
+            None
+        };
+
+        Some(OptionFuture::from(future).map(NamespacedResult as fn(_) -> _))
+    }
+}
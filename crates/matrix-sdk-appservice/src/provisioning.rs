@@ -0,0 +1,94 @@
+// Copyright 2022 Famedly GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use mime::Mime;
+use ruma::RoomId;
+
+/// The default number of virtual users that are provisioned concurrently.
+const DEFAULT_CONCURRENCY: usize = 10;
+
+/// A virtual user to provision with
+/// [`AppService::provision_virtual_users()`](crate::AppService::provision_virtual_users).
+#[derive(Clone, Debug)]
+pub struct VirtualUserProfile {
+    pub(crate) localpart: String,
+    pub(crate) displayname: Option<String>,
+}
+
+impl VirtualUserProfile {
+    /// Create a new profile for the virtual user with the given `localpart`.
+    pub fn new(localpart: impl Into<String>) -> Self {
+        Self { localpart: localpart.into(), displayname: None }
+    }
+
+    /// Set the display name of the virtual user.
+    pub fn displayname(mut self, displayname: impl Into<String>) -> Self {
+        self.displayname = Some(displayname.into());
+        self
+    }
+}
+
+/// Settings for provisioning a batch of virtual users with
+/// [`AppService::provision_virtual_users()`](crate::AppService::provision_virtual_users).
+///
+/// # Example
+///
+/// ```
+/// # use matrix_sdk_appservice::{ProvisioningConfig, ruma::room_id};
+/// let config = ProvisioningConfig::new()
+///     .concurrency(50)
+///     .avatar(mime::IMAGE_PNG, b"...".to_vec())
+///     .room(room_id!("!bridged:localhost"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProvisioningConfig {
+    pub(crate) concurrency: usize,
+    pub(crate) avatar: Option<(Mime, Vec<u8>)>,
+    pub(crate) rooms: Vec<Box<RoomId>>,
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        Self { concurrency: DEFAULT_CONCURRENCY, avatar: None, rooms: Vec::new() }
+    }
+}
+
+impl ProvisioningConfig {
+    /// Create a new default `ProvisioningConfig`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the maximum number of virtual users that are provisioned
+    /// concurrently, defaults to 10.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set the avatar of all virtual users.
+    ///
+    /// The avatar is uploaded once and the resulting MXC URI is used for all
+    /// virtual users of the batch.
+    pub fn avatar(mut self, content_type: Mime, data: Vec<u8>) -> Self {
+        self.avatar = Some((content_type, data));
+        self
+    }
+
+    /// Add a room all virtual users should join.
+    pub fn room(mut self, room_id: &RoomId) -> Self {
+        self.rooms.push(room_id.to_owned());
+        self
+    }
+}
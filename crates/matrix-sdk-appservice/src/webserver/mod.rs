@@ -1,2 +1,5 @@
+mod service;
 #[cfg(feature = "warp")]
 pub mod warp;
+
+pub use service::HttpService;
@@ -0,0 +1,452 @@
+// Copyright 2022 Famedly GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    task::{Context, Poll},
+};
+
+use http::{
+    header::{AUTHORIZATION, CONTENT_LENGTH},
+    Method, StatusCode,
+};
+use http_body::{Body, Full};
+use matrix_sdk::{
+    bytes::{Buf, BufMut, Bytes},
+    ruma::{
+        self,
+        api::{
+            appservice::{
+                event::push_events::v1 as push_events,
+                query::{query_room_alias::v1 as query_room, query_user_id::v1 as query_user},
+                thirdparty::{
+                    get_location_for_protocol::v1 as get_location_for_protocol,
+                    get_location_for_room_alias::v1 as get_location_for_room_alias,
+                    get_protocol::v1 as get_protocol,
+                    get_user_for_protocol::v1 as get_user_for_protocol,
+                    get_user_for_user_id::v1 as get_user_for_user_id,
+                },
+            },
+            IncomingRequest,
+        },
+    },
+};
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use tracing::{debug, warn};
+
+use crate::{
+    event_handler::{BoxFuture, ThirdPartyLocationQuery, ThirdPartyUserQuery},
+    AppService, EncryptionData, Error, Result,
+};
+
+/// The default maximum size of request bodies, in bytes.
+const DEFAULT_MAX_BODY_SIZE: usize = 20 * 1024 * 1024;
+
+/// The appservice API endpoints served by [`HttpService`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    Transaction,
+    User,
+    Room,
+    Ping,
+    ThirdPartyProtocol,
+    ThirdPartyUserForProtocol,
+    ThirdPartyUserForUserId,
+    ThirdPartyLocationForProtocol,
+    ThirdPartyLocationForAlias,
+}
+
+impl Route {
+    /// Find the route for the given path.
+    ///
+    /// Handles [legacy routes], but the path needs to be relative to the
+    /// location of the appservice, i.e. if it's located on a sub path, that
+    /// sub path must be stripped first. Paths of other routes never match.
+    ///
+    /// [legacy routes]: https://matrix.org/docs/spec/application_service/r0.1.2#legacy-routes
+    pub(crate) fn from_path(path: &str) -> Option<Self> {
+        let path = path.trim_end_matches('/');
+
+        if path == "/_matrix/app/v1/ping" || path == "/_matrix/app/unstable/fi.mau.msc2659/ping" {
+            return Some(Self::Ping);
+        }
+
+        // The legacy routes of transactions, users and rooms have no prefix,
+        // the ones of third-party lookups use the unstable prefix.
+        let (path, legacy_routes, thirdparty_routes) =
+            if let Some(path) = path.strip_prefix("/_matrix/app/v1/") {
+                (path, true, true)
+            } else if let Some(path) = path.strip_prefix("/_matrix/app/unstable/") {
+                (path, false, true)
+            } else {
+                (path.strip_prefix('/')?, true, false)
+            };
+
+        let segments: Vec<&str> = path.split('/').collect();
+
+        if segments.contains(&"") {
+            return None;
+        }
+
+        match segments.as_slice() {
+            ["transactions", _] if legacy_routes => Some(Self::Transaction),
+            ["users", _] if legacy_routes => Some(Self::User),
+            ["rooms", _] if legacy_routes => Some(Self::Room),
+            ["thirdparty", "protocol", _] if thirdparty_routes => Some(Self::ThirdPartyProtocol),
+            ["thirdparty", "user", _] if thirdparty_routes => Some(Self::ThirdPartyUserForProtocol),
+            ["thirdparty", "user"] if thirdparty_routes => Some(Self::ThirdPartyUserForUserId),
+            ["thirdparty", "location", _] if thirdparty_routes => {
+                Some(Self::ThirdPartyLocationForProtocol)
+            }
+            ["thirdparty", "location"] if thirdparty_routes => {
+                Some(Self::ThirdPartyLocationForAlias)
+            }
+            _ => None,
+        }
+    }
+
+    fn method(self) -> Method {
+        match self {
+            Self::Transaction => Method::PUT,
+            Self::Ping => Method::POST,
+            Self::User
+            | Self::Room
+            | Self::ThirdPartyProtocol
+            | Self::ThirdPartyUserForProtocol
+            | Self::ThirdPartyUserForUserId
+            | Self::ThirdPartyLocationForProtocol
+            | Self::ThirdPartyLocationForAlias => Method::GET,
+        }
+    }
+}
+
+/// A [`tower::Service`] implementing the [application service API].
+///
+/// It answers transactions, user, room and third-party queries and pings of
+/// the homeserver, including the legacy routes, and can be mounted into any
+/// hyper-based webserver, e.g. as a fallback service of an axum router.
+/// Requests to paths that aren't part of the application service API are
+/// answered with `404 Not Found`, requests with a body larger than
+/// [`HttpService::max_body_size()`] with `413 Payload Too Large`.
+///
+/// Use [`AppService::service()`] to create it.
+///
+/// [`tower::Service`]: tower_service::Service
+/// [application service API]: https://spec.matrix.org/unstable/application-service-api/
+#[derive(Clone, Debug)]
+pub struct HttpService {
+    appservice: AppService,
+    max_body_size: usize,
+}
+
+impl HttpService {
+    pub(crate) fn new(appservice: AppService) -> Self {
+        Self { appservice, max_body_size: DEFAULT_MAX_BODY_SIZE }
+    }
+
+    /// Set the maximum size of request bodies in bytes, defaults to 20 MiB.
+    ///
+    /// Larger requests are rejected before their body is received.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Handle a request whose body was already collected.
+    pub(crate) async fn handle(&self, request: http::Request<Bytes>) -> http::Response<Bytes> {
+        let route = match Route::from_path(request.uri().path()) {
+            Some(route) => route,
+            None => return error_response(StatusCode::NOT_FOUND, "M_UNRECOGNIZED"),
+        };
+
+        if request.method() != route.method() {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED, "M_UNRECOGNIZED");
+        }
+
+        if !self.is_authorized(&request) {
+            return error_response(StatusCode::UNAUTHORIZED, "M_FORBIDDEN");
+        }
+
+        let result = match route {
+            Route::Transaction => self.transaction(request).await,
+            Route::User => self.user(request).await,
+            Route::Room => self.room(request).await,
+            Route::Ping => Ok(Some(json!({}))),
+            Route::ThirdPartyProtocol => self.protocol(request).await,
+            Route::ThirdPartyUserForProtocol
+            | Route::ThirdPartyUserForUserId
+            | Route::ThirdPartyLocationForProtocol
+            | Route::ThirdPartyLocationForAlias => self.thirdparty(route, request).await,
+        };
+
+        match result {
+            Ok(Some(body)) => json_response(StatusCode::OK, &body),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, "M_NOT_FOUND"),
+            Err(Error::UriPathUnknown | Error::UriEmptyPath) => {
+                error_response(StatusCode::NOT_FOUND, "M_UNRECOGNIZED")
+            }
+            Err(Error::HttpRequest(e)) => {
+                debug!("Received invalid {:?} request: {}", route, e);
+                error_response(StatusCode::BAD_REQUEST, "M_BAD_JSON")
+            }
+            Err(Error::SerdeJson(e)) => {
+                debug!("Received invalid {:?} request: {}", route, e);
+                error_response(StatusCode::BAD_REQUEST, "M_BAD_JSON")
+            }
+            Err(e) => {
+                warn!("Failed to handle {:?} request: {}", route, e);
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN")
+            }
+        }
+    }
+
+    /// Check the `hs_token` given as `access_token` query parameter or, as
+    /// newer homeservers do, in the `Authorization` header.
+    fn is_authorized(&self, request: &http::Request<Bytes>) -> bool {
+        let query = request.uri().query().unwrap_or_default();
+        let query: Vec<(String, String)> =
+            ruma::serde::urlencoded::from_str(query).unwrap_or_default();
+
+        let header_token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        query
+            .iter()
+            .filter(|(key, _)| key == "access_token")
+            .map(|(_, value)| value.as_str())
+            .chain(header_token)
+            .any(|token| self.appservice.compare_hs_token(token))
+    }
+
+    async fn transaction(&self, request: http::Request<Bytes>) -> Result<Option<JsonValue>> {
+        let request = crate::transform_request_path(request)?;
+        let encryption_data: EncryptionData = serde_json::from_slice(request.body())?;
+        let transaction = push_events::IncomingRequest::try_from_http_request(request)?;
+
+        self.appservice
+            .receive_transaction_with_encryption_data(transaction, encryption_data)
+            .await?;
+
+        Ok(Some(json!({})))
+    }
+
+    async fn user(&self, request: http::Request<Bytes>) -> Result<Option<JsonValue>> {
+        if let Some(user_exists) = self.appservice.event_handler.users.lock().await.as_mut() {
+            let request = crate::transform_request_path(request)?;
+            let request = query_user::IncomingRequest::try_from_http_request(request)?;
+
+            return Ok(user_exists(self.appservice.clone(), request).await.then(|| json!({})));
+        }
+
+        Ok(Some(json!({})))
+    }
+
+    async fn room(&self, request: http::Request<Bytes>) -> Result<Option<JsonValue>> {
+        if let Some(room_exists) = self.appservice.event_handler.rooms.lock().await.as_mut() {
+            let request = crate::transform_request_path(request)?;
+            let request = query_room::IncomingRequest::try_from_http_request(request)?;
+
+            return Ok(room_exists(self.appservice.clone(), request).await.then(|| json!({})));
+        }
+
+        Ok(Some(json!({})))
+    }
+
+    async fn protocol(&self, request: http::Request<Bytes>) -> Result<Option<JsonValue>> {
+        let mut handler = self.appservice.event_handler.protocols.lock().await;
+        let get_protocol = match handler.as_mut() {
+            Some(get_protocol) => get_protocol,
+            None => return Ok(None),
+        };
+
+        let request = crate::transform_request_path(request)?;
+        let request = get_protocol::IncomingRequest::try_from_http_request(request)?;
+
+        match get_protocol(self.appservice.clone(), request).await {
+            Some(protocol) => Ok(Some(serde_json::to_value(protocol)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Answer the third-party user and location queries, an empty result is
+    /// answered with `404 Not Found`.
+    async fn thirdparty(
+        &self,
+        route: Route,
+        request: http::Request<Bytes>,
+    ) -> Result<Option<JsonValue>> {
+        let request = crate::transform_request_path(request)?;
+        let event_handler = &self.appservice.event_handler;
+
+        let response = match route {
+            Route::ThirdPartyUserForProtocol | Route::ThirdPartyUserForUserId => {
+                let mut handler = event_handler.thirdparty_users.lock().await;
+                let get_users = match handler.as_mut() {
+                    Some(get_users) => get_users,
+                    None => return Ok(None),
+                };
+
+                let query = if route == Route::ThirdPartyUserForProtocol {
+                    ThirdPartyUserQuery::Protocol(
+                        get_user_for_protocol::IncomingRequest::try_from_http_request(request)?,
+                    )
+                } else {
+                    ThirdPartyUserQuery::UserId(
+                        get_user_for_user_id::IncomingRequest::try_from_http_request(request)?,
+                    )
+                };
+
+                let users = get_users(self.appservice.clone(), query).await;
+                (!users.is_empty()).then(|| serde_json::to_value(users)).transpose()?
+            }
+            _ => {
+                let mut handler = event_handler.thirdparty_locations.lock().await;
+                let get_locations = match handler.as_mut() {
+                    Some(get_locations) => get_locations,
+                    None => return Ok(None),
+                };
+
+                let query = if route == Route::ThirdPartyLocationForProtocol {
+                    ThirdPartyLocationQuery::Protocol(
+                        get_location_for_protocol::IncomingRequest::try_from_http_request(request)?,
+                    )
+                } else {
+                    ThirdPartyLocationQuery::Alias(
+                        get_location_for_room_alias::IncomingRequest::try_from_http_request(
+                            request,
+                        )?,
+                    )
+                };
+
+                let locations = get_locations(self.appservice.clone(), query).await;
+                (!locations.is_empty()).then(|| serde_json::to_value(locations)).transpose()?
+            }
+        };
+
+        Ok(response)
+    }
+}
+
+impl<B> tower_service::Service<http::Request<B>> for HttpService
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    type Response = http::Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+
+            let content_length = parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<u64>().ok());
+
+            let body = if content_length.map_or(false, |l| l > service.max_body_size as u64) {
+                Err(CollectBodyError::TooLarge)
+            } else {
+                collect_body(body, service.max_body_size).await
+            };
+
+            let response = match body {
+                Ok(body) => service.handle(http::Request::from_parts(parts, body)).await,
+                Err(CollectBodyError::TooLarge) => {
+                    debug!(
+                        "Rejecting a request with a body larger than {} bytes",
+                        service.max_body_size
+                    );
+                    error_response(StatusCode::PAYLOAD_TOO_LARGE, "M_TOO_LARGE")
+                }
+                Err(CollectBodyError::Body(e)) => {
+                    let e: Box<dyn StdError + Send + Sync> = e.into();
+                    debug!("Failed to receive request body: {}", e);
+                    error_response(StatusCode::BAD_REQUEST, "M_UNKNOWN")
+                }
+            };
+
+            Ok(response.map(Full::new))
+        })
+    }
+}
+
+/// Errors that can happen while receiving a request body.
+enum CollectBodyError<E> {
+    /// The body is larger than the maximum body size.
+    TooLarge,
+    /// Receiving the body failed.
+    Body(E),
+}
+
+/// Receive the whole body, stopping as soon as it exceeds `max_size` bytes.
+async fn collect_body<B: Body>(
+    body: B,
+    max_size: usize,
+) -> Result<Bytes, CollectBodyError<B::Error>> {
+    if body.size_hint().lower() > max_size as u64 {
+        return Err(CollectBodyError::TooLarge);
+    }
+
+    let mut body = Box::pin(body);
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(CollectBodyError::Body)?;
+
+        if bytes.len().saturating_add(chunk.remaining()) > max_size {
+            return Err(CollectBodyError::TooLarge);
+        }
+
+        bytes.put(chunk);
+    }
+
+    Ok(bytes.into())
+}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    code: u16,
+    errcode: &'static str,
+    message: String,
+}
+
+fn error_response(code: StatusCode, errcode: &'static str) -> http::Response<Bytes> {
+    let message = code.canonical_reason().unwrap_or_default().to_uppercase();
+    json_response(code, &ErrorMessage { code: code.as_u16(), errcode, message })
+}
+
+fn json_response(code: StatusCode, body: &impl Serialize) -> http::Response<Bytes> {
+    let body = serde_json::to_vec(body).expect("JSON serialization of responses can't fail");
+
+    http::Response::builder()
+        .status(code)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .expect("Responses with a valid status and header can't fail to build")
+}
@@ -14,21 +14,10 @@
 
 use std::net::ToSocketAddrs;
 
-use matrix_sdk::{
-    bytes::Bytes,
-    ruma::{
-        self,
-        api::{
-            appservice::query::{
-                query_room_alias::v1 as query_room, query_user_id::v1 as query_user,
-            },
-            IncomingRequest,
-        },
-    },
-};
-use serde::Serialize;
-use warp::{filters::BoxedFilter, path::FullPath, Filter, Rejection, Reply};
+use matrix_sdk::bytes::Bytes;
+use warp::{filters::BoxedFilter, path::Peek, Filter, Rejection, Reply};
 
+use super::service::{HttpService, Route};
 use crate::{AppService, Error, Result};
 
 pub async fn run_server(
@@ -47,85 +36,35 @@ pub async fn run_server(
     }
 }
 
+/// A thin adaptor that passes all requests to appservice API paths on to the
+/// [`HttpService`] and rejects everything else, so other filters can be
+/// combined with it.
+///
+/// Paths are matched against the part of the path that wasn't consumed by
+/// previous filters yet, so the appservice can be located on a sub path.
 pub fn warp_filter(appservice: AppService) -> BoxedFilter<(impl Reply,)> {
-    // TODO: try to use a struct instead of needlessly cloning appservice multiple
-    // times on every request
+    let service = HttpService::new(appservice);
+
     warp::any()
-        .and(filters::transactions(appservice.clone()))
-        .or(filters::users(appservice.clone()))
-        .or(filters::rooms(appservice))
-        .recover(handle_rejection)
+        .and(filters::appservice_path())
+        .and(filters::http_request())
+        .and_then(move |request| {
+            let service = service.clone();
+            async move { Ok::<_, Rejection>(service.handle(request).await) }
+        })
         .boxed()
 }
 
 mod filters {
     use super::*;
 
-    pub fn users(appservice: AppService) -> BoxedFilter<(impl Reply,)> {
-        warp::get()
-            .and(
-                warp::path!("_matrix" / "app" / "v1" / "users" / String)
-                    // legacy route
-                    .or(warp::path!("users" / String))
-                    .unify(),
-            )
-            .and(warp::path::end())
-            .and(common(appservice))
-            .and_then(handlers::user)
-            .boxed()
-    }
-
-    pub fn rooms(appservice: AppService) -> BoxedFilter<(impl Reply,)> {
-        warp::get()
-            .and(
-                warp::path!("_matrix" / "app" / "v1" / "rooms" / String)
-                    // legacy route
-                    .or(warp::path!("rooms" / String))
-                    .unify(),
-            )
-            .and(warp::path::end())
-            .and(common(appservice))
-            .and_then(handlers::room)
-            .boxed()
-    }
-
-    pub fn transactions(appservice: AppService) -> BoxedFilter<(impl Reply,)> {
-        warp::put()
-            .and(
-                warp::path!("_matrix" / "app" / "v1" / "transactions" / String)
-                    // legacy route
-                    .or(warp::path!("transactions" / String))
-                    .unify(),
-            )
-            .and(warp::path::end())
-            .and(common(appservice))
-            .and_then(handlers::transaction)
-            .boxed()
-    }
-
-    fn common(appservice: AppService) -> BoxedFilter<(AppService, http::Request<Bytes>)> {
-        warp::any()
-            .and(filters::valid_access_token(appservice.registration().hs_token.clone()))
-            .map(move || appservice.clone())
-            .and(http_request().and_then(|request| async move {
-                let request = crate::transform_request_path(request).map_err(Error::from)?;
-                Ok::<http::Request<Bytes>, Rejection>(request)
-            }))
-            .boxed()
-    }
-
-    pub fn valid_access_token(token: String) -> BoxedFilter<()> {
-        warp::any()
-            .map(move || token.clone())
-            .and(warp::query::raw())
-            .and_then(|token: String, query: String| async move {
-                let query: Vec<(String, String)> =
-                    ruma::serde::urlencoded::from_str(&query).map_err(Error::from)?;
-
-                if query.into_iter().any(|(key, value)| key == "access_token" && value == token) {
-                    Ok::<(), Rejection>(())
+    pub fn appservice_path() -> BoxedFilter<()> {
+        warp::path::peek()
+            .and_then(|path: Peek| async move {
+                if Route::from_path(&format!("/{}", path.as_str())).is_some() {
+                    Ok(())
                 } else {
-                    Err(warp::reject::custom(Unauthorized))
+                    Err(warp::reject::not_found())
                 }
             })
             .untuple_one()
@@ -138,13 +77,21 @@ mod filters {
         // blocked by https://github.com/seanmonstar/warp/issues/139
         warp::any()
             .and(warp::method())
-            .and(warp::filters::path::full())
-            .and(warp::filters::query::raw())
+            // the service only sees the path relative to the appservice
+            .and(warp::filters::path::peek())
+            // the query is optional, missing access tokens are handled by the service
+            .and(warp::filters::query::raw().or(warp::any().map(String::new)).unify())
             .and(warp::header::headers_cloned())
             .and(warp::body::bytes())
-            .and_then(|method, path: FullPath, query, headers, bytes| async move {
+            .and_then(|method, path: Peek, query: String, headers, bytes| async move {
+                let path_and_query = if query.is_empty() {
+                    format!("/{}", path.as_str())
+                } else {
+                    format!("/{}?{}", path.as_str(), query)
+                };
+
                 let uri = http::uri::Builder::new()
-                    .path_and_query(format!("{}?{}", path.as_str(), query))
+                    .path_and_query(path_and_query)
                     .build()
                     .map_err(Error::from)?;
 
@@ -160,79 +107,3 @@ mod filters {
             })
     }
 }
-
-mod handlers {
-    use super::*;
-
-    pub async fn user(
-        _user_id: String,
-        appservice: AppService,
-        request: http::Request<Bytes>,
-    ) -> Result<impl warp::Reply, Rejection> {
-        if let Some(user_exists) = appservice.event_handler.users.lock().await.as_mut() {
-            let request =
-                query_user::IncomingRequest::try_from_http_request(request).map_err(Error::from)?;
-            return if user_exists(appservice.clone(), request).await {
-                Ok(warp::reply::json(&String::from("{}")))
-            } else {
-                Err(warp::reject::not_found())
-            };
-        }
-        Ok(warp::reply::json(&String::from("{}")))
-    }
-
-    pub async fn room(
-        _room_id: String,
-        appservice: AppService,
-        request: http::Request<Bytes>,
-    ) -> Result<impl warp::Reply, Rejection> {
-        if let Some(room_exists) = appservice.event_handler.rooms.lock().await.as_mut() {
-            let request =
-                query_room::IncomingRequest::try_from_http_request(request).map_err(Error::from)?;
-            return if room_exists(appservice.clone(), request).await {
-                Ok(warp::reply::json(&String::from("{}")))
-            } else {
-                Err(warp::reject::not_found())
-            };
-        }
-        Ok(warp::reply::json(&String::from("{}")))
-    }
-
-    pub async fn transaction(
-        _txn_id: String,
-        appservice: AppService,
-        request: http::Request<Bytes>,
-    ) -> Result<impl warp::Reply, Rejection> {
-        let incoming_transaction: ruma::api::appservice::event::push_events::v1::IncomingRequest =
-            ruma::api::IncomingRequest::try_from_http_request(request).map_err(Error::from)?;
-
-        let client = appservice.get_cached_client(None)?;
-        client.receive_transaction(incoming_transaction).await.map_err(Error::from)?;
-
-        Ok(warp::reply::json(&String::from("{}")))
-    }
-}
-
-#[derive(Debug)]
-struct Unauthorized;
-
-impl warp::reject::Reject for Unauthorized {}
-
-#[derive(Serialize)]
-struct ErrorMessage {
-    code: u16,
-    message: String,
-}
-
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
-    if err.find::<Unauthorized>().is_some() || err.find::<warp::reject::InvalidQuery>().is_some() {
-        let code = http::StatusCode::UNAUTHORIZED;
-        let message = "UNAUTHORIZED";
-
-        let json =
-            warp::reply::json(&ErrorMessage { code: code.as_u16(), message: message.into() });
-        Ok(warp::reply::with_status(json, code))
-    } else {
-        Err(err)
-    }
-}
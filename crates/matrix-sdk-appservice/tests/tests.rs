@@ -1,16 +1,20 @@
 use std::{
+    collections::BTreeMap,
     future,
     sync::{Arc, Mutex},
 };
 
+use http_body::Full;
 use matrix_sdk::{
+    bytes::Bytes,
     config::{ClientConfig, RequestConfig},
     ruma::{api::appservice::Registration, events::room::member::SyncRoomMemberEvent},
 };
 use matrix_sdk_appservice::*;
 use matrix_sdk_test::{appservice::TransactionBuilder, async_test, EventsJson};
-use ruma::room_id;
+use ruma::{room_id, thirdparty::User, user_id};
 use serde_json::json;
+use tower_service::Service;
 #[cfg(feature = "warp")]
 use warp::{Filter, Reply};
 
@@ -19,6 +23,13 @@ fn registration_string() -> String {
 }
 
 async fn appservice(registration: Option<Registration>) -> Result<AppService> {
+    appservice_with_config(registration, ClientConfig::default()).await
+}
+
+async fn appservice_with_config(
+    registration: Option<Registration>,
+    client_config: ClientConfig,
+) -> Result<AppService> {
     // env::set_var(
     //     "RUST_LOG",
     //     "mockito=debug,matrix_sdk=debug,ruma=debug,warp=debug",
@@ -33,8 +44,24 @@ async fn appservice(registration: Option<Registration>) -> Result<AppService> {
     let homeserver_url = mockito::server_url();
     let server_name = "localhost";
 
-    let client_config =
-        ClientConfig::default().request_config(RequestConfig::default().disable_retry());
+    let client_config = client_config.request_config(RequestConfig::default().disable_retry());
+
+    // With the `encryption` feature a device is created for every client by
+    // logging in, the mock needs to outlive this function since the clients of
+    // virtual users are created later on.
+    #[cfg(feature = "encryption")]
+    std::mem::forget(
+        mockito::mock("POST", "/_matrix/client/r0/login")
+            .with_body(
+                json!({
+                    "access_token": "abc123",
+                    "device_id": "APPSERVICEDEVICE",
+                    "user_id": "@_appservice:localhost"
+                })
+                .to_string(),
+            )
+            .create(),
+    );
 
     AppService::new_with_config(homeserver_url.as_ref(), server_name, registration, client_config)
         .await
@@ -44,7 +71,7 @@ async fn appservice(registration: Option<Registration>) -> Result<AppService> {
 async fn test_register_virtual_user() -> Result<()> {
     let appservice = appservice(None).await?;
 
-    let localpart = "someone";
+    let localpart = "_appservice_someone";
     let _mock = mockito::mock("POST", "/_matrix/client/r0/register")
         .match_query(mockito::Matcher::Missing)
         .match_header(
@@ -70,6 +97,128 @@ async fn test_register_virtual_user() -> Result<()> {
     Ok(())
 }
 
+#[async_test]
+async fn test_register_virtual_user_outside_namespace() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    assert!(matches!(
+        appservice.register_virtual_user("someone").await,
+        Err(Error::UserNotInExclusiveNamespace(_))
+    ));
+    assert!(matches!(
+        appservice.virtual_user_client("someone").await,
+        Err(Error::UserNotInNamespace(_))
+    ));
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_provision_virtual_users() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    let _register_a = mockito::mock("POST", "/_matrix/client/r0/register")
+        .match_body(mockito::Matcher::PartialJson(json!({
+            "username": "_appservice_provisioned_a",
+        })))
+        .with_body(
+            json!({
+                "access_token": "abc123",
+                "device_id": "GHTYAJCE",
+                "user_id": "@_appservice_provisioned_a:localhost"
+            })
+            .to_string(),
+        )
+        .create();
+    // already registered users are skipped
+    let _register_b = mockito::mock("POST", "/_matrix/client/r0/register")
+        .match_body(mockito::Matcher::PartialJson(json!({
+            "username": "_appservice_provisioned_b",
+        })))
+        .with_status(400)
+        .with_body(
+            json!({ "errcode": "M_USER_IN_USE", "error": "User ID already taken." }).to_string(),
+        )
+        .create();
+    let upload = mockito::mock("POST", "/_matrix/media/r0/upload")
+        .with_body(json!({ "content_uri": "mxc://localhost/avatar" }).to_string())
+        .expect(1)
+        .create();
+    let displayname = mockito::mock(
+        "PUT",
+        mockito::Matcher::Regex(
+            r"^/_matrix/client/r0/profile/[^/]*_provisioned_[ab][^/]*/displayname$".to_owned(),
+        ),
+    )
+    .with_body("{}")
+    .expect(2)
+    .create();
+    let avatar_url = mockito::mock(
+        "PUT",
+        mockito::Matcher::Regex(
+            r"^/_matrix/client/r0/profile/[^/]*_provisioned_[ab][^/]*/avatar_url$".to_owned(),
+        ),
+    )
+    .with_body("{}")
+    .expect(2)
+    .create();
+    let join = mockito::mock(
+        "POST",
+        mockito::Matcher::Regex(
+            r"^/_matrix/client/r0/rooms/[^/]*provisioned[^/]*/join$".to_owned(),
+        ),
+    )
+    .with_body(json!({ "room_id": "!provisioned:localhost" }).to_string())
+    .expect(2)
+    .create();
+
+    let users = ["a", "b"].iter().map(|user| {
+        VirtualUserProfile::new(format!("_appservice_provisioned_{}", user))
+            .displayname(format!("Provisioned {}", user))
+    });
+    let config = ProvisioningConfig::new()
+        .concurrency(2)
+        .avatar(mime::IMAGE_PNG, b"avatar".to_vec())
+        .room(room_id!("!provisioned:localhost"));
+
+    let results = appservice.provision_virtual_users(users, config).await?;
+
+    assert_eq!(results.len(), 2);
+    for (localpart, result) in results {
+        assert!(result.is_ok(), "{}: {:?}", localpart, result.err());
+    }
+
+    upload.assert();
+    displayname.assert();
+    avatar_url.assert();
+    join.assert();
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_namespaces() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    assert!(appservice.is_user_id_in_namespace("@_appservice_someone:localhost"));
+    assert!(!appservice.is_user_id_in_namespace("@someone:localhost"));
+    assert!(!appservice.alias_is_in_namespace("#magicforest:example.com"));
+    assert!(!appservice.room_id_is_in_namespace("!SVkFJHzfwvuaIEawgC:localhost"));
+
+    Ok(())
+}
+
+#[async_test]
+#[allow(deprecated)]
+async fn test_deprecated_user_id_is_in_namespace() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    assert!(appservice.user_id_is_in_namespace("@_appservice_someone:localhost")?);
+    assert!(!appservice.user_id_is_in_namespace("@someone:localhost")?);
+
+    Ok(())
+}
+
 #[async_test]
 async fn test_put_transaction() -> Result<()> {
     let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
@@ -138,6 +287,54 @@ async fn test_get_room() -> Result<()> {
     Ok(())
 }
 
+#[async_test]
+async fn test_thirdparty_queries() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    appservice.register_protocol_query(Box::new(|_, _| Box::pin(async move { None }))).await;
+    appservice
+        .register_thirdparty_user_query(Box::new(|_, query| {
+            Box::pin(async move {
+                match query {
+                    event_handler::ThirdPartyUserQuery::Protocol(request) => vec![User::new(
+                        user_id!("@_appservice_irc_someone:localhost").to_owned(),
+                        request.protocol,
+                        BTreeMap::new(),
+                    )],
+                    event_handler::ThirdPartyUserQuery::UserId(_) => Vec::new(),
+                }
+            })
+        }))
+        .await;
+
+    let requests = [
+        ("/_matrix/app/v1/thirdparty/protocol/irc?access_token=hs_token", 404),
+        ("/_matrix/app/v1/thirdparty/user/irc?access_token=hs_token&nick=someone", 200),
+        (
+            "/_matrix/app/v1/thirdparty/user?access_token=hs_token&userid=%40someone%3Alocalhost",
+            404,
+        ),
+        // no location handler registered
+        ("/_matrix/app/v1/thirdparty/location/irc?access_token=hs_token", 404),
+    ];
+
+    #[cfg(feature = "warp")]
+    for (uri, expected_status) in requests {
+        let status = warp::test::request()
+            .method("GET")
+            .path(uri)
+            .filter(&appservice.warp_filter())
+            .await
+            .unwrap()
+            .into_response()
+            .status();
+
+        assert_eq!(status, expected_status, "{}", uri);
+    }
+
+    Ok(())
+}
+
 #[async_test]
 async fn test_invalid_access_token() -> Result<()> {
     let uri = "/_matrix/app/v1/transactions/1?access_token=invalid_token";
@@ -229,6 +426,274 @@ async fn test_event_handler() -> Result<()> {
     Ok(())
 }
 
+#[async_test]
+async fn test_namespaced_event_handler() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    #[allow(clippy::mutex_atomic)]
+    let on_state_member = Arc::new(Mutex::new(false));
+    appservice
+        .register_namespaced_event_handler({
+            let on_state_member = on_state_member.clone();
+            move |_ev: SyncRoomMemberEvent| {
+                *on_state_member.lock().unwrap() = true;
+                future::ready(())
+            }
+        })
+        .await?;
+
+    let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+    // The member event is sent by and to `@example:localhost`, which is not in
+    // the namespaces of the registration
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let transaction = transaction_builder.build_json_transaction();
+
+    #[cfg(feature = "warp")]
+    warp::test::request()
+        .method("PUT")
+        .path(uri)
+        .json(&transaction)
+        .filter(&appservice.warp_filter())
+        .await
+        .unwrap();
+
+    let on_room_member_called = *on_state_member.lock().unwrap();
+    assert!(!on_room_member_called);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_transaction_replay() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    let on_state_member = Arc::new(Mutex::new(0));
+    appservice
+        .register_event_handler({
+            let on_state_member = on_state_member.clone();
+            move |_ev: SyncRoomMemberEvent| {
+                *on_state_member.lock().unwrap() += 1;
+                future::ready(())
+            }
+        })
+        .await?;
+
+    let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let transaction = transaction_builder.build_json_transaction();
+
+    #[cfg(feature = "warp")]
+    for _ in 0..2 {
+        let status = warp::test::request()
+            .method("PUT")
+            .path(uri)
+            .json(&transaction)
+            .filter(&appservice.warp_filter())
+            .await
+            .unwrap()
+            .into_response()
+            .status();
+
+        assert_eq!(status, 200);
+    }
+
+    assert_eq!(*on_state_member.lock().unwrap(), 1);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_interrupted_transaction_retry() -> Result<()> {
+    let appservice = appservice(None).await?;
+
+    // The first call of the event handler panics, this interrupts processing
+    // the transaction after its changes were saved, like a crash would.
+    let on_state_member = Arc::new(Mutex::new(0));
+    appservice
+        .register_event_handler({
+            let on_state_member = on_state_member.clone();
+            move |_ev: SyncRoomMemberEvent| {
+                let calls = {
+                    let mut calls = on_state_member.lock().unwrap();
+                    *calls += 1;
+                    *calls
+                };
+                if calls == 1 {
+                    panic!("crashed while handling the event");
+                }
+                future::ready(())
+            }
+        })
+        .await?;
+
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let transaction = serde_json::to_vec(&transaction_builder.build_json_transaction())?;
+    let request = || {
+        http::Request::builder()
+            .method("PUT")
+            .uri("/_matrix/app/v1/transactions/1?access_token=hs_token")
+            .body(Full::new(Bytes::from(transaction.clone())))
+            .unwrap()
+    };
+
+    let mut service = appservice.service();
+
+    assert!(tokio::spawn(service.call(request())).await.is_err());
+    let client = appservice.get_cached_client(None)?;
+    assert_eq!(client.store().get_sync_token().await?.as_deref(), Some("1"));
+
+    // The homeserver retries the transaction, the event handler needs to be
+    // called again even though the changes were already saved.
+    let response = service.call(request()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(*on_state_member.lock().unwrap(), 2);
+
+    // Further retries of the completed transaction are ignored.
+    let response = service.call(request()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(*on_state_member.lock().unwrap(), 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+#[async_test]
+async fn test_transaction_routing() -> Result<()> {
+    use matrix_sdk::{room::Room, ruma::events::room::message::SyncRoomMessageEvent};
+
+    let appservice = appservice(None).await?;
+    let mut service = appservice.service();
+
+    let mut received = BTreeMap::new();
+    for localpart in ["_appservice_alice", "_appservice_bob"] {
+        let rooms = Arc::new(Mutex::new(Vec::new()));
+        appservice
+            .virtual_user_client(localpart)
+            .await?
+            .register_event_handler({
+                let rooms = rooms.clone();
+                move |_ev: SyncRoomMessageEvent, room: Room| {
+                    rooms.lock().unwrap().push(room.room_id().to_owned());
+                    future::ready(())
+                }
+            })
+            .await;
+        received.insert(localpart, rooms);
+    }
+
+    let member = |room_id: &str, localpart: &str| {
+        json!({
+            "type": "m.room.member",
+            "event_id": format!("${}_{}:localhost", localpart, &room_id[1..2]),
+            "room_id": room_id,
+            "sender": format!("@{}:localhost", localpart),
+            "state_key": format!("@{}:localhost", localpart),
+            "origin_server_ts": 1,
+            "content": { "membership": "join" }
+        })
+    };
+    let message = |room_id: &str, id: &str| {
+        json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:localhost", id),
+            "room_id": room_id,
+            "sender": "@example:localhost",
+            "origin_server_ts": 2,
+            "content": { "msgtype": "m.text", "body": "Hello" }
+        })
+    };
+
+    let transactions = [
+        json!({
+            "events": [
+                member("!a:localhost", "_appservice_alice"),
+                member("!b:localhost", "_appservice_bob"),
+                message("!a:localhost", "first_a"),
+                message("!b:localhost", "first_b"),
+            ]
+        }),
+        // The memberships are known from the previous transaction.
+        json!({ "events": [message("!a:localhost", "second_a")] }),
+    ];
+
+    for (txn_id, transaction) in transactions.iter().enumerate() {
+        let request = http::Request::builder()
+            .method("PUT")
+            .uri(format!("/_matrix/app/v1/transactions/{}?access_token=hs_token", txn_id))
+            .body(Full::new(Bytes::from(serde_json::to_vec(transaction)?)))?;
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let room_a = room_id!("!a:localhost").to_owned();
+    let room_b = room_id!("!b:localhost").to_owned();
+    assert_eq!(*received["_appservice_alice"].lock().unwrap(), [room_a.clone(), room_a]);
+    assert_eq!(*received["_appservice_bob"].lock().unwrap(), [room_b]);
+
+    Ok(())
+}
+
+#[cfg(all(feature = "encryption", feature = "sled_state_store"))]
+#[async_test]
+async fn test_to_device_events_for_uncached_client() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    // Creating the client of the virtual user persists its device ID in the
+    // store of the main user.
+    {
+        let config = ClientConfig::default().store_path(dir.path());
+        let appservice = appservice_with_config(None, config).await?;
+        appservice.virtual_user_client("_appservice_bob").await?;
+    }
+
+    // After a restart the client of the virtual user isn't cached anymore.
+    let config = ClientConfig::default().store_path(dir.path());
+    let appservice = appservice_with_config(None, config).await?;
+    assert!(appservice.get_cached_client(Some("_appservice_bob")).is_err());
+
+    let mut service = appservice.service();
+    let request = |txn_id: &str, localpart: &str| {
+        let transaction = json!({
+            "events": [],
+            "de.sorunome.msc2409.to_device": [{
+                "type": "m.dummy",
+                "sender": "@alice:example.org",
+                "to_user_id": format!("@{}:localhost", localpart),
+                "to_device_id": "APPSERVICEDEVICE",
+                "content": {}
+            }]
+        });
+
+        http::Request::builder()
+            .method("PUT")
+            .uri(format!("/_matrix/app/v1/transactions/{}?access_token=hs_token", txn_id))
+            .body(Full::new(Bytes::from(serde_json::to_vec(&transaction).unwrap())))
+            .unwrap()
+    };
+
+    // The client is restored with its persisted device to receive the events.
+    let response = service.call(request("1", "_appservice_bob")).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let client = appservice.get_cached_client(Some("_appservice_bob"))?;
+    assert_eq!(client.session().await.unwrap().device_id.as_str(), "APPSERVICEDEVICE");
+
+    // We never had a client for this user, the transaction isn't completed so
+    // the homeserver retries it instead of the events getting lost.
+    let response = service.call(request("2", "_appservice_carol")).await.unwrap();
+    assert_eq!(response.status(), 500);
+    let response = service.call(request("2", "_appservice_carol")).await.unwrap();
+    assert_eq!(response.status(), 500);
+    assert!(appservice.get_cached_client(Some("_appservice_carol")).is_err());
+
+    Ok(())
+}
+
 #[async_test]
 async fn test_unrelated_path() -> Result<()> {
     let appservice = appservice(None).await?;
@@ -254,6 +719,49 @@ async fn test_unrelated_path() -> Result<()> {
     Ok(())
 }
 
+#[async_test]
+async fn test_foreign_routes() -> Result<()> {
+    let appservice = appservice(None).await?;
+    let mut service = appservice.service();
+
+    // Paths that merely end like appservice routes belong to other handlers.
+    let paths = [
+        "/api/transactions/1",
+        "/api/users/%40_appservice_someone%3Alocalhost",
+        "/api/rooms/%23room%3Alocalhost",
+        "/api/thirdparty/user",
+        "/api/_matrix/app/v1/ping",
+        "/transactions/1/api",
+    ];
+
+    for path in paths {
+        #[cfg(feature = "warp")]
+        {
+            let consumer_filter =
+                warp::any().and(appservice.warp_filter()).or(warp::any().map(|| "foreign"));
+
+            let response = warp::test::request()
+                .method("PUT")
+                .path(&format!("{}?access_token=hs_token", path))
+                .json(&json!({ "events": [] }))
+                .reply(&consumer_filter)
+                .await;
+
+            assert_eq!(response.status(), 200, "{}", path);
+            assert_eq!(response.body().as_ref(), b"foreign", "{}", path);
+        }
+
+        let request = http::Request::builder()
+            .method("PUT")
+            .uri(format!("{}?access_token=hs_token", path))
+            .body(Full::new(Bytes::from_static(b"{}")))?;
+        let response = service.call(request).await.unwrap();
+        assert_eq!(response.status(), 404, "{}", path);
+    }
+
+    Ok(())
+}
+
 #[async_test]
 async fn test_appservice_on_sub_path() -> Result<()> {
     let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
@@ -299,6 +807,114 @@ async fn test_appservice_on_sub_path() -> Result<()> {
     Ok(())
 }
 
+#[async_test]
+async fn test_service() -> Result<()> {
+    let appservice = appservice(None).await?;
+    let mut service = appservice.service();
+
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let transaction = transaction_builder.build_json_transaction();
+
+    let request = http::Request::builder()
+        .method("PUT")
+        .uri("/_matrix/app/v1/transactions/1?access_token=hs_token")
+        .body(Full::new(Bytes::from(serde_json::to_vec(&transaction)?)))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("/_matrix/app/v1/ping")
+        .header("authorization", "Bearer hs_token")
+        .body(Full::new(Bytes::from_static(b"{}")))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let request = http::Request::builder()
+        .method("GET")
+        .uri("/_matrix/app/v1/users/%40_appservice_someone%3Alocalhost")
+        .body(Full::new(Bytes::new()))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 401);
+
+    let request = http::Request::builder()
+        .method("GET")
+        .uri("/unrelated?access_token=hs_token")
+        .body(Full::new(Bytes::new()))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_service_max_body_size() -> Result<()> {
+    let appservice = appservice(None).await?;
+    let mut service = appservice.service().max_body_size(16);
+
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("/_matrix/app/v1/ping")
+        .header("authorization", "Bearer hs_token")
+        .body(Full::new(Bytes::from_static(b"{}")))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let transaction = transaction_builder.build_json_transaction();
+
+    let request = http::Request::builder()
+        .method("PUT")
+        .uri("/_matrix/app/v1/transactions/1?access_token=hs_token")
+        .body(Full::new(Bytes::from(serde_json::to_vec(&transaction)?)))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 413);
+
+    // The announced length is checked before the body is received.
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("/_matrix/app/v1/ping")
+        .header("authorization", "Bearer hs_token")
+        .header("content-length", "1024")
+        .body(Full::new(Bytes::from_static(b"{}")))?;
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.status(), 413);
+
+    Ok(())
+}
+
+#[test]
+fn test_encryption_data() -> Result<()> {
+    let mut transaction_builder = TransactionBuilder::new();
+    transaction_builder.add_room_event(EventsJson::Member);
+    let mut transaction = transaction_builder.build_json_transaction();
+
+    let data: EncryptionData = serde_json::from_value(transaction.clone())?;
+    assert!(data.to_device.is_empty());
+
+    transaction["de.sorunome.msc2409.to_device"] = json!([{
+        "type": "m.room.encrypted",
+        "sender": "@alice:example.org",
+        "to_user_id": "@_appservice_bob:localhost",
+        "to_device_id": "BOBDEVICE",
+        "content": {}
+    }]);
+    transaction["org.matrix.msc3202.device_lists"] = json!({ "changed": ["@alice:example.org"] });
+    transaction["org.matrix.msc3202.device_one_time_keys_count"] = json!({
+        "@_appservice_bob:localhost": { "BOBDEVICE": { "signed_curve25519": 50 } }
+    });
+
+    let data: EncryptionData = serde_json::from_value(transaction)?;
+    assert_eq!(data.to_device.len(), 1);
+    assert_eq!(data.device_lists.changed.len(), 1);
+    assert_eq!(data.device_one_time_keys_count.len(), 1);
+    assert!(data.device_unused_fallback_key_types.is_empty());
+
+    Ok(())
+}
+
 mod registration {
     use super::*;
 
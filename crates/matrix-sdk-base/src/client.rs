@@ -69,11 +69,15 @@ use crate::{
     error::Result,
     rooms::{Room, RoomInfo, RoomType},
     session::Session,
-    store::{ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, Store},
+    store::{
+        ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, Store, TimelineChunk,
+    },
 };
 
 pub type Token = String;
 
+type SyncHook = Box<dyn Fn(&SyncResponse, &mut StateChanges) -> StoreResult<()> + Send + Sync>;
+
 /// A no IO Client implementation.
 ///
 /// This Client is a state machine that receives responses and events and
@@ -95,6 +99,8 @@ pub struct BaseClient {
     cryptostore: Arc<Mutex<Option<Box<dyn CryptoStore>>>>,
     #[allow(dead_code)]
     store_passphrase: Arc<Option<Zeroizing<String>>>,
+    /// Hooks that can add changes to the changes of a sync response.
+    sync_hooks: Arc<RwLock<Vec<SyncHook>>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -265,6 +271,7 @@ impl BaseClient {
             #[cfg(feature = "encryption")]
             cryptostore: Mutex::new(crypto_store).into(),
             store_passphrase: config.passphrase.into(),
+            sync_hooks: Default::default(),
         })
     }
 }
@@ -294,11 +301,51 @@ impl BaseClient {
             #[cfg(feature = "encryption")]
             cryptostore: Mutex::new(config.crypto_store).into(),
             store_passphrase: config.passphrase.into(),
+            sync_hooks: Default::default(),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite_state_store")]
+impl BaseClient {
+    /// Create a new client.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - An optional session if the user already has one from a
+    /// previous login call.
+    pub async fn new_with_config(config: BaseClientConfig) -> Result<Self> {
+        let store = if let Some(path) = &config.store_path {
+            if config.passphrase.is_some() {
+                info!("Opening an encrypted store in path {}", path.display());
+            } else {
+                info!("Opening store in path {}", path.display());
+            }
+            Store::open_default(path, config.passphrase.as_deref().map(|p| p.as_str()))?
+        } else {
+            Store::open_temporary()?
+        };
+
+        Ok(BaseClient {
+            session: store.session.clone(),
+            sync_token: store.sync_token.clone(),
+            store_path: config.store_path,
+            store,
+            #[cfg(feature = "encryption")]
+            olm: Mutex::new(None).into(),
+            #[cfg(feature = "encryption")]
+            cryptostore: Mutex::new(config.crypto_store).into(),
+            store_passphrase: config.passphrase.into(),
+            sync_hooks: Default::default(),
         })
     }
 }
 
-#[cfg(not(any(feature = "sled_state_store", feature = "indexeddb_state_store")))]
+#[cfg(not(any(
+    feature = "sled_state_store",
+    feature = "sqlite_state_store",
+    feature = "indexeddb_state_store"
+)))]
 impl BaseClient {
     /// Create a new client.
     ///
@@ -319,6 +366,7 @@ impl BaseClient {
             #[cfg(feature = "encryption")]
             cryptostore: Mutex::new(config.crypto_store).into(),
             store_passphrase: config.passphrase.into(),
+            sync_hooks: Default::default(),
         })
     }
 }
@@ -408,7 +456,30 @@ impl BaseClient {
                     }
                 }
 
-                #[cfg(not(feature = "sled_cryptostore"))]
+                #[cfg(feature = "sqlite_cryptostore")]
+                {
+                    if let Some(path) = self.store_path.as_ref() {
+                        let store = matrix_sdk_crypto::store::SqliteStore::open_with_passphrase(
+                            path,
+                            self.store_passphrase.as_deref().map(|p| p.as_str()),
+                        )
+                        .map_err(OlmError::Store)?;
+
+                        *olm = Some(
+                            OlmMachine::new_with_store(
+                                session.user_id.to_owned(),
+                                session.device_id.as_str().into(),
+                                Box::new(store),
+                            )
+                            .await
+                            .map_err(OlmError::from)?,
+                        );
+                    } else {
+                        *olm = Some(OlmMachine::new(&session.user_id, &session.device_id));
+                    }
+                }
+
+                #[cfg(not(any(feature = "sled_cryptostore", feature = "sqlite_cryptostore")))]
                 {
                     *olm = Some(OlmMachine::new(&session.user_id, &session.device_id));
                 }
@@ -728,6 +799,38 @@ impl BaseClient {
     pub async fn receive_sync_response(
         &self,
         response: api::sync::sync_events::Response,
+    ) -> Result<SyncResponse> {
+        // The server might respond multiple times with the same sync token, in
+        // that case we already received this response and there's nothing to
+        // do.
+        if self.sync_token.read().await.as_ref() == Some(&response.next_batch) {
+            return Ok(SyncResponse::new(response.next_batch));
+        }
+
+        self.process_sync_response(response).await
+    }
+
+    /// Receive a response from a sync call again, even if a response with the
+    /// same `next_batch` token was already received.
+    ///
+    /// The changes of the response are processed and saved again, this is
+    /// meant for responses whose processing was interrupted after their
+    /// changes were saved, e.g. application service transactions that are
+    /// retried by the homeserver after a crash.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The response that should be processed again.
+    pub async fn replay_sync_response(
+        &self,
+        response: api::sync::sync_events::Response,
+    ) -> Result<SyncResponse> {
+        self.process_sync_response(response).await
+    }
+
+    async fn process_sync_response(
+        &self,
+        response: api::sync::sync_events::Response,
     ) -> Result<SyncResponse> {
         #[allow(unused_variables)]
         let api::sync::sync_events::Response {
@@ -742,13 +845,6 @@ impl BaseClient {
             ..
         } = response;
 
-        // The server might respond multiple times with the same sync token, in
-        // that case we already received this response and there's nothing to
-        // do.
-        if self.sync_token.read().await.as_ref() == Some(&next_batch) {
-            return Ok(SyncResponse::new(next_batch));
-        }
-
         let now = Instant::now();
 
         #[cfg(feature = "encryption")]
@@ -928,14 +1024,8 @@ impl BaseClient {
 
         changes.ambiguity_maps = ambiguity_cache.cache;
 
-        self.store.save_changes(&changes).await?;
-        *self.sync_token.write().await = Some(next_batch.clone());
-        self.apply_changes(&changes).await;
-
-        info!("Processed a sync response in {:?}", now.elapsed());
-
         let response = SyncResponse {
-            next_batch,
+            next_batch: next_batch.clone(),
             rooms: new_rooms,
             presence,
             account_data,
@@ -946,12 +1036,55 @@ impl BaseClient {
                 .map(|(k, v)| (k, v.into()))
                 .collect(),
             ambiguity_changes: AmbiguityChanges { changes: ambiguity_cache.changes },
-            notifications: changes.notifications,
+            notifications: changes.notifications.clone(),
         };
 
+        let timelines =
+            response.rooms.join.iter().map(|(room_id, room)| (room_id, &room.timeline)).chain(
+                response.rooms.leave.iter().map(|(room_id, room)| (room_id, &room.timeline)),
+            );
+
+        for (room_id, timeline) in timelines.filter(|(_, t)| !t.events.is_empty()) {
+            changes.add_timeline_chunk(TimelineChunk {
+                room_id: room_id.clone(),
+                events: timeline.events.clone(),
+                prev_batch: timeline.prev_batch.clone(),
+                next_batch: next_batch.clone(),
+            });
+        }
+
+        for hook in self.sync_hooks.read().await.iter() {
+            hook(&response, &mut changes)?;
+        }
+
+        self.store.save_changes(&changes).await?;
+        *self.sync_token.write().await = Some(next_batch.clone());
+        self.apply_changes(&changes).await;
+
+        info!("Processed a sync response in {:?}", now.elapsed());
+
         Ok(response)
     }
 
+    /// Register a hook that is called for every sync response after it was
+    /// processed, but before its changes are saved.
+    ///
+    /// The hook can add its own changes to the changes of the sync response,
+    /// e.g. the values of a [`KeyValueStore`] using
+    /// [`KeyValueStore::set_in_changes()`]. Those are saved atomically with
+    /// the state of the sync response, so they never diverge from it. If the
+    /// hook returns an error, nothing is saved and the error is returned from
+    /// [`BaseClient::receive_sync_response()`].
+    ///
+    /// [`KeyValueStore`]: crate::KeyValueStore
+    /// [`KeyValueStore::set_in_changes()`]: crate::KeyValueStore::set_in_changes
+    pub async fn register_sync_hook(
+        &self,
+        hook: impl Fn(&SyncResponse, &mut StateChanges) -> StoreResult<()> + Send + Sync + 'static,
+    ) {
+        self.sync_hooks.write().await.push(Box::new(hook));
+    }
+
     async fn apply_changes(&self, changes: &StateChanges) {
         for (room_id, room_info) in &changes.room_infos {
             if let Some(room) = self.store.get_room(room_id) {
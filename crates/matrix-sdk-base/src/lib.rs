@@ -28,6 +28,19 @@
 #[cfg(all(feature = "sled_state_store", feature = "indexeddb_state_store"))]
 compile_error!("sled_state_store and indexeddb_state_store are mutually exclusive and cannot be enabled together");
 
+#[cfg(all(feature = "sled_state_store", feature = "sqlite_state_store"))]
+compile_error!(
+    "sled_state_store and sqlite_state_store are mutually exclusive and cannot be enabled together"
+);
+
+#[cfg(all(feature = "sqlite_state_store", feature = "indexeddb_state_store"))]
+compile_error!("sqlite_state_store and indexeddb_state_store are mutually exclusive and cannot be enabled together");
+
+#[cfg(all(feature = "sled_cryptostore", feature = "sqlite_cryptostore"))]
+compile_error!(
+    "sled_cryptostore and sqlite_cryptostore are mutually exclusive and cannot be enabled together"
+);
+
 #[cfg(all(feature = "indexeddb_state_store", not(target_arch = "wasm32")))]
 compile_error!("indexeddb_state_store only works for wasm32 target");
 
@@ -52,4 +65,7 @@ pub use client::{BaseClient, BaseClientConfig};
 #[cfg(feature = "encryption")]
 pub use matrix_sdk_crypto as crypto;
 pub use rooms::{Room, RoomInfo, RoomMember, RoomType};
-pub use store::{StateChanges, StateStore, Store, StoreError};
+pub use store::{
+    KeyValueStore, PendingEvent, StateChanges, StateStore, Store, StoreError, TimelineChunk,
+    TimelinePruning,
+};
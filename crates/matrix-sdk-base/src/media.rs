@@ -1,5 +1,7 @@
 //! Common types for [media content](https://matrix.org/docs/spec/client_server/r0.6.1#id66).
 
+use std::time::Duration;
+
 use ruma::{
     api::client::r0::media::get_content_thumbnail::Method,
     events::{
@@ -12,8 +14,9 @@ use ruma::{
         },
         sticker::StickerEventContent,
     },
-    MxcUri, UInt,
+    MilliSecondsSinceUnixEpoch, MxcUri, UInt,
 };
+use serde::{Deserialize, Serialize};
 
 const UNIQUE_SEPARATOR: &str = "_";
 
@@ -99,6 +102,255 @@ impl UniqueKey for MediaRequest {
     }
 }
 
+/// Metadata about a media content that is stored in the media cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediaCacheEntry {
+    /// The unique key of the [`MediaType`] of the content.
+    pub media_key: String,
+
+    /// The unique key of the [`MediaFormat`] of the content.
+    pub format_key: String,
+
+    /// Whether the content is a thumbnail.
+    pub thumbnail: bool,
+
+    /// The size of the content in bytes.
+    pub size: u64,
+
+    /// The last time the content was added to or fetched from the cache.
+    pub last_access: MilliSecondsSinceUnixEpoch,
+}
+
+impl MediaCacheEntry {
+    /// Create the cache entry for the content of the given request, that was
+    /// just accessed.
+    pub fn new(request: &MediaRequest, size: usize) -> Self {
+        Self {
+            media_key: request.media_type.unique_key(),
+            format_key: request.format.unique_key(),
+            thumbnail: matches!(request.format, MediaFormat::Thumbnail(_)),
+            size: size as u64,
+            last_access: MilliSecondsSinceUnixEpoch::now(),
+        }
+    }
+
+    /// Create the cache entry for content that was cached before its metadata
+    /// was tracked, from the unique keys it's stored with.
+    ///
+    /// The content is treated as if it was just accessed.
+    pub(crate) fn backfill(media_key: String, format_key: String, size: usize) -> Self {
+        Self {
+            thumbnail: format_key != MediaFormat::File.unique_key(),
+            media_key,
+            format_key,
+            size: size as u64,
+            last_access: MilliSecondsSinceUnixEpoch::now(),
+        }
+    }
+}
+
+impl UniqueKey for MediaCacheEntry {
+    fn unique_key(&self) -> String {
+        format!("{}{}{}", self.media_key, UNIQUE_SEPARATOR, self.format_key)
+    }
+}
+
+/// The default minimum time between two automatic clean-ups of the media
+/// cache.
+const DEFAULT_CLEAN_UP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits for the media cache of the state store.
+///
+/// Content that exceeds a limit is evicted, least recently used content
+/// first.
+///
+/// Listing the whole cache is expensive, so the cache is only cleaned up when
+/// content is added and either a size limit is exceeded or the
+/// [`clean_up_interval`](Self::clean_up_interval) elapsed since the last
+/// clean-up. Content that exceeds the `max_age` might thus stay a bit longer in
+/// the cache.
+///
+/// # Example
+///
+/// ```
+/// # use std::time::Duration;
+/// # use matrix_sdk_base::media::MediaCacheConfig;
+/// let config = MediaCacheConfig::new()
+///     .max_size(500 * 1024 * 1024)
+///     .max_thumbnail_size(50 * 1024 * 1024)
+///     .max_age(Duration::from_secs(60 * 60 * 24 * 30));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MediaCacheConfig {
+    max_size: Option<u64>,
+    max_file_size: Option<u64>,
+    max_thumbnail_size: Option<u64>,
+    max_age: Option<Duration>,
+    clean_up_interval: Option<Duration>,
+}
+
+impl MediaCacheConfig {
+    /// Create a new `MediaCacheConfig` without any limits.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the maximum size in bytes of all the content in the cache.
+    #[must_use]
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Set the maximum size in bytes of all the full files in the cache.
+    #[must_use]
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = Some(size);
+        self
+    }
+
+    /// Set the maximum size in bytes of all the thumbnails in the cache.
+    #[must_use]
+    pub fn max_thumbnail_size(mut self, size: u64) -> Self {
+        self.max_thumbnail_size = Some(size);
+        self
+    }
+
+    /// Set the maximum time content is kept in the cache since it was last
+    /// accessed.
+    #[must_use]
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Set the minimum time between two clean-ups of the cache that are not
+    /// caused by exceeding a size limit.
+    ///
+    /// Defaults to one minute.
+    #[must_use]
+    pub fn clean_up_interval(mut self, interval: Duration) -> Self {
+        self.clean_up_interval = Some(interval);
+        self
+    }
+
+    /// Whether the cache needs to be cleaned up, given its current statistics
+    /// and the time since the last clean-up.
+    pub(crate) fn needs_clean_up(&self, stats: &MediaCacheStats, elapsed: Duration) -> bool {
+        let exceeds = |max: Option<u64>, size: u64| max.map_or(false, |max| size > max);
+
+        elapsed >= self.clean_up_interval.unwrap_or(DEFAULT_CLEAN_UP_INTERVAL)
+            || exceeds(self.max_size, stats.total_size())
+            || exceeds(self.max_file_size, stats.file_size)
+            || exceeds(self.max_thumbnail_size, stats.thumbnail_size)
+    }
+
+    /// Get the entries that should be evicted from the cache to satisfy the
+    /// limits of this config.
+    pub(crate) fn entries_to_evict(
+        &self,
+        mut entries: Vec<MediaCacheEntry>,
+    ) -> Vec<MediaCacheEntry> {
+        // Oldest access first, so the least recently used content is evicted
+        // before anything else.
+        entries.sort_by_key(|e| e.last_access);
+
+        let mut evict = vec![false; entries.len()];
+
+        if let Some(age) = self.max_age {
+            let now = MilliSecondsSinceUnixEpoch::now().0;
+            let age = u64::try_from(age.as_millis()).ok().and_then(UInt::new).unwrap_or(UInt::MAX);
+            let cutoff = MilliSecondsSinceUnixEpoch(now.saturating_sub(age));
+
+            for (entry, evict) in entries.iter().zip(evict.iter_mut()) {
+                *evict |= entry.last_access < cutoff;
+            }
+        }
+
+        let mut enforce_quota = |max: Option<u64>, matches: &dyn Fn(&MediaCacheEntry) -> bool| {
+            if let Some(max) = max {
+                let mut size: u64 = entries
+                    .iter()
+                    .zip(&evict)
+                    .filter(|(e, evict)| !**evict && matches(e))
+                    .map(|(e, _)| e.size)
+                    .sum();
+
+                for (entry, evict) in entries.iter().zip(evict.iter_mut()) {
+                    if size <= max {
+                        break;
+                    }
+
+                    if !*evict && matches(entry) {
+                        *evict = true;
+                        size -= entry.size;
+                    }
+                }
+            }
+        };
+
+        enforce_quota(self.max_thumbnail_size, &|e| e.thumbnail);
+        enforce_quota(self.max_file_size, &|e| !e.thumbnail);
+        enforce_quota(self.max_size, &|_| true);
+
+        entries.into_iter().zip(evict).filter_map(|(e, evict)| evict.then(|| e)).collect()
+    }
+}
+
+/// Statistics about the content of the media cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaCacheStats {
+    /// The number of full files in the cache.
+    pub file_count: usize,
+
+    /// The size in bytes of all the full files in the cache.
+    pub file_size: u64,
+
+    /// The number of thumbnails in the cache.
+    pub thumbnail_count: usize,
+
+    /// The size in bytes of all the thumbnails in the cache.
+    pub thumbnail_size: u64,
+}
+
+impl MediaCacheStats {
+    /// The size in bytes of all the content in the cache.
+    pub fn total_size(&self) -> u64 {
+        self.file_size + self.thumbnail_size
+    }
+
+    /// Count the given entry in these statistics.
+    pub(crate) fn add(&mut self, entry: &MediaCacheEntry) {
+        if entry.thumbnail {
+            self.thumbnail_count += 1;
+            self.thumbnail_size += entry.size;
+        } else {
+            self.file_count += 1;
+            self.file_size += entry.size;
+        }
+    }
+
+    /// Stop counting the given entry in these statistics.
+    pub(crate) fn remove(&mut self, entry: &MediaCacheEntry) {
+        if entry.thumbnail {
+            self.thumbnail_count -= 1;
+            self.thumbnail_size -= entry.size;
+        } else {
+            self.file_count -= 1;
+            self.file_size -= entry.size;
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a MediaCacheEntry> for MediaCacheStats {
+    fn from_iter<I: IntoIterator<Item = &'a MediaCacheEntry>>(iter: I) -> Self {
+        iter.into_iter().fold(Self::default(), |mut stats, entry| {
+            stats.add(entry);
+            stats
+        })
+    }
+}
+
 /// Trait for media event content.
 pub trait MediaEventContent {
     /// Get the type of the file for `Self`.
@@ -210,15 +462,103 @@ impl MediaEventContent for LocationMessageEventContent {
     }
 }
 
-#[cfg(all(feature = "encryption", test))]
+#[cfg(test)]
 pub(crate) mod test {
+    #[cfg(feature = "encryption")]
     use ruma::events::room::{
         message::{FileInfo, LocationInfo, VideoInfo},
         ImageInfo,
     };
+    use ruma::uint;
 
     use super::*;
 
+    fn cache_entry(id: &str, thumbnail: bool, size: u64, last_access: u64) -> MediaCacheEntry {
+        let format = if thumbnail {
+            MediaFormat::Thumbnail(MediaThumbnailSize {
+                method: Method::Scale,
+                width: uint!(100),
+                height: uint!(100),
+            })
+        } else {
+            MediaFormat::File
+        };
+        let request = MediaRequest { media_type: MediaType::Uri(id.into()), format };
+
+        MediaCacheEntry {
+            last_access: MilliSecondsSinceUnixEpoch(UInt::new(last_access).unwrap()),
+            ..MediaCacheEntry::new(&request, size as usize)
+        }
+    }
+
+    fn media_ids(entries: &[MediaCacheEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.media_key.as_str()).collect()
+    }
+
+    #[test]
+    fn entries_to_evict_oldest_first() {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().0.into();
+        let entries = vec![
+            cache_entry("mxc://localhost/new", false, 10, now),
+            cache_entry("mxc://localhost/oldest", false, 10, now - 3000),
+            cache_entry("mxc://localhost/old", false, 10, now - 2000),
+        ];
+
+        let config = MediaCacheConfig::new().max_size(20);
+        assert_eq!(
+            media_ids(&config.entries_to_evict(entries.clone())),
+            ["mxc://localhost/oldest"]
+        );
+
+        let config = MediaCacheConfig::new().max_size(10);
+        assert_eq!(
+            media_ids(&config.entries_to_evict(entries.clone())),
+            ["mxc://localhost/oldest", "mxc://localhost/old"]
+        );
+
+        let config = MediaCacheConfig::new().max_age(Duration::from_millis(2500));
+        assert_eq!(media_ids(&config.entries_to_evict(entries)), ["mxc://localhost/oldest"]);
+    }
+
+    #[test]
+    fn entries_to_evict_independent_quotas() {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().0.into();
+        let entries = vec![
+            cache_entry("mxc://localhost/old_thumbnail", true, 10, now - 4000),
+            cache_entry("mxc://localhost/old_file", false, 10, now - 3000),
+            cache_entry("mxc://localhost/thumbnail", true, 10, now - 2000),
+            cache_entry("mxc://localhost/file", false, 10, now - 1000),
+        ];
+
+        // Only the thumbnails exceed their quota, the older file is kept.
+        let config = MediaCacheConfig::new().max_thumbnail_size(10).max_file_size(20);
+        assert_eq!(
+            media_ids(&config.entries_to_evict(entries.clone())),
+            ["mxc://localhost/old_thumbnail"]
+        );
+
+        // Only the files exceed their quota, the older thumbnail is kept.
+        let config = MediaCacheConfig::new().max_thumbnail_size(20).max_file_size(10);
+        assert_eq!(media_ids(&config.entries_to_evict(entries)), ["mxc://localhost/old_file"]);
+    }
+
+    #[test]
+    fn needs_clean_up() {
+        let stats: MediaCacheStats =
+            [cache_entry("mxc://localhost/file", false, 10, 0)].iter().collect();
+
+        let config = MediaCacheConfig::new().max_size(10);
+        assert!(!config.needs_clean_up(&stats, Duration::from_secs(1)));
+        assert!(config.needs_clean_up(&stats, DEFAULT_CLEAN_UP_INTERVAL));
+
+        let config = MediaCacheConfig::new().max_file_size(5);
+        assert!(config.needs_clean_up(&stats, Duration::from_secs(1)));
+
+        let config = MediaCacheConfig::new().max_thumbnail_size(5);
+        assert!(!config.needs_clean_up(&stats, Duration::from_secs(1)));
+    }
+
+    #[cfg(feature = "encryption")]
     fn encrypted_test_data() -> (Box<MxcUri>, EncryptedFile) {
         let c = &mut std::io::Cursor::new("some content");
         let reader = crate::crypto::AttachmentEncryptor::new(c);
@@ -236,6 +576,7 @@ pub(crate) mod test {
         (url, file)
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_audio_content_prefer_crypt_type() {
         let (u, f) = encrypted_test_data();
@@ -244,6 +585,7 @@ pub(crate) mod test {
         assert!(matches!(c.file(), Some(MediaType::Encrypted(_))));
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_file_content_prefer_crypt_type() {
         let (u, f) = encrypted_test_data();
@@ -257,6 +599,7 @@ pub(crate) mod test {
         assert!(matches!(c.thumbnail(), Some(MediaType::Encrypted(_))));
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_image_content_prefer_crypt_type() {
         let (u, f) = encrypted_test_data();
@@ -270,6 +613,7 @@ pub(crate) mod test {
         assert!(matches!(c.thumbnail(), Some(MediaType::Encrypted(_))));
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_video_content_prefer_crypt_type() {
         let (u, f) = encrypted_test_data();
@@ -283,6 +627,7 @@ pub(crate) mod test {
         assert!(matches!(c.thumbnail(), Some(MediaType::Encrypted(_))));
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_location_content_prefer_crypt_type() {
         let (u, f) = encrypted_test_data();
@@ -15,7 +15,7 @@
 use std::collections::BTreeSet;
 
 use indexed_db_futures::prelude::*;
-use matrix_sdk_common::{async_trait, SafeEncode};
+use matrix_sdk_common::{async_trait, SafeEncode, ESCAPED, KEY_SEPARATOR};
 use ruma::{
     events::{
         presence::PresenceEvent,
@@ -25,16 +25,19 @@ use ruma::{
     },
     receipt::ReceiptType,
     serde::Raw,
-    EventId, MxcUri, RoomId, UserId,
+    EventId, MxcUri, RoomId, TransactionId, UserId,
 };
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::JsValue;
 
 use self::store_key::{EncryptedEvent, StoreKey};
-use super::{store_key, Result, RoomInfo, StateChanges, StateStore, StoreError};
+use super::{
+    sort_timeline_chunks, store_key, PendingEvent, Result, RoomInfo, StateChanges, StateStore,
+    StoreError, TimelineChunk, TimelinePruning,
+};
 use crate::{
-    deserialized_responses::MemberEvent,
-    media::{MediaRequest, UniqueKey},
+    deserialized_responses::{MemberEvent, SyncRoomEvent},
+    media::{MediaCacheEntry, MediaRequest, UniqueKey},
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,14 +81,23 @@ mod KEYS {
     pub const ROOM_EVENT_RECEIPTS: &'static str = "room_event_receipts";
 
     pub const MEDIA: &'static str = "media";
+    pub const MEDIA_METADATA: &'static str = "media_metadata";
 
     pub const CUSTOM: &'static str = "custom";
+    pub const KEY_VALUES: &'static str = "key_values";
+
+    pub const PENDING_EVENTS: &'static str = "pending_events";
+
+    pub const TIMELINE_CHUNKS: &'static str = "timeline_chunks";
+    pub const TIMELINE_EVENTS: &'static str = "timeline_events";
+    pub const TIMELINE_REDACTIONS: &'static str = "timeline_redactions";
 
     // static keys
 
     pub const STORE_KEY: &'static str = "store_key";
     pub const FILTER: &'static str = "filter";
     pub const SYNC_TOKEN: &'static str = "sync_token";
+    pub const BACKFILL_MEDIA_METADATA: &'static str = "backfill_media_metadata";
 }
 
 impl From<SerializationError> for StoreError {
@@ -115,8 +127,7 @@ impl std::fmt::Debug for IndexeddbStore {
 
 impl IndexeddbStore {
     async fn open_helper(name: String, store_key: Option<StoreKey>) -> Result<Self> {
-        // Open my_db v1
-        let mut db_req: OpenDbRequest = IdbDatabase::open_f64(&name, 1.0)?;
+        let mut db_req: OpenDbRequest = IdbDatabase::open_f64(&name, 6.0)?;
         db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
             if evt.old_version() < 1.0 {
                 // migrating to version 1
@@ -148,12 +159,107 @@ impl IndexeddbStore {
 
                 db.create_object_store(KEYS::CUSTOM)?;
             }
+
+            if evt.old_version() < 2.0 {
+                // migrating to version 2
+                let db = evt.db();
+
+                db.create_object_store(KEYS::PENDING_EVENTS)?;
+            }
+
+            if evt.old_version() < 3.0 {
+                // migrating to version 3
+                let db = evt.db();
+
+                db.create_object_store(KEYS::TIMELINE_CHUNKS)?;
+                db.create_object_store(KEYS::TIMELINE_EVENTS)?;
+            }
+
+            if evt.old_version() < 4.0 {
+                // migrating to version 4
+                let db = evt.db();
+
+                let metadata = db.create_object_store(KEYS::MEDIA_METADATA)?;
+
+                if evt.old_version() >= 1.0 {
+                    // The metadata of the content that is already cached is
+                    // backfilled once the database is open, mark it as pending
+                    // in the same transaction.
+                    metadata.put_key_val(
+                        &JsValue::from_str(KEYS::BACKFILL_MEDIA_METADATA),
+                        &JsValue::TRUE,
+                    )?;
+                }
+            }
+
+            if evt.old_version() < 5.0 {
+                // migrating to version 5
+                let db = evt.db();
+
+                db.create_object_store(KEYS::KEY_VALUES)?;
+            }
+
+            if evt.old_version() < 6.0 {
+                // migrating to version 6
+                let db = evt.db();
+
+                db.create_object_store(KEYS::TIMELINE_REDACTIONS)?;
+            }
+
             Ok(())
         }));
 
         let db: IdbDatabase = db_req.into_future().await?;
 
-        Ok(Self { name, inner: db, store_key })
+        let store = Self { name, inner: db, store_key };
+        store.backfill_media_metadata().await?;
+
+        Ok(store)
+    }
+
+    /// Create the metadata of the content in the media cache, for content that
+    /// was cached before its metadata was tracked.
+    ///
+    /// Only does something if the upgrade of the database marked the backfill
+    /// as pending.
+    async fn backfill_media_metadata(&self) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let media = tx.object_store(KEYS::MEDIA)?;
+        let metadata = tx.object_store(KEYS::MEDIA_METADATA)?;
+        let marker = JsValue::from_str(KEYS::BACKFILL_MEDIA_METADATA);
+
+        if metadata.get(&marker)?.await?.is_none() {
+            return Ok(());
+        }
+
+        for key in media.get_all_keys()?.await?.iter() {
+            let content: Vec<u8> = match media.get(&key)?.await? {
+                Some(content) => self.deserialize_event(content)?,
+                None => continue,
+            };
+
+            let entry = key.as_string().as_deref().and_then(|k| k.split_once(KEY_SEPARATOR)).map(
+                |(media_key, format_key)| {
+                    MediaCacheEntry::backfill(
+                        media_key.replace(ESCAPED, KEY_SEPARATOR),
+                        format_key.replace(ESCAPED, KEY_SEPARATOR),
+                        content.len(),
+                    )
+                },
+            );
+
+            if let Some(entry) = entry {
+                metadata.put_key_val(&key, &self.serialize_event(&entry)?)?;
+            }
+        }
+
+        metadata.delete(&marker)?;
+
+        tx.await.into_result().map_err(|e| e.into())
     }
     #[allow(dead_code)]
     pub async fn open() -> Result<Self> {
@@ -284,6 +390,7 @@ impl IndexeddbStore {
             (!changes.stripped_state.is_empty(), KEYS::STRIPPED_ROOM_STATE),
             (!changes.stripped_members.is_empty(), KEYS::STRIPPED_MEMBERS),
             (!changes.stripped_room_infos.is_empty(), KEYS::STRIPPED_ROOM_INFOS),
+            (!changes.key_values.is_empty(), KEYS::KEY_VALUES),
         ]
         .iter()
         .filter_map(|(id, key)| if *id { Some(*key) } else { None })
@@ -302,6 +409,10 @@ impl IndexeddbStore {
             stores.extend([KEYS::ROOM_EVENT_RECEIPTS, KEYS::ROOM_USER_RECEIPTS])
         }
 
+        if !changes.timeline_chunks.is_empty() {
+            stores.extend([KEYS::TIMELINE_CHUNKS, KEYS::TIMELINE_EVENTS, KEYS::TIMELINE_REDACTIONS])
+        }
+
         if stores.len() == 0 {
             // nothing to do, quit early
             return Ok(());
@@ -398,6 +509,21 @@ impl IndexeddbStore {
             }
         }
 
+        if !changes.key_values.is_empty() {
+            let store = tx.object_store(KEYS::KEY_VALUES)?;
+            for (namespace, values) in &changes.key_values {
+                for (key, value) in values {
+                    let db_key = (namespace, key).encode();
+
+                    if let Some(value) = value {
+                        store.put_key_val(&db_key, &self.serialize_event(&(key, value))?)?;
+                    } else {
+                        store.delete(&db_key)?;
+                    }
+                }
+            }
+        }
+
         if !changes.members.is_empty() {
             for (room, events) in &changes.members {
                 let profile_changes = changes.profiles.get(room);
@@ -468,6 +594,35 @@ impl IndexeddbStore {
             }
         }
 
+        if !changes.timeline_chunks.is_empty() {
+            let chunks = tx.object_store(KEYS::TIMELINE_CHUNKS)?;
+            let events = tx.object_store(KEYS::TIMELINE_EVENTS)?;
+            let redactions = tx.object_store(KEYS::TIMELINE_REDACTIONS)?;
+
+            for chunk in &changes.timeline_chunks {
+                for event_id in chunk.redacted_event_ids() {
+                    let key = (&chunk.room_id, &event_id).encode();
+
+                    events.delete(&key)?;
+                    redactions.put_key_val(&key, &JsValue::TRUE)?;
+                }
+
+                for (event_id, event) in chunk.events_by_id() {
+                    let key = (&chunk.room_id, &event_id).encode();
+
+                    if redactions.get(&key)?.await?.is_none() {
+                        events.put_key_val(&key, &self.serialize_event(event)?)?;
+                    }
+                }
+
+                let (prev_batch, next_batch) = chunk.tokens();
+                chunks.put_key_val(
+                    &(&chunk.room_id, prev_batch, next_batch).encode(),
+                    &self.serialize_event(chunk)?,
+                )?;
+            }
+        }
+
         tx.await.into_result().map_err::<StoreError, _>(|e| e.into())
     }
 
@@ -716,24 +871,43 @@ impl IndexeddbStore {
 
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         let key = (&request.media_type.unique_key(), &request.format.unique_key()).encode();
-        let tx =
-            self.inner.transaction_on_one_with_mode(KEYS::MEDIA, IdbTransactionMode::Readwrite)?;
+        let entry = MediaCacheEntry::new(request, data.len());
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
 
         tx.object_store(KEYS::MEDIA)?.put_key_val(&key, &self.serialize_event(&data)?)?;
+        tx.object_store(KEYS::MEDIA_METADATA)?.put_key_val(&key, &self.serialize_event(&entry)?)?;
 
         tx.await.into_result().map_err(|e| e.into())
     }
 
     async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
         let key = (&request.media_type.unique_key(), &request.format.unique_key()).encode();
-        Ok(self
-            .inner
-            .transaction_on_one_with_mode(KEYS::MEDIA, IdbTransactionMode::Readonly)?
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let content: Option<Vec<u8>> = tx
             .object_store(KEYS::MEDIA)?
             .get(&key)?
             .await?
             .map(|f| self.deserialize_event(f))
-            .transpose()?)
+            .transpose()?;
+
+        if let Some(content) = &content {
+            // Track the access so the least recently used content is evicted
+            // first.
+            let entry = MediaCacheEntry::new(request, content.len());
+            tx.object_store(KEYS::MEDIA_METADATA)?
+                .put_key_val(&key, &self.serialize_event(&entry)?)?;
+        }
+
+        tx.await.into_result().map_err::<StoreError, _>(|e| e.into())?;
+
+        Ok(content)
     }
 
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -770,24 +944,201 @@ impl IndexeddbStore {
         Ok(prev)
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        Ok(self
+            .inner
+            .transaction_on_one_with_mode(KEYS::KEY_VALUES, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::KEY_VALUES)?
+            .get(&(namespace, key).encode())?
+            .await?
+            .map(|f| self.deserialize_event::<(String, serde_json::Value)>(f))
+            .transpose()?
+            .map(|(_, v)| v))
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        let range = namespace.encode_to_range().map_err(|e| StoreError::Codec(e))?;
+        let mut entries = self
+            .inner
+            .transaction_on_one_with_mode(KEYS::KEY_VALUES, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::KEY_VALUES)?
+            .get_all_with_key(&range)?
+            .await?
+            .iter()
+            .map(|f| self.deserialize_event::<(String, serde_json::Value)>(f))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The escaping of the encoded keys changes the ordering of some keys.
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(entries)
+    }
+
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
         let key = (&request.media_type.unique_key(), &request.format.unique_key()).encode();
-        let tx =
-            self.inner.transaction_on_one_with_mode(KEYS::MEDIA, IdbTransactionMode::Readwrite)?;
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
 
         tx.object_store(KEYS::MEDIA)?.delete(&key)?;
+        tx.object_store(KEYS::MEDIA_METADATA)?.delete(&key)?;
 
         tx.await.into_result().map_err(|e| e.into())
     }
 
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         let range = uri.encode_to_range().map_err(|e| StoreError::Codec(e))?;
-        let tx =
-            self.inner.transaction_on_one_with_mode(KEYS::MEDIA, IdbTransactionMode::Readwrite)?;
-        let store = tx.object_store(KEYS::MEDIA)?;
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
 
-        for k in store.get_all_keys_with_key(&range)?.await?.iter() {
-            store.delete(&k)?;
+        for store_name in [KEYS::MEDIA, KEYS::MEDIA_METADATA] {
+            let store = tx.object_store(store_name)?;
+            for k in store.get_all_keys_with_key(&range)?.await?.iter() {
+                store.delete(&k)?;
+            }
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        self.inner
+            .transaction_on_one_with_mode(KEYS::MEDIA_METADATA, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::MEDIA_METADATA)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|e| self.deserialize_event(e).map_err(StoreError::from))
+            .collect()
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let media = tx.object_store(KEYS::MEDIA)?;
+        let metadata = tx.object_store(KEYS::MEDIA_METADATA)?;
+
+        for entry in entries {
+            let key = (&entry.media_key, &entry.format_key).encode();
+            media.delete(&key)?;
+            metadata.delete(&key)?;
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::MEDIA, KEYS::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        tx.object_store(KEYS::MEDIA)?.clear()?;
+        tx.object_store(KEYS::MEDIA_METADATA)?.clear()?;
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        let key = (&event.room_id, &event.transaction_id).encode();
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(KEYS::PENDING_EVENTS, IdbTransactionMode::Readwrite)?;
+
+        tx.object_store(KEYS::PENDING_EVENTS)?.put_key_val(&key, &self.serialize_event(event)?)?;
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        let mut events = self
+            .inner
+            .transaction_on_one_with_mode(KEYS::PENDING_EVENTS, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::PENDING_EVENTS)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|e| self.deserialize_event::<PendingEvent>(e).map_err(StoreError::from))
+            .collect::<Result<Vec<_>>>()?;
+
+        events.sort_by_key(|e| e.sequence);
+
+        Ok(events)
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        let key = (room_id, transaction_id).encode();
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(KEYS::PENDING_EVENTS, IdbTransactionMode::Readwrite)?;
+
+        tx.object_store(KEYS::PENDING_EVENTS)?.delete(&key)?;
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        let range = room_id.encode_to_range().map_err(|e| StoreError::Codec(e))?;
+        let mut chunks = self
+            .inner
+            .transaction_on_one_with_mode(KEYS::TIMELINE_CHUNKS, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::TIMELINE_CHUNKS)?
+            .get_all_with_key(&range)?
+            .await?
+            .iter()
+            .map(|c| self.deserialize_event::<TimelineChunk>(c).map_err(StoreError::from))
+            .collect::<Result<Vec<_>>>()?;
+
+        sort_timeline_chunks(&mut chunks);
+
+        Ok(chunks)
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        Ok(self
+            .inner
+            .transaction_on_one_with_mode(KEYS::TIMELINE_EVENTS, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::TIMELINE_EVENTS)?
+            .get(&(room_id, event_id).encode())?
+            .await?
+            .map(|e| self.deserialize_event(e))
+            .transpose()?)
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        let chunks = self.get_timeline_chunks(room_id).await?;
+
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[KEYS::TIMELINE_CHUNKS, KEYS::TIMELINE_EVENTS],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let chunk_store = tx.object_store(KEYS::TIMELINE_CHUNKS)?;
+        let event_store = tx.object_store(KEYS::TIMELINE_EVENTS)?;
+
+        let pruned = pruning.prune(&chunks);
+
+        for chunk in pruned.chunks {
+            let (prev_batch, next_batch) = chunk.tokens();
+            chunk_store.delete(&(room_id, prev_batch, next_batch).encode())?;
+        }
+
+        for event_id in pruned.event_ids {
+            event_store.delete(&(room_id, &event_id).encode())?;
         }
 
         tx.await.into_result().map_err(|e| e.into())
@@ -808,6 +1159,10 @@ impl IndexeddbStore {
             KEYS::ROOM_USER_RECEIPTS,
             KEYS::STRIPPED_ROOM_STATE,
             KEYS::STRIPPED_MEMBERS,
+            KEYS::PENDING_EVENTS,
+            KEYS::TIMELINE_CHUNKS,
+            KEYS::TIMELINE_EVENTS,
+            KEYS::TIMELINE_REDACTIONS,
         ];
 
         let all_stores = {
@@ -961,6 +1316,14 @@ impl StateStore for IndexeddbStore {
         self.set_custom_value(key, value).await
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        self.get_key_value(namespace, key).await
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        self.get_key_values(namespace).await
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.add_media_content(request, data).await
     }
@@ -977,6 +1340,50 @@ impl StateStore for IndexeddbStore {
         self.remove_media_content_for_uri(uri).await
     }
 
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        self.get_media_cache_entries().await
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        self.remove_media_cache_entries(entries).await
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.clear_media_cache().await
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        self.save_pending_event(event).await
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        self.get_pending_events().await
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        self.remove_pending_event(room_id, transaction_id).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.get_timeline_chunks(room_id).await
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        self.get_timeline_event(room_id, event_id).await
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        self.prune_timeline(room_id, pruning).await
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.remove_room(room_id).await
     }
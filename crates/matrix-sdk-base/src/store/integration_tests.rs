@@ -18,6 +18,7 @@ macro_rules! statestore_integration_tests {
                         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent,
                         AnySyncStateEvent, EventType, Unsigned,
                     },
+                    TransactionId,
                     mxc_uri,
                     receipt::ReceiptType,
                     room_id,
@@ -26,13 +27,19 @@ macro_rules! statestore_integration_tests {
                 };
                 use serde_json::{json, Value as JsonValue};
 
-                use std::collections::{BTreeMap, BTreeSet};
+                use std::{collections::{BTreeMap, BTreeSet}, time::Duration};
 
                 use crate::{
                     RoomType, Session,
-                    deserialized_responses::{MemberEvent, StrippedMemberEvent},
-                    media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
+                    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent},
+                    media::{
+                        MediaCacheConfig, MediaCacheStats, MediaFormat, MediaRequest,
+                        MediaThumbnailSize, MediaType,
+                    },
                     store::{
+                        PendingEvent,
+                        TimelineChunk,
+                        TimelinePruning,
                         Store,
                         StateStore,
                         Result,
@@ -447,6 +454,66 @@ macro_rules! statestore_integration_tests {
                     assert!(store.get_media_content(&request_thumbnail).await.unwrap().is_none());
                 }
 
+                #[async_test]
+                async fn test_media_cache() -> Result<()> {
+                    let store = Store::new(Box::new(get_store().await?));
+
+                    let uri = mxc_uri!("mxc://localhost/media");
+                    let content: Vec<u8> = "somebinarydata".into();
+
+                    let request_file =
+                        MediaRequest { media_type: MediaType::Uri(uri.to_owned()), format: MediaFormat::File };
+
+                    let request_thumbnail = MediaRequest {
+                        media_type: MediaType::Uri(uri.to_owned()),
+                        format: MediaFormat::Thumbnail(MediaThumbnailSize {
+                            method: Method::Crop,
+                            width: uint!(100),
+                            height: uint!(100),
+                        }),
+                    };
+
+                    assert_eq!(store.media_cache_stats().await?, MediaCacheStats::default());
+
+                    store.add_media_content(&request_file, content.clone()).await?;
+                    store.add_media_content(&request_thumbnail, content.clone()).await?;
+
+                    let stats = store.media_cache_stats().await?;
+                    assert_eq!(stats.file_count, 1);
+                    assert_eq!(stats.file_size, content.len() as u64);
+                    assert_eq!(stats.thumbnail_count, 1);
+                    assert_eq!(stats.thumbnail_size, content.len() as u64);
+
+                    let config = MediaCacheConfig::new().max_age(Duration::from_secs(60 * 60));
+                    assert_eq!(store.clean_up_media_cache(&config).await?, 0);
+
+                    let config = MediaCacheConfig::new().max_thumbnail_size(0);
+                    assert_eq!(store.clean_up_media_cache(&config).await?, 1);
+                    assert!(store.get_media_content(&request_file).await?.is_some());
+                    assert!(store.get_media_content(&request_thumbnail).await?.is_none());
+
+                    let config = MediaCacheConfig::new().max_size(content.len() as u64 - 1);
+                    assert_eq!(store.clean_up_media_cache(&config).await?, 1);
+                    assert!(store.get_media_content(&request_file).await?.is_none());
+
+                    store.add_media_content(&request_file, content.clone()).await?;
+                    store.add_media_content(&request_thumbnail, content.clone()).await?;
+
+                    // Adding content cleans up the cache when a limit is exceeded.
+                    let config = MediaCacheConfig::new().max_thumbnail_size(0);
+                    store.cache_media_content(&request_file, content.clone(), &config).await?;
+                    store.cache_media_content(&request_thumbnail, content, &config).await?;
+                    assert!(store.get_media_content(&request_thumbnail).await?.is_none());
+                    assert!(store.get_media_content(&request_file).await?.is_some());
+
+                    store.clear_media_cache().await?;
+                    assert_eq!(store.media_cache_stats().await?, MediaCacheStats::default());
+                    assert!(store.get_media_content(&request_file).await?.is_none());
+                    assert!(store.get_media_content(&request_thumbnail).await?.is_none());
+
+                    Ok(())
+                }
+
                 #[async_test]
                 async fn test_custom_storage() -> Result<()> {
                     let key = "my_key";
@@ -462,6 +529,252 @@ macro_rules! statestore_integration_tests {
                     Ok(())
                 }
 
+                #[async_test]
+                async fn test_key_value_store() -> Result<()> {
+                    let store = Store::new(Box::new(get_store().await?));
+                    let counters = store.key_value_store::<String, u64>("counters");
+                    let others = store.key_value_store::<String, u64>("counters.other");
+
+                    assert_eq!(counters.get(&"a".to_owned()).await?, None);
+
+                    counters.set(&"a".to_owned(), &1).await?;
+                    others.set(&"a".to_owned(), &10).await?;
+                    assert_eq!(counters.get(&"a".to_owned()).await?, Some(1));
+                    assert_eq!(others.get(&"a".to_owned()).await?, Some(10));
+
+                    let mut changes = StateChanges::default();
+                    counters.set_in_changes(&mut changes, &"b".to_owned(), &2)?;
+                    counters.set_in_changes(&mut changes, &"ab".to_owned(), &3)?;
+                    counters.remove_in_changes(&mut changes, &"a".to_owned())?;
+                    store.save_changes(&changes).await?;
+
+                    assert_eq!(counters.get(&"a".to_owned()).await?, None);
+                    assert_eq!(
+                        counters.entries().await?,
+                        vec![("ab".to_owned(), 3), ("b".to_owned(), 2)]
+                    );
+                    assert_eq!(others.entries().await?, vec![("a".to_owned(), 10)]);
+
+                    others.remove(&"a".to_owned()).await?;
+                    assert!(others.entries().await?.is_empty());
+
+                    Ok(())
+                }
+
+                fn pending_event(room_id: &RoomId, sequence: u64) -> PendingEvent {
+                    PendingEvent {
+                        room_id: room_id.to_owned(),
+                        transaction_id: TransactionId::new(),
+                        event_type: "m.room.message".to_owned(),
+                        content: Raw::from_json(
+                            serde_json::value::to_raw_value(
+                                &json!({ "body": "Hello", "msgtype": "m.text" }),
+                            )
+                            .unwrap(),
+                        ),
+                        sequence,
+                    }
+                }
+
+                #[async_test]
+                async fn test_pending_events() -> Result<()> {
+                    let store = get_store().await?;
+                    let room_id = room_id();
+                    let stripped_room_id = stripped_room_id();
+
+                    assert!(store.get_pending_events().await?.is_empty());
+
+                    let second = pending_event(room_id, 2);
+                    let first = pending_event(room_id, 1);
+                    let other_room = pending_event(stripped_room_id, 3);
+
+                    store.save_pending_event(&second).await?;
+                    store.save_pending_event(&first).await?;
+                    store.save_pending_event(&other_room).await?;
+
+                    let events = store.get_pending_events().await?;
+                    assert_eq!(events.len(), 3);
+                    assert_eq!(events[0].transaction_id, first.transaction_id);
+                    assert_eq!(events[1].transaction_id, second.transaction_id);
+                    assert_eq!(events[2].transaction_id, other_room.transaction_id);
+
+                    store.remove_pending_event(room_id, &first.transaction_id).await?;
+
+                    let events = store.get_pending_events().await?;
+                    assert_eq!(events.len(), 2);
+                    assert_eq!(events[0].transaction_id, second.transaction_id);
+
+                    store.remove_room(room_id).await?;
+
+                    let events = store.get_pending_events().await?;
+                    assert_eq!(events.len(), 1);
+                    assert_eq!(events[0].transaction_id, other_room.transaction_id);
+
+                    Ok(())
+                }
+
+                fn timeline_chunk(
+                    room_id: &RoomId,
+                    next_batch: &str,
+                    events: &[(&str, u64)],
+                ) -> TimelineChunk {
+                    let events = events
+                        .iter()
+                        .map(|(event_id, ts)| {
+                            let event = json!({
+                                "type": "m.room.message",
+                                "event_id": event_id,
+                                "sender": user_id(),
+                                "origin_server_ts": ts,
+                                "content": { "body": "Hello", "msgtype": "m.text" },
+                            });
+
+                            SyncRoomEvent::from(Raw::from_json(
+                                serde_json::value::to_raw_value(&event).unwrap(),
+                            ))
+                        })
+                        .collect();
+
+                    TimelineChunk {
+                        room_id: room_id.to_owned(),
+                        events,
+                        prev_batch: Some(format!("{}_prev", next_batch)),
+                        next_batch: next_batch.to_owned(),
+                    }
+                }
+
+                #[async_test]
+                async fn test_timeline_chunks() -> Result<()> {
+                    let store = get_store().await?;
+                    let room_id = room_id();
+                    let now: u64 = MilliSecondsSinceUnixEpoch::now().0.into();
+
+                    assert!(store.get_timeline_chunks(room_id).await?.is_empty());
+
+                    let newest = timeline_chunk(room_id, "t3", &[("$d", now - 1), ("$e", now)]);
+                    let oldest = timeline_chunk(room_id, "t1", &[("$a", 1000)]);
+                    let middle = timeline_chunk(room_id, "t2", &[("$b", now - 3), ("$c", now - 2)]);
+
+                    let other_room = timeline_chunk(stripped_room_id(), "t4", &[("$f", now)]);
+
+                    let mut changes = StateChanges::default();
+                    changes.add_timeline_chunk(newest);
+                    changes.add_timeline_chunk(oldest);
+                    changes.add_timeline_chunk(middle);
+                    changes.add_timeline_chunk(other_room);
+                    store.save_changes(&changes).await?;
+
+                    let chunks = store.get_timeline_chunks(room_id).await?;
+                    let tokens: Vec<_> = chunks.iter().map(|c| c.next_batch.as_str()).collect();
+                    assert_eq!(tokens, ["t1", "t2", "t3"]);
+                    assert_eq!(chunks[2].prev_batch.as_deref(), Some("t3_prev"));
+
+                    assert!(store.get_timeline_event(room_id, event_id!("$c")).await?.is_some());
+                    assert!(store.get_timeline_event(room_id, event_id!("$f")).await?.is_none());
+
+                    let pruning = TimelinePruning::MaxAge(Duration::from_secs(3600));
+                    store.prune_timeline(room_id, pruning).await?;
+
+                    let chunks = store.get_timeline_chunks(room_id).await?;
+                    assert_eq!(chunks.len(), 2);
+                    assert!(store.get_timeline_event(room_id, event_id!("$a")).await?.is_none());
+
+                    store.prune_timeline(room_id, TimelinePruning::MaxEvents(3)).await?;
+
+                    let chunks = store.get_timeline_chunks(room_id).await?;
+                    assert_eq!(chunks.len(), 1);
+                    assert_eq!(chunks[0].next_batch, "t3");
+                    assert!(store.get_timeline_event(room_id, event_id!("$b")).await?.is_none());
+                    assert!(store.get_timeline_event(room_id, event_id!("$e")).await?.is_some());
+
+                    store.remove_room(room_id).await?;
+
+                    assert!(store.get_timeline_chunks(room_id).await?.is_empty());
+                    assert!(store.get_timeline_event(room_id, event_id!("$e")).await?.is_none());
+                    assert_eq!(store.get_timeline_chunks(stripped_room_id()).await?.len(), 1);
+
+                    Ok(())
+                }
+
+                #[async_test]
+                async fn test_overlapping_timeline_chunks() -> Result<()> {
+                    let store = get_store().await?;
+                    let room_id = room_id();
+                    let now: u64 = MilliSecondsSinceUnixEpoch::now().0.into();
+
+                    // A chunk that was back-paginated from the token of a sync
+                    // ends with the same token as the chunk of that sync.
+                    let synced = timeline_chunk(room_id, "t2", &[("$b", now - 1), ("$c", now)]);
+                    let mut paginated =
+                        timeline_chunk(room_id, "t2", &[("$a", now - 2), ("$b", now - 1)]);
+                    paginated.prev_batch = Some("t1".to_owned());
+
+                    let mut changes = StateChanges::default();
+                    changes.add_timeline_chunk(synced);
+                    changes.add_timeline_chunk(paginated);
+                    store.save_changes(&changes).await?;
+
+                    assert_eq!(store.get_timeline_chunks(room_id).await?.len(), 2);
+
+                    store.prune_timeline(room_id, TimelinePruning::MaxEvents(2)).await?;
+
+                    let chunks = store.get_timeline_chunks(room_id).await?;
+                    assert_eq!(chunks.len(), 1);
+                    assert_eq!(chunks[0].prev_batch.as_deref(), Some("t2_prev"));
+                    assert!(store.get_timeline_event(room_id, event_id!("$a")).await?.is_none());
+                    // The event is still part of the remaining chunk.
+                    assert!(store.get_timeline_event(room_id, event_id!("$b")).await?.is_some());
+
+                    Ok(())
+                }
+
+                #[async_test]
+                async fn test_redacted_timeline_events() -> Result<()> {
+                    let store = get_store().await?;
+                    let room_id = room_id();
+                    let now: u64 = MilliSecondsSinceUnixEpoch::now().0.into();
+
+                    let mut changes = StateChanges::default();
+                    changes.add_timeline_chunk(timeline_chunk(
+                        room_id,
+                        "t1",
+                        &[("$a", now - 2), ("$b", now - 1)],
+                    ));
+                    store.save_changes(&changes).await?;
+
+                    assert!(store.get_timeline_event(room_id, event_id!("$a")).await?.is_some());
+
+                    let redaction = json!({
+                        "type": "m.room.redaction",
+                        "event_id": "$r",
+                        "sender": user_id(),
+                        "origin_server_ts": now,
+                        "redacts": "$a",
+                        "content": {},
+                    });
+                    let mut redacting = timeline_chunk(room_id, "t2", &[]);
+                    redacting.events.push(SyncRoomEvent::from(Raw::from_json(
+                        serde_json::value::to_raw_value(&redaction).unwrap(),
+                    )));
+
+                    let mut changes = StateChanges::default();
+                    changes.add_timeline_chunk(redacting);
+                    store.save_changes(&changes).await?;
+
+                    assert!(store.get_timeline_event(room_id, event_id!("$a")).await?.is_none());
+                    assert!(store.get_timeline_event(room_id, event_id!("$b")).await?.is_some());
+                    assert!(store.get_timeline_event(room_id, event_id!("$r")).await?.is_some());
+
+                    // Paginating over the redacted event again doesn't bring it back.
+                    let mut changes = StateChanges::default();
+                    changes.add_timeline_chunk(timeline_chunk(room_id, "t1", &[("$a", now - 2)]));
+                    store.save_changes(&changes).await?;
+
+                    assert!(store.get_timeline_event(room_id, event_id!("$a")).await?.is_none());
+
+                    Ok(())
+                }
+
                 #[async_test]
                 async fn test_persist_invited_room() -> Result<()> {
                     let stripped_room_id = stripped_room_id();
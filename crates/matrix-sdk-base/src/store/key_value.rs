@@ -0,0 +1,147 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Result, StateChanges, Store};
+
+/// A typed, user-defined key-value store that lives in a namespace of the
+/// state store.
+///
+/// Keys and values are serialized as JSON. The values are encrypted with the
+/// same key as the rest of the state store if the store was opened with a
+/// passphrase.
+///
+/// Writes can be committed on their own with [`set()`](#method.set) and
+/// [`remove()`](#method.remove), or added to a [`StateChanges`] with
+/// [`set_in_changes()`](#method.set_in_changes) so they are committed
+/// atomically together with the other changes once they are saved. To save
+/// values atomically with the state of a sync response, add them to its
+/// changes in a hook registered with [`BaseClient::register_sync_hook()`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use matrix_sdk_base::{StateChanges, Store, StoreError};
+/// # async fn example(store: Store) -> Result<(), StoreError> {
+/// let cooldowns = store.key_value_store::<String, u64>("bot.cooldowns");
+///
+/// let mut changes = StateChanges::default();
+/// cooldowns.set_in_changes(&mut changes, &"!room:localhost".to_owned(), &1643648411)?;
+/// cooldowns.set_in_changes(&mut changes, &"!other:localhost".to_owned(), &1643648472)?;
+///
+/// // Both values are written in a single transaction.
+/// store.save_changes(&changes).await?;
+///
+/// assert_eq!(cooldowns.get(&"!room:localhost".to_owned()).await?, Some(1643648411));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`BaseClient::register_sync_hook()`]: crate::BaseClient::register_sync_hook
+pub struct KeyValueStore<K, V> {
+    store: Store,
+    namespace: String,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for KeyValueStore<K, V> {
+    fn clone(&self) -> Self {
+        Self { store: self.store.clone(), namespace: self.namespace.clone(), _types: PhantomData }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<K, V> fmt::Debug for KeyValueStore<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyValueStore").field("namespace", &self.namespace).finish()
+    }
+}
+
+impl<K, V> KeyValueStore<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub(crate) fn new(store: Store, namespace: &str) -> Self {
+        Self { store, namespace: namespace.to_owned(), _types: PhantomData }
+    }
+
+    /// The namespace of this store.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Get the value of the given key.
+    pub async fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = serde_json::to_string(key)?;
+
+        Ok(self
+            .store
+            .get_key_value(&self.namespace, &key)
+            .await?
+            .map(serde_json::from_value)
+            .transpose()?)
+    }
+
+    /// Get all the entries of this store, ordered by their serialized key.
+    pub async fn entries(&self) -> Result<Vec<(K, V)>> {
+        self.store
+            .get_key_values(&self.namespace)
+            .await?
+            .into_iter()
+            .map(|(k, v)| Ok((serde_json::from_str(&k)?, serde_json::from_value(v)?)))
+            .collect()
+    }
+
+    /// Set the value of the given key.
+    pub async fn set(&self, key: &K, value: &V) -> Result<()> {
+        let mut changes = StateChanges::default();
+        self.set_in_changes(&mut changes, key, value)?;
+
+        self.store.save_changes(&changes).await
+    }
+
+    /// Remove the value of the given key.
+    pub async fn remove(&self, key: &K) -> Result<()> {
+        let mut changes = StateChanges::default();
+        self.remove_in_changes(&mut changes, key)?;
+
+        self.store.save_changes(&changes).await
+    }
+
+    /// Add setting the value of the given key to `changes`.
+    ///
+    /// The value is written once the changes are saved.
+    pub fn set_in_changes(&self, changes: &mut StateChanges, key: &K, value: &V) -> Result<()> {
+        changes.add_key_value(
+            &self.namespace,
+            serde_json::to_string(key)?,
+            Some(serde_json::to_value(value)?),
+        );
+
+        Ok(())
+    }
+
+    /// Add removing the value of the given key to `changes`.
+    ///
+    /// The value is removed once the changes are saved.
+    pub fn remove_in_changes(&self, changes: &mut StateChanges, key: &K) -> Result<()> {
+        changes.add_key_value(&self.namespace, serde_json::to_string(key)?, None);
+
+        Ok(())
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, RwLock},
 };
 
@@ -31,15 +31,18 @@ use ruma::{
     },
     receipt::ReceiptType,
     serde::Raw,
-    EventId, MxcUri, RoomId, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, MxcUri, RoomId, TransactionId, UserId,
 };
 #[allow(unused_imports)]
 use tracing::info;
 
-use super::{Result, RoomInfo, StateChanges, StateStore};
+use super::{
+    sort_timeline_chunks, PendingEvent, Result, RoomInfo, StateChanges, StateStore, TimelineChunk,
+    TimelinePruning,
+};
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
-    media::{MediaRequest, UniqueKey},
+    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent},
+    media::{MediaCacheEntry, MediaRequest, UniqueKey},
 };
 
 #[allow(clippy::type_complexity)]
@@ -66,8 +69,13 @@ pub struct MemoryStore {
     room_event_receipts: Arc<
         DashMap<Box<RoomId>, DashMap<String, DashMap<Box<EventId>, DashMap<Box<UserId>, Receipt>>>>,
     >,
-    media: Arc<Mutex<LruCache<String, Vec<u8>>>>,
+    media: Arc<Mutex<LruCache<String, (MediaCacheEntry, Vec<u8>)>>>,
     custom: Arc<DashMap<Vec<u8>, Vec<u8>>>,
+    key_values: Arc<DashMap<String, BTreeMap<String, serde_json::Value>>>,
+    pending_events: Arc<DashMap<Box<RoomId>, DashMap<Box<TransactionId>, PendingEvent>>>,
+    timeline_chunks: Arc<DashMap<Box<RoomId>, DashMap<(String, String), TimelineChunk>>>,
+    timeline_events: Arc<DashMap<Box<RoomId>, DashMap<Box<EventId>, SyncRoomEvent>>>,
+    timeline_redactions: Arc<DashMap<Box<RoomId>, DashSet<Box<EventId>>>>,
 }
 
 impl MemoryStore {
@@ -93,6 +101,11 @@ impl MemoryStore {
             room_event_receipts: Default::default(),
             media: Arc::new(Mutex::new(LruCache::new(100))),
             custom: DashMap::new().into(),
+            key_values: Default::default(),
+            pending_events: Default::default(),
+            timeline_chunks: Default::default(),
+            timeline_events: Default::default(),
+            timeline_redactions: Default::default(),
         }
     }
 
@@ -274,6 +287,43 @@ impl MemoryStore {
             }
         }
 
+        for (namespace, values) in &changes.key_values {
+            let mut entries =
+                self.key_values.entry(namespace.clone()).or_insert_with(BTreeMap::new);
+
+            for (key, value) in values {
+                if let Some(value) = value {
+                    entries.insert(key.clone(), value.clone());
+                } else {
+                    entries.remove(key);
+                }
+            }
+        }
+
+        for chunk in &changes.timeline_chunks {
+            let redactions =
+                self.timeline_redactions.entry(chunk.room_id.clone()).or_insert_with(DashSet::new);
+            let events =
+                self.timeline_events.entry(chunk.room_id.clone()).or_insert_with(DashMap::new);
+
+            for event_id in chunk.redacted_event_ids() {
+                events.remove(&event_id);
+                redactions.insert(event_id);
+            }
+
+            for (event_id, event) in chunk.events_by_id() {
+                if !redactions.contains(&event_id) {
+                    events.insert(event_id, event.clone());
+                }
+            }
+
+            let (prev_batch, next_batch) = chunk.tokens();
+            self.timeline_chunks
+                .entry(chunk.room_id.clone())
+                .or_insert_with(DashMap::new)
+                .insert((prev_batch.to_owned(), next_batch.to_owned()), chunk.clone());
+        }
+
         info!("Saved changes in {:?}", now.elapsed());
 
         Ok(())
@@ -408,14 +458,30 @@ impl MemoryStore {
         Ok(self.custom.insert(key.to_vec(), value))
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        Ok(self.key_values.get(namespace).and_then(|e| e.get(key).cloned()))
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        Ok(self
+            .key_values
+            .get(namespace)
+            .map(|e| e.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default())
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
-        self.media.lock().await.put(request.unique_key(), data);
+        let entry = MediaCacheEntry::new(request, data.len());
+        self.media.lock().await.put(request.unique_key(), (entry, data));
 
         Ok(())
     }
 
     async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
-        Ok(self.media.lock().await.get(&request.unique_key()).cloned())
+        Ok(self.media.lock().await.get_mut(&request.unique_key()).map(|(entry, data)| {
+            entry.last_access = MilliSecondsSinceUnixEpoch::now();
+            data.clone()
+        }))
     }
 
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
@@ -441,6 +507,100 @@ impl MemoryStore {
         Ok(())
     }
 
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        Ok(self.media.lock().await.iter().map(|(_, (entry, _))| entry.clone()).collect())
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        let mut media_store = self.media.lock().await;
+
+        for entry in entries {
+            media_store.pop(&entry.unique_key());
+        }
+
+        Ok(())
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.media.lock().await.clear();
+
+        Ok(())
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        self.pending_events
+            .entry(event.room_id.clone())
+            .or_insert_with(DashMap::new)
+            .insert(event.transaction_id.clone(), event.clone());
+
+        Ok(())
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        let mut events: Vec<PendingEvent> = self
+            .pending_events
+            .iter()
+            .flat_map(|r| r.iter().map(|e| e.value().clone()).collect::<Vec<_>>())
+            .collect();
+
+        events.sort_by_key(|e| e.sequence);
+
+        Ok(events)
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        if let Some(events) = self.pending_events.get(room_id) {
+            events.remove(transaction_id);
+        }
+
+        Ok(())
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        let mut chunks: Vec<TimelineChunk> = self
+            .timeline_chunks
+            .get(room_id)
+            .map(|c| c.iter().map(|c| c.value().clone()).collect())
+            .unwrap_or_default();
+
+        sort_timeline_chunks(&mut chunks);
+
+        Ok(chunks)
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        Ok(self.timeline_events.get(room_id).and_then(|e| e.get(event_id).map(|e| e.clone())))
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        let chunks = self.get_timeline_chunks(room_id).await?;
+
+        let pruned = pruning.prune(&chunks);
+
+        if let Some(chunks) = self.timeline_chunks.get(room_id) {
+            for chunk in pruned.chunks {
+                let (prev_batch, next_batch) = chunk.tokens();
+                chunks.remove(&(prev_batch.to_owned(), next_batch.to_owned()));
+            }
+        }
+
+        if let Some(events) = self.timeline_events.get(room_id) {
+            for event_id in pruned.event_ids {
+                events.remove(&event_id);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.members.remove(room_id);
         self.profiles.remove(room_id);
@@ -455,6 +615,10 @@ impl MemoryStore {
         self.stripped_members.remove(room_id);
         self.room_user_receipts.remove(room_id);
         self.room_event_receipts.remove(room_id);
+        self.pending_events.remove(room_id);
+        self.timeline_chunks.remove(room_id);
+        self.timeline_events.remove(room_id);
+        self.timeline_redactions.remove(room_id);
 
         Ok(())
     }
@@ -589,6 +753,14 @@ impl StateStore for MemoryStore {
         self.set_custom_value(key, value).await
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        self.get_key_value(namespace, key).await
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        self.get_key_values(namespace).await
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.add_media_content(request, data).await
     }
@@ -605,6 +777,50 @@ impl StateStore for MemoryStore {
         self.remove_media_content_for_uri(uri).await
     }
 
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        self.get_media_cache_entries().await
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        self.remove_media_cache_entries(entries).await
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.clear_media_cache().await
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        self.save_pending_event(event).await
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        self.get_pending_events().await
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        self.remove_pending_event(room_id, transaction_id).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.get_timeline_chunks(room_id).await
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        self.get_timeline_event(room_id, event_id).await
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        self.prune_timeline(room_id, pruning).await
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.remove_room(room_id).await
     }
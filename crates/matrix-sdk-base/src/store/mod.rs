@@ -12,12 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "sled_state_store")]
+#[cfg(any(feature = "sled_state_store", feature = "sqlite_state_store"))]
 use std::path::Path;
 use std::{
     collections::{BTreeMap, BTreeSet},
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
 };
 
 #[cfg(test)]
@@ -25,22 +26,27 @@ use std::{
 pub mod integration_tests;
 
 use dashmap::DashMap;
-use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
+use matrix_sdk_common::{async_trait, instant::Instant, locks::RwLock, AsyncTraitDeps};
 use ruma::{
     api::client::r0::push::get_notifications::Notification,
     events::{
         presence::PresenceEvent,
         receipt::{Receipt, ReceiptEventContent},
         room::member::RoomMemberEventContent,
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
-        AnySyncStateEvent, EventContent, EventType,
+        AnyGlobalAccountDataEvent, AnyMessageEventContent, AnyRoomAccountDataEvent,
+        AnyStrippedStateEvent, AnySyncStateEvent, EventContent, EventType,
     },
     receipt::ReceiptType,
     serde::Raw,
-    EventId, MxcUri, RoomId, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, MxcUri, RoomId, TransactionId, UInt, UserId,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[cfg(any(feature = "sled_state_store", feature = "indexeddb_state_store"))]
+#[cfg(any(
+    feature = "sled_state_store",
+    feature = "sqlite_state_store",
+    feature = "indexeddb_state_store"
+))]
 mod store_key;
 
 #[cfg(feature = "sled_state_store")]
@@ -50,23 +56,33 @@ use sled::Db;
 mod indexeddb_store;
 
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
-    media::MediaRequest,
+    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent},
+    media::{MediaCacheConfig, MediaCacheEntry, MediaCacheStats, MediaRequest},
     rooms::{RoomInfo, RoomType},
     Room, Session,
 };
 
 pub(crate) mod ambiguity_map;
+mod key_value;
 mod memory_store;
 #[cfg(feature = "sled_state_store")]
 mod sled_store;
+#[cfg(feature = "sqlite_state_store")]
+mod sqlite_store;
 
 #[cfg(feature = "indexeddb_state_store")]
 use self::indexeddb_store::IndexeddbStore;
-#[cfg(not(any(feature = "sled_state_store", feature = "indexeddb_state_store")))]
+pub use self::key_value::KeyValueStore;
+#[cfg(not(any(
+    feature = "sled_state_store",
+    feature = "sqlite_state_store",
+    feature = "indexeddb_state_store"
+)))]
 use self::memory_store::MemoryStore;
 #[cfg(feature = "sled_state_store")]
 use self::sled_store::SledStore;
+#[cfg(feature = "sqlite_state_store")]
+use self::sqlite_store::SqliteStore;
 
 /// State store specific error type.
 #[derive(Debug, thiserror::Error)]
@@ -75,6 +91,10 @@ pub enum StoreError {
     #[cfg(feature = "sled_state_store")]
     #[error(transparent)]
     Sled(#[from] sled::Error),
+    /// An error happened in the underlying SQLite database.
+    #[cfg(feature = "sqlite_state_store")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
     /// An error happened in the underlying Indexed Database.
     #[cfg(feature = "indexeddb_state_store")]
     #[error("IndexDB error: {name} ({code}): {message}")]
@@ -107,7 +127,7 @@ pub enum StoreError {
     #[error("Error encoding or decoding data from the store: {0}")]
     Codec(String),
     /// An error happened while running a tokio task.
-    #[cfg(feature = "sled_state_store")]
+    #[cfg(any(feature = "sled_state_store", feature = "sqlite_state_store"))]
     #[error(transparent)]
     Task(#[from] tokio::task::JoinError),
 }
@@ -317,6 +337,23 @@ pub trait StateStore: AsyncTraitDeps {
     /// * `value` - The value to insert
     async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
+    /// Get the value of a key of a user-defined key-value store.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the key-value store.
+    ///
+    /// * `key` - The serialized key to fetch the value for.
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>>;
+
+    /// Get all the entries of a user-defined key-value store, ordered by their
+    /// key.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the key-value store.
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>>;
+
     /// Add a media file's content in the media store.
     ///
     /// # Arguments
@@ -348,6 +385,82 @@ pub trait StateStore: AsyncTraitDeps {
     /// * `uri` - The `MxcUri` of the media files.
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()>;
 
+    /// Get the metadata of all the media content in the media store.
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>>;
+
+    /// Removes the media content described by the given entries from the
+    /// media store.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The entries of the media content to remove.
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()>;
+
+    /// Removes all the media content from the media store.
+    async fn clear_media_cache(&self) -> Result<()>;
+
+    /// Save an event that is waiting to be sent to a room.
+    ///
+    /// An already stored pending event with the same room id and transaction
+    /// id will be replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The `PendingEvent` that should be stored.
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()>;
+
+    /// Get all the events that are waiting to be sent, ordered by the sequence
+    /// number they were queued with.
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>>;
+
+    /// Remove an event from the list of events that are waiting to be sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event should be sent to.
+    ///
+    /// * `transaction_id` - The transaction id of the pending event.
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()>;
+
+    /// Get all the stored timeline chunks of a room, ordered from the oldest
+    /// to the newest chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>>;
+
+    /// Get a stored timeline event of a room.
+    ///
+    /// Events that were redacted by an event of a stored chunk are not
+    /// returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event belongs to.
+    ///
+    /// * `event_id` - The id of the event.
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>>;
+
+    /// Remove the timeline chunks of a room that don't satisfy the given
+    /// pruning rule, together with the events that aren't part of any of the
+    /// remaining chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    ///
+    /// * `pruning` - The rule that decides which chunks should be removed.
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()>;
+
     /// Removes a room and all elements associated from the state store.
     ///
     /// # Arguments
@@ -367,6 +480,9 @@ pub struct Store {
     pub(crate) sync_token: Arc<RwLock<Option<String>>>,
     rooms: Arc<DashMap<Box<RoomId>, Room>>,
     stripped_rooms: Arc<DashMap<Box<RoomId>, Room>>,
+    /// The time of the last clean-up of the media cache and an estimate of
+    /// the content that was cached since then.
+    media_cache_usage: Arc<StdMutex<Option<(Instant, MediaCacheStats)>>>,
 }
 
 #[cfg(feature = "sled_state_store")]
@@ -396,6 +512,31 @@ impl Store {
     }
 }
 
+#[cfg(feature = "sqlite_state_store")]
+impl Store {
+    /// Open the default SQLite store.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the store should reside in.
+    ///
+    /// * `passphrase` - A passphrase that should be used to encrypt the state
+    /// store.
+    pub fn open_default(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        let inner = if let Some(passphrase) = passphrase {
+            SqliteStore::open_with_passphrase(path, passphrase)?
+        } else {
+            SqliteStore::open_with_path(path)?
+        };
+
+        Ok(Self::new(Box::new(inner)))
+    }
+
+    pub(crate) fn open_temporary() -> Result<Self> {
+        Ok(Self::new(Box::new(SqliteStore::open()?)))
+    }
+}
+
 #[cfg(feature = "indexeddb_state_store")]
 impl Store {
     /// Open the default IndexedDB store.
@@ -417,7 +558,11 @@ impl Store {
     }
 }
 
-#[cfg(not(any(feature = "sled_state_store", feature = "indexeddb_state_store")))]
+#[cfg(not(any(
+    feature = "sled_state_store",
+    feature = "sqlite_state_store",
+    feature = "indexeddb_state_store"
+)))]
 impl Store {
     pub(crate) fn open_memory_store() -> Self {
         let inner = Box::new(MemoryStore::new());
@@ -434,6 +579,7 @@ impl Store {
             sync_token: Default::default(),
             rooms: Default::default(),
             stripped_rooms: Default::default(),
+            media_cache_usage: Default::default(),
         }
     }
 
@@ -506,6 +652,74 @@ impl Store {
             .or_insert_with(|| Room::new(user_id, self.inner.clone(), room_id, room_type))
             .clone()
     }
+
+    /// Get the user-defined key-value store with the given namespace.
+    ///
+    /// Stores with different namespaces never share entries.
+    pub fn key_value_store<K, V>(&self, namespace: &str) -> KeyValueStore<K, V>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        KeyValueStore::new(self.clone(), namespace)
+    }
+
+    /// Get statistics about the content of the media store.
+    pub async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        Ok(self.inner.get_media_cache_entries().await?.iter().collect())
+    }
+
+    /// Add the given media content to the media store and evict the content
+    /// that exceeds the limits of the given config.
+    ///
+    /// Unlike [`clean_up_media_cache()`](Self::clean_up_media_cache), the
+    /// content of the store is only listed when the limits might be exceeded,
+    /// see [`MediaCacheConfig`].
+    pub async fn cache_media_content(
+        &self,
+        request: &MediaRequest,
+        content: Vec<u8>,
+        config: &MediaCacheConfig,
+    ) -> Result<()> {
+        let entry = MediaCacheEntry::new(request, content.len());
+        self.inner.add_media_content(request, content).await?;
+
+        let needs_clean_up = match &mut *self.media_cache_usage.lock().unwrap() {
+            Some((cleaned_up_at, stats)) => {
+                stats.add(&entry);
+                config.needs_clean_up(stats, cleaned_up_at.elapsed())
+            }
+            None => true,
+        };
+
+        if needs_clean_up {
+            self.clean_up_media_cache(config).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Evict the media content that exceeds the limits of the given config
+    /// from the media store.
+    ///
+    /// Returns the number of evicted media files.
+    pub async fn clean_up_media_cache(&self, config: &MediaCacheConfig) -> Result<usize> {
+        let entries = self.inner.get_media_cache_entries().await?;
+        let mut stats: MediaCacheStats = entries.iter().collect();
+        let entries = config.entries_to_evict(entries);
+
+        if !entries.is_empty() {
+            self.inner.remove_media_cache_entries(&entries).await?;
+        }
+
+        for entry in &entries {
+            stats.remove(entry);
+        }
+
+        *self.media_cache_usage.lock().unwrap() = Some((Instant::now(), stats));
+
+        Ok(entries.len())
+    }
 }
 
 impl Deref for Store {
@@ -516,8 +730,160 @@ impl Deref for Store {
     }
 }
 
+/// An event that was queued to be sent to a room but hasn't been accepted by
+/// the homeserver yet.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingEvent {
+    /// The id of the room the event should be sent to.
+    pub room_id: Box<RoomId>,
+    /// The transaction id that was assigned to the event when it was queued.
+    pub transaction_id: Box<TransactionId>,
+    /// The type of the event.
+    pub event_type: String,
+    /// The unencrypted content of the event.
+    pub content: Raw<AnyMessageEventContent>,
+    /// The position of the event in the queue, events are sent in ascending
+    /// order of their sequence number.
+    pub sequence: u64,
+}
+
+/// A chunk of consecutive events of a room timeline.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimelineChunk {
+    /// The id of the room the events belong to.
+    pub room_id: Box<RoomId>,
+    /// The events of the chunk, ordered from the oldest to the newest event.
+    pub events: Vec<SyncRoomEvent>,
+    /// The token to fetch the events that come before this chunk, `None` if
+    /// the chunk starts at the beginning of the room.
+    pub prev_batch: Option<String>,
+    /// The token to fetch the events that come after this chunk.
+    pub next_batch: String,
+}
+
+#[derive(Deserialize)]
+struct TimelineEventDetails {
+    event_id: Box<EventId>,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    redacts: Option<Box<EventId>>,
+}
+
+impl TimelineChunk {
+    /// The time the newest event of this chunk was sent at, `None` if the
+    /// chunk doesn't contain any events.
+    pub fn newest_event_ts(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.event_details().map(|(details, _)| details.origin_server_ts).max()
+    }
+
+    /// Get the tokens that identify this chunk in the store.
+    ///
+    /// The `next_batch` token alone isn't unique, a chunk that was
+    /// back-paginated from the token of a sync ends with the same token as
+    /// the chunk of that sync.
+    pub(crate) fn tokens(&self) -> (&str, &str) {
+        (self.prev_batch.as_deref().unwrap_or_default(), &self.next_batch)
+    }
+
+    /// Get the ids of the events that are redacted by events of this chunk.
+    pub(crate) fn redacted_event_ids(&self) -> impl Iterator<Item = Box<EventId>> + '_ {
+        self.event_details().filter_map(|(details, _)| details.redacts)
+    }
+
+    /// Get the events of the chunk together with their event id.
+    ///
+    /// Events that don't contain an event id are skipped.
+    pub(crate) fn events_by_id(&self) -> impl Iterator<Item = (Box<EventId>, &SyncRoomEvent)> {
+        self.event_details().map(|(details, event)| (details.event_id, event))
+    }
+
+    fn event_details(&self) -> impl Iterator<Item = (TimelineEventDetails, &SyncRoomEvent)> {
+        self.events.iter().filter_map(|e| Some((e.event.deserialize_as().ok()?, e)))
+    }
+}
+
+/// A rule deciding which timeline chunks of a room should be removed from the
+/// store. See [`StateStore::prune_timeline`].
+#[derive(Clone, Copy, Debug)]
+pub enum TimelinePruning {
+    /// Keep at most the given number of events, the oldest chunks are removed
+    /// first. Chunks are removed as a whole.
+    MaxEvents(usize),
+    /// Remove the chunks whose newest event is older than the given duration.
+    MaxAge(Duration),
+}
+
+/// The chunks and events that should be removed from the store to apply a
+/// [`TimelinePruning`].
+#[derive(Debug)]
+pub(crate) struct PrunedTimeline<'a> {
+    /// The chunks that should be removed.
+    pub chunks: Vec<&'a TimelineChunk>,
+    /// The ids of the events of the removed chunks that aren't part of any of
+    /// the remaining chunks.
+    pub event_ids: BTreeSet<Box<EventId>>,
+}
+
+impl TimelinePruning {
+    /// Get the chunks and events that should be removed, `chunks` need to be
+    /// sorted using [`sort_timeline_chunks`].
+    ///
+    /// Chunks can overlap, e.g. when the same part of the timeline was
+    /// paginated twice, events that are still part of a remaining chunk are
+    /// kept.
+    pub(crate) fn prune<'a>(&self, chunks: &'a [TimelineChunk]) -> PrunedTimeline<'a> {
+        let removed = self.chunks_to_remove(chunks);
+
+        let kept: BTreeSet<Box<EventId>> = chunks
+            .iter()
+            .filter(|c| !removed.iter().any(|r| std::ptr::eq(*r, *c)))
+            .flat_map(|c| c.events_by_id().map(|(event_id, _)| event_id))
+            .collect();
+
+        let event_ids = removed
+            .iter()
+            .flat_map(|c| c.events_by_id().map(|(event_id, _)| event_id))
+            .filter(|event_id| !kept.contains(event_id))
+            .collect();
+
+        PrunedTimeline { chunks: removed, event_ids }
+    }
+
+    fn chunks_to_remove<'a>(&self, chunks: &'a [TimelineChunk]) -> Vec<&'a TimelineChunk> {
+        match self {
+            Self::MaxEvents(max) => {
+                let mut count = 0;
+
+                chunks
+                    .iter()
+                    .rev()
+                    .filter(|c| {
+                        count += c.events.len();
+                        count > *max
+                    })
+                    .collect()
+            }
+            Self::MaxAge(age) => {
+                let now = MilliSecondsSinceUnixEpoch::now().0;
+                let age =
+                    u64::try_from(age.as_millis()).ok().and_then(UInt::new).unwrap_or(UInt::MAX);
+                let cutoff = MilliSecondsSinceUnixEpoch(now.saturating_sub(age));
+
+                chunks
+                    .iter()
+                    .filter(|c| c.newest_event_ts().map_or(true, |ts| ts < cutoff))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Sort timeline chunks from the oldest to the newest chunk.
+pub(crate) fn sort_timeline_chunks(chunks: &mut [TimelineChunk]) {
+    chunks.sort_by_cached_key(|c| c.newest_event_ts());
+}
+
 /// Store state changes and pass them to the StateStore.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct StateChanges {
     /// The sync token that relates to this update.
     pub sync_token: Option<String>,
@@ -562,6 +928,15 @@ pub struct StateChanges {
     pub ambiguity_maps: BTreeMap<Box<RoomId>, BTreeMap<String, BTreeSet<Box<UserId>>>>,
     /// A map of `RoomId` to a vector of `Notification`s
     pub notifications: BTreeMap<Box<RoomId>, Vec<Notification>>,
+    /// A map of namespaces of user-defined key-value stores to a map of
+    /// serialized keys and their new values, a `None` value removes the key.
+    pub key_values: BTreeMap<String, BTreeMap<String, Option<serde_json::Value>>>,
+    /// The chunks of room timelines that should be stored.
+    ///
+    /// An already stored chunk of the same room with the same tokens will be
+    /// replaced. The events of the chunks can be looked up by their event id
+    /// afterwards.
+    pub timeline_chunks: Vec<TimelineChunk>,
 }
 
 impl StateChanges {
@@ -635,12 +1010,31 @@ impl StateChanges {
             .insert(event.state_key().to_string(), raw_event);
     }
 
+    /// Update the `StateChanges` struct with a new value of a key of a
+    /// user-defined key-value store, `None` removes the key.
+    pub fn add_key_value(
+        &mut self,
+        namespace: &str,
+        key: String,
+        value: Option<serde_json::Value>,
+    ) {
+        self.key_values
+            .entry(namespace.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(key, value);
+    }
+
     /// Update the `StateChanges` struct with the given room with a new
     /// `Notification`.
     pub fn add_notification(&mut self, room_id: &RoomId, notification: Notification) {
         self.notifications.entry(room_id.to_owned()).or_insert_with(Vec::new).push(notification);
     }
 
+    /// Update the `StateChanges` struct with a new `TimelineChunk`.
+    pub fn add_timeline_chunk(&mut self, chunk: TimelineChunk) {
+        self.timeline_chunks.push(chunk);
+    }
+
     /// Update the `StateChanges` struct with the given room with a new
     /// `Receipts`.
     pub fn add_receipts(&mut self, room_id: &RoomId, event: ReceiptEventContent) {
@@ -32,7 +32,7 @@ use ruma::{
     },
     receipt::ReceiptType,
     serde::Raw,
-    EventId, MxcUri, RoomId, UserId,
+    EventId, MxcUri, RoomId, TransactionId, UserId,
 };
 use serde::{Deserialize, Serialize};
 use sled::{
@@ -43,10 +43,13 @@ use tokio::task::spawn_blocking;
 use tracing::info;
 
 use self::store_key::{EncryptedEvent, StoreKey};
-use super::{store_key, Result, RoomInfo, StateChanges, StateStore, StoreError};
+use super::{
+    sort_timeline_chunks, store_key, PendingEvent, Result, RoomInfo, StateChanges, StateStore,
+    StoreError, TimelineChunk, TimelinePruning,
+};
 use crate::{
-    deserialized_responses::MemberEvent,
-    media::{MediaRequest, UniqueKey},
+    deserialized_responses::{MemberEvent, SyncRoomEvent},
+    media::{MediaCacheEntry, MediaRequest, UniqueKey},
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -204,7 +207,13 @@ pub struct SledStore {
     room_user_receipts: Tree,
     room_event_receipts: Tree,
     media: Tree,
+    media_metadata: Tree,
     custom: Tree,
+    key_values: Tree,
+    pending_events: Tree,
+    timeline_chunks: Tree,
+    timeline_events: Tree,
+    timeline_redactions: Tree,
 }
 
 impl std::fmt::Debug for SledStore {
@@ -240,11 +249,24 @@ impl SledStore {
         let room_user_receipts = db.open_tree("room_user_receipts")?;
         let room_event_receipts = db.open_tree("room_event_receipts")?;
 
+        // Stores created before the metadata of the media cache was tracked
+        // need to have it backfilled.
+        let backfill_media_metadata =
+            !db.tree_names().iter().any(|name| name.as_ref() == b"media_metadata");
+
         let media = db.open_tree("media")?;
+        let media_metadata = db.open_tree("media_metadata")?;
 
         let custom = db.open_tree("custom")?;
+        let key_values = db.open_tree("key_values")?;
+
+        let pending_events = db.open_tree("pending_events")?;
+
+        let timeline_chunks = db.open_tree("timeline_chunks")?;
+        let timeline_events = db.open_tree("timeline_events")?;
+        let timeline_redactions = db.open_tree("timeline_redactions")?;
 
-        Ok(Self {
+        let store = Self {
             path,
             inner: db,
             store_key: store_key.into(),
@@ -265,8 +287,20 @@ impl SledStore {
             room_user_receipts,
             room_event_receipts,
             media,
+            media_metadata,
             custom,
-        })
+            key_values,
+            pending_events,
+            timeline_chunks,
+            timeline_events,
+            timeline_redactions,
+        };
+
+        if backfill_media_metadata {
+            store.backfill_media_metadata()?;
+        }
+
+        Ok(store)
     }
 
     pub fn open() -> Result<Self> {
@@ -364,7 +398,7 @@ impl SledStore {
             &self.room_info,
             &self.room_state,
             &self.room_account_data,
-            &self.presence,
+            &self.key_values,
             &self.stripped_room_infos,
             &self.stripped_members,
             &self.stripped_room_state,
@@ -381,7 +415,7 @@ impl SledStore {
                     rooms,
                     state,
                     room_account_data,
-                    presence,
+                    key_values,
                     striped_rooms,
                     stripped_members,
                     stripped_state,
@@ -478,12 +512,20 @@ impl SledStore {
                         )?;
                     }
 
-                    for (sender, event) in &changes.presence {
-                        presence.insert(
-                            sender.encode(),
-                            self.serialize_event(&event)
-                                .map_err(ConflictableTransactionError::Abort)?,
-                        )?;
+                    for (namespace, values) in &changes.key_values {
+                        for (key, value) in values {
+                            let db_key = (namespace.as_str(), key.as_str()).encode();
+
+                            if let Some(value) = value {
+                                key_values.insert(
+                                    db_key,
+                                    self.serialize_event(&(key, value))
+                                        .map_err(ConflictableTransactionError::Abort)?,
+                                )?;
+                            } else {
+                                key_values.remove(db_key)?;
+                            }
+                        }
                     }
 
                     for (room_id, info) in &changes.stripped_room_infos {
@@ -523,9 +565,33 @@ impl SledStore {
 
         ret?;
 
-        let ret: Result<(), TransactionError<SerializationError>> =
-            (&self.room_user_receipts, &self.room_event_receipts).transaction(
-                |(room_user_receipts, room_event_receipts)| {
+        // Sled transactions support at most 14 trees, the ephemeral data and
+        // the timeline chunks are saved in a second transaction.
+        let ret: Result<(), TransactionError<SerializationError>> = (
+            &self.presence,
+            &self.room_user_receipts,
+            &self.room_event_receipts,
+            &self.timeline_chunks,
+            &self.timeline_events,
+            &self.timeline_redactions,
+        )
+            .transaction(
+                |(
+                    presence,
+                    room_user_receipts,
+                    room_event_receipts,
+                    timeline_chunks,
+                    timeline_events,
+                    timeline_redactions,
+                )| {
+                    for (sender, event) in &changes.presence {
+                        presence.insert(
+                            sender.encode(),
+                            self.serialize_event(&event)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
                     for (room, content) in &changes.receipts {
                         for (event_id, receipts) in &content.0 {
                             for (receipt_type, receipts) in receipts {
@@ -569,6 +635,35 @@ impl SledStore {
                         }
                     }
 
+                    for chunk in &changes.timeline_chunks {
+                        let room_id = chunk.room_id.as_str();
+
+                        for event_id in chunk.redacted_event_ids() {
+                            let key = (room_id, event_id.as_str()).encode();
+                            timeline_events.remove(key.as_slice())?;
+                            timeline_redactions.insert(key, event_id.as_str())?;
+                        }
+
+                        for (event_id, event) in chunk.events_by_id() {
+                            let key = (room_id, event_id.as_str()).encode();
+
+                            if timeline_redactions.get(key.as_slice())?.is_none() {
+                                timeline_events.insert(
+                                    key,
+                                    self.serialize_event(event)
+                                        .map_err(ConflictableTransactionError::Abort)?,
+                                )?;
+                            }
+                        }
+
+                        let (prev_batch, next_batch) = chunk.tokens();
+                        timeline_chunks.insert(
+                            (room_id, prev_batch, next_batch).encode(),
+                            self.serialize_event(chunk)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
                     Ok(())
                 },
             );
@@ -811,11 +906,19 @@ impl SledStore {
     }
 
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
-        self.media.insert(
-            (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
-                .encode(),
-            data,
-        )?;
+        let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+            .encode();
+        let entry = self.serialize_event(&MediaCacheEntry::new(request, data.len()))?;
+
+        let ret: Result<(), TransactionError<SerializationError>> =
+            (&self.media, &self.media_metadata).transaction(|(media, metadata)| {
+                media.insert(key.as_slice(), data.as_slice())?;
+                metadata.insert(key.as_slice(), entry.as_slice())?;
+
+                Ok(())
+            });
+
+        ret?;
 
         self.inner.flush_async().await?;
 
@@ -826,8 +929,21 @@ impl SledStore {
         let db = self.clone();
         let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
             .encode();
+        let request = request.clone();
+
+        spawn_blocking(move || {
+            let content = db.media.get(&key)?.map(|m| m.to_vec());
+
+            if let Some(content) = &content {
+                // Track the access so the least recently used content is
+                // evicted first.
+                let entry = MediaCacheEntry::new(&request, content.len());
+                db.media_metadata.insert(key, db.serialize_event(&entry)?)?;
+            }
 
-        spawn_blocking(move || Ok(db.media.get(key)?.map(|m| m.to_vec()))).await?
+            Ok(content)
+        })
+        .await?
     }
 
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -843,13 +959,55 @@ impl SledStore {
         Ok(ret)
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        let db = self.clone();
+        let key = (namespace, key).encode();
+
+        spawn_blocking(move || {
+            Ok(db
+                .key_values
+                .get(key)?
+                .map(|v| db.deserialize_event::<(String, serde_json::Value)>(&v))
+                .transpose()?
+                .map(|(_, v)| v))
+        })
+        .await?
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        let db = self.clone();
+        let key = namespace.encode();
+
+        spawn_blocking(move || {
+            let mut entries = db
+                .key_values
+                .scan_prefix(key)
+                .values()
+                .map(|v| Ok(db.deserialize_event::<(String, serde_json::Value)>(&v?)?))
+                .collect::<Result<Vec<_>>>()?;
+
+            // The separator of the encoded keys changes the ordering of keys
+            // that are a prefix of each other.
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Ok(entries)
+        })
+        .await?
+    }
+
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
-        self.media.remove(
-            (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
-                .encode(),
-        )?;
+        let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+            .encode();
 
-        Ok(())
+        let ret: Result<(), TransactionError<SerializationError>> =
+            (&self.media, &self.media_metadata).transaction(|(media, metadata)| {
+                media.remove(key.as_slice())?;
+                metadata.remove(key.as_slice())?;
+
+                Ok(())
+            });
+
+        Ok(ret?)
     }
 
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
@@ -860,7 +1018,187 @@ impl SledStore {
             batch.remove(key?);
         }
 
-        Ok(self.media.apply_batch(batch)?)
+        self.remove_media_batch(batch)
+    }
+
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        let db = self.clone();
+
+        spawn_blocking(move || {
+            // The metadata might be missing if the backfill when the store was
+            // opened got interrupted.
+            db.backfill_media_metadata()?;
+
+            db.media_metadata
+                .iter()
+                .values()
+                .map(|e| db.deserialize_event(&e?).map_err(StoreError::from))
+                .collect()
+        })
+        .await?
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for entry in entries {
+            batch.remove((entry.media_key.as_str(), entry.format_key.as_str()).encode());
+        }
+
+        self.remove_media_batch(batch)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.media.clear()?;
+        self.media_metadata.clear()?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Create the missing metadata of the content in the media cache, for
+    /// content that was cached before its metadata was tracked.
+    fn backfill_media_metadata(&self) -> Result<()> {
+        let mut batch = sled::Batch::default();
+
+        for key in self.media.iter().keys() {
+            let key = key?;
+
+            if self.media_metadata.contains_key(&key)? {
+                continue;
+            }
+
+            let size = match self.media.get(&key)? {
+                Some(content) => content.len(),
+                None => continue,
+            };
+
+            if let (Some(media_key), Some(format_key)) =
+                (decode_key_value(&key, 0), decode_key_value(&key, 1))
+            {
+                let entry = MediaCacheEntry::backfill(media_key, format_key, size);
+                batch.insert(key, self.serialize_event(&entry)?);
+            }
+        }
+
+        Ok(self.media_metadata.apply_batch(batch)?)
+    }
+
+    /// Apply the given batch of removals to the media content and its
+    /// metadata.
+    fn remove_media_batch(&self, batch: sled::Batch) -> Result<()> {
+        let ret: Result<(), TransactionError<SerializationError>> =
+            (&self.media, &self.media_metadata).transaction(|(media, metadata)| {
+                media.apply_batch(&batch)?;
+                metadata.apply_batch(&batch)?;
+
+                Ok(())
+            });
+
+        Ok(ret?)
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        self.pending_events.insert(
+            (event.room_id.as_str(), event.transaction_id.as_str()).encode(),
+            self.serialize_event(event)?,
+        )?;
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        let db = self.clone();
+        spawn_blocking(move || {
+            let mut events = db
+                .pending_events
+                .iter()
+                .map(|e| db.deserialize_event(&e?.1).map_err(StoreError::from))
+                .collect::<Result<Vec<PendingEvent>>>()?;
+
+            events.sort_by_key(|e| e.sequence);
+
+            Ok(events)
+        })
+        .await?
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        self.pending_events.remove((room_id.as_str(), transaction_id.as_str()).encode())?;
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        let db = self.clone();
+        let key = room_id.encode();
+        spawn_blocking(move || {
+            let mut chunks = db
+                .timeline_chunks
+                .scan_prefix(key)
+                .map(|c| db.deserialize_event(&c?.1).map_err(StoreError::from))
+                .collect::<Result<Vec<TimelineChunk>>>()?;
+
+            sort_timeline_chunks(&mut chunks);
+
+            Ok(chunks)
+        })
+        .await?
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        let db = self.clone();
+        let key = (room_id.as_str(), event_id.as_str()).encode();
+        spawn_blocking(move || {
+            Ok(db.timeline_events.get(key)?.map(|e| db.deserialize_event(&e)).transpose()?)
+        })
+        .await?
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        let chunks = self.get_timeline_chunks(room_id).await?;
+
+        let mut chunks_batch = sled::Batch::default();
+        let mut events_batch = sled::Batch::default();
+
+        let pruned = pruning.prune(&chunks);
+
+        for chunk in pruned.chunks {
+            let (prev_batch, next_batch) = chunk.tokens();
+            chunks_batch.remove((room_id.as_str(), prev_batch, next_batch).encode());
+        }
+
+        for event_id in pruned.event_ids {
+            events_batch.remove((room_id.as_str(), event_id.as_str()).encode());
+        }
+
+        let ret: Result<(), TransactionError<SerializationError>> =
+            (&self.timeline_chunks, &self.timeline_events).transaction(|(chunks, events)| {
+                chunks.apply_batch(&chunks_batch)?;
+                events.apply_batch(&events_batch)?;
+
+                Ok(())
+            });
+
+        ret?;
+
+        self.inner.flush_async().await?;
+
+        Ok(())
     }
 
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
@@ -921,6 +1259,11 @@ impl SledStore {
             room_event_receipts_batch.remove(key?)
         }
 
+        let mut pending_events_batch = sled::Batch::default();
+        for key in self.pending_events.scan_prefix(room_key.as_slice()).keys() {
+            pending_events_batch.remove(key?)
+        }
+
         let ret: Result<(), TransactionError<SerializationError>> = (
             &self.members,
             &self.profiles,
@@ -935,6 +1278,7 @@ impl SledStore {
             &self.stripped_room_state,
             &self.room_user_receipts,
             &self.room_event_receipts,
+            &self.pending_events,
         )
             .transaction(
                 |(
@@ -951,6 +1295,7 @@ impl SledStore {
                     stripped_state,
                     room_user_receipts,
                     room_event_receipts,
+                    pending_events,
                 )| {
                     rooms.remove(room_key.as_slice())?;
                     stripped_rooms.remove(room_key.as_slice())?;
@@ -966,6 +1311,7 @@ impl SledStore {
                     stripped_state.apply_batch(&stripped_room_state_batch)?;
                     room_user_receipts.apply_batch(&room_user_receipts_batch)?;
                     room_event_receipts.apply_batch(&room_event_receipts_batch)?;
+                    pending_events.apply_batch(&pending_events_batch)?;
 
                     Ok(())
                 },
@@ -973,6 +1319,27 @@ impl SledStore {
 
         ret?;
 
+        // The transaction above already spans the maximum number of trees, the
+        // cached timeline of the room is removed separately.
+        let mut timeline_chunks_batch = sled::Batch::default();
+        for key in self.timeline_chunks.scan_prefix(room_key.as_slice()).keys() {
+            timeline_chunks_batch.remove(key?)
+        }
+
+        let mut timeline_events_batch = sled::Batch::default();
+        for key in self.timeline_events.scan_prefix(room_key.as_slice()).keys() {
+            timeline_events_batch.remove(key?)
+        }
+
+        let mut timeline_redactions_batch = sled::Batch::default();
+        for key in self.timeline_redactions.scan_prefix(room_key.as_slice()).keys() {
+            timeline_redactions_batch.remove(key?)
+        }
+
+        self.timeline_chunks.apply_batch(timeline_chunks_batch)?;
+        self.timeline_events.apply_batch(timeline_events_batch)?;
+        self.timeline_redactions.apply_batch(timeline_redactions_batch)?;
+
         self.inner.flush_async().await?;
 
         Ok(())
@@ -1103,6 +1470,14 @@ impl StateStore for SledStore {
         self.set_custom_value(key, value).await
     }
 
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        self.get_key_value(namespace, key).await
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        self.get_key_values(namespace).await
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.add_media_content(request, data).await
     }
@@ -1119,6 +1494,50 @@ impl StateStore for SledStore {
         self.remove_media_content_for_uri(uri).await
     }
 
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        self.get_media_cache_entries().await
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        self.remove_media_cache_entries(entries).await
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.clear_media_cache().await
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        self.save_pending_event(event).await
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        self.get_pending_events().await
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        self.remove_pending_event(room_id, transaction_id).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.get_timeline_chunks(room_id).await
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        self.get_timeline_event(room_id, event_id).await
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        self.prune_timeline(room_id, pruning).await
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.remove_room(room_id).await
     }
@@ -1126,12 +1545,43 @@ impl StateStore for SledStore {
 
 #[cfg(test)]
 mod test {
+    use matrix_sdk_test::async_test;
+    use ruma::{api::client::r0::media::get_content_thumbnail::Method, mxc_uri, uint};
 
     use super::{Result, SledStore};
+    use crate::{
+        media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
+        store::StateStore,
+    };
 
     async fn get_store() -> Result<SledStore> {
         SledStore::open()
     }
 
     statestore_integration_tests! { integration }
+
+    #[async_test]
+    async fn backfill_media_metadata() -> Result<()> {
+        let store = SledStore::open()?;
+        let request = MediaRequest {
+            media_type: MediaType::Uri(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSize {
+                method: Method::Scale,
+                width: uint!(100),
+                height: uint!(100),
+            }),
+        };
+
+        store.add_media_content(&request, b"content".to_vec()).await?;
+
+        // Content that was cached before its metadata was tracked.
+        store.media_metadata.clear()?;
+
+        let entries = store.get_media_cache_entries().await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].size, 7);
+        assert!(entries[0].thumbnail);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,1285 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use matrix_sdk_common::async_trait;
+use ruma::{
+    events::{
+        presence::PresenceEvent,
+        receipt::Receipt,
+        room::member::{MembershipState, RoomMemberEventContent},
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncStateEvent, EventType,
+    },
+    receipt::ReceiptType,
+    serde::Raw,
+    EventId, MxcUri, RoomId, TransactionId, UserId,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tracing::{debug, info};
+
+use self::store_key::{EncryptedEvent, StoreKey};
+use super::{
+    sort_timeline_chunks, store_key, PendingEvent, PrunedTimeline, Result, RoomInfo, StateChanges,
+    StateStore, StoreError, TimelineChunk, TimelinePruning,
+};
+use crate::{
+    deserialized_responses::{MemberEvent, SyncRoomEvent},
+    media::{MediaCacheEntry, MediaRequest, UniqueKey},
+};
+
+/// The version of the database schema, stored in the `user_version` pragma.
+const DATABASE_VERSION: u32 = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DatabaseType {
+    Unencrypted,
+    Encrypted(store_key::EncryptedStoreKey),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SerializationError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Encryption(#[from] store_key::Error),
+}
+
+impl From<SerializationError> for StoreError {
+    fn from(e: SerializationError) -> Self {
+        match e {
+            SerializationError::Json(e) => StoreError::Json(e),
+            SerializationError::Encryption(e) => match e {
+                store_key::Error::Random(e) => StoreError::Encryption(e.to_string()),
+                store_key::Error::Serialization(e) => StoreError::Json(e),
+                store_key::Error::Encryption(e) => StoreError::Encryption(e),
+            },
+        }
+    }
+}
+
+const ENCODE_SEPARATOR: u8 = 0xff;
+
+trait EncodeKey {
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl<T: EncodeKey> EncodeKey for &T {
+    fn encode(&self) -> Vec<u8> {
+        T::encode(self)
+    }
+}
+
+impl<T: EncodeKey> EncodeKey for Box<T> {
+    fn encode(&self) -> Vec<u8> {
+        T::encode(self)
+    }
+}
+
+impl EncodeKey for UserId {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for RoomId {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for str {
+    fn encode(&self) -> Vec<u8> {
+        [self.as_bytes(), &[ENCODE_SEPARATOR]].concat()
+    }
+}
+
+impl EncodeKey for (&str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [self.0.as_bytes(), &[ENCODE_SEPARATOR], self.1.as_bytes(), &[ENCODE_SEPARATOR]].concat()
+    }
+}
+
+impl EncodeKey for (&str, &str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.0.as_bytes(),
+            &[ENCODE_SEPARATOR],
+            self.1.as_bytes(),
+            &[ENCODE_SEPARATOR],
+            self.2.as_bytes(),
+            &[ENCODE_SEPARATOR],
+        ]
+        .concat()
+    }
+}
+
+impl EncodeKey for (&str, &str, &str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.0.as_bytes(),
+            &[ENCODE_SEPARATOR],
+            self.1.as_bytes(),
+            &[ENCODE_SEPARATOR],
+            self.2.as_bytes(),
+            &[ENCODE_SEPARATOR],
+            self.3.as_bytes(),
+            &[ENCODE_SEPARATOR],
+        ]
+        .concat()
+    }
+}
+
+impl EncodeKey for EventType {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+/// Get the value at `position` in encoded `key`.
+///
+/// The key must have been encoded with the `EncodeKey` trait. `position`
+/// corresponds to the position in the tuple before the key was encoded. If it
+/// wasn't encoded in a tuple, use `0`.
+fn decode_key_value(key: &[u8], position: usize) -> Result<String> {
+    key.split(|v| *v == ENCODE_SEPARATOR)
+        .nth(position)
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .ok_or_else(|| StoreError::Codec("A key in the store wasn't properly encoded".to_owned()))
+}
+
+/// A key-value table in the database.
+///
+/// Every table maps an encoded key to a, possibly encrypted, serialized value,
+/// keys are compared byte-wise so prefix scans work like they do for sled
+/// trees.
+#[derive(Clone, Copy, Debug)]
+struct Table(&'static str);
+
+type KeyValue = (Vec<u8>, Vec<u8>);
+
+impl Table {
+    fn get(self, conn: &Connection, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        conn.prepare_cached(&format!("SELECT value FROM {} WHERE key = ?1", self.0))?
+            .query_row(params![key], |row| row.get(0))
+            .optional()
+    }
+
+    fn insert(self, conn: &Connection, key: &[u8], value: &[u8]) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+            self.0
+        ))?
+        .execute(params![key, value])?;
+
+        Ok(())
+    }
+
+    fn remove(self, conn: &Connection, key: &[u8]) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!("DELETE FROM {} WHERE key = ?1", self.0))?
+            .execute(params![key])?;
+
+        Ok(())
+    }
+
+    fn remove_prefix(self, conn: &Connection, prefix: &[u8]) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!(
+            "DELETE FROM {} WHERE key >= ?1 AND substr(key, 1, length(?1)) = ?1",
+            self.0
+        ))?
+        .execute(params![prefix])?;
+
+        Ok(())
+    }
+
+    fn clear(self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!("DELETE FROM {}", self.0))?.execute(params![])?;
+
+        Ok(())
+    }
+
+    fn scan_prefix(self, conn: &Connection, prefix: &[u8]) -> rusqlite::Result<Vec<KeyValue>> {
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT key, value FROM {} WHERE key >= ?1 AND substr(key, 1, length(?1)) = ?1 \
+             ORDER BY key",
+            self.0
+        ))?;
+
+        let values = statement
+            .query_map(params![prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(values)
+    }
+
+    fn iter(self, conn: &Connection) -> rusqlite::Result<Vec<KeyValue>> {
+        let mut statement =
+            conn.prepare_cached(&format!("SELECT key, value FROM {} ORDER BY key", self.0))?;
+
+        let values = statement
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(values)
+    }
+}
+
+const METADATA: Table = Table("metadata");
+const SESSION: Table = Table("session");
+const ACCOUNT_DATA: Table = Table("account_data");
+const MEMBERS: Table = Table("members");
+const PROFILES: Table = Table("profiles");
+const DISPLAY_NAMES: Table = Table("display_names");
+const JOINED_USER_IDS: Table = Table("joined_user_ids");
+const INVITED_USER_IDS: Table = Table("invited_user_ids");
+const ROOM_INFOS: Table = Table("room_infos");
+const ROOM_STATE: Table = Table("room_state");
+const ROOM_ACCOUNT_DATA: Table = Table("room_account_data");
+const STRIPPED_ROOM_INFOS: Table = Table("stripped_room_infos");
+const STRIPPED_ROOM_STATE: Table = Table("stripped_room_state");
+const STRIPPED_MEMBERS: Table = Table("stripped_members");
+const PRESENCE: Table = Table("presence");
+const ROOM_USER_RECEIPTS: Table = Table("room_user_receipts");
+const ROOM_EVENT_RECEIPTS: Table = Table("room_event_receipts");
+const MEDIA: Table = Table("media");
+const MEDIA_METADATA: Table = Table("media_metadata");
+const CUSTOM: Table = Table("custom");
+const KEY_VALUES: Table = Table("key_values");
+const PENDING_EVENTS: Table = Table("pending_events");
+const TIMELINE_CHUNKS: Table = Table("timeline_chunks");
+const TIMELINE_EVENTS: Table = Table("timeline_events");
+const TIMELINE_REDACTIONS: Table = Table("timeline_redactions");
+
+/// Bring the schema of the database up to `DATABASE_VERSION`.
+///
+/// Returns the version of the database before the upgrade, the data is
+/// migrated and the version updated by the caller in the same transaction.
+fn upgrade(txn: &Transaction<'_>) -> Result<u32> {
+    let version: u32 = txn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+
+    if version == DATABASE_VERSION {
+        return Ok(version);
+    }
+
+    debug!(version, new_version = DATABASE_VERSION, "Upgrading the SQLite state store");
+
+    if version < 1 {
+        for table in [
+            METADATA,
+            SESSION,
+            ACCOUNT_DATA,
+            MEMBERS,
+            PROFILES,
+            DISPLAY_NAMES,
+            JOINED_USER_IDS,
+            INVITED_USER_IDS,
+            ROOM_INFOS,
+            ROOM_STATE,
+            ROOM_ACCOUNT_DATA,
+            STRIPPED_ROOM_INFOS,
+            STRIPPED_ROOM_STATE,
+            STRIPPED_MEMBERS,
+            PRESENCE,
+            ROOM_USER_RECEIPTS,
+            ROOM_EVENT_RECEIPTS,
+            MEDIA,
+            CUSTOM,
+            PENDING_EVENTS,
+            TIMELINE_CHUNKS,
+            TIMELINE_EVENTS,
+        ] {
+            create_table(txn, table)?;
+        }
+    }
+
+    if version < 2 {
+        create_table(txn, MEDIA_METADATA)?;
+    }
+
+    if version < 3 {
+        create_table(txn, KEY_VALUES)?;
+    }
+
+    if version < 4 {
+        create_table(txn, TIMELINE_REDACTIONS)?;
+    }
+
+    Ok(version)
+}
+
+fn create_table(txn: &Transaction<'_>, table: Table) -> Result<()> {
+    txn.execute_batch(&format!(
+        "CREATE TABLE {} (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL) WITHOUT ROWID;",
+        table.0
+    ))?;
+
+    Ok(())
+}
+
+/// Create the metadata of the content in the media cache, for content that was
+/// cached before its metadata was tracked.
+fn backfill_media_metadata(txn: &Transaction<'_>, store_key: Option<&StoreKey>) -> Result<()> {
+    let mut statement = txn.prepare(&format!(
+        "SELECT key, length(value) FROM {} WHERE key NOT IN (SELECT key FROM {})",
+        MEDIA.0, MEDIA_METADATA.0
+    ))?;
+
+    let media = statement
+        .query_map(params![], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (key, size) in media {
+        let entry = MediaCacheEntry::backfill(
+            decode_key_value(&key, 0)?,
+            decode_key_value(&key, 1)?,
+            usize::try_from(size).unwrap_or_default(),
+        );
+        MEDIA_METADATA.insert(txn, &key, &serialize_value(store_key, &entry)?)?;
+    }
+
+    Ok(())
+}
+
+/// Serialize the given value, encrypting it if a store key is given.
+fn serialize_value(
+    store_key: Option<&StoreKey>,
+    value: &impl Serialize,
+) -> Result<Vec<u8>, SerializationError> {
+    if let Some(key) = store_key {
+        let encrypted = key.encrypt(value)?;
+        Ok(serde_json::to_vec(&encrypted)?)
+    } else {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+/// A [SQLite] based state store.
+///
+/// [SQLite]: https://www.sqlite.org/
+#[derive(Clone)]
+pub struct SqliteStore {
+    path: Option<PathBuf>,
+    conn: Arc<Mutex<Connection>>,
+    store_key: Arc<Option<StoreKey>>,
+}
+
+impl std::fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            f.debug_struct("SqliteStore").field("path", &path).finish()
+        } else {
+            f.debug_struct("SqliteStore").field("path", &"memory store").finish()
+        }
+    }
+}
+
+impl SqliteStore {
+    fn open_helper(
+        mut conn: Connection,
+        path: Option<PathBuf>,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+
+        let txn = conn.transaction()?;
+        let version = upgrade(&txn)?;
+
+        let store_key = if let Some(passphrase) = passphrase {
+            let store_key: Option<DatabaseType> = METADATA
+                .get(&txn, &"store_key".encode())?
+                .map(|k| serde_json::from_slice(&k).map_err(StoreError::Json))
+                .transpose()?;
+
+            Some(if let Some(key) = store_key {
+                if let DatabaseType::Encrypted(k) = key {
+                    StoreKey::import(passphrase, k).map_err(|_| StoreError::StoreLocked)?
+                } else {
+                    return Err(StoreError::UnencryptedStore);
+                }
+            } else {
+                let key = StoreKey::new().map_err::<StoreError, _>(|e| e.into())?;
+                let encrypted_key = DatabaseType::Encrypted(
+                    key.export(passphrase).map_err::<StoreError, _>(|e| e.into())?,
+                );
+                METADATA.insert(
+                    &txn,
+                    &"store_key".encode(),
+                    &serde_json::to_vec(&encrypted_key)?,
+                )?;
+                key
+            })
+        } else {
+            None
+        };
+
+        if version < 2 {
+            backfill_media_metadata(&txn, store_key.as_ref())?;
+        }
+
+        if version != DATABASE_VERSION {
+            txn.execute_batch(&format!("PRAGMA user_version = {};", DATABASE_VERSION))?;
+        }
+
+        txn.commit()?;
+
+        Ok(Self { path, conn: Arc::new(Mutex::new(conn)), store_key: store_key.into() })
+    }
+
+    /// Open a temporary, in-memory, store.
+    pub fn open() -> Result<Self> {
+        SqliteStore::open_helper(Connection::open_in_memory()?, None, None)
+    }
+
+    /// Open the store in the given directory, values will be encrypted using
+    /// the given passphrase.
+    pub fn open_with_passphrase(path: impl AsRef<Path>, passphrase: &str) -> Result<Self> {
+        let path = path.as_ref().join("matrix-sdk-state.sqlite3");
+        let conn = Connection::open(&path)?;
+
+        SqliteStore::open_helper(conn, Some(path), Some(passphrase))
+    }
+
+    /// Open the store in the given directory.
+    pub fn open_with_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().join("matrix-sdk-state.sqlite3");
+        let conn = Connection::open(&path)?;
+
+        SqliteStore::open_helper(conn, Some(path), None)
+    }
+
+    fn serialize_event(&self, event: &impl Serialize) -> Result<Vec<u8>, SerializationError> {
+        serialize_value((*self.store_key).as_ref(), event)
+    }
+
+    fn deserialize_event<T: DeserializeOwned>(
+        &self,
+        event: &[u8],
+    ) -> Result<T, SerializationError> {
+        if let Some(key) = &*self.store_key {
+            let encrypted: EncryptedEvent = serde_json::from_slice(event)?;
+            Ok(key.decrypt(encrypted)?)
+        } else {
+            Ok(serde_json::from_slice(event)?)
+        }
+    }
+
+    /// Run the given closure inside of a transaction on a thread where blocking
+    /// is acceptable, the transaction is only committed if the closure
+    /// succeeds.
+    async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self, &Transaction<'_>) -> Result<T> + Send + 'static,
+    {
+        let db = self.clone();
+
+        spawn_blocking(move || {
+            let mut conn = db.conn.lock().unwrap();
+            let txn = conn.transaction()?;
+            let ret = f(&db, &txn)?;
+            txn.commit()?;
+
+            Ok(ret)
+        })
+        .await?
+    }
+
+    /// Run the given closure with the connection on a thread where blocking is
+    /// acceptable.
+    async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self, &Connection) -> Result<T> + Send + 'static,
+    {
+        let db = self.clone();
+
+        spawn_blocking(move || {
+            let conn = db.conn.lock().unwrap();
+            f(&db, &conn)
+        })
+        .await?
+    }
+
+    async fn get_value<T>(&self, table: Table, key: Vec<u8>) -> Result<Option<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        self.read(move |db, conn| {
+            Ok(table.get(conn, &key)?.map(|v| db.deserialize_event(&v)).transpose()?)
+        })
+        .await
+    }
+
+    async fn get_user_ids_with_prefix(
+        &self,
+        table: Table,
+        room_id: &RoomId,
+    ) -> Result<Vec<Box<UserId>>> {
+        let key = room_id.encode();
+
+        self.read(move |_, conn| {
+            table
+                .scan_prefix(conn, &key)?
+                .into_iter()
+                .map(|(_, u)| {
+                    Box::<UserId>::try_from(String::from_utf8_lossy(&u).to_string())
+                        .map_err(StoreError::Identifier)
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn save_changes_helper(&self, changes: StateChanges) -> Result<()> {
+        self.transaction(move |db, txn| {
+            if let Some(s) = &changes.sync_token {
+                SESSION.insert(txn, &"sync_token".encode(), s.as_bytes())?;
+            }
+
+            for (room, events) in &changes.members {
+                let profile_changes = changes.profiles.get(room);
+
+                for event in events.values() {
+                    let key = (room.as_str(), event.state_key.as_str()).encode();
+
+                    match event.content.membership {
+                        MembershipState::Join => {
+                            JOINED_USER_IDS.insert(txn, &key, event.state_key.as_bytes())?;
+                            INVITED_USER_IDS.remove(txn, &key)?;
+                        }
+                        MembershipState::Invite => {
+                            INVITED_USER_IDS.insert(txn, &key, event.state_key.as_bytes())?;
+                            JOINED_USER_IDS.remove(txn, &key)?;
+                        }
+                        _ => {
+                            JOINED_USER_IDS.remove(txn, &key)?;
+                            INVITED_USER_IDS.remove(txn, &key)?;
+                        }
+                    }
+
+                    MEMBERS.insert(txn, &key, &db.serialize_event(&event)?)?;
+
+                    if let Some(profile) = profile_changes.and_then(|p| p.get(&event.state_key)) {
+                        PROFILES.insert(txn, &key, &db.serialize_event(&profile)?)?;
+                    }
+                }
+            }
+
+            for (room_id, ambiguity_maps) in &changes.ambiguity_maps {
+                for (display_name, map) in ambiguity_maps {
+                    DISPLAY_NAMES.insert(
+                        txn,
+                        &(room_id.as_str(), display_name.as_str()).encode(),
+                        &db.serialize_event(&map)?,
+                    )?;
+                }
+            }
+
+            for (event_type, event) in &changes.account_data {
+                ACCOUNT_DATA.insert(txn, &event_type.encode(), &db.serialize_event(&event)?)?;
+            }
+
+            for (room, events) in &changes.room_account_data {
+                for (event_type, event) in events {
+                    ROOM_ACCOUNT_DATA.insert(
+                        txn,
+                        &(room.as_str(), event_type.as_str()).encode(),
+                        &db.serialize_event(&event)?,
+                    )?;
+                }
+            }
+
+            for (room, event_types) in &changes.state {
+                for (event_type, events) in event_types {
+                    for (state_key, event) in events {
+                        ROOM_STATE.insert(
+                            txn,
+                            &(room.as_str(), event_type.as_str(), state_key.as_str()).encode(),
+                            &db.serialize_event(&event)?,
+                        )?;
+                    }
+                }
+            }
+
+            for (room_id, room_info) in &changes.room_infos {
+                ROOM_INFOS.insert(txn, &room_id.encode(), &db.serialize_event(room_info)?)?;
+            }
+
+            for (sender, event) in &changes.presence {
+                PRESENCE.insert(txn, &sender.encode(), &db.serialize_event(&event)?)?;
+            }
+
+            for (namespace, values) in &changes.key_values {
+                for (key, value) in values {
+                    let db_key = (namespace.as_str(), key.as_str()).encode();
+
+                    if let Some(value) = value {
+                        KEY_VALUES.insert(txn, &db_key, &db.serialize_event(&(key, value))?)?;
+                    } else {
+                        KEY_VALUES.remove(txn, &db_key)?;
+                    }
+                }
+            }
+
+            for (room_id, info) in &changes.stripped_room_infos {
+                STRIPPED_ROOM_INFOS.insert(txn, &room_id.encode(), &db.serialize_event(&info)?)?;
+            }
+
+            for (room, events) in &changes.stripped_members {
+                for event in events.values() {
+                    STRIPPED_MEMBERS.insert(
+                        txn,
+                        &(room.as_str(), event.state_key.as_str()).encode(),
+                        &db.serialize_event(&event)?,
+                    )?;
+                }
+            }
+
+            for (room, event_types) in &changes.stripped_state {
+                for (event_type, events) in event_types {
+                    for (state_key, event) in events {
+                        STRIPPED_ROOM_STATE.insert(
+                            txn,
+                            &(room.as_str(), event_type.as_str(), state_key.as_str()).encode(),
+                            &db.serialize_event(&event)?,
+                        )?;
+                    }
+                }
+            }
+
+            for (room, content) in &changes.receipts {
+                for (event_id, receipts) in &content.0 {
+                    for (receipt_type, receipts) in receipts {
+                        for (user_id, receipt) in receipts {
+                            let user_key =
+                                (room.as_str(), receipt_type.as_ref(), user_id.as_str()).encode();
+
+                            // Remove the old receipt from the room event receipts
+                            if let Some(old) = ROOM_USER_RECEIPTS.get(txn, &user_key)? {
+                                let (old_event, _): (Box<EventId>, Receipt) =
+                                    db.deserialize_event(&old)?;
+                                ROOM_EVENT_RECEIPTS.remove(
+                                    txn,
+                                    &(
+                                        room.as_str(),
+                                        receipt_type.as_ref(),
+                                        old_event.as_str(),
+                                        user_id.as_str(),
+                                    )
+                                        .encode(),
+                                )?;
+                            }
+
+                            // Add the receipt to the room user receipts
+                            ROOM_USER_RECEIPTS.insert(
+                                txn,
+                                &user_key,
+                                &db.serialize_event(&(event_id, receipt))?,
+                            )?;
+
+                            // Add the receipt to the room event receipts
+                            ROOM_EVENT_RECEIPTS.insert(
+                                txn,
+                                &(
+                                    room.as_str(),
+                                    receipt_type.as_ref(),
+                                    event_id.as_str(),
+                                    user_id.as_str(),
+                                )
+                                    .encode(),
+                                &db.serialize_event(receipt)?,
+                            )?;
+                        }
+                    }
+                }
+            }
+
+            for chunk in &changes.timeline_chunks {
+                let room_id = chunk.room_id.as_str();
+
+                for event_id in chunk.redacted_event_ids() {
+                    let key = (room_id, event_id.as_str()).encode();
+
+                    TIMELINE_EVENTS.remove(txn, &key)?;
+                    TIMELINE_REDACTIONS.insert(txn, &key, event_id.as_bytes())?;
+                }
+
+                for (event_id, event) in chunk.events_by_id() {
+                    let key = (room_id, event_id.as_str()).encode();
+
+                    if TIMELINE_REDACTIONS.get(txn, &key)?.is_none() {
+                        TIMELINE_EVENTS.insert(txn, &key, &db.serialize_event(event)?)?;
+                    }
+                }
+
+                let (prev_batch, next_batch) = chunk.tokens();
+                TIMELINE_CHUNKS.insert(
+                    txn,
+                    &(room_id, prev_batch, next_batch).encode(),
+                    &db.serialize_event(chunk)?,
+                )?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_timeline_chunks(
+        &self,
+        room_id: &RoomId,
+        pruned: PrunedTimeline<'_>,
+    ) -> Result<()> {
+        let chunk_keys: Vec<_> = pruned
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let (prev_batch, next_batch) = chunk.tokens();
+                (room_id.as_str(), prev_batch, next_batch).encode()
+            })
+            .collect();
+        let event_keys: Vec<_> = pruned
+            .event_ids
+            .iter()
+            .map(|event_id| (room_id.as_str(), event_id.as_str()).encode())
+            .collect();
+
+        self.transaction(move |_, txn| {
+            for key in &chunk_keys {
+                TIMELINE_CHUNKS.remove(txn, key)?;
+            }
+
+            for key in &event_keys {
+                TIMELINE_EVENTS.remove(txn, key)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_room_helper(&self, room_id: &RoomId) -> Result<()> {
+        let room_key = room_id.encode();
+
+        self.transaction(move |_, txn| {
+            ROOM_INFOS.remove(txn, &room_key)?;
+            STRIPPED_ROOM_INFOS.remove(txn, &room_key)?;
+
+            for table in [
+                MEMBERS,
+                PROFILES,
+                DISPLAY_NAMES,
+                JOINED_USER_IDS,
+                INVITED_USER_IDS,
+                ROOM_STATE,
+                ROOM_ACCOUNT_DATA,
+                STRIPPED_MEMBERS,
+                STRIPPED_ROOM_STATE,
+                ROOM_USER_RECEIPTS,
+                ROOM_EVENT_RECEIPTS,
+                PENDING_EVENTS,
+                TIMELINE_CHUNKS,
+                TIMELINE_EVENTS,
+                TIMELINE_REDACTIONS,
+            ] {
+                table.remove_prefix(txn, &room_key)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn save_filter(&self, filter_name: &str, filter_id: &str) -> Result<()> {
+        let key = ("filter", filter_name).encode();
+        let filter_id = filter_id.to_owned();
+
+        self.transaction(move |_, txn| Ok(SESSION.insert(txn, &key, filter_id.as_bytes())?)).await
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let now = Instant::now();
+
+        // The changes are moved to a blocking thread, which requires us to
+        // own them.
+        self.save_changes_helper(changes.clone()).await?;
+
+        info!("Saved changes in {:?}", now.elapsed());
+
+        Ok(())
+    }
+
+    async fn get_filter(&self, filter_name: &str) -> Result<Option<String>> {
+        let key = ("filter", filter_name).encode();
+
+        self.read(move |_, conn| {
+            Ok(SESSION.get(conn, &key)?.map(|f| String::from_utf8_lossy(&f).to_string()))
+        })
+        .await
+    }
+
+    async fn get_sync_token(&self) -> Result<Option<String>> {
+        self.read(|_, conn| {
+            Ok(SESSION
+                .get(conn, &"sync_token".encode())?
+                .map(|t| String::from_utf8_lossy(&t).to_string()))
+        })
+        .await
+    }
+
+    async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
+        self.get_value(PRESENCE, user_id.encode()).await
+    }
+
+    async fn get_state_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+        state_key: &str,
+    ) -> Result<Option<Raw<AnySyncStateEvent>>> {
+        let key = (room_id.as_str(), event_type.as_str(), state_key).encode();
+        self.get_value(ROOM_STATE, key).await
+    }
+
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        let key = (room_id.as_str(), event_type.as_str()).encode();
+
+        self.read(move |db, conn| {
+            Ok(ROOM_STATE
+                .scan_prefix(conn, &key)?
+                .into_iter()
+                .map(|(_, e)| db.deserialize_event(&e))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn get_profile(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<RoomMemberEventContent>> {
+        let key = (room_id.as_str(), user_id.as_str()).encode();
+        self.get_value(PROFILES, key).await
+    }
+
+    async fn get_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<MemberEvent>> {
+        let key = (room_id.as_str(), state_key.as_str()).encode();
+        self.get_value(MEMBERS, key).await
+    }
+
+    async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
+        let key = room_id.encode();
+
+        self.read(move |_, conn| {
+            MEMBERS
+                .scan_prefix(conn, &key)?
+                .into_iter()
+                .map(|(k, _)| Ok(Box::<UserId>::try_from(decode_key_value(&k, 1)?)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn get_invited_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
+        self.get_user_ids_with_prefix(INVITED_USER_IDS, room_id).await
+    }
+
+    async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<Box<UserId>>> {
+        self.get_user_ids_with_prefix(JOINED_USER_IDS, room_id).await
+    }
+
+    async fn get_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.read(|db, conn| {
+            Ok(ROOM_INFOS
+                .iter(conn)?
+                .into_iter()
+                .map(|(_, r)| db.deserialize_event(&r))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.read(|db, conn| {
+            Ok(STRIPPED_ROOM_INFOS
+                .iter(conn)?
+                .into_iter()
+                .map(|(_, r)| db.deserialize_event(&r))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn get_users_with_display_name(
+        &self,
+        room_id: &RoomId,
+        display_name: &str,
+    ) -> Result<BTreeSet<Box<UserId>>> {
+        let key = (room_id.as_str(), display_name).encode();
+        Ok(self.get_value(DISPLAY_NAMES, key).await?.unwrap_or_default())
+    }
+
+    async fn get_account_data_event(
+        &self,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyGlobalAccountDataEvent>>> {
+        self.get_value(ACCOUNT_DATA, event_type.encode()).await
+    }
+
+    async fn get_room_account_data_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyRoomAccountDataEvent>>> {
+        let key = (room_id.as_str(), event_type.as_str()).encode();
+        self.get_value(ROOM_ACCOUNT_DATA, key).await
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        user_id: &UserId,
+    ) -> Result<Option<(Box<EventId>, Receipt)>> {
+        let key = (room_id.as_str(), receipt_type.as_ref(), user_id.as_str()).encode();
+        self.get_value(ROOM_USER_RECEIPTS, key).await
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        event_id: &EventId,
+    ) -> Result<Vec<(Box<UserId>, Receipt)>> {
+        let key = (room_id.as_str(), receipt_type.as_ref(), event_id.as_str()).encode();
+
+        self.read(move |db, conn| {
+            ROOM_EVENT_RECEIPTS
+                .scan_prefix(conn, &key)?
+                .into_iter()
+                .map(|(key, value)| {
+                    let user_id = Box::<UserId>::try_from(decode_key_value(&key, 3)?)?;
+                    Ok((user_id, db.deserialize_event(&value)?))
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_owned();
+        self.read(move |_, conn| Ok(CUSTOM.get(conn, &key)?)).await
+    }
+
+    async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let key = key.to_owned();
+
+        self.transaction(move |_, txn| {
+            let old = CUSTOM.get(txn, &key)?;
+            CUSTOM.insert(txn, &key, &value)?;
+
+            Ok(old)
+        })
+        .await
+    }
+
+    async fn get_key_value(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        let value: Option<(String, serde_json::Value)> =
+            self.get_value(KEY_VALUES, (namespace, key).encode()).await?;
+
+        Ok(value.map(|(_, v)| v))
+    }
+
+    async fn get_key_values(&self, namespace: &str) -> Result<Vec<(String, serde_json::Value)>> {
+        let prefix = namespace.encode();
+
+        self.read(move |db, conn| {
+            let mut entries = KEY_VALUES
+                .scan_prefix(conn, &prefix)?
+                .into_iter()
+                .map(|(_, value)| Ok(db.deserialize_event::<(String, serde_json::Value)>(&value)?))
+                .collect::<Result<Vec<_>>>()?;
+
+            // The separator of the encoded keys changes the ordering of keys
+            // that are a prefix of each other.
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
+        let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+            .encode();
+        let entry = self.serialize_event(&MediaCacheEntry::new(request, data.len()))?;
+
+        self.transaction(move |_, txn| {
+            MEDIA.insert(txn, &key, &data)?;
+            MEDIA_METADATA.insert(txn, &key, &entry)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+            .encode();
+        let request = request.clone();
+
+        self.read(move |db, conn| {
+            let content = MEDIA.get(conn, &key)?;
+
+            if let Some(content) = &content {
+                // Track the access so the least recently used content is
+                // evicted first.
+                let entry = MediaCacheEntry::new(&request, content.len());
+                MEDIA_METADATA.insert(conn, &key, &db.serialize_event(&entry)?)?;
+            }
+
+            Ok(content)
+        })
+        .await
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        let key = (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+            .encode();
+
+        self.transaction(move |_, txn| {
+            MEDIA.remove(txn, &key)?;
+            MEDIA_METADATA.remove(txn, &key)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        let prefix = uri.as_str().encode();
+
+        self.transaction(move |_, txn| {
+            MEDIA.remove_prefix(txn, &prefix)?;
+            MEDIA_METADATA.remove_prefix(txn, &prefix)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_media_cache_entries(&self) -> Result<Vec<MediaCacheEntry>> {
+        self.read(|db, conn| {
+            MEDIA_METADATA
+                .iter(conn)?
+                .into_iter()
+                .map(|(_, e)| db.deserialize_event(&e).map_err(StoreError::from))
+                .collect()
+        })
+        .await
+    }
+
+    async fn remove_media_cache_entries(&self, entries: &[MediaCacheEntry]) -> Result<()> {
+        let keys: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.media_key.as_str(), entry.format_key.as_str()).encode())
+            .collect();
+
+        self.transaction(move |_, txn| {
+            for key in &keys {
+                MEDIA.remove(txn, key)?;
+                MEDIA_METADATA.remove(txn, key)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn clear_media_cache(&self) -> Result<()> {
+        self.transaction(|_, txn| {
+            MEDIA.clear(txn)?;
+            MEDIA_METADATA.clear(txn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn save_pending_event(&self, event: &PendingEvent) -> Result<()> {
+        let key = (event.room_id.as_str(), event.transaction_id.as_str()).encode();
+        let event = self.serialize_event(event)?;
+
+        self.transaction(move |_, txn| Ok(PENDING_EVENTS.insert(txn, &key, &event)?)).await
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<PendingEvent>> {
+        self.read(|db, conn| {
+            let mut events = PENDING_EVENTS
+                .iter(conn)?
+                .into_iter()
+                .map(|(_, e)| db.deserialize_event(&e).map_err(StoreError::from))
+                .collect::<Result<Vec<PendingEvent>>>()?;
+
+            events.sort_by_key(|e| e.sequence);
+
+            Ok(events)
+        })
+        .await
+    }
+
+    async fn remove_pending_event(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &TransactionId,
+    ) -> Result<()> {
+        let key = (room_id.as_str(), transaction_id.as_str()).encode();
+
+        self.transaction(move |_, txn| Ok(PENDING_EVENTS.remove(txn, &key)?)).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        let key = room_id.encode();
+
+        self.read(move |db, conn| {
+            let mut chunks = TIMELINE_CHUNKS
+                .scan_prefix(conn, &key)?
+                .into_iter()
+                .map(|(_, c)| db.deserialize_event(&c).map_err(StoreError::from))
+                .collect::<Result<Vec<TimelineChunk>>>()?;
+
+            sort_timeline_chunks(&mut chunks);
+
+            Ok(chunks)
+        })
+        .await
+    }
+
+    async fn get_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<SyncRoomEvent>> {
+        let key = (room_id.as_str(), event_id.as_str()).encode();
+        self.get_value(TIMELINE_EVENTS, key).await
+    }
+
+    async fn prune_timeline(&self, room_id: &RoomId, pruning: TimelinePruning) -> Result<()> {
+        let chunks = self.get_timeline_chunks(room_id).await?;
+        self.remove_timeline_chunks(room_id, pruning.prune(&chunks)).await
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.remove_room_helper(room_id).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_test::async_test;
+    use ruma::mxc_uri;
+    use rusqlite::{params, Connection};
+
+    use super::{create_table, EncodeKey, Result, SqliteStore, MEDIA, METADATA};
+    use crate::{
+        media::{MediaFormat, MediaRequest, MediaType, UniqueKey},
+        store::StateStore,
+    };
+
+    async fn get_store() -> Result<SqliteStore> {
+        SqliteStore::open()
+    }
+
+    statestore_integration_tests! { integration }
+
+    mod encrypted {
+        use tempfile::tempdir;
+
+        use super::super::{Result, SqliteStore};
+
+        async fn get_store() -> Result<SqliteStore> {
+            let path = tempdir().expect("Can't create a temporary dir").into_path();
+            SqliteStore::open_with_passphrase(path, "secret passphrase")
+        }
+
+        statestore_integration_tests! { integration }
+    }
+
+    #[async_test]
+    async fn backfill_media_metadata() -> Result<()> {
+        let request = MediaRequest {
+            media_type: MediaType::Uri(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // A store that was created before the metadata of the media cache was
+        // tracked.
+        let mut conn = Connection::open_in_memory()?;
+        let txn = conn.transaction()?;
+        create_table(&txn, METADATA)?;
+        create_table(&txn, MEDIA)?;
+        MEDIA.insert(
+            &txn,
+            &(request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
+                .encode(),
+            b"content",
+        )?;
+        txn.execute_batch("PRAGMA user_version = 1;")?;
+        txn.commit()?;
+
+        let store = SqliteStore::open_helper(conn, None, None)?;
+
+        let entries = store.get_media_cache_entries().await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].media_key, request.media_type.unique_key());
+        assert_eq!(entries[0].size, 7);
+        assert!(!entries[0].thumbnail);
+
+        let version: u32 =
+            store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        assert_eq!(version, super::DATABASE_VERSION);
+
+        Ok(())
+    }
+}
@@ -19,7 +19,7 @@ pub mod util;
 #[cfg(target_arch = "wasm32")]
 mod wasm_helpers;
 #[cfg(target_arch = "wasm32")]
-pub use wasm_helpers::SafeEncode;
+pub use wasm_helpers::{SafeEncode, ESCAPED, KEY_SEPARATOR};
 
 /// Super trait that is used for our store traits, this trait will differ if
 /// it's used on WASM. WASM targets will not require `Send` and `Sync` to have
@@ -28,12 +28,16 @@ use olm_rs::{
     pk::{OlmPkDecryption, PkMessage},
 };
 use rand::{thread_rng, Error as RandomError, Fill};
+use ruma::api::client::r0::backup::SessionData;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use zeroize::{Zeroize, Zeroizing};
 
 use super::MegolmV1BackupKey;
-use crate::utilities::{decode_url_safe, encode, encode_url_safe};
+use crate::{
+    olm::BackedUpRoomKey,
+    utilities::{decode_url_safe, encode, encode_url_safe},
+};
 
 const NONCE_SIZE: usize = 12;
 
@@ -60,6 +64,17 @@ pub enum DecodeError {
     Io(#[from] std::io::Error),
 }
 
+/// Error type for the decryption of a backed up room key.
+#[derive(Debug, Error)]
+pub enum DecryptionError {
+    /// The backed up room key couldn't be decrypted.
+    #[error(transparent)]
+    Decryption(#[from] OlmPkDecryptionError),
+    /// The decrypted room key isn't a valid backed up room key.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
 #[derive(Debug, Error)]
 pub enum UnpicklingError {
     #[error(transparent)]
@@ -71,7 +86,7 @@ pub enum UnpicklingError {
 }
 
 /// The private part of a backup key.
-#[derive(Zeroize)]
+#[derive(Clone, Zeroize)]
 pub struct RecoveryKey {
     inner: [u8; RecoveryKey::KEY_SIZE],
 }
@@ -304,6 +319,25 @@ impl RecoveryKey {
 
         pk.decrypt(message)
     }
+
+    /// Try to decrypt the session data of a backed up room key.
+    ///
+    /// The session data can be found in the [`KeyBackupData`] struct that the
+    /// server returns for every room key that is part of the backup.
+    ///
+    /// [`KeyBackupData`]: ruma::api::client::r0::backup::KeyBackupData
+    pub fn decrypt_session_data(
+        &self,
+        session_data: SessionData,
+    ) -> Result<BackedUpRoomKey, DecryptionError> {
+        let plaintext = Zeroizing::new(self.decrypt_v1(
+            session_data.mac,
+            session_data.ephemeral,
+            session_data.ciphertext,
+        )?);
+
+        Ok(serde_json::from_str(&plaintext)?)
+    }
 }
 
 #[cfg(test)]
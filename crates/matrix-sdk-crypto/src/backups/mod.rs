@@ -38,14 +38,14 @@ use serde_json::Value;
 use tracing::{debug, info, instrument, trace, warn};
 
 use crate::{
-    olm::{Account, InboundGroupSession},
+    olm::{Account, ExportedRoomKey, InboundGroupSession},
     store::{BackupKeys, Changes, RoomKeyCounts, Store},
-    CryptoStoreError, KeysBackupRequest, OutgoingRequest,
+    CryptoStoreError, KeysBackupRequest, OutgoingRequest, RoomKeyImportResult,
 };
 
 mod keys;
 
-pub use keys::{DecodeError, MegolmV1BackupKey, PickledRecoveryKey, RecoveryKey};
+pub use keys::{DecodeError, DecryptionError, MegolmV1BackupKey, PickledRecoveryKey, RecoveryKey};
 pub use olm_rs::errors::OlmPkDecryptionError;
 
 /// A state machine that handles backing up room keys.
@@ -64,7 +64,7 @@ pub struct BackupMachine {
 
 #[derive(Debug, Clone)]
 struct PendingBackup {
-    request_id: &TransactionId,
+    request_id: Box<TransactionId>,
     request: KeysBackupRequest,
     sessions: BTreeMap<Box<RoomId>, BTreeMap<String, BTreeSet<String>>>,
 }
@@ -238,6 +238,86 @@ impl BackupMachine {
         self.store.load_backup_keys().await
     }
 
+    /// Decrypt and import room keys that were downloaded from the server-side
+    /// key backup.
+    ///
+    /// The room keys should be fetched from the server using the
+    /// [`/room_keys/keys`] endpoint, either for the whole backup or for a
+    /// single room. Room keys that fail to be decrypted with the given
+    /// `RecoveryKey` are skipped.
+    ///
+    /// The decrypted room keys are imported the same way
+    /// [`OlmMachine::import_keys`] imports them, and they are marked as
+    /// already backed up.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The private part of the backup key that was used to
+    /// encrypt the room keys.
+    ///
+    /// * `rooms` - A map from a room id to the backed up room keys of this
+    /// room.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were processed so far and the total number of room
+    /// keys that are going to be imported.
+    ///
+    /// [`OlmMachine::import_keys`]: crate::OlmMachine::import_keys
+    /// [`/room_keys/keys`]: https://spec.matrix.org/unstable/client-server-api/#get_matrixclientv3room_keyskeys
+    #[instrument(skip(self, recovery_key, rooms, progress_listener))]
+    pub async fn import_backed_up_room_keys(
+        &self,
+        recovery_key: &RecoveryKey,
+        rooms: BTreeMap<Box<RoomId>, RoomKeyBackup>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult, CryptoStoreError> {
+        let room_keys = Self::decrypt_room_keys(recovery_key, rooms);
+
+        self.store.import_room_keys(room_keys, true, progress_listener).await
+    }
+
+    fn decrypt_room_keys(
+        recovery_key: &RecoveryKey,
+        rooms: BTreeMap<Box<RoomId>, RoomKeyBackup>,
+    ) -> Vec<ExportedRoomKey> {
+        let mut room_keys = Vec::new();
+
+        for (room_id, room_key_backup) in rooms {
+            for (session_id, key_backup_data) in room_key_backup.sessions {
+                let key_backup_data = match key_backup_data.deserialize() {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!(
+                            room_id = room_id.as_str(),
+                            session_id = session_id.as_str(),
+                            error =? e,
+                            "Couldn't deserialize a backed up room key"
+                        );
+                        continue;
+                    }
+                };
+
+                match recovery_key.decrypt_session_data(key_backup_data.session_data) {
+                    Ok(room_key) => room_keys.push(ExportedRoomKey::from_backed_up_room_key(
+                        &room_id,
+                        &session_id,
+                        room_key,
+                    )),
+                    Err(e) => {
+                        warn!(
+                            room_id = room_id.as_str(),
+                            session_id = session_id.as_str(),
+                            error =? e,
+                            "Couldn't decrypt a backed up room key"
+                        );
+                    }
+                }
+            }
+        }
+
+        room_keys
+    }
+
     /// Encrypt a batch of room keys and return a request that needs to be sent
     /// out to backup the room keys.
     pub async fn backup(&self) -> Result<Option<OutgoingRequest>, CryptoStoreError> {
@@ -264,7 +344,7 @@ impl BackupMachine {
         let mut request = self.pending_backup.write().await;
 
         if let Some(r) = &*request {
-            if r.request_id == request_id {
+            if *r.request_id == *request_id {
                 let sessions: Vec<_> = self
                     .store
                     .get_inbound_group_sessions()
@@ -392,7 +472,7 @@ mod test {
     use ruma::{device_id, room_id, user_id, DeviceId, RoomId, UserId};
 
     use super::RecoveryKey;
-    use crate::{OlmError, OlmMachine};
+    use crate::{OlmError, OlmMachine, OutgoingRequests};
 
     fn alice_id() -> &'static UserId {
         user_id!("@alice:example.org")
@@ -438,7 +518,7 @@ mod test {
             "Calling backup again without uploading creates the same backup request"
         );
 
-        backup_machine.mark_request_as_sent(request.request_id).await?;
+        backup_machine.mark_request_as_sent(&request.request_id).await?;
 
         let counts = backup_machine.store.inbound_group_session_counts().await?;
         assert_eq!(counts.total, 2);
@@ -461,6 +541,52 @@ mod test {
         Ok(())
     }
 
+    #[async_test]
+    async fn backed_up_room_keys_importing() -> Result<(), OlmError> {
+        let machine = OlmMachine::new(alice_id(), alice_device_id());
+        let backup_machine = machine.backup_machine();
+
+        machine.create_outbound_group_session_with_defaults(room_id()).await?;
+        machine.create_outbound_group_session_with_defaults(room_id2()).await?;
+
+        let recovery_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let backup_key = recovery_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+
+        backup_machine.enable_backup_v1(backup_key).await?;
+
+        let request =
+            backup_machine.backup().await?.expect("Created a backup request successfully");
+        let rooms = match request.request() {
+            OutgoingRequests::KeysBackup(r) => r.rooms.clone(),
+            _ => panic!("Expected a keys backup request"),
+        };
+
+        let new_machine = OlmMachine::new(alice_id(), alice_device_id());
+        let new_backup_machine = new_machine.backup_machine();
+
+        let result = new_backup_machine
+            .import_backed_up_room_keys(&recovery_key, rooms.clone(), |_, _| {})
+            .await?;
+
+        assert_eq!(result.total_count, 2, "Both backed up room keys were decrypted");
+        assert_eq!(result.imported_count, 2, "Both backed up room keys were imported");
+        assert!(result.keys.contains_key(room_id()));
+        assert!(result.keys.contains_key(room_id2()));
+
+        let counts = new_backup_machine.store.inbound_group_session_counts().await?;
+        assert_eq!(counts.total, 2);
+        assert_eq!(counts.backed_up, 2, "Imported room keys are marked as backed up");
+
+        let wrong_key = RecoveryKey::new().expect("Can't create new recovery key");
+        let result =
+            new_backup_machine.import_backed_up_room_keys(&wrong_key, rooms, |_, _| {}).await?;
+
+        assert_eq!(result.total_count, 0, "Room keys can't be decrypted with the wrong key");
+
+        Ok(())
+    }
+
     #[async_test]
     async fn memory_store_backups() -> Result<(), OlmError> {
         let machine = OlmMachine::new(alice_id(), alice_device_id());
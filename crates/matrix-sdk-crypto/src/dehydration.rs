@@ -0,0 +1,570 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for dehydrated devices as described in [MSC2697].
+//!
+//! A dehydrated device is an Olm account that gets pickled, encrypted with a
+//! key derived from the secret storage key, and uploaded to the homeserver.
+//! Other devices will treat the dehydrated device like any other device of the
+//! user and send room keys to it.
+//!
+//! Once the user logs in again, the dehydrated device can be downloaded and
+//! rehydrated. The to-device events that were sent to the dehydrated device
+//! can then be claimed and decrypted, and the room keys they contain are
+//! imported into the store of the newly logged in device.
+//!
+//! [MSC2697]: https://github.com/matrix-org/matrix-doc/pull/2697
+
+use std::collections::BTreeMap;
+
+use olm_rs::{errors::OlmAccountError, PicklingMode};
+use ruma::{
+    api::client::r0::sync::sync_events::{DeviceLists, ToDevice},
+    events::AnyToDeviceEvent,
+    serde::Raw,
+    DeviceId,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::{
+    olm::{PickledAccount, ReadOnlyAccount},
+    secret_storage::SecretStorageKey,
+    CryptoStoreError, OlmError, OlmMachine, RoomKeyImportResult,
+};
+
+/// The name of the only dehydrated device data algorithm we support, the
+/// device data contains a libolm account pickle.
+pub const LIBOLM_PICKLE_ALGORITHM: &str = "org.matrix.msc2697.v1.olm.libolm_pickle";
+
+/// The info string that is used to derive the pickle key of a dehydrated
+/// device from the secret storage key.
+const PICKLE_KEY_INFO: &str = "org.matrix.msc2697.v2.dehydrated_device";
+
+/// Error type describing failures that can happen while creating or
+/// rehydrating a dehydrated device.
+#[derive(Debug, Error)]
+pub enum DehydrationError {
+    /// The dehydrated device uses an algorithm we don't support.
+    #[error("the dehydrated device uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    /// The dehydrated device couldn't be unpickled, most likely because the
+    /// wrong secret storage key was used.
+    #[error("the dehydrated device couldn't be unpickled: {0}")]
+    Pickle(#[from] OlmAccountError),
+    /// The room keys of the rehydrated device couldn't be stored.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+    /// The to-device events of the rehydrated device couldn't be decrypted.
+    #[error(transparent)]
+    Olm(#[from] OlmError),
+}
+
+/// The device data of a dehydrated device, as it gets stored on the
+/// homeserver.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DehydratedDeviceData {
+    /// The algorithm of the dehydrated device, only
+    /// [`LIBOLM_PICKLE_ALGORITHM`] is supported.
+    pub algorithm: String,
+    /// The encrypted pickle of the Olm account of the dehydrated device.
+    pub account: String,
+}
+
+/// Endpoint to upload a new dehydrated device, replacing any previously
+/// uploaded one.
+pub mod put_dehydrated_device {
+    use std::collections::BTreeMap;
+
+    use ruma::{
+        api::ruma_api,
+        encryption::{DeviceKeys, OneTimeKey},
+        serde::Raw,
+        DeviceId, DeviceKeyId,
+    };
+
+    use super::DehydratedDeviceData;
+
+    ruma_api! {
+        metadata: {
+            description: "Upload a dehydrated device.",
+            method: PUT,
+            name: "put_dehydrated_device",
+            path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The unique ID of the dehydrated device.
+            pub device_id: Box<DeviceId>,
+
+            /// The encrypted account of the dehydrated device.
+            pub device_data: DehydratedDeviceData,
+
+            /// The display name of the dehydrated device.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub initial_device_display_name: Option<String>,
+
+            /// The signed identity keys of the dehydrated device.
+            pub device_keys: DeviceKeys,
+
+            /// The signed one-time keys of the dehydrated device.
+            pub one_time_keys: BTreeMap<Box<DeviceKeyId>, Raw<OneTimeKey>>,
+        }
+
+        response: {
+            /// The unique ID of the dehydrated device.
+            pub device_id: Box<DeviceId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl Request {
+        /// Creates a new `Request` with the given device ID, device data and
+        /// keys.
+        pub fn new(
+            device_id: Box<DeviceId>,
+            device_data: DehydratedDeviceData,
+            device_keys: DeviceKeys,
+            one_time_keys: BTreeMap<Box<DeviceKeyId>, Raw<OneTimeKey>>,
+        ) -> Self {
+            Self {
+                device_id,
+                device_data,
+                initial_device_display_name: None,
+                device_keys,
+                one_time_keys,
+            }
+        }
+    }
+
+    impl Response {
+        /// Creates a new `Response` with the given device ID.
+        pub fn new(device_id: Box<DeviceId>) -> Self {
+            Self { device_id }
+        }
+    }
+}
+
+/// Endpoint to download the dehydrated device of the user.
+pub mod get_dehydrated_device {
+    use ruma::{api::ruma_api, DeviceId};
+
+    use super::DehydratedDeviceData;
+
+    ruma_api! {
+        metadata: {
+            description: "Get the dehydrated device of the user.",
+            method: GET,
+            name: "get_dehydrated_device",
+            path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            /// The unique ID of the dehydrated device.
+            pub device_id: Box<DeviceId>,
+
+            /// The encrypted account of the dehydrated device.
+            pub device_data: DehydratedDeviceData,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl Request {
+        /// Creates an empty `Request`.
+        pub fn new() -> Self {
+            Self {}
+        }
+    }
+
+    impl Response {
+        /// Creates a new `Response` with the given device ID and device data.
+        pub fn new(device_id: Box<DeviceId>, device_data: DehydratedDeviceData) -> Self {
+            Self { device_id, device_data }
+        }
+    }
+}
+
+/// Endpoint to claim the to-device events that were sent to the dehydrated
+/// device.
+pub mod get_events {
+    use ruma::{api::ruma_api, events::AnyToDeviceEvent, serde::Raw, DeviceId};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the to-device events of the dehydrated device.",
+            method: POST,
+            name: "get_dehydrated_device_events",
+            path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device/:device_id/events",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The unique ID of the dehydrated device.
+            #[ruma_api(path)]
+            pub device_id: &'a DeviceId,
+
+            /// The batch token returned by a previous request, if any.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<&'a str>,
+        }
+
+        response: {
+            /// The to-device events that were sent to the dehydrated device.
+            pub events: Vec<Raw<AnyToDeviceEvent>>,
+
+            /// The token to fetch the next batch of events.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` with the given device ID.
+        pub fn new(device_id: &'a DeviceId) -> Self {
+            Self { device_id, next_batch: None }
+        }
+    }
+
+    impl Response {
+        /// Creates a new `Response` with the given events.
+        pub fn new(events: Vec<Raw<AnyToDeviceEvent>>) -> Self {
+            Self { events, next_batch: None }
+        }
+    }
+}
+
+/// Object that creates and rehydrates dehydrated devices for an
+/// [`OlmMachine`].
+///
+/// Can be created using the [`OlmMachine::dehydrated_devices()`] method.
+#[derive(Debug, Clone)]
+pub struct DehydratedDevices {
+    inner: OlmMachine,
+}
+
+impl DehydratedDevices {
+    pub(crate) fn new(machine: OlmMachine) -> Self {
+        Self { inner: machine }
+    }
+
+    /// Create a new dehydrated device.
+    ///
+    /// Returns the request that needs to be sent out to upload the dehydrated
+    /// device. The upload replaces any previously uploaded dehydrated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The secret storage key that is used to derive the key which
+    /// encrypts the dehydrated device.
+    ///
+    /// * `display_name` - The display name the dehydrated device should have.
+    pub async fn create(
+        &self,
+        key: &SecretStorageKey,
+        display_name: Option<&str>,
+    ) -> put_dehydrated_device::Request {
+        let device_id = DeviceId::new();
+        let account = ReadOnlyAccount::new(self.inner.user_id(), &device_id);
+
+        let device_keys = account.device_keys().await;
+        let one_time_keys = account.signed_one_time_keys().await.unwrap_or_default();
+
+        // The dehydrated device won't upload keys by itself, so mark the keys
+        // as published before pickling it.
+        account.mark_keys_as_published().await;
+        account.mark_as_shared();
+
+        let pickle_key = key.derive_subkey(PICKLE_KEY_INFO);
+        let pickle = account.pickle(PicklingMode::Encrypted { key: pickle_key.to_vec() }).await;
+
+        let device_data = DehydratedDeviceData {
+            algorithm: LIBOLM_PICKLE_ALGORITHM.to_owned(),
+            account: pickle.pickle.as_str().to_owned(),
+        };
+
+        info!(device_id = device_id.as_str(), "Created a new dehydrated device");
+
+        let mut request =
+            put_dehydrated_device::Request::new(device_id, device_data, device_keys, one_time_keys);
+        request.initial_device_display_name = display_name.map(ToOwned::to_owned);
+
+        request
+    }
+
+    /// Rehydrate a dehydrated device that was downloaded from the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The secret storage key that was used to create the dehydrated
+    /// device.
+    ///
+    /// * `device_id` - The unique ID of the dehydrated device.
+    ///
+    /// * `device_data` - The device data of the dehydrated device.
+    pub fn rehydrate(
+        &self,
+        key: &SecretStorageKey,
+        device_id: &DeviceId,
+        device_data: DehydratedDeviceData,
+    ) -> Result<RehydratedDevice, DehydrationError> {
+        if device_data.algorithm != LIBOLM_PICKLE_ALGORITHM {
+            return Err(DehydrationError::UnsupportedAlgorithm(device_data.algorithm));
+        }
+
+        let pickle = PickledAccount {
+            user_id: self.inner.user_id().to_owned(),
+            device_id: device_id.to_owned(),
+            pickle: device_data.account.into(),
+            shared: true,
+            uploaded_signed_key_count: 0,
+        };
+
+        let pickle_key = key.derive_subkey(PICKLE_KEY_INFO);
+        let account = ReadOnlyAccount::from_pickle(
+            pickle,
+            PicklingMode::Encrypted { key: pickle_key.to_vec() },
+        )?;
+
+        debug!(device_id = device_id.as_str(), "Rehydrated a dehydrated device");
+
+        Ok(RehydratedDevice {
+            rehydrated: OlmMachine::new_with_account(account),
+            original: self.inner.clone(),
+        })
+    }
+}
+
+/// A dehydrated device that was rehydrated.
+///
+/// The to-device events that were sent to the dehydrated device should be
+/// claimed from the server and passed to the
+/// [`RehydratedDevice::receive_events()`] method, this imports the room keys
+/// the events contain into the store of the original `OlmMachine`.
+///
+/// The Olm sessions of the rehydrated device are tied to its identity keys and
+/// are thus only used to decrypt the claimed events, they don't get imported.
+///
+/// Once the events have been claimed the dehydrated device shouldn't be used
+/// anymore and a new dehydrated device should be created.
+#[derive(Debug)]
+pub struct RehydratedDevice {
+    rehydrated: OlmMachine,
+    original: OlmMachine,
+}
+
+impl RehydratedDevice {
+    /// The unique ID of the rehydrated device.
+    pub fn device_id(&self) -> &DeviceId {
+        self.rehydrated.device_id()
+    }
+
+    /// Decrypt the given to-device events that were sent to the dehydrated
+    /// device and import the room keys they contain.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - A batch of to-device events that were claimed from the
+    /// server.
+    pub async fn receive_events(
+        &self,
+        events: Vec<Raw<AnyToDeviceEvent>>,
+    ) -> Result<RoomKeyImportResult, DehydrationError> {
+        let mut to_device = ToDevice::new();
+        to_device.events = events;
+
+        self.rehydrated
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await?;
+
+        let room_keys = self.rehydrated.export_keys(|_| true).await?;
+
+        debug!(
+            device_id = self.device_id().as_str(),
+            room_key_count = room_keys.len(),
+            "Importing room keys from a rehydrated device"
+        );
+
+        Ok(self.original.import_keys(room_keys, false, |_, _| {}).await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, iter};
+
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        api::{
+            client::r0::keys::{claim_keys, get_keys},
+            IncomingResponse as _,
+        },
+        device_id, event_id,
+        events::{
+            room::message::RoomMessageEventContent, AnyMessageEventContent, SyncMessageEvent,
+            Unsigned,
+        },
+        room_id,
+        to_device::DeviceIdOrAllDevices,
+        user_id, MilliSecondsSinceUnixEpoch, TransactionId,
+    };
+    use serde_json::json;
+
+    use super::{DehydrationError, LIBOLM_PICKLE_ALGORITHM};
+    use crate::{
+        machine::test::response_from_file, secret_storage::SecretStorageKey, IncomingResponse,
+        OlmMachine,
+    };
+
+    fn machine() -> OlmMachine {
+        OlmMachine::new(user_id!("@alice:example.org"), device_id!("ALICEDEVICE"))
+    }
+
+    #[async_test]
+    async fn dehydration_roundtrip() {
+        let machine = machine();
+        let key = SecretStorageKey::new();
+
+        let request = machine.dehydrated_devices().create(&key, Some("Dehydrated")).await;
+
+        assert_eq!(request.device_data.algorithm, LIBOLM_PICKLE_ALGORITHM);
+        assert_eq!(request.initial_device_display_name.as_deref(), Some("Dehydrated"));
+        assert_eq!(&request.device_keys.device_id, &request.device_id);
+        assert!(!request.one_time_keys.is_empty());
+
+        let rehydrated = machine
+            .dehydrated_devices()
+            .rehydrate(&key, &request.device_id, request.device_data.clone())
+            .unwrap();
+
+        assert_eq!(rehydrated.device_id(), &*request.device_id);
+    }
+
+    #[async_test]
+    async fn rehydrated_device_imports_room_keys() {
+        let alice = machine();
+        let bob = OlmMachine::new(user_id!("@bob:example.org"), device_id!("BOBDEVICE"));
+        let room_id = room_id!("!test:example.org");
+        let key = SecretStorageKey::new();
+
+        let request = alice.dehydrated_devices().create(&key, None).await;
+
+        // Bob discovers the dehydrated device and establishes an Olm session
+        // with it, like he would with any other device of Alice.
+        let keys_query = json!({
+            "device_keys": {
+                alice.user_id().as_str(): { request.device_id.as_str(): request.device_keys },
+            },
+        });
+        let keys_query =
+            get_keys::Response::try_from_http_response(response_from_file(&keys_query)).unwrap();
+        bob.mark_request_as_sent(&TransactionId::new(), IncomingResponse::KeysQuery(&keys_query))
+            .await
+            .unwrap();
+
+        let (key_id, one_time_key) = request.one_time_keys.iter().next().unwrap();
+        let keys_claim = claim_keys::Response::new(BTreeMap::from([(
+            alice.user_id().to_owned(),
+            BTreeMap::from([(
+                request.device_id.clone(),
+                BTreeMap::from([(key_id.clone(), one_time_key.clone())]),
+            )]),
+        )]));
+        bob.mark_request_as_sent(&TransactionId::new(), IncomingResponse::KeysClaim(&keys_claim))
+            .await
+            .unwrap();
+
+        let requests = bob
+            .share_group_session(room_id, iter::once(alice.user_id()), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let content = requests[0]
+            .messages
+            .get(alice.user_id())
+            .and_then(|m| m.get(&DeviceIdOrAllDevices::DeviceId(request.device_id.clone())))
+            .unwrap();
+        let event = serde_json::from_value(json!({
+            "sender": bob.user_id(),
+            "type": "m.room.encrypted",
+            "content": content,
+        }))
+        .unwrap();
+
+        // Alice logs in again and rehydrates the device to get the room key.
+        let rehydrated = alice
+            .dehydrated_devices()
+            .rehydrate(&key, &request.device_id, request.device_data)
+            .unwrap();
+        let result = rehydrated.receive_events(vec![event]).await.unwrap();
+
+        assert_eq!(result.imported_count, 1);
+        assert!(result.keys.contains_key(room_id));
+
+        let encrypted = bob
+            .encrypt(
+                room_id,
+                AnyMessageEventContent::RoomMessage(RoomMessageEventContent::text_plain(
+                    "It is a secret to everybody",
+                )),
+            )
+            .await
+            .unwrap();
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org").to_owned(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: bob.user_id().to_owned(),
+            content: encrypted,
+            unsigned: Unsigned::default(),
+        };
+
+        assert!(alice.decrypt_room_event(&event, room_id).await.is_ok());
+    }
+
+    #[async_test]
+    async fn rehydration_with_wrong_key() {
+        let machine = machine();
+
+        let request = machine.dehydrated_devices().create(&SecretStorageKey::new(), None).await;
+
+        let result = machine.dehydrated_devices().rehydrate(
+            &SecretStorageKey::new(),
+            &request.device_id,
+            request.device_data.clone(),
+        );
+        assert!(matches!(result, Err(DehydrationError::Pickle(_))));
+
+        let mut device_data = request.device_data;
+        device_data.algorithm = "m.unknown".to_owned();
+
+        let result = machine.dehydrated_devices().rehydrate(
+            &SecretStorageKey::new(),
+            &request.device_id,
+            device_data,
+        );
+        assert!(matches!(result, Err(DehydrationError::UnsupportedAlgorithm(_))));
+    }
+}
@@ -18,6 +18,7 @@ use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 use super::store::CryptoStoreError;
+use crate::olm::WithheldCode;
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -79,8 +80,14 @@ pub enum MegolmError {
 
     /// Decryption failed because we're missing the room key that was to encrypt
     /// the event.
-    #[error("decryption failed because the room key is missing")]
-    MissingRoomKey,
+    ///
+    /// Contains the reason why the room key was withheld from us, if the
+    /// sender sent us a `m.room_key.withheld` notice.
+    #[error(
+        "decryption failed because the room key is missing{}",
+        .0.as_ref().map(|c| format!(", the sender withheld it: {}", c)).unwrap_or_default()
+    )]
+    MissingRoomKey(Option<WithheldCode>),
 
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
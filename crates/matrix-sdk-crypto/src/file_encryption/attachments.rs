@@ -15,6 +15,8 @@
 use std::{
     collections::BTreeMap,
     io::{Error as IoError, ErrorKind, Read},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 use aes::{
@@ -22,6 +24,8 @@ use aes::{
     Aes256, Aes256Ctr,
 };
 use base64::DecodeError;
+use futures_io::AsyncRead;
+use futures_util::ready;
 use getrandom::getrandom;
 use ruma::{
     events::room::{EncryptedFile, JsonWebKey, JsonWebKeyInit},
@@ -128,21 +132,91 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
         input: &'a mut R,
         info: MediaEncryptionInfo,
     ) -> Result<AttachmentDecryptor<'a, R>, DecryptorError> {
-        if info.version != VERSION {
-            return Err(DecryptorError::UnknownVersion);
-        }
+        let (expected_hash, aes) = decryption_state(info)?;
+
+        Ok(AttachmentDecryptor { inner: input, expected_hash, sha: Sha256::default(), aes })
+    }
+}
+
+/// Get the expected hash and the cipher to decrypt an attachment with the
+/// given encryption info.
+fn decryption_state(info: MediaEncryptionInfo) -> Result<(Vec<u8>, Aes256Ctr), DecryptorError> {
+    if info.version != VERSION {
+        return Err(DecryptorError::UnknownVersion);
+    }
+
+    let hash = info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?.as_bytes().to_owned();
+    let key = Zeroizing::from(info.web_key.k.into_inner());
+    let iv = info.iv.into_inner();
+    let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
+
+    let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
+    let aes = Aes256Ctr::from_block_cipher(aes, &iv);
+
+    Ok((hash, aes))
+}
+
+/// A wrapper that transparently decrypts anything that implements `AsyncRead`
+/// as a Matrix attachment.
+///
+/// This is the asynchronous counterpart of [`AttachmentDecryptor`], it takes
+/// ownership of the reader so it can be used to decrypt an attachment while
+/// it's being streamed.
+pub struct AsyncAttachmentDecryptor<R> {
+    inner: R,
+    expected_hash: Vec<u8>,
+    sha: Sha256,
+    aes: Aes256Ctr,
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for AsyncAttachmentDecryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncAttachmentDecryptor")
+            .field("inner", &self.inner)
+            .field("expected_hash", &self.expected_hash)
+            .finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncAttachmentDecryptor<R> {
+    /// Wrap the given reader decrypting all the data we read from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The `AsyncRead` that should be wrapped and decrypted.
+    ///
+    /// * `info` - The encryption info that is necessary to decrypt data from
+    /// the reader.
+    pub fn new(reader: R, info: MediaEncryptionInfo) -> Result<Self, DecryptorError> {
+        let (expected_hash, aes) = decryption_state(info)?;
 
-        let hash =
-            info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?.as_bytes().to_owned();
-        let key = Zeroizing::from(info.web_key.k.into_inner());
-        let iv = info.iv.into_inner();
-        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
+        Ok(Self { inner: reader, expected_hash, sha: Sha256::default(), aes })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncAttachmentDecryptor<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let read_bytes = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
 
-        let sha = Sha256::default();
-        let aes = Aes256::new_from_slice(&key).map_err(|_| DecryptorError::KeyNonceLength)?;
-        let aes = Aes256Ctr::from_block_cipher(aes, &iv);
+        Poll::Ready(if read_bytes == 0 {
+            let hash = this.sha.finalize_reset();
+
+            if hash.as_slice() == this.expected_hash.as_slice() {
+                Ok(0)
+            } else {
+                Err(IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
+            }
+        } else {
+            this.sha.update(&buf[0..read_bytes]);
+            this.aes.apply_keystream(&mut buf[0..read_bytes]);
 
-        Ok(AttachmentDecryptor { inner: input, expected_hash: hash, sha, aes })
+            Ok(read_bytes)
+        })
     }
 }
 
@@ -215,32 +289,12 @@ impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
     /// let key = encryptor.finish();
     /// ```
     pub fn new(reader: &'a mut R) -> Self {
-        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
-        let mut iv = Zeroizing::new([0u8; IV_SIZE]);
-
-        getrandom(&mut *key).expect("Can't generate randomness");
-        // Only populate the first 8 bytes with randomness, the rest is 0
-        // initialized for the counter.
-        getrandom(&mut iv[0..8]).expect("Can't generate randomness");
-
-        let web_key = JsonWebKey::from(JsonWebKeyInit {
-            kty: "oct".to_owned(),
-            key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
-            alg: "A256CTR".to_owned(),
-            k: Base64::new((*key).to_vec()),
-            ext: true,
-        });
-        let encoded_iv = Base64::new((*iv).to_vec());
-        let iv = GenericArray::from_slice(&*iv);
-        let key = GenericArray::from_slice(&*key);
-
-        let aes = Aes256::new(key);
-        let aes = Aes256Ctr::from_block_cipher(aes, iv);
+        let (web_key, iv, aes) = encryption_state();
 
         AttachmentEncryptor {
             finished: false,
             inner: reader,
-            iv: encoded_iv,
+            iv,
             web_key,
             hashes: BTreeMap::new(),
             aes,
@@ -249,18 +303,137 @@ impl<'a, R: Read + 'a> AttachmentEncryptor<'a, R> {
     }
 
     /// Consume the encryptor and get the encryption key.
-    pub fn finish(mut self) -> MediaEncryptionInfo {
-        let hash = self.sha.finalize();
-        self.hashes
-            .entry("sha256".to_owned())
-            .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
-
-        MediaEncryptionInfo {
-            version: VERSION.to_string(),
-            hashes: self.hashes,
-            iv: self.iv,
-            web_key: self.web_key,
+    pub fn finish(self) -> MediaEncryptionInfo {
+        encryption_info(self.sha, self.hashes, self.iv, self.web_key)
+    }
+}
+
+/// Create a fresh key, initialization vector and the cipher to encrypt an
+/// attachment.
+///
+/// # Panics
+///
+/// Panics if we can't generate enough random data to create a fresh
+/// encryption key.
+fn encryption_state() -> (JsonWebKey, Base64, Aes256Ctr) {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    let mut iv = Zeroizing::new([0u8; IV_SIZE]);
+
+    getrandom(&mut *key).expect("Can't generate randomness");
+    // Only populate the first 8 bytes with randomness, the rest is 0
+    // initialized for the counter.
+    getrandom(&mut iv[0..8]).expect("Can't generate randomness");
+
+    let web_key = JsonWebKey::from(JsonWebKeyInit {
+        kty: "oct".to_owned(),
+        key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+        alg: "A256CTR".to_owned(),
+        k: Base64::new((*key).to_vec()),
+        ext: true,
+    });
+    let encoded_iv = Base64::new((*iv).to_vec());
+    let iv = GenericArray::from_slice(&*iv);
+    let key = GenericArray::from_slice(&*key);
+
+    let aes = Aes256::new(key);
+    let aes = Aes256Ctr::from_block_cipher(aes, iv);
+
+    (web_key, encoded_iv, aes)
+}
+
+fn encryption_info(
+    sha: Sha256,
+    mut hashes: BTreeMap<String, Base64>,
+    iv: Base64,
+    web_key: JsonWebKey,
+) -> MediaEncryptionInfo {
+    let hash = sha.finalize();
+    hashes.entry("sha256".to_owned()).or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
+
+    MediaEncryptionInfo { version: VERSION.to_string(), hashes, iv, web_key }
+}
+
+/// A wrapper that transparently encrypts anything that implements `AsyncRead`.
+///
+/// This is the asynchronous counterpart of [`AttachmentEncryptor`], it takes
+/// ownership of the reader so the encrypted data can be streamed, e.g. while
+/// it's being uploaded.
+pub struct AsyncAttachmentEncryptor<R> {
+    inner: R,
+    web_key: JsonWebKey,
+    iv: Base64,
+    hashes: BTreeMap<String, Base64>,
+    aes: Aes256Ctr,
+    sha: Sha256,
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for AsyncAttachmentEncryptor<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncAttachmentEncryptor").field("inner", &self.inner).finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncAttachmentEncryptor<R> {
+    /// Wrap the given reader encrypting all the data we read from it.
+    ///
+    /// After all the data was read a call to [`finish()`](#method.finish) is
+    /// necessary to get the decryption key for the data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we can't generate enough random data to create a fresh
+    /// encryption key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use futures::{executor::block_on, io::{AsyncReadExt, Cursor}};
+    /// # use matrix_sdk_crypto::{AsyncAttachmentDecryptor, AsyncAttachmentEncryptor};
+    /// # block_on(async {
+    /// let mut encryptor = AsyncAttachmentEncryptor::new(Cursor::new("Hello world"));
+    ///
+    /// let mut encrypted = Vec::new();
+    /// encryptor.read_to_end(&mut encrypted).await.unwrap();
+    /// let info = encryptor.finish();
+    ///
+    /// let mut decryptor = AsyncAttachmentDecryptor::new(Cursor::new(encrypted), info).unwrap();
+    /// let mut decrypted = Vec::new();
+    /// decryptor.read_to_end(&mut decrypted).await.unwrap();
+    ///
+    /// assert_eq!(decrypted, b"Hello world");
+    /// # });
+    /// ```
+    pub fn new(reader: R) -> Self {
+        let (web_key, iv, aes) = encryption_state();
+
+        Self { inner: reader, web_key, iv, hashes: BTreeMap::new(), aes, sha: Sha256::default() }
+    }
+
+    /// Consume the encryptor and get the encryption key.
+    pub fn finish(self) -> MediaEncryptionInfo {
+        encryption_info(self.sha, self.hashes, self.iv, self.web_key)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncAttachmentEncryptor<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let read_bytes = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        if read_bytes == 0 {
+            let hash = this.sha.finalize_reset();
+            this.hashes
+                .entry("sha256".to_owned())
+                .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
+        } else {
+            this.aes.apply_keystream(&mut buf[0..read_bytes]);
+            this.sha.update(&buf[0..read_bytes]);
         }
+
+        Poll::Ready(Ok(read_bytes))
     }
 }
 
@@ -289,9 +462,13 @@ impl From<EncryptedFile> for MediaEncryptionInfo {
 mod test {
     use std::io::{Cursor, Read};
 
+    use futures::{executor::block_on, io::AsyncReadExt};
     use serde_json::json;
 
-    use super::{AttachmentDecryptor, AttachmentEncryptor, MediaEncryptionInfo};
+    use super::{
+        AsyncAttachmentDecryptor, AsyncAttachmentEncryptor, AttachmentDecryptor,
+        AttachmentEncryptor, MediaEncryptionInfo,
+    };
 
     const EXAMPLE_DATA: &[u8] = &[
         179, 154, 118, 127, 186, 127, 110, 33, 203, 33, 33, 134, 67, 100, 173, 46, 235, 27, 215,
@@ -341,6 +518,43 @@ mod test {
         assert_eq!(data, decrypted);
     }
 
+    #[test]
+    fn async_encrypt_decrypt_cycle() {
+        block_on(async {
+            let data = "Hello world".to_owned();
+
+            let mut encryptor = AsyncAttachmentEncryptor::new(futures::io::Cursor::new(&data));
+
+            let mut encrypted = Vec::new();
+            encryptor.read_to_end(&mut encrypted).await.unwrap();
+            let key = encryptor.finish();
+            assert_ne!(encrypted.as_slice(), data.as_bytes());
+
+            // The async and the sync variants need to be interchangeable.
+            let mut cursor = Cursor::new(encrypted.clone());
+            let mut decryptor = AttachmentDecryptor::new(&mut cursor, key).unwrap();
+            let mut decrypted_data = Vec::new();
+            decryptor.read_to_end(&mut decrypted_data).unwrap();
+            assert_eq!(data.as_bytes(), decrypted_data);
+
+            let mut decryptor = AsyncAttachmentDecryptor::new(
+                futures::io::Cursor::new(EXAMPLE_DATA),
+                example_key(),
+            )
+            .unwrap();
+            let mut decrypted_data = Vec::new();
+            decryptor.read_to_end(&mut decrypted_data).await.unwrap();
+            assert_eq!(b"It's a secret to everybody", decrypted_data.as_slice());
+
+            let mut decryptor = AsyncAttachmentDecryptor::new(
+                futures::io::Cursor::new("fake message"),
+                example_key(),
+            )
+            .unwrap();
+            assert!(decryptor.read_to_end(&mut Vec::new()).await.is_err());
+        })
+    }
+
     #[test]
     fn real_decrypt() {
         let mut cursor = Cursor::new(EXAMPLE_DATA.to_vec());
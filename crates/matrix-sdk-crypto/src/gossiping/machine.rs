@@ -20,7 +20,10 @@
 // If we don't trust the device store an object that remembers the request and
 // let the users introspect that object.
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
 
 use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use ruma::{
@@ -43,10 +46,13 @@ use ruma::{
 };
 use tracing::{debug, info, trace, warn};
 
-use super::{GossipRequest, KeyForwardDecision, RequestEvent, RequestInfo, SecretInfo, WaitQueue};
+use super::{
+    DefaultKeyShareDecider, GossipRequest, KeyForwardDecision, KeyShareAuditEntry, KeyShareDecider,
+    KeyShareRequest, RequestEvent, RequestInfo, SecretInfo, WaitQueue,
+};
 use crate::{
     error::{OlmError, OlmResult},
-    olm::{InboundGroupSession, Session, ShareState},
+    olm::{InboundGroupSession, RoomKeyWithheldContent, Session, ShareState, WithheldCode},
     requests::{OutgoingRequest, ToDeviceRequest},
     session_manager::GroupSessionCache,
     store::{Changes, CryptoStoreError, SecretImportError, Store},
@@ -63,6 +69,7 @@ pub(crate) struct GossipMachine {
     incoming_key_requests: Arc<DashMap<RequestInfo, RequestEvent>>,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<Box<UserId>, DashSet<Box<DeviceId>>>>,
+    key_share_decider: Arc<RwLock<Arc<dyn KeyShareDecider>>>,
 }
 
 impl GossipMachine {
@@ -82,9 +89,16 @@ impl GossipMachine {
             incoming_key_requests: Default::default(),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            key_share_decider: Arc::new(RwLock::new(Arc::new(DefaultKeyShareDecider))),
         }
     }
 
+    /// Set the policy that decides if room keys are shared with devices that
+    /// request them.
+    pub fn set_key_share_decider(&self, decider: Arc<dyn KeyShareDecider>) {
+        *self.key_share_decider.write().unwrap() = decider;
+    }
+
     /// Load stored outgoing requests that were not yet sent out.
     async fn load_outgoing_requests(&self) -> Result<Vec<OutgoingRequest>, CryptoStoreError> {
         Ok(self
@@ -347,7 +361,19 @@ impl GossipMachine {
             )
             .await?;
 
-        let session = if let Some(s) = session {
+        let device =
+            self.store.get_device(&event.sender, &event.content.requesting_device_id).await?;
+
+        let decider = self.key_share_decider.read().unwrap().clone();
+        let audit_entry = KeyShareAuditEntry {
+            user_id: &event.sender,
+            device_id: &event.content.requesting_device_id,
+            key_info,
+            device: device.as_ref(),
+            session: session.as_ref(),
+        };
+
+        let session = if let Some(s) = &session {
             s
         } else {
             debug!(
@@ -357,14 +383,19 @@ impl GossipMachine {
                 room_id = key_info.room_id.as_str(),
                 "Received a room key request for an unknown inbound group session",
             );
+            decider.audit(&audit_entry, &Err(KeyForwardDecision::UnknownSession));
+
             return Ok(None);
         };
 
-        let device =
-            self.store.get_device(&event.sender, &event.content.requesting_device_id).await?;
+        if let Some(device) = &device {
+            let decision = self.should_share_key(device, session).await;
 
-        if let Some(device) = device {
-            match self.should_share_key(&device, &session).await {
+            let request = KeyShareRequest { device, session };
+            let decision = decider.decide(&request, decision);
+            decider.audit(&audit_entry, &decision);
+
+            match decision {
                 Err(e) => {
                     if let KeyForwardDecision::ChangedSenderKey = e {
                         warn!(
@@ -382,6 +413,15 @@ impl GossipMachine {
                         );
                     }
 
+                    // The room key is ours but the device never received it, or
+                    // our policy forbids sharing it, let the device know that
+                    // it isn't allowed to get it.
+                    if let KeyForwardDecision::OutboundSessionNotShared
+                    | KeyForwardDecision::RejectedByPolicy(_) = e
+                    {
+                        self.withhold_session(key_info, device, WithheldCode::Unauthorised);
+                    }
+
                     Ok(None)
                 }
                 Ok(message_index) => {
@@ -394,7 +434,7 @@ impl GossipMachine {
                         "Serving a room key request",
                     );
 
-                    match self.share_session(&session, &device, message_index).await {
+                    match self.share_session(session, device, message_index).await {
                         Ok(s) => Ok(Some(s)),
                         Err(OlmError::MissingSession) => {
                             info!(
@@ -404,7 +444,10 @@ impl GossipMachine {
                                 "Key request is missing an Olm session, \
                                 putting the request in the wait queue",
                             );
-                            self.handle_key_share_without_session(device, event.to_owned().into());
+                            self.handle_key_share_without_session(
+                                device.clone(),
+                                event.to_owned().into(),
+                            );
 
                             Ok(None)
                         }
@@ -418,6 +461,7 @@ impl GossipMachine {
                 device_id = event.content.requesting_device_id.as_str(),
                 "Received a key request from an unknown device",
             );
+            decider.audit(&audit_entry, &Err(KeyForwardDecision::UnknownDevice));
             self.store.update_tracked_user(&event.sender, true).await?;
 
             Ok(None)
@@ -447,6 +491,25 @@ impl GossipMachine {
         Ok(used_session)
     }
 
+    /// Queue up a `m.room_key.withheld` notice for the given device, telling
+    /// it why we won't serve its key request.
+    fn withhold_session(&self, key_info: &RequestedKeyInfo, device: &Device, code: WithheldCode) {
+        let content = RoomKeyWithheldContent::new(
+            key_info.algorithm.clone(),
+            &key_info.room_id,
+            &key_info.session_id,
+            &key_info.sender_key,
+            code,
+        );
+
+        let request = content.to_device_request(device);
+        let request = OutgoingRequest {
+            request_id: request.txn_id.clone(),
+            request: Arc::new(request.into()),
+        };
+        self.outgoing_requests.insert(request.request_id.clone(), request);
+    }
+
     async fn share_session(
         &self,
         session: &InboundGroupSession,
@@ -934,7 +997,10 @@ impl GossipMachine {
 
 #[cfg(test)]
 mod test {
-    use std::{convert::TryInto, sync::Arc};
+    use std::{
+        convert::TryInto,
+        sync::{Arc, Mutex as StdMutex},
+    };
 
     use dashmap::DashMap;
     use matches::assert_matches;
@@ -945,16 +1011,19 @@ mod test {
         events::{
             forwarded_room_key::ToDeviceForwardedRoomKeyEventContent,
             room::encrypted::ToDeviceRoomEncryptedEventContent,
-            room_key_request::ToDeviceRoomKeyRequestEventContent,
+            room_key_request::{Action, RequestedKeyInfo, ToDeviceRoomKeyRequestEventContent},
             secret::request::{RequestAction, SecretName, ToDeviceSecretRequestEventContent},
             AnyToDeviceEvent, ToDeviceEvent,
         },
         room_id,
         to_device::DeviceIdOrAllDevices,
-        user_id, DeviceId, DeviceKeyAlgorithm, RoomId, UserId,
+        user_id, DeviceId, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, TransactionId,
+        UserId,
     };
 
-    use super::{GossipMachine, KeyForwardDecision};
+    use super::{
+        GossipMachine, KeyForwardDecision, KeyShareAuditEntry, KeyShareDecider, KeyShareRequest,
+    };
     use crate::{
         identities::{LocalTrust, ReadOnlyDevice},
         olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
@@ -1542,6 +1611,111 @@ mod test {
         assert!(!alice_machine.outgoing_requests.is_empty());
     }
 
+    #[async_test]
+    async fn key_share_decider() {
+        #[derive(Debug, Default)]
+        struct ForbidForwarding {
+            audit_log: StdMutex<Vec<(Box<DeviceId>, Result<Option<u32>, KeyForwardDecision>)>>,
+        }
+
+        impl KeyShareDecider for ForbidForwarding {
+            fn decide(
+                &self,
+                _: &KeyShareRequest<'_>,
+                _: Result<Option<u32>, KeyForwardDecision>,
+            ) -> Result<Option<u32>, KeyForwardDecision> {
+                Err(KeyForwardDecision::RejectedByPolicy("forwarding is forbidden".to_owned()))
+            }
+
+            fn audit(
+                &self,
+                entry: &KeyShareAuditEntry<'_>,
+                decision: &Result<Option<u32>, KeyForwardDecision>,
+            ) {
+                let device_id = entry.device_id.to_owned();
+                self.audit_log.lock().unwrap().push((device_id, decision.clone()));
+            }
+        }
+
+        let machine = get_machine().await;
+        let account = account();
+
+        let decider = Arc::new(ForbidForwarding::default());
+        machine.set_key_share_decider(decider.clone());
+
+        // Our own verified device would get the session by default.
+        let own_device =
+            machine.store.get_device(alice_id(), alice2_device_id()).await.unwrap().unwrap();
+        own_device.set_trust_state(LocalTrust::Verified);
+
+        let (_, inbound) =
+            account.create_group_session_pair_with_defaults(room_id()).await.unwrap();
+        machine.store.save_inbound_group_sessions(&[inbound.clone()]).await.unwrap();
+
+        let content = ToDeviceRoomKeyRequestEventContent::new(
+            Action::Request,
+            Some(RequestedKeyInfo::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                room_id().to_owned(),
+                inbound.sender_key().to_owned(),
+                inbound.session_id().to_owned(),
+            )),
+            alice2_device_id().to_owned(),
+            TransactionId::new(),
+        );
+        let event = ToDeviceEvent { sender: alice_id().to_owned(), content };
+
+        machine.receive_incoming_key_request(&event);
+        machine.collect_incoming_key_requests().await.unwrap();
+
+        {
+            let audit_log = decider.audit_log.lock().unwrap();
+            assert_eq!(audit_log.len(), 1);
+            assert_eq!(audit_log[0].0.as_ref(), alice2_device_id());
+            assert_matches!(audit_log[0].1, Err(KeyForwardDecision::RejectedByPolicy(_)));
+        }
+
+        // Requests that never reach the decider get audited as well.
+        let unknown_session = ToDeviceRoomKeyRequestEventContent::new(
+            Action::Request,
+            Some(RequestedKeyInfo::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                room_id().to_owned(),
+                inbound.sender_key().to_owned(),
+                "unknown_session_id".to_owned(),
+            )),
+            alice2_device_id().to_owned(),
+            TransactionId::new(),
+        );
+        let event = ToDeviceEvent { sender: alice_id().to_owned(), content: unknown_session };
+
+        machine.receive_incoming_key_request(&event);
+        machine.collect_incoming_key_requests().await.unwrap();
+
+        let unknown_device = ToDeviceRoomKeyRequestEventContent::new(
+            Action::Request,
+            Some(RequestedKeyInfo::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                room_id().to_owned(),
+                inbound.sender_key().to_owned(),
+                inbound.session_id().to_owned(),
+            )),
+            device_id!("UNKNOWNDEVICE").to_owned(),
+            TransactionId::new(),
+        );
+        let event = ToDeviceEvent { sender: alice_id().to_owned(), content: unknown_device };
+
+        machine.receive_incoming_key_request(&event);
+        machine.collect_incoming_key_requests().await.unwrap();
+
+        let audit_log = decider.audit_log.lock().unwrap();
+        assert_eq!(audit_log.len(), 3);
+        assert_eq!(audit_log[1].0.as_ref(), alice2_device_id());
+        assert_matches!(audit_log[1].1, Err(KeyForwardDecision::UnknownSession));
+        assert_eq!(audit_log[2].0.as_ref(), device_id!("UNKNOWNDEVICE"));
+        assert_matches!(audit_log[2].1, Err(KeyForwardDecision::UnknownDevice));
+    }
+
     #[async_test]
     async fn key_share_cycle_without_session() {
         let alice_machine = get_machine().await;
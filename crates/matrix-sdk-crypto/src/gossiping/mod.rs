@@ -14,7 +14,7 @@
 
 mod machine;
 
-use std::sync::Arc;
+use std::{fmt::Debug, sync::Arc};
 
 use dashmap::{DashMap, DashSet};
 pub(crate) use machine::GossipMachine;
@@ -38,6 +38,7 @@ use thiserror::Error;
 use tracing::error;
 
 use crate::{
+    olm::InboundGroupSession,
     requests::{OutgoingRequest, ToDeviceRequest},
     Device,
 };
@@ -60,8 +61,147 @@ pub enum KeyForwardDecision {
     /// accidentally or maliciously changed their curve25519 sender key.
     #[error("the device has changed their curve25519 sender key")]
     ChangedSenderKey,
+    /// The [`KeyShareDecider`] refused to share the session, the string
+    /// contains the reason it gave.
+    #[error("the key share policy refused to share the session: {0}")]
+    RejectedByPolicy(String),
+    /// The requested session is unknown to us, there's nothing we could share.
+    #[error("the requested session is unknown")]
+    UnknownSession,
+    /// The requesting device is unknown to us, its keys will be queried before
+    /// it can receive any sessions.
+    #[error("the requesting device is unknown")]
+    UnknownDevice,
 }
 
+/// A room key request of another device that needs to be decided by a
+/// [`KeyShareDecider`].
+#[derive(Debug, Clone, Copy)]
+pub struct KeyShareRequest<'a> {
+    /// The device that requested the room key.
+    pub device: &'a Device,
+    /// The requested session.
+    pub session: &'a InboundGroupSession,
+}
+
+/// A room key request that was handled, passed to [`KeyShareDecider::audit()`]
+/// together with the final decision.
+///
+/// Unlike a [`KeyShareRequest`], the request might not have reached the
+/// [`KeyShareDecider`], e.g. because the requesting device or the requested
+/// session is unknown.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyShareAuditEntry<'a> {
+    /// The user that requested the room key.
+    pub user_id: &'a UserId,
+    /// The ID of the device that requested the room key.
+    pub device_id: &'a DeviceId,
+    /// Information about the requested room key.
+    pub key_info: &'a RequestedKeyInfo,
+    /// The device that requested the room key, `None` if the device is
+    /// unknown.
+    pub device: Option<&'a Device>,
+    /// The requested session, `None` if the session is unknown.
+    pub session: Option<&'a InboundGroupSession>,
+}
+
+/// A policy deciding if room keys are forwarded to devices that request them.
+///
+/// The [`OlmMachine`] decides, for every incoming room key request, if the
+/// requested session may be shared with the requesting device. By default it
+/// shares the session with our own verified devices and, starting from the
+/// message index they originally received, with devices the session was
+/// shared with. A custom policy can be set using
+/// [`OlmMachine::set_key_share_decider()`] to override that decision.
+///
+/// # Examples
+///
+/// Only forward room keys to our own cross-signed devices and log every
+/// decision:
+///
+/// ```
+/// # use matrix_sdk_crypto::{
+/// #     KeyForwardDecision, KeyShareAuditEntry, KeyShareDecider, KeyShareRequest,
+/// # };
+/// #[derive(Debug)]
+/// struct CrossSignedOnly;
+///
+/// impl KeyShareDecider for CrossSignedOnly {
+///     fn decide(
+///         &self,
+///         request: &KeyShareRequest<'_>,
+///         default: Result<Option<u32>, KeyForwardDecision>,
+///     ) -> Result<Option<u32>, KeyForwardDecision> {
+///         if request.device.is_cross_signing_trusted() {
+///             default
+///         } else {
+///             Err(KeyForwardDecision::RejectedByPolicy("device isn't cross-signed".to_owned()))
+///         }
+///     }
+///
+///     fn audit(
+///         &self,
+///         entry: &KeyShareAuditEntry<'_>,
+///         decision: &Result<Option<u32>, KeyForwardDecision>,
+///     ) {
+///         println!(
+///             "Key request of {} {} for {}: {:?}",
+///             entry.user_id, entry.device_id, entry.key_info.session_id, decision,
+///         );
+///     }
+/// }
+/// ```
+///
+/// [`OlmMachine`]: crate::OlmMachine
+/// [`OlmMachine::set_key_share_decider()`]: crate::OlmMachine::set_key_share_decider
+pub trait KeyShareDecider: Debug + Send + Sync {
+    /// Decide if the requested session should be shared with the requesting
+    /// device.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The room key request that should be decided.
+    ///
+    /// * `default` - The decision of the default policy.
+    ///
+    /// # Return value
+    ///
+    /// - `Ok(None)`: Share the entire session, starting with the earliest known
+    ///   index.
+    /// - `Ok(Some(i))`: Share the session, but only starting from index i.
+    /// - `Err(x)`: *Refuse* to share the session, `x` is the reason for the
+    ///   refusal.
+    fn decide(
+        &self,
+        request: &KeyShareRequest<'_>,
+        default: Result<Option<u32>, KeyForwardDecision>,
+    ) -> Result<Option<u32>, KeyForwardDecision> {
+        let _ = request;
+        default
+    }
+
+    /// Get notified about the final decision of a room key request, e.g. to
+    /// keep an audit log of accepted and rejected requests.
+    ///
+    /// This is called for every room key request, including the ones that
+    /// never reached [`KeyShareDecider::decide()`] because the requesting
+    /// device or the requested session is unknown.
+    fn audit(
+        &self,
+        entry: &KeyShareAuditEntry<'_>,
+        decision: &Result<Option<u32>, KeyForwardDecision>,
+    ) {
+        let _ = (entry, decision);
+    }
+}
+
+/// The [`KeyShareDecider`] that is used if no custom one is set, it keeps the
+/// decision of the default policy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultKeyShareDecider;
+
+impl KeyShareDecider for DefaultKeyShareDecider {}
+
 /// A struct describing an outgoing key request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GossipRequest {
@@ -64,6 +64,13 @@ pub struct ReadOnlyDevice {
         deserialize_with = "local_trust_deserializer"
     )]
     trust_state: Arc<Atomic<LocalTrust>>,
+    /// Has a `m.no_olm` withheld notice been sent to this device.
+    #[serde(
+        default,
+        serialize_with = "atomic_bool_serializer",
+        deserialize_with = "atomic_bool_deserializer"
+    )]
+    withheld_code_sent: Arc<AtomicBool>,
 }
 
 impl std::fmt::Debug for ReadOnlyDevice {
@@ -75,6 +82,7 @@ impl std::fmt::Debug for ReadOnlyDevice {
             .field("keys", self.keys())
             .field("deleted", &self.deleted.load(Ordering::SeqCst))
             .field("trust_state", &self.trust_state)
+            .field("withheld_code_sent", &self.withheld_code_sent.load(Ordering::SeqCst))
             .finish()
     }
 }
@@ -380,6 +388,7 @@ impl ReadOnlyDevice {
             inner: device_keys.into(),
             trust_state: Arc::new(Atomic::new(trust_state)),
             deleted: Arc::new(AtomicBool::new(false)),
+            withheld_code_sent: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -448,6 +457,17 @@ impl ReadOnlyDevice {
         self.deleted.load(Ordering::Relaxed)
     }
 
+    /// Has a `m.no_olm` withheld notice been sent to this device, telling it
+    /// that we couldn't establish an Olm session with it.
+    pub(crate) fn was_withheld_code_sent(&self) -> bool {
+        self.withheld_code_sent.load(Ordering::Relaxed)
+    }
+
+    /// Remember that a `m.no_olm` withheld notice was sent to this device.
+    pub(crate) fn mark_withheld_code_as_sent(&self) {
+        self.withheld_code_sent.store(true, Ordering::Relaxed);
+    }
+
     pub(crate) fn verified(
         &self,
         own_identity: &Option<ReadOnlyOwnUserIdentity>,
@@ -591,6 +611,7 @@ impl TryFrom<&DeviceKeys> for ReadOnlyDevice {
         let device = Self {
             inner: device_keys.clone().into(),
             deleted: Arc::new(AtomicBool::new(false)),
+            withheld_code_sent: Arc::new(AtomicBool::new(false)),
             trust_state: Arc::new(Atomic::new(LocalTrust::Unset)),
         };
 
@@ -30,6 +30,7 @@ compile_error!("indexeddb_cryptostore only works for wasm32 target");
 
 #[cfg(feature = "backups_v1")]
 pub mod backups;
+pub mod dehydration;
 mod error;
 mod file_encryption;
 mod gossiping;
@@ -37,6 +38,7 @@ mod identities;
 mod machine;
 pub mod olm;
 mod requests;
+pub mod secret_storage;
 mod session_manager;
 pub mod store;
 mod utilities;
@@ -72,8 +74,12 @@ impl RoomKeyImportResult {
 
 pub use error::{MegolmError, OlmError, SignatureError};
 pub use file_encryption::{
-    decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, KeyExportError, MediaEncryptionInfo,
+    decrypt_key_export, encrypt_key_export, AsyncAttachmentDecryptor, AsyncAttachmentEncryptor,
+    AttachmentDecryptor, AttachmentEncryptor, DecryptorError, KeyExportError, MediaEncryptionInfo,
+};
+pub use gossiping::{
+    DefaultKeyShareDecider, KeyForwardDecision, KeyShareAuditEntry, KeyShareDecider,
+    KeyShareRequest,
 };
 pub use identities::{
     Device, LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
@@ -83,12 +89,14 @@ pub use machine::OlmMachine;
 #[cfg(feature = "qrcode")]
 pub use matrix_qrcode;
 pub(crate) use olm::ReadOnlyAccount;
-pub use olm::{CrossSigningStatus, EncryptionSettings};
+pub use olm::{CrossSigningStatus, EncryptionSettings, WithheldCode};
 pub use requests::{
     IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest, UploadSigningKeysRequest,
 };
 pub use store::{CrossSigningKeyExport, CryptoStoreError, SecretImportError};
-pub use verification::{AcceptSettings, CancelInfo, Emoji, Sas, Verification, VerificationRequest};
+pub use verification::{
+    AcceptSettings, CancelInfo, Emoji, Sas, Verification, VerificationChange, VerificationRequest,
+};
 #[cfg(feature = "qrcode")]
 pub use verification::{QrVerification, ScanError};
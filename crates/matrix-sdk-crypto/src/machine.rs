@@ -15,7 +15,7 @@
 #[cfg(feature = "sled_cryptostore")]
 use std::path::Path;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
+    collections::{BTreeMap, HashSet},
     mem,
     sync::Arc,
 };
@@ -57,13 +57,14 @@ use crate::backups::BackupMachine;
 #[cfg(feature = "sled_cryptostore")]
 use crate::store::sled::SledStore;
 use crate::{
+    dehydration::DehydratedDevices,
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    gossiping::GossipMachine,
+    gossiping::{GossipMachine, KeyShareDecider},
     identities::{user::UserIdentities, Device, IdentityManager, UserDevices},
     olm::{
         Account, CrossSigningStatus, EncryptionSettings, ExportedRoomKey, GroupSessionKey,
         IdentityKeys, InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, SessionType,
+        ReadOnlyAccount, RoomKeyWithheldContent, RoomKeyWithheldEvent, SessionType,
     },
     requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
     session_manager::{GroupSessionManager, SessionManager},
@@ -147,6 +148,22 @@ impl OlmMachine {
         )
     }
 
+    /// Create a new memory based `OlmMachine` that uses the given, already
+    /// existing, account.
+    pub(crate) fn new_with_account(account: ReadOnlyAccount) -> Self {
+        let user_id = account.user_id().to_owned();
+        let device_id = account.device_id().to_owned();
+        let store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+
+        OlmMachine::new_helper(
+            &user_id,
+            device_id,
+            store,
+            account,
+            PrivateCrossSigningIdentity::empty(user_id.clone()),
+        )
+    }
+
     fn new_helper(
         user_id: &UserId,
         device_id: Box<DeviceId>,
@@ -263,7 +280,32 @@ impl OlmMachine {
             }
         };
 
-        Ok(OlmMachine::new_helper(&user_id, device_id, store, account, identity))
+        let machine = OlmMachine::new_helper(&user_id, device_id, store, account, identity);
+
+        #[cfg(feature = "backups_v1")]
+        machine.resume_backup().await?;
+
+        Ok(machine)
+    }
+
+    /// Re-enable the backup of room keys if a backup key and version were
+    /// stored by a previous instance of the `OlmMachine`.
+    #[cfg(feature = "backups_v1")]
+    async fn resume_backup(&self) -> StoreResult<()> {
+        let backup_keys = self.store.load_backup_keys().await?;
+
+        if let (Some(recovery_key), Some(version)) =
+            (backup_keys.recovery_key, backup_keys.backup_version)
+        {
+            let backup_key = recovery_key.megolm_v1_public_key();
+            backup_key.set_version(version);
+
+            debug!(backup_key =? backup_key, "Restored the room key backup state");
+
+            self.backup_machine.enable_backup_v1(backup_key).await?;
+        }
+
+        Ok(())
     }
 
     /// Create a new machine with the default crypto store.
@@ -339,6 +381,11 @@ impl OlmMachine {
         requests.append(&mut self.verification_machine.outgoing_messages());
         requests.append(&mut self.key_request_machine.outgoing_to_device_requests().await?);
 
+        #[cfg(feature = "backups_v1")]
+        if let Some(request) = self.backup_machine.backup().await? {
+            requests.push(request);
+        }
+
         Ok(requests)
     }
 
@@ -380,7 +427,7 @@ impl OlmMachine {
             }
             IncomingResponse::KeysBackup(_) => {
                 #[cfg(feature = "backups_v1")]
-                self.backup_machine.mark_request_as_sent(*request_id).await?;
+                self.backup_machine.mark_request_as_sent(request_id).await?;
             }
         };
 
@@ -1000,6 +1047,39 @@ impl OlmMachine {
 
                     raw_event = decrypted.event;
                 }
+                e if e.event_type() == RoomKeyWithheldContent::EVENT_TYPE => {
+                    match raw_event.deserialize_as::<RoomKeyWithheldEvent>() {
+                        Ok(e) => {
+                            info!(
+                                sender = e.sender.as_str(),
+                                room_id = ?e.content.room_id,
+                                session_id = ?e.content.session_id,
+                                code = e.content.code.as_str(),
+                                "Received a room key withheld notice"
+                            );
+
+                            // Withheld notices aren't encrypted, only trust
+                            // them if the sender key belongs to one of the
+                            // devices of the sender.
+                            if self
+                                .store
+                                .get_device_from_curve_key(&e.sender, &e.content.sender_key)
+                                .await?
+                                .is_some()
+                            {
+                                changes.withheld_session_info.push(e);
+                            } else {
+                                warn!(
+                                    sender = e.sender.as_str(),
+                                    sender_key = e.content.sender_key.as_str(),
+                                    "Received a room key withheld notice with a sender key that \
+                                     doesn't belong to a known device of the sender, ignoring it"
+                                );
+                            }
+                        }
+                        Err(e) => warn!(error =? e, "Received an invalid room key withheld notice"),
+                    }
+                }
                 e => self.handle_to_device_event(&e).await,
             }
 
@@ -1139,7 +1219,14 @@ impl OlmMachine {
                 .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
                 .await?;
 
-            Err(MegolmError::MissingRoomKey)
+            let withheld_code = self
+                .store
+                .get_withheld_info(room_id, &content.session_id)
+                .await?
+                .filter(|i| i.content.sender_key == content.sender_key)
+                .map(|i| i.content.code);
+
+            Err(MegolmError::MissingRoomKey(withheld_code))
         }
     }
 
@@ -1160,13 +1247,13 @@ impl OlmMachine {
                 match self.decrypt_megolm_v1_event(room_id, event, c).await {
                     Ok(r) => Ok(r),
                     Err(e) => {
-                        if let MegolmError::MissingRoomKey = e {
-                            // TODO log the withheld reason if we have one.
+                        if let MegolmError::MissingRoomKey(withheld_code) = &e {
                             debug!(
                                 sender = event.sender.as_str(),
                                 room_id = room_id.as_str(),
                                 sender_key = c.sender_key.as_str(),
                                 session_id = c.session_id.as_str(),
+                                withheld_code = withheld_code.as_ref().map(|c| c.as_str()),
                                 "Failed to decrypt a room event, the room key is missing"
                             );
                         } else {
@@ -1318,85 +1405,10 @@ impl OlmMachine {
     pub async fn import_keys(
         &self,
         exported_keys: Vec<ExportedRoomKey>,
-        #[allow(unused_variables)] from_backup: bool,
+        from_backup: bool,
         progress_listener: impl Fn(usize, usize),
     ) -> StoreResult<RoomKeyImportResult> {
-        type SessionIdToIndexMap = BTreeMap<Arc<str>, u32>;
-
-        #[derive(Debug)]
-        struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, BTreeMap<Arc<str>, SessionIdToIndexMap>>,
-        }
-
-        impl ShallowSessions {
-            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
-                self.inner
-                    .get(&session.room_id)
-                    .and_then(|m| {
-                        m.get(&session.sender_key).and_then(|m| {
-                            m.get(&session.session_id)
-                                .map(|existing| existing <= &session.first_known_index())
-                        })
-                    })
-                    .unwrap_or(false)
-            }
-        }
-
-        let mut sessions = Vec::new();
-
-        let existing_sessions = ShallowSessions {
-            inner: self.store.get_inbound_group_sessions().await?.into_iter().fold(
-                BTreeMap::new(),
-                |mut acc, s| {
-                    let index = s.first_known_index();
-
-                    acc.entry(s.room_id)
-                        .or_default()
-                        .entry(s.sender_key)
-                        .or_default()
-                        .insert(s.session_id, index);
-
-                    acc
-                },
-            ),
-        };
-
-        let total_count = exported_keys.len();
-        let mut keys = BTreeMap::new();
-
-        for (i, key) in exported_keys.into_iter().enumerate() {
-            let session = InboundGroupSession::from_export(key)?;
-
-            // Only import the session if we didn't have this session or if it's
-            // a better version of the same session, that is the first known
-            // index is lower.
-            if !existing_sessions.has_better_session(&session) {
-                #[cfg(feature = "backups_v1")]
-                if from_backup {
-                    session.mark_as_backed_up()
-                }
-
-                keys.entry(session.room_id().to_owned())
-                    .or_insert_with(BTreeMap::new)
-                    .entry(session.sender_key().to_owned())
-                    .or_insert_with(BTreeSet::new)
-                    .insert(session.session_id().to_owned());
-
-                sessions.push(session)
-            }
-
-            progress_listener(i, total_count)
-        }
-
-        let imported_count = sessions.len();
-
-        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
-
-        self.store.save_changes(changes).await?;
-
-        info!(total_count, imported_count, room_keys =? keys, "Successfully imported room keys");
-
-        Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
+        self.store.import_room_keys(exported_keys, from_backup, progress_listener).await
     }
 
     /// Export the keys that match the given predicate.
@@ -1547,6 +1559,24 @@ impl OlmMachine {
     pub fn backup_machine(&self) -> &BackupMachine {
         &self.backup_machine
     }
+
+    /// Get an object that can be used to create and rehydrate dehydrated
+    /// devices.
+    ///
+    /// Dehydrated devices allow users to receive room keys while none of
+    /// their devices are online.
+    pub fn dehydrated_devices(&self) -> DehydratedDevices {
+        DehydratedDevices::new(self.clone())
+    }
+
+    /// Set the policy that decides if room keys are forwarded to devices that
+    /// request them.
+    ///
+    /// The decider gets the decision of the default policy and can override
+    /// it, see [`KeyShareDecider`] for details.
+    pub fn set_key_share_decider(&self, decider: Arc<dyn KeyShareDecider>) {
+        self.key_request_machine.set_key_share_decider(decider)
+    }
 }
 
 #[cfg(test)]
@@ -1559,7 +1589,10 @@ pub(crate) mod test {
     use matrix_sdk_test::{async_test, test_json};
     use ruma::{
         api::{
-            client::r0::keys::{claim_keys, get_keys, upload_keys},
+            client::r0::{
+                keys::{claim_keys, get_keys, upload_keys},
+                sync::sync_events::{DeviceLists, ToDevice},
+            },
             IncomingResponse,
         },
         device_id,
@@ -1583,9 +1616,9 @@ pub(crate) mod test {
 
     use crate::{
         machine::OlmMachine,
-        olm::Utility,
+        olm::{RoomKeyWithheldContent, Utility, WithheldCode},
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, LocalTrust, MegolmError, ReadOnlyDevice, ToDeviceRequest,
     };
 
     /// These keys need to be periodically uploaded to the server.
@@ -2056,6 +2089,105 @@ pub(crate) mod test {
         }
     }
 
+    #[async_test]
+    async fn test_withheld_room_key() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+        bob_device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        assert_eq!(to_device_requests[0].event_type.as_ref(), RoomKeyWithheldContent::EVENT_TYPE);
+
+        let content: RoomKeyWithheldContent = to_device_requests[0]
+            .messages
+            .values()
+            .next()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap()
+            .deserialize_as()
+            .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Blacklisted);
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": RoomKeyWithheldContent::EVENT_TYPE,
+            "content": content,
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(serde_json::from_value(event).unwrap());
+
+        bob.receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await
+            .unwrap();
+
+        let content = RoomMessageEventContent::text_plain("It is a secret to everybody");
+
+        let encrypted_content =
+            alice.encrypt(room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org").to_owned(),
+            origin_server_ts: milli_seconds_since_unix_epoch(),
+            sender: alice.user_id().to_owned(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let error = bob.decrypt_room_event(&event, room_id).await.unwrap_err();
+
+        assert!(matches!(error, MegolmError::MissingRoomKey(Some(WithheldCode::Blacklisted))));
+    }
+
+    #[async_test]
+    async fn test_withheld_notice_with_unknown_sender_key() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let session_id = alice
+            .group_session_manager
+            .get_outbound_group_session(room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": RoomKeyWithheldContent::EVENT_TYPE,
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "room_id": room_id,
+                "session_id": session_id,
+                "sender_key": "not a key of alice",
+                "code": "m.blacklisted",
+            },
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(serde_json::from_value(event).unwrap());
+
+        bob.receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await
+            .unwrap();
+
+        assert!(bob.store.get_withheld_info(room_id, &session_id).await.unwrap().is_none());
+    }
+
     #[async_test]
     #[cfg(feature = "sled_cryptostore")]
     async fn test_machine_with_default_store() {
@@ -2094,6 +2226,39 @@ pub(crate) mod test {
         assert_eq!(ed25519_key, machine.identity_keys().ed25519());
     }
 
+    #[async_test]
+    #[cfg(all(feature = "sled_cryptostore", feature = "backups_v1"))]
+    async fn backup_is_resumed_from_the_store() {
+        use tempfile::tempdir;
+
+        use crate::backups::RecoveryKey;
+
+        let tmpdir = tempdir().unwrap();
+
+        let machine =
+            OlmMachine::new_with_default_store(user_id(), alice_device_id(), tmpdir.as_ref(), None)
+                .await
+                .unwrap();
+
+        let recovery_key = RecoveryKey::new().unwrap();
+        machine
+            .backup_machine()
+            .save_recovery_key(Some(recovery_key), Some("1".to_owned()))
+            .await
+            .unwrap();
+
+        assert!(!machine.backup_machine().enabled().await);
+
+        drop(machine);
+
+        let machine =
+            OlmMachine::new_with_default_store(user_id(), alice_device_id(), tmpdir.as_ref(), None)
+                .await
+                .unwrap();
+
+        assert!(machine.backup_machine().enabled().await);
+    }
+
     #[async_test]
     async fn interactive_verification() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -232,8 +232,8 @@ impl InboundGroupSession {
         self.backed_up.store(false, SeqCst)
     }
 
-    #[cfg(test)]
-    #[allow(dead_code)]
+    /// Mark the session as backed up.
+    #[cfg(any(test, feature = "backups_v1"))]
     pub(crate) fn mark_as_backed_up(&self) {
         self.backed_up.store(true, SeqCst)
     }
@@ -25,11 +25,13 @@ use zeroize::Zeroize;
 
 mod inbound;
 mod outbound;
+mod withheld;
 
 pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
 pub use outbound::{
     EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo, ShareState,
 };
+pub use withheld::{RoomKeyWithheldContent, RoomKeyWithheldEvent, WithheldCode};
 
 /// The private session key of a group session.
 /// Can be used to create a new inbound group session.
@@ -95,6 +97,29 @@ pub struct BackedUpRoomKey {
     pub forwarding_curve25519_key_chain: Vec<String>,
 }
 
+impl ExportedRoomKey {
+    /// Create an `ExportedRoomKey` from a `BackedUpRoomKey`.
+    ///
+    /// The room id and the session id aren't part of the backed up room key,
+    /// the server-side key backup stores them as the keys of the maps that
+    /// contain the backed up room keys.
+    pub fn from_backed_up_room_key(
+        room_id: &RoomId,
+        session_id: &str,
+        room_key: BackedUpRoomKey,
+    ) -> Self {
+        Self {
+            algorithm: room_key.algorithm,
+            room_id: room_id.to_owned(),
+            sender_key: room_key.sender_key,
+            session_id: session_id.to_owned(),
+            session_key: room_key.session_key,
+            sender_claimed_keys: room_key.sender_claimed_keys,
+            forwarding_curve25519_key_chain: room_key.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
 impl TryInto<ToDeviceForwardedRoomKeyEventContent> for ExportedRoomKey {
     type Error = ();
 
@@ -53,7 +53,7 @@ use tracing::{debug, error, info};
 
 use super::{
     super::{deserialize_instant, serialize_instant},
-    GroupSessionKey,
+    GroupSessionKey, WithheldCode,
 };
 use crate::{Device, ToDeviceRequest};
 
@@ -80,6 +80,10 @@ pub struct EncryptionSettings {
     pub rotation_period_msgs: u64,
     /// The history visibility of the room when the session was created.
     pub history_visibility: HistoryVisibility,
+    /// Should the room key only be shared with verified devices. Unverified
+    /// devices will receive a `m.room_key.withheld` notice instead.
+    #[serde(default)]
+    pub only_allow_trusted_devices: bool,
 }
 
 impl Default for EncryptionSettings {
@@ -89,6 +93,7 @@ impl Default for EncryptionSettings {
             rotation_period: ROTATION_PERIOD,
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
+            only_allow_trusted_devices: false,
         }
     }
 }
@@ -107,6 +112,7 @@ impl EncryptionSettings {
             rotation_period,
             rotation_period_msgs,
             history_visibility,
+            only_allow_trusted_devices: false,
         }
     }
 }
@@ -132,6 +138,9 @@ pub struct OutboundGroupSession {
     pub(crate) shared_with_set: Arc<DashMap<Box<UserId>, DashMap<Box<DeviceId>, ShareInfo>>>,
     #[allow(clippy::type_complexity)]
     to_share_with_set: Arc<DashMap<Box<TransactionId>, (Arc<ToDeviceRequest>, ShareInfoSet)>>,
+    /// The set of user/device pairs that received a `m.room_key.withheld`
+    /// notice for this session.
+    pub(crate) withheld_set: Arc<DashMap<Box<UserId>, DashMap<Box<DeviceId>, WithheldCode>>>,
 }
 
 /// A a map of userid/device it to a `ShareInfo`.
@@ -187,6 +196,7 @@ impl OutboundGroupSession {
             settings: Arc::new(settings),
             shared_with_set: Arc::new(DashMap::new()),
             to_share_with_set: Arc::new(DashMap::new()),
+            withheld_set: Arc::new(DashMap::new()),
         }
     }
 
@@ -199,6 +209,24 @@ impl OutboundGroupSession {
         self.to_share_with_set.insert(request_id, (request, share_infos));
     }
 
+    /// Has a `m.room_key.withheld` notice for this session been sent to the
+    /// given user/device pair.
+    pub(crate) fn is_withheld_from(&self, device: &Device) -> bool {
+        self.withheld_set
+            .get(device.user_id())
+            .map(|d| d.contains_key(device.device_id()))
+            .unwrap_or(false)
+    }
+
+    /// Remember that a `m.room_key.withheld` notice with the given code is
+    /// sent to the given user/device pair, so it isn't sent out again.
+    pub(crate) fn mark_as_withheld_from(&self, device: &Device, code: WithheldCode) {
+        self.withheld_set
+            .entry(device.user_id().to_owned())
+            .or_insert_with(DashMap::new)
+            .insert(device.device_id().to_owned(), code);
+    }
+
     /// This should be called if an the user wishes to rotate this session.
     pub fn invalidate_session(&self) {
         self.invalidated.store(true, Ordering::Relaxed)
@@ -506,6 +534,13 @@ impl OutboundGroupSession {
                     .collect(),
             ),
             to_share_with_set: Arc::new(pickle.requests.into_iter().collect()),
+            withheld_set: Arc::new(
+                pickle
+                    .withheld_set
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into_iter().collect()))
+                    .collect(),
+            ),
         })
     }
 
@@ -544,6 +579,16 @@ impl OutboundGroupSession {
                 .iter()
                 .map(|r| (r.key().clone(), r.value().clone()))
                 .collect(),
+            withheld_set: self
+                .withheld_set
+                .iter()
+                .map(|u| {
+                    (
+                        u.key().clone(),
+                        u.value().iter().map(|d| (d.key().clone(), d.value().clone())).collect(),
+                    )
+                })
+                .collect(),
         }
     }
 }
@@ -594,6 +639,10 @@ pub struct PickledOutboundGroupSession {
     pub shared_with_set: BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, ShareInfo>>,
     /// Requests that need to be sent out to share the session.
     pub requests: BTreeMap<Box<TransactionId>, (Arc<ToDeviceRequest>, ShareInfoSet)>,
+    /// The set of users/devices that received a withheld notice for the
+    /// session.
+    #[serde(default)]
+    pub withheld_set: BTreeMap<Box<UserId>, BTreeMap<Box<DeviceId>, WithheldCode>>,
 }
 
 #[cfg(test)]
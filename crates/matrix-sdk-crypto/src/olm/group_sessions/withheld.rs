@@ -0,0 +1,243 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, fmt, iter};
+
+use ruma::{
+    events::{AnyToDeviceEventContent, EventType},
+    serde::Raw,
+    to_device::DeviceIdOrAllDevices,
+    EventEncryptionAlgorithm, RoomId, TransactionId, UserId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Device, ToDeviceRequest};
+
+/// The reason why a room key was withheld from a device.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum WithheldCode {
+    /// The device was blacklisted by the sender.
+    Blacklisted,
+    /// The device isn't verified and the sender only shares room keys with
+    /// verified devices.
+    Unverified,
+    /// The device isn't allowed to receive the room key, e.g. because the
+    /// owner of the device wasn't in the room when the key was created.
+    Unauthorised,
+    /// The sender doesn't have the requested room key.
+    Unavailable,
+    /// No Olm session could be established with the device.
+    NoOlm,
+    /// A code that isn't defined by the spec.
+    Custom(String),
+}
+
+impl WithheldCode {
+    /// Get the string representation of the code, as it is sent over the wire.
+    pub fn as_str(&self) -> &str {
+        match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+            WithheldCode::Custom(c) => c,
+        }
+    }
+
+    /// Get a human readable reason for the code, used as the `reason` field of
+    /// the withheld notice.
+    fn reason(&self) -> Option<&'static str> {
+        match self {
+            WithheldCode::Blacklisted => Some("The sender has blocked you."),
+            WithheldCode::Unverified => {
+                Some("The sender has disabled encrypting to unverified devices.")
+            }
+            WithheldCode::Unauthorised => Some("You are not authorised to read the message."),
+            WithheldCode::Unavailable => Some("The requested key was not found."),
+            WithheldCode::NoOlm => Some("Unable to establish a secure channel."),
+            WithheldCode::Custom(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for WithheldCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "m.blacklisted" => WithheldCode::Blacklisted,
+            "m.unverified" => WithheldCode::Unverified,
+            "m.unauthorised" => WithheldCode::Unauthorised,
+            "m.unavailable" => WithheldCode::Unavailable,
+            "m.no_olm" => WithheldCode::NoOlm,
+            _ => WithheldCode::Custom(code),
+        }
+    }
+}
+
+impl From<WithheldCode> for String {
+    fn from(code: WithheldCode) -> Self {
+        match code {
+            WithheldCode::Custom(c) => c,
+            c => c.as_str().to_owned(),
+        }
+    }
+}
+
+/// The content of a `m.room_key.withheld` to-device event.
+///
+/// This event is sent to devices that won't receive a room key, so they can
+/// tell their users why a message can't be decrypted.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RoomKeyWithheldContent {
+    /// The encryption algorithm of the withheld room key.
+    pub algorithm: EventEncryptionAlgorithm,
+    /// The room the withheld room key is used in, may be missing if the code
+    /// is `m.no_olm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<Box<RoomId>>,
+    /// The ID of the withheld room key, may be missing if the code is
+    /// `m.no_olm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// The Curve25519 key of the device that withheld the room key.
+    pub sender_key: String,
+    /// The reason why the room key was withheld.
+    pub code: WithheldCode,
+    /// A human readable version of the code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl RoomKeyWithheldContent {
+    /// The event type of the withheld notice.
+    pub const EVENT_TYPE: &'static str = "m.room_key.withheld";
+
+    pub(crate) fn new(
+        algorithm: EventEncryptionAlgorithm,
+        room_id: &RoomId,
+        session_id: &str,
+        sender_key: &str,
+        code: WithheldCode,
+    ) -> Self {
+        Self {
+            algorithm,
+            room_id: Some(room_id.to_owned()),
+            session_id: Some(session_id.to_owned()),
+            sender_key: sender_key.to_owned(),
+            reason: code.reason().map(ToOwned::to_owned),
+            code,
+        }
+    }
+
+    /// Create a `m.no_olm` withheld notice.
+    ///
+    /// The notice tells the receiving device that we couldn't establish an
+    /// Olm session with it, it isn't tied to a specific room key.
+    pub(crate) fn no_olm(algorithm: EventEncryptionAlgorithm, sender_key: &str) -> Self {
+        let code = WithheldCode::NoOlm;
+
+        Self {
+            algorithm,
+            room_id: None,
+            session_id: None,
+            sender_key: sender_key.to_owned(),
+            reason: code.reason().map(ToOwned::to_owned),
+            code,
+        }
+    }
+
+    /// Create a to-device request that sends this withheld notice to the given
+    /// devices.
+    pub(crate) fn to_request<'a>(
+        &self,
+        devices: impl IntoIterator<Item = &'a Device>,
+    ) -> ToDeviceRequest {
+        let content: Raw<AnyToDeviceEventContent> = Raw::from_json(
+            serde_json::value::to_raw_value(self).expect("Can't serialize a withheld notice"),
+        );
+
+        let mut messages: BTreeMap<Box<UserId>, BTreeMap<DeviceIdOrAllDevices, _>> =
+            BTreeMap::new();
+
+        for device in devices {
+            messages.entry(device.user_id().to_owned()).or_default().insert(
+                DeviceIdOrAllDevices::DeviceId(device.device_id().to_owned()),
+                content.clone(),
+            );
+        }
+
+        ToDeviceRequest {
+            event_type: EventType::from(Self::EVENT_TYPE),
+            txn_id: TransactionId::new(),
+            messages,
+        }
+    }
+
+    /// Create a to-device request that sends this withheld notice to a single
+    /// device.
+    pub(crate) fn to_device_request(&self, device: &Device) -> ToDeviceRequest {
+        self.to_request(iter::once(device))
+    }
+}
+
+/// A `m.room_key.withheld` to-device event that we received.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RoomKeyWithheldEvent {
+    /// The user that sent the withheld notice.
+    pub sender: Box<UserId>,
+    /// The content of the withheld notice.
+    pub content: RoomKeyWithheldContent,
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{room_id, EventEncryptionAlgorithm};
+    use serde_json::json;
+
+    use super::{RoomKeyWithheldContent, WithheldCode};
+
+    #[test]
+    fn withheld_content_serialization() {
+        let content = RoomKeyWithheldContent::new(
+            EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id!("!test:localhost"),
+            "session_id",
+            "sender_key",
+            WithheldCode::Blacklisted,
+        );
+
+        let json = serde_json::to_value(&content).unwrap();
+
+        assert_eq!(json["code"], "m.blacklisted");
+        assert_eq!(json["room_id"], "!test:localhost");
+
+        let content: RoomKeyWithheldContent = serde_json::from_value(json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "sender_key": "sender_key",
+            "code": "org.example.custom",
+        }))
+        .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Custom("org.example.custom".to_owned()));
+        assert!(content.room_id.is_none());
+        assert!(content.session_id.is_none());
+    }
+}
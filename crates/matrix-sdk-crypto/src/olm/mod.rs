@@ -26,8 +26,10 @@ mod utility;
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
 pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
 pub use group_sessions::{
-    EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession,
+    InboundGroupSessionPickle, OutboundGroupSession, PickledInboundGroupSession,
+    PickledOutboundGroupSession, RoomKeyWithheldContent, RoomKeyWithheldEvent, ShareInfo,
+    WithheldCode,
 };
 pub(crate) use group_sessions::{GroupSessionKey, ShareState};
 use matrix_sdk_common::instant::{Duration, Instant};
@@ -0,0 +1,683 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the [secret storage] mechanism of Matrix.
+//!
+//! Secret storage allows secrets, like the private parts of the cross signing
+//! keys or the backup recovery key, to be stored in the global account data of
+//! the user. The secrets are encrypted using a secret storage key, which in
+//! turn is either presented to the user as a base58 encoded string or derived
+//! from a passphrase.
+//!
+//! Only the `m.secret_storage.v1.aes-hmac-sha2` algorithm is supported.
+//!
+//! [secret storage]: https://spec.matrix.org/unstable/client-server-api/#storage
+
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
+    Aes256, Aes256Ctr,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Sha256, Sha512};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::utilities::{decode, encode, DecodeError as Base64DecodeError};
+
+/// The event type of the global account data event that contains the ID of
+/// the default secret storage key.
+pub const DEFAULT_KEY_EVENT_TYPE: &str = "m.secret_storage.default_key";
+
+/// The prefix of the event type of the global account data event that
+/// contains the description of a secret storage key.
+pub const KEY_EVENT_TYPE_PREFIX: &str = "m.secret_storage.key.";
+
+/// The name of the only secret storage algorithm we support.
+pub const AES_HMAC_SHA2_ALGORITHM: &str = "m.secret_storage.v1.aes-hmac-sha2";
+
+/// The name of the only passphrase key derivation algorithm we support.
+pub const PBKDF2_ALGORITHM: &str = "m.pbkdf2";
+
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const KEY_ID_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 500_000;
+const PREFIX: [u8; 2] = [0x8b, 0x01];
+
+/// Error type for the secret storage.
+#[derive(Debug, Error)]
+pub enum SecretStorageError {
+    /// The secret storage key uses an algorithm we don't support.
+    #[error("The secret storage key uses an unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    /// The secret storage key wasn't derived from a passphrase.
+    #[error("The secret storage key wasn't derived from a passphrase")]
+    MissingPassphrase,
+    /// The given key doesn't match the secret storage key description, or the
+    /// MAC of an encrypted secret is invalid.
+    #[error("The MAC check failed, the key is wrong or the data has been tampered with")]
+    InvalidMac,
+    /// No default secret storage key has been set up.
+    #[error("No default secret storage key has been set up")]
+    MissingDefaultKey,
+    /// The description of the secret storage key couldn't be found.
+    #[error("The description of the secret storage key {0} couldn't be found")]
+    MissingKeyDescription(String),
+    /// The secret isn't encrypted using the given secret storage key.
+    #[error("The secret isn't encrypted with the secret storage key {0}")]
+    MissingKey(String),
+    /// The decoded secret storage key has an invalid prefix.
+    #[error("The decoded secret storage key has an invalid prefix: expected {0:?}, got {1:?}")]
+    Prefix([u8; 2], [u8; 2]),
+    /// The parity byte of the secret storage key didn't match.
+    #[error("The parity byte of the secret storage key doesn't match: expected {0:?}, got {1:?}")]
+    Parity(u8, u8),
+    /// The secret storage key isn't valid base58.
+    #[error(transparent)]
+    Base58(#[from] bs58::decode::Error),
+    /// Some part of the encrypted data isn't valid base64.
+    #[error(transparent)]
+    Base64(#[from] Base64DecodeError),
+    /// The secret storage key is too short, we couldn't read enough data.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The decrypted secret isn't valid UTF-8.
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    /// The content of an account data event couldn't be deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// The content of the `m.secret_storage.default_key` account data event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DefaultKeyContent {
+    /// The ID of the default secret storage key.
+    pub key: String,
+}
+
+/// Information on how a secret storage key was derived from a passphrase.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PassphraseInfo {
+    /// The key derivation algorithm, only `m.pbkdf2` is supported.
+    pub algorithm: String,
+    /// The salt that was used for the key derivation.
+    pub salt: String,
+    /// The number of PBKDF2 iterations.
+    pub iterations: u32,
+    /// The number of bits that should be derived, defaults to 256.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u32>,
+}
+
+/// The content of the `m.secret_storage.key.[key_id]` account data event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SecretStorageKeyDescription {
+    /// The encryption algorithm the key uses.
+    pub algorithm: String,
+    /// The human readable name of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Information on how to derive the key from a passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseInfo>,
+    /// The IV that was used to create the MAC that checks the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iv: Option<String>,
+    /// The MAC that allows us to check if a key matches this description.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(flatten)]
+    other: BTreeMap<String, Value>,
+}
+
+/// A secret, encrypted with the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AesHmacSha2EncryptedData {
+    /// The 16-byte initialization vector, encoded as base64.
+    pub iv: String,
+    /// The AES-CTR encrypted data, encoded as base64.
+    pub ciphertext: String,
+    /// The MAC of the ciphertext, encoded as base64.
+    pub mac: String,
+}
+
+/// The content of an account data event that holds an encrypted secret.
+///
+/// The event type is the name of the secret, e.g. `m.cross_signing.master`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EncryptedSecretContent {
+    /// Map from the ID of a secret storage key to the secret encrypted with
+    /// that key.
+    pub encrypted: BTreeMap<String, Value>,
+}
+
+impl EncryptedSecretContent {
+    /// Get the secret encrypted with the secret storage key with the given ID.
+    pub fn get(&self, key_id: &str) -> Result<AesHmacSha2EncryptedData, SecretStorageError> {
+        let data = self
+            .encrypted
+            .get(key_id)
+            .ok_or_else(|| SecretStorageError::MissingKey(key_id.to_owned()))?;
+
+        Ok(serde_json::from_value(data.clone())?)
+    }
+
+    /// Add, or replace, the secret encrypted with the secret storage key with
+    /// the given ID.
+    pub fn insert(&mut self, key_id: &str, data: AesHmacSha2EncryptedData) {
+        self.encrypted.insert(
+            key_id.to_owned(),
+            serde_json::to_value(data).expect("Can't serialize the encrypted secret"),
+        );
+    }
+}
+
+/// A secret storage key, used to encrypt and decrypt secrets that are stored
+/// in the global account data.
+pub struct SecretStorageKey {
+    key_id: String,
+    description: SecretStorageKeyDescription,
+    key: Box<[u8; KEY_SIZE]>,
+}
+
+impl Drop for SecretStorageKey {
+    fn drop(&mut self) {
+        self.key.zeroize()
+    }
+}
+
+impl std::fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStorageKey")
+            .field("key_id", &self.key_id)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+impl SecretStorageKey {
+    /// Create a new random secret storage key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new() -> Self {
+        let mut key = Box::new([0u8; KEY_SIZE]);
+        getrandom(&mut key[..]).expect("Can't generate randomness");
+
+        Self::from_parts(key, None)
+    }
+
+    /// Create a new secret storage key that is derived from the given
+    /// passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new_from_passphrase(passphrase: &str) -> Self {
+        Self::new_from_passphrase_helper(passphrase, PBKDF2_ITERATIONS)
+    }
+
+    fn new_from_passphrase_helper(passphrase: &str, iterations: u32) -> Self {
+        let salt: String =
+            thread_rng().sample_iter(Alphanumeric).take(SALT_LENGTH).map(char::from).collect();
+
+        let passphrase_info = PassphraseInfo {
+            algorithm: PBKDF2_ALGORITHM.to_owned(),
+            salt,
+            iterations,
+            bits: Some((KEY_SIZE * 8) as u32),
+        };
+
+        let key = Self::derive_key(passphrase, &passphrase_info);
+
+        Self::from_parts(key, Some(passphrase_info))
+    }
+
+    fn from_parts(key: Box<[u8; KEY_SIZE]>, passphrase: Option<PassphraseInfo>) -> Self {
+        let key_id: String =
+            thread_rng().sample_iter(Alphanumeric).take(KEY_ID_LENGTH).map(char::from).collect();
+
+        let mut key = Self {
+            key_id,
+            description: SecretStorageKeyDescription {
+                algorithm: AES_HMAC_SHA2_ALGORITHM.to_owned(),
+                name: None,
+                passphrase,
+                iv: None,
+                mac: None,
+                other: BTreeMap::new(),
+            },
+            key,
+        };
+
+        let check = key.encrypt_bytes("", &mut [0u8; KEY_SIZE]);
+        key.description.iv = Some(check.iv);
+        key.description.mac = Some(check.mac);
+
+        key
+    }
+
+    fn derive_key(passphrase: &str, info: &PassphraseInfo) -> Box<[u8; KEY_SIZE]> {
+        let mut key = Box::new([0u8; KEY_SIZE]);
+
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            &mut key[..],
+        );
+
+        key
+    }
+
+    /// Restore a secret storage key from its base58 encoded form.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The ID of the secret storage key.
+    ///
+    /// * `description` - The description of the key, found in the
+    /// `m.secret_storage.key.[key_id]` account data event.
+    ///
+    /// * `recovery_key` - The base58 encoded key, as it was presented to the
+    /// user.
+    pub fn from_recovery_key(
+        key_id: &str,
+        description: SecretStorageKeyDescription,
+        recovery_key: &str,
+    ) -> Result<Self, SecretStorageError> {
+        // Remove any whitespace we might have
+        let value: String = recovery_key.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let decoded =
+            Zeroizing::new(bs58::decode(value).with_alphabet(bs58::Alphabet::BITCOIN).into_vec()?);
+        let mut decoded = Cursor::new(decoded.as_slice());
+
+        let mut prefix = [0u8; 2];
+        let mut key = Box::new([0u8; KEY_SIZE]);
+        let mut expected_parity = [0u8; 1];
+
+        decoded.read_exact(&mut prefix)?;
+        decoded.read_exact(&mut key[..])?;
+        decoded.read_exact(&mut expected_parity)?;
+
+        let expected_parity = expected_parity[0];
+        let parity = parity_byte(&key[..]);
+
+        if prefix != PREFIX {
+            Err(SecretStorageError::Prefix(PREFIX, prefix))
+        } else if expected_parity != parity {
+            Err(SecretStorageError::Parity(expected_parity, parity))
+        } else {
+            Self::from_description(key_id, description, key)
+        }
+    }
+
+    /// Restore a secret storage key by deriving it from a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The ID of the secret storage key.
+    ///
+    /// * `description` - The description of the key, found in the
+    /// `m.secret_storage.key.[key_id]` account data event.
+    ///
+    /// * `passphrase` - The passphrase the key was derived from.
+    pub fn from_passphrase(
+        key_id: &str,
+        description: SecretStorageKeyDescription,
+        passphrase: &str,
+    ) -> Result<Self, SecretStorageError> {
+        let info = description.passphrase.as_ref().ok_or(SecretStorageError::MissingPassphrase)?;
+
+        if info.algorithm != PBKDF2_ALGORITHM {
+            return Err(SecretStorageError::UnsupportedAlgorithm(info.algorithm.to_owned()));
+        }
+
+        let key = Self::derive_key(passphrase, info);
+
+        Self::from_description(key_id, description, key)
+    }
+
+    fn from_description(
+        key_id: &str,
+        description: SecretStorageKeyDescription,
+        key: Box<[u8; KEY_SIZE]>,
+    ) -> Result<Self, SecretStorageError> {
+        if description.algorithm != AES_HMAC_SHA2_ALGORITHM {
+            return Err(SecretStorageError::UnsupportedAlgorithm(description.algorithm));
+        }
+
+        let key = Self { key_id: key_id.to_owned(), description, key };
+
+        // Keys that were created before the key check was introduced don't
+        // have an IV and MAC, we can't check those.
+        if let (Some(iv), Some(mac)) = (&key.description.iv, &key.description.mac) {
+            key.check_key(iv, mac)?;
+        }
+
+        Ok(key)
+    }
+
+    /// Check that this key matches the IV and MAC of its description.
+    ///
+    /// The MAC is calculated over the ciphertext of 32 zero bytes, encrypted
+    /// with the given IV and the keys that are derived using an empty name.
+    fn check_key(&self, iv: &str, mac: &str) -> Result<(), SecretStorageError> {
+        let iv = decode_padded(iv)?;
+        let mac = decode_padded(mac)?;
+
+        if iv.len() != IV_SIZE {
+            return Err(SecretStorageError::InvalidMac);
+        }
+
+        let keys = self.expand_keys("");
+        let (aes_key, hmac_key) = keys.split_at(KEY_SIZE);
+
+        let mut ciphertext = [0u8; KEY_SIZE];
+        let aes = Aes256::new(GenericArray::from_slice(aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(&mut ciphertext);
+
+        // `verify_slice()` compares the MACs in constant time.
+        let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create HMAC object");
+        hmac.update(&ciphertext);
+        hmac.verify_slice(&mac).map_err(|_| SecretStorageError::InvalidMac)
+    }
+
+    /// Get the ID of the secret storage key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Get the description of the key, this should be uploaded as the
+    /// `m.secret_storage.key.[key_id]` account data event.
+    pub fn description(&self) -> &SecretStorageKeyDescription {
+        &self.description
+    }
+
+    /// Get the event type of the account data event that holds the description
+    /// of this key.
+    pub fn event_type(&self) -> String {
+        format!("{}{}", KEY_EVENT_TYPE_PREFIX, self.key_id)
+    }
+
+    /// Export the key as a base58 encoded string.
+    ///
+    /// This is the form that should be presented to the user, it can be used
+    /// to restore the key using the [`SecretStorageKey::from_recovery_key()`]
+    /// method.
+    pub fn to_base58(&self) -> String {
+        let bytes = Zeroizing::new(
+            [PREFIX.as_ref(), &self.key[..], [parity_byte(&self.key[..])].as_ref()].concat(),
+        );
+
+        bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string()
+    }
+
+    /// Encrypt the given secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, i.e. the event type of the
+    /// account data event the secret will be stored in.
+    ///
+    /// * `secret` - The secret that should be encrypted.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn encrypt(&self, secret_name: &str, secret: &str) -> AesHmacSha2EncryptedData {
+        let mut plaintext = Zeroizing::new(secret.as_bytes().to_vec());
+        self.encrypt_bytes(secret_name, &mut plaintext)
+    }
+
+    /// Decrypt the given secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, i.e. the event type of the
+    /// account data event the secret was stored in.
+    ///
+    /// * `data` - The encrypted secret.
+    pub fn decrypt(
+        &self,
+        secret_name: &str,
+        data: &AesHmacSha2EncryptedData,
+    ) -> Result<Zeroizing<String>, SecretStorageError> {
+        let plaintext = self.decrypt_bytes(secret_name, data)?;
+        Ok(Zeroizing::new(String::from_utf8(plaintext)?))
+    }
+
+    fn expand_keys(&self, secret_name: &str) -> Zeroizing<[u8; KEY_SIZE * 2]> {
+        let mut keys = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE][..]), &self.key[..]);
+        hkdf.expand(secret_name.as_bytes(), &mut keys[..])
+            .expect("Can't expand the secret storage key");
+
+        keys
+    }
+
+    /// Derive a key for a purpose other than secret storage, the `info`
+    /// string makes sure that different purposes get independent keys.
+    pub(crate) fn derive_subkey(&self, info: &str) -> Zeroizing<[u8; KEY_SIZE]> {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE][..]), &self.key[..]);
+        hkdf.expand(info.as_bytes(), &mut key[..]).expect("Can't derive a subkey");
+
+        key
+    }
+
+    fn encrypt_bytes(&self, secret_name: &str, plaintext: &mut [u8]) -> AesHmacSha2EncryptedData {
+        let mut iv = [0u8; IV_SIZE];
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        let mut iv = u128::from_be_bytes(iv);
+        iv &= !(1 << 63);
+        let iv = iv.to_be_bytes();
+
+        let keys = self.expand_keys(secret_name);
+        let (aes_key, hmac_key) = keys.split_at(KEY_SIZE);
+
+        let aes = Aes256::new(GenericArray::from_slice(aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(plaintext);
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create HMAC object");
+        hmac.update(plaintext);
+        let mac = hmac.finalize().into_bytes();
+
+        AesHmacSha2EncryptedData { iv: encode(iv), ciphertext: encode(plaintext), mac: encode(mac) }
+    }
+
+    fn decrypt_bytes(
+        &self,
+        secret_name: &str,
+        data: &AesHmacSha2EncryptedData,
+    ) -> Result<Vec<u8>, SecretStorageError> {
+        let iv = decode_padded(&data.iv)?;
+        let mac = decode_padded(&data.mac)?;
+        let mut ciphertext = decode_padded(&data.ciphertext)?;
+
+        if iv.len() != IV_SIZE {
+            return Err(SecretStorageError::InvalidMac);
+        }
+
+        let keys = self.expand_keys(secret_name);
+        let (aes_key, hmac_key) = keys.split_at(KEY_SIZE);
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create HMAC object");
+        hmac.update(&ciphertext);
+        hmac.verify_slice(&mac).map_err(|_| SecretStorageError::InvalidMac)?;
+
+        let aes = Aes256::new(GenericArray::from_slice(aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(&mut ciphertext);
+
+        Ok(ciphertext)
+    }
+}
+
+impl Default for SecretStorageKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode base64 that might be padded, other clients, e.g. Element, pad the
+/// values they upload.
+fn decode_padded(input: &str) -> Result<Vec<u8>, Base64DecodeError> {
+    decode(input.trim_end_matches('='))
+}
+
+fn parity_byte(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(PREFIX[0] ^ PREFIX[1], |acc, x| acc ^ x)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{
+        AesHmacSha2EncryptedData, EncryptedSecretContent, SecretStorageError, SecretStorageKey,
+    };
+
+    const SECRET_NAME: &str = "m.cross_signing.master";
+
+    #[test]
+    fn encryption_roundtrip() {
+        let key = SecretStorageKey::new();
+        let secret = "It's a secret to everybody";
+
+        let encrypted = key.encrypt(SECRET_NAME, secret);
+        let decrypted = key.decrypt(SECRET_NAME, &encrypted).unwrap();
+
+        assert_eq!(secret, decrypted.as_str());
+        assert!(matches!(
+            key.decrypt("m.cross_signing.self_signing", &encrypted),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn recovery_key_restoring() {
+        let key = SecretStorageKey::new();
+        let encrypted = key.encrypt(SECRET_NAME, "secret");
+
+        let restored = SecretStorageKey::from_recovery_key(
+            key.key_id(),
+            key.description().clone(),
+            &key.to_base58(),
+        )
+        .unwrap();
+
+        assert_eq!(restored.key_id(), key.key_id());
+        assert_eq!(restored.decrypt(SECRET_NAME, &encrypted).unwrap().as_str(), "secret");
+
+        let other_key = SecretStorageKey::new();
+
+        assert!(matches!(
+            SecretStorageKey::from_recovery_key(
+                key.key_id(),
+                key.description().clone(),
+                &other_key.to_base58(),
+            ),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn passphrase_restoring() {
+        // Use a small number of iterations, the tests would be slow otherwise.
+        let key = SecretStorageKey::new_from_passphrase_helper("It's a secret to everybody", 10);
+
+        let mut content = EncryptedSecretContent::default();
+        content.insert(key.key_id(), key.encrypt(SECRET_NAME, "secret"));
+
+        let restored = SecretStorageKey::from_passphrase(
+            key.key_id(),
+            key.description().clone(),
+            "It's a secret to everybody",
+        )
+        .unwrap();
+
+        let encrypted = content.get(restored.key_id()).unwrap();
+        assert_eq!(restored.decrypt(SECRET_NAME, &encrypted).unwrap().as_str(), "secret");
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(
+                key.key_id(),
+                key.description().clone(),
+                "Wrong passphrase"
+            ),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn restoring_a_key_of_another_client() {
+        // Created like Element creates keys, the values are padded base64.
+        let description = serde_json::from_value(json!({
+            "algorithm": "m.secret_storage.v1.aes-hmac-sha2",
+            "iv": "CrMGgjA1Zhs426IcwlNSMQ==",
+            "mac": "GXX0H/EZsvCjYpv+hlhPIZEbJ7ry91E28dVh0CEilX4=",
+        }))
+        .unwrap();
+        let recovery_key = "EsTG XfWX jZfZ zhvD yios njGb Wqr6 BiUH VV1E 1gMZ ynRD JJnG";
+
+        let key = SecretStorageKey::from_recovery_key("key_id", description, recovery_key).unwrap();
+
+        let encrypted = AesHmacSha2EncryptedData {
+            iv: "wThnW9N8fkkxgmSr82N5Qg==".to_owned(),
+            ciphertext: "kDGPLg0sss7HflnCvg191b1SMej4tCQ1jZI=".to_owned(),
+            mac: "Iy16A1B0KCdYo1K+nAWSF/99Zrp08BBnHBg1QaJBcnA=".to_owned(),
+        };
+
+        assert_eq!(
+            key.decrypt(SECRET_NAME, &encrypted).unwrap().as_str(),
+            "It's a secret to everybody"
+        );
+
+        let description = serde_json::from_value(json!({
+            "algorithm": "m.secret_storage.v1.aes-hmac-sha2",
+            "iv": "7UNEc+0sSy875X+xHSam3w==",
+            "mac": "jNt16HaUHJK3UE5e48WI/pFc2Bi0R0F3O0HC22hRCNk=",
+            "passphrase": {
+                "algorithm": "m.pbkdf2",
+                "salt": "MmMRBBsYDeOLkEqdCPJlmQcNsV4Wv1Qz",
+                "iterations": 1000,
+            },
+        }))
+        .unwrap();
+
+        SecretStorageKey::from_passphrase("key_id", description, "It's a secret to everybody")
+            .unwrap();
+    }
+}
@@ -22,10 +22,7 @@ use dashmap::DashMap;
 use futures_util::future::join_all;
 use matrix_sdk_common::executor::spawn;
 use ruma::{
-    events::{
-        room::{encrypted::RoomEncryptedEventContent, history_visibility::HistoryVisibility},
-        AnyToDeviceEventContent, EventType,
-    },
+    events::{room::encrypted::RoomEncryptedEventContent, AnyToDeviceEventContent, EventType},
     serde::Raw,
     to_device::DeviceIdOrAllDevices,
     DeviceId, RoomId, TransactionId, UserId,
@@ -35,7 +32,10 @@ use tracing::{debug, info, trace};
 
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
-    olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareInfo, ShareState},
+    olm::{
+        Account, InboundGroupSession, OutboundGroupSession, RoomKeyWithheldContent, Session,
+        ShareInfo, ShareState, WithheldCode,
+    },
     store::{Changes, Result as StoreResult, Store},
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
@@ -105,6 +105,17 @@ impl GroupSessionCache {
     }
 }
 
+/// The result of calculating the recipients of a room key.
+#[derive(Debug)]
+pub(crate) struct CollectRecipientsResult {
+    /// Does the outbound session need to be rotated.
+    pub should_rotate: bool,
+    /// The devices that should receive the room key, grouped by user.
+    pub devices: HashMap<Box<UserId>, Vec<Device>>,
+    /// The devices that won't receive the room key and the reason why.
+    pub withheld_devices: Vec<(Device, WithheldCode)>,
+}
+
 #[derive(Debug, Clone)]
 pub struct GroupSessionManager {
     account: Account,
@@ -267,7 +278,8 @@ impl GroupSessionManager {
 
                     Some(session)
                 }
-                // TODO we'll want to create m.room_key.withheld here.
+                // The caller will send out a `m.room_key.withheld` notice to
+                // devices we couldn't encrypt the room key for.
                 Err(OlmError::MissingSession)
                 | Err(OlmError::EventError(EventError::MissingSenderKey)) => None,
                 Err(e) => return Err(e),
@@ -317,16 +329,19 @@ impl GroupSessionManager {
     /// Given a list of user and an outbound session, return the list of users
     /// and their devices that this session should be shared with.
     ///
-    /// Returns a boolean indicating whether the session needs to be rotated and
-    /// the list of users/devices that should receive the session.
+    /// Returns a boolean indicating whether the session needs to be rotated,
+    /// the list of users/devices that should receive the session and the list
+    /// of devices the session should be withheld from.
     pub async fn collect_session_recipients(
         &self,
         users: impl Iterator<Item = &UserId>,
-        history_visibility: HistoryVisibility,
+        settings: &EncryptionSettings,
         outbound: &OutboundGroupSession,
-    ) -> OlmResult<(bool, HashMap<Box<UserId>, Vec<Device>>)> {
+    ) -> OlmResult<CollectRecipientsResult> {
         let users: HashSet<&UserId> = users.collect();
+        let history_visibility = &settings.history_visibility;
         let mut devices: HashMap<Box<UserId>, Vec<Device>> = HashMap::new();
+        let mut withheld_devices = Vec::new();
 
         trace!(
             users = ?users,
@@ -346,12 +361,13 @@ impl GroupSessionManager {
         // get the session but is in the set of users that received the session.
         let user_left = !users_shared_with.difference(&users).collect::<HashSet<_>>().is_empty();
 
-        let visibility_changed = outbound.settings().history_visibility != history_visibility;
+        let visibility_changed = &outbound.settings().history_visibility != history_visibility;
 
         // To protect the room history we need to rotate the session if either:
         //
         // 1. Any user left the room.
-        // 2. Any of the users' devices got deleted or blacklisted.
+        // 2. Any of the users' devices got deleted, blacklisted or, if only trusted
+        //    devices should receive the session, unverified.
         // 3. The history visibility changed.
         //
         // This is calculated in the following code and stored in this variable.
@@ -359,17 +375,26 @@ impl GroupSessionManager {
 
         for user_id in users {
             let user_devices = self.store.get_user_devices(user_id).await?;
-            let non_blacklisted_devices: Vec<Device> =
-                user_devices.devices().filter(|d| !d.is_blacklisted()).collect();
+            let mut recipient_devices = Vec::new();
+
+            for device in user_devices.devices() {
+                if device.is_blacklisted() {
+                    withheld_devices.push((device, WithheldCode::Blacklisted));
+                } else if settings.only_allow_trusted_devices && !device.verified() {
+                    withheld_devices.push((device, WithheldCode::Unverified));
+                } else {
+                    recipient_devices.push(device);
+                }
+            }
 
             // If we haven't already concluded that the session should be
             // rotated for other reasons, we also need to check whether any
-            // of the devices in the session got deleted or blacklisted in the
+            // of the devices in the session got deleted or excluded in the
             // meantime. If so, we should also rotate the session.
             if !should_rotate {
                 // Device IDs that should receive this session
-                let non_blacklisted_device_ids: HashSet<&DeviceId> =
-                    non_blacklisted_devices.iter().map(|d| d.device_id()).collect();
+                let recipient_device_ids: HashSet<&DeviceId> =
+                    recipient_devices.iter().map(|d| d.device_id()).collect();
 
                 if let Some(shared) = outbound.shared_with_set.get(user_id) {
                     // Devices that received this session
@@ -382,21 +407,18 @@ impl GroupSessionManager {
                     // 1. Devices that had previously received the session, and
                     // 2. Devices that would now receive the session
                     //
-                    // represents newly deleted or blacklisted devices. If this
+                    // represents newly deleted or excluded devices. If this
                     // set is non-empty, we must rotate.
-                    let newly_deleted_or_blacklisted =
-                        shared.difference(&non_blacklisted_device_ids).collect::<HashSet<_>>();
+                    let newly_deleted_or_excluded =
+                        shared.difference(&recipient_device_ids).collect::<HashSet<_>>();
 
-                    if !newly_deleted_or_blacklisted.is_empty() {
+                    if !newly_deleted_or_excluded.is_empty() {
                         should_rotate = true;
                     }
                 };
             }
 
-            devices
-                .entry(user_id.to_owned())
-                .or_insert_with(Vec::new)
-                .extend(non_blacklisted_devices);
+            devices.entry(user_id.to_owned()).or_insert_with(Vec::new).extend(recipient_devices);
         }
 
         trace!(
@@ -406,25 +428,137 @@ impl GroupSessionManager {
             "Done calculating group session recipients"
         );
 
-        Ok((should_rotate, devices))
+        Ok(CollectRecipientsResult { should_rotate, devices, withheld_devices })
     }
 
+    /// Encrypt the room key for the given chunk of devices.
+    ///
+    /// Returns the Olm sessions that were used and the devices that couldn't
+    /// receive the room key since we don't have an Olm session with them.
     pub async fn encrypt_request(
         chunk: Vec<Device>,
         content: AnyToDeviceEventContent,
         outbound: OutboundGroupSession,
         message_index: u32,
         being_shared: Arc<DashMap<Box<TransactionId>, OutboundGroupSession>>,
-    ) -> OlmResult<Vec<Session>> {
+    ) -> OlmResult<(Vec<Session>, Vec<Device>)> {
         let (id, request, share_infos, used_sessions) =
-            Self::encrypt_session_for(content.clone(), chunk, message_index).await?;
+            Self::encrypt_session_for(content.clone(), chunk.clone(), message_index).await?;
+
+        let no_olm_devices = chunk
+            .into_iter()
+            .filter(|d| {
+                !share_infos.get(d.user_id()).map_or(false, |i| i.contains_key(d.device_id()))
+            })
+            .collect();
 
         if !request.messages.is_empty() {
             outbound.add_request(id.clone(), request.into(), share_infos);
             being_shared.insert(id, outbound.clone());
         }
 
-        Ok(used_sessions)
+        Ok((used_sessions, no_olm_devices))
+    }
+
+    /// Queue up `m.room_key.withheld` notices for the devices that won't
+    /// receive the room key of the given outbound session.
+    ///
+    /// The notices are sent out together with the room key requests, devices
+    /// that already received a notice for this session are skipped.
+    fn withhold_session_from(
+        &self,
+        outbound: &OutboundGroupSession,
+        devices: Vec<(Device, WithheldCode)>,
+    ) {
+        let mut devices_per_code: HashMap<WithheldCode, Vec<Device>> = HashMap::new();
+
+        for (device, code) in devices {
+            if !outbound.is_withheld_from(&device) {
+                devices_per_code.entry(code).or_default().push(device);
+            }
+        }
+
+        let sender_key = self.account.identity_keys().curve25519();
+
+        for (code, devices) in devices_per_code {
+            let recipients = devices.iter().fold(BTreeMap::new(), |mut acc, d| {
+                acc.entry(d.user_id()).or_insert_with(BTreeSet::new).insert(d.device_id());
+                acc
+            });
+
+            info!(
+                room_id = outbound.room_id().as_str(),
+                session_id = outbound.session_id(),
+                code = code.as_str(),
+                ?recipients,
+                "Withholding the room key from some devices"
+            );
+
+            let content = RoomKeyWithheldContent::new(
+                outbound.settings().algorithm.clone(),
+                outbound.room_id(),
+                outbound.session_id(),
+                sender_key,
+                code.clone(),
+            );
+
+            for chunk in devices.chunks(Self::MAX_TO_DEVICE_MESSAGES) {
+                let request = content.to_request(chunk);
+                let id = request.txn_id.clone();
+
+                for device in chunk {
+                    outbound.mark_as_withheld_from(device, code.clone());
+                }
+
+                outbound.add_request(id.clone(), request.into(), BTreeMap::new());
+                self.sessions.sessions_being_shared.insert(id, outbound.clone());
+            }
+        }
+    }
+
+    /// Queue up `m.no_olm` withheld notices for the devices we couldn't
+    /// establish an Olm session with.
+    ///
+    /// Unlike the other withheld codes, `m.no_olm` isn't tied to a room key,
+    /// so every device only receives it once. The notices are sent out
+    /// together with the room key requests of the given outbound session.
+    fn send_no_olm_notices(
+        &self,
+        outbound: &OutboundGroupSession,
+        devices: Vec<Device>,
+        changes: &mut Changes,
+    ) {
+        let devices: Vec<Device> =
+            devices.into_iter().filter(|d| !d.was_withheld_code_sent()).collect();
+
+        if devices.is_empty() {
+            return;
+        }
+
+        let recipients = devices.iter().fold(BTreeMap::new(), |mut acc, d| {
+            acc.entry(d.user_id()).or_insert_with(BTreeSet::new).insert(d.device_id());
+            acc
+        });
+
+        info!(?recipients, "Notifying devices that we couldn't establish an Olm session with them");
+
+        let content = RoomKeyWithheldContent::no_olm(
+            outbound.settings().algorithm.clone(),
+            self.account.identity_keys().curve25519(),
+        );
+
+        for chunk in devices.chunks(Self::MAX_TO_DEVICE_MESSAGES) {
+            let request = content.to_request(chunk);
+            let id = request.txn_id.clone();
+
+            for device in chunk {
+                device.mark_withheld_code_as_sent();
+                changes.devices.changed.push(device.inner.clone());
+            }
+
+            outbound.add_request(id.clone(), request.into(), BTreeMap::new());
+            self.sessions.sessions_being_shared.insert(id, outbound.clone());
+        }
     }
 
     pub(crate) fn session_cache(&self) -> GroupSessionCache {
@@ -451,7 +585,6 @@ impl GroupSessionManager {
         trace!(room_id = room_id.as_str(), "Checking if a room key needs to be shared",);
 
         let encryption_settings = encryption_settings.into();
-        let history_visibility = encryption_settings.history_visibility.clone();
         let mut changes = Changes::default();
 
         let (outbound, inbound) =
@@ -462,8 +595,8 @@ impl GroupSessionManager {
             changes.inbound_group_sessions.push(inbound);
         }
 
-        let (should_rotate, devices) =
-            self.collect_session_recipients(users, history_visibility, &outbound).await?;
+        let CollectRecipientsResult { should_rotate, devices, withheld_devices } =
+            self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
 
         let outbound = if should_rotate {
             let old_session_id = outbound.session_id();
@@ -525,12 +658,18 @@ impl GroupSessionManager {
             })
             .collect();
 
+        let mut no_olm_devices = Vec::new();
+
         for result in join_all(tasks).await {
-            let used_sessions: OlmResult<Vec<Session>> = result.expect("Encryption task panicked");
+            let (used_sessions, devices) = result.expect("Encryption task panicked")?;
 
-            changes.sessions.extend(used_sessions?);
+            changes.sessions.extend(used_sessions);
+            no_olm_devices.extend(devices);
         }
 
+        self.withhold_session_from(&outbound, withheld_devices);
+        self.send_no_olm_notices(&outbound, no_olm_devices, &mut changes);
+
         let requests = outbound.pending_requests();
 
         if requests.is_empty() {
@@ -557,7 +696,6 @@ impl GroupSessionManager {
 
             let transaction_ids: Vec<_> = requests.iter().map(|r| r.txn_id.clone()).collect();
 
-            // TODO log the withheld reasons here as well.
             info!(
                 room_id = room_id.as_str(),
                 session_id = outbound.session_id(),
@@ -595,11 +733,18 @@ mod test {
             client::r0::keys::{claim_keys, get_keys},
             IncomingResponse,
         },
-        device_id, room_id, user_id, DeviceId, TransactionId, UserId,
+        device_id,
+        events::EventType,
+        room_id,
+        to_device::DeviceIdOrAllDevices,
+        user_id, DeviceId, TransactionId, UserId,
     };
     use serde_json::Value;
 
-    use crate::{EncryptionSettings, OlmMachine};
+    use crate::{
+        olm::{RoomKeyWithheldContent, WithheldCode},
+        EncryptionSettings, LocalTrust, OlmMachine,
+    };
 
     fn alice_id() -> &'static UserId {
         user_id!("@alice:example.org")
@@ -651,11 +796,108 @@ mod test {
             .await
             .unwrap();
 
-        let event_count: usize = requests.iter().map(|r| r.message_count()).sum();
+        let event_count: usize = requests
+            .iter()
+            .filter(|r| r.event_type == EventType::RoomEncrypted)
+            .map(|r| r.message_count())
+            .sum();
 
         // The keys claim response has a couple of one-time keys with invalid
         // signatures, thus only 148 sessions are actually created, we check
         // that all 148 valid sessions get an room key.
         assert_eq!(event_count, 148);
+
+        // The devices we couldn't create a session with get a withheld notice.
+        let withheld_count: usize = requests
+            .iter()
+            .filter(|r| r.event_type.as_ref() == RoomKeyWithheldContent::EVENT_TYPE)
+            .map(|r| r.message_count())
+            .sum();
+
+        assert_ne!(withheld_count, 0);
+    }
+
+    #[async_test]
+    async fn test_withheld_notice_is_sent_once() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let (user_id, device_id) = keys_claim
+            .one_time_keys
+            .iter()
+            .find_map(|(u, d)| d.keys().next().map(|d| (u.clone(), d.clone())))
+            .unwrap();
+
+        let device = machine.get_device(&user_id, &device_id).await.unwrap().unwrap();
+        device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests = machine
+            .share_group_session(room_id, users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let blacklisted_notices: Vec<RoomKeyWithheldContent> = requests
+            .iter()
+            .filter(|r| r.event_type.as_ref() == RoomKeyWithheldContent::EVENT_TYPE)
+            .filter_map(|r| r.messages.get(&user_id))
+            .flat_map(|m| m.get(&DeviceIdOrAllDevices::DeviceId(device_id.clone())))
+            .map(|c| c.deserialize_as().unwrap())
+            .collect();
+
+        assert_eq!(blacklisted_notices.len(), 1);
+        assert_eq!(blacklisted_notices[0].code, WithheldCode::Blacklisted);
+
+        let request_count = requests.len();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests = machine
+            .share_group_session(room_id, users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        // No new notices are queued up, only the pending requests are returned.
+        assert_eq!(requests.len(), request_count);
+    }
+
+    #[async_test]
+    async fn test_no_olm_notice_is_sent_once_per_device() {
+        let machine = machine().await;
+        let keys_claim = keys_claim_response();
+
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests = machine
+            .share_group_session(room_id!("!test:localhost"), users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let no_olm_notices: Vec<RoomKeyWithheldContent> = requests
+            .iter()
+            .filter(|r| r.event_type.as_ref() == RoomKeyWithheldContent::EVENT_TYPE)
+            .flat_map(|r| r.messages.values().flat_map(|m| m.values()))
+            .map(|c| c.deserialize_as().unwrap())
+            .collect();
+
+        assert!(!no_olm_notices.is_empty());
+
+        for notice in &no_olm_notices {
+            assert_eq!(notice.code, WithheldCode::NoOlm);
+            assert!(notice.room_id.is_none());
+            assert!(notice.session_id.is_none());
+        }
+
+        // Sharing a room key in another room doesn't notify the same devices
+        // again.
+        let users = keys_claim.one_time_keys.keys().map(Deref::deref);
+        let requests = machine
+            .share_group_session(room_id!("!other:localhost"), users, EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        assert!(!requests.is_empty());
+        assert!(requests
+            .iter()
+            .all(|r| r.event_type.as_ref() != RoomKeyWithheldContent::EVENT_TYPE));
     }
 }
@@ -32,7 +32,7 @@ use super::{
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{OutboundGroupSession, PrivateCrossSigningIdentity, RoomKeyWithheldEvent},
 };
 
 /// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
@@ -67,6 +67,8 @@ mod KEYS {
     pub const UNSENT_SECRET_REQUESTS: &'static str = "unsent_secret_requests";
     pub const SECRET_REQUESTS_BY_INFO: &'static str = "secret_requests_by_info";
 
+    pub const WITHHELD_SESSIONS: &'static str = "withheld_sessions";
+
     // KEYS
     pub const PICKLE_KEY: &'static str = "pickle_key";
     pub const ACCOUNT: &'static str = "account";
@@ -100,8 +102,8 @@ impl IndexeddbStore {
                 .expect("Default Pickle always works. qed")
         });
 
-        // Open my_db v1
-        let mut db_req: OpenDbRequest = IdbDatabase::open_f64(&name, 1.0)?;
+        // Open my_db v2
+        let mut db_req: OpenDbRequest = IdbDatabase::open_f64(&name, 2.0)?;
         db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
             if evt.old_version() < 1.0 {
                 // migrating to version 1
@@ -121,6 +123,13 @@ impl IndexeddbStore {
                 db.create_object_store(KEYS::UNSENT_SECRET_REQUESTS)?;
                 db.create_object_store(KEYS::SECRET_REQUESTS_BY_INFO)?;
             }
+
+            if evt.old_version() < 2.0 {
+                // migrating to version 2
+                let db = evt.db();
+
+                db.create_object_store(KEYS::WITHHELD_SESSIONS)?;
+            }
             Ok(())
         }));
 
@@ -222,6 +231,7 @@ impl IndexeddbStore {
             (!changes.inbound_group_sessions.is_empty(), KEYS::INBOUND_GROUP_SESSIONS),
             (!changes.outbound_group_sessions.is_empty(), KEYS::OUTBOUND_GROUP_SESSIONS),
             (!changes.message_hashes.is_empty(), KEYS::OLM_HASHES),
+            (!changes.withheld_session_info.is_empty(), KEYS::WITHHELD_SESSIONS),
         ]
         .iter()
         .filter_map(|(id, key)| if *id { Some(*key) } else { None })
@@ -309,6 +319,7 @@ impl IndexeddbStore {
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
         let key_requests = changes.key_requests;
+        let withheld_session_info = changes.withheld_session_info;
 
         if !device_changes.new.is_empty() || !device_changes.changed.is_empty() {
             let device_store = tx.object_store(KEYS::DEVICES)?;
@@ -365,6 +376,21 @@ impl IndexeddbStore {
             }
         }
 
+        if !withheld_session_info.is_empty() {
+            let withheld_sessions = tx.object_store(KEYS::WITHHELD_SESSIONS)?;
+
+            for info in &withheld_session_info {
+                if let (Some(room_id), Some(session_id)) =
+                    (&info.content.room_id, &info.content.session_id)
+                {
+                    withheld_sessions.put_key_val(
+                        &(room_id.as_str(), session_id.as_str()).encode(),
+                        &JsValue::from_serde(&info)?,
+                    )?;
+                }
+            }
+        }
+
         tx.await.into_result()?;
 
         // all good, let's update our caches:indexeddb
@@ -606,6 +632,22 @@ impl CryptoStore for IndexeddbStore {
         self.load_outbound_group_session(room_id).await
     }
 
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        let key = (room_id.as_str(), session_id).encode();
+        Ok(self
+            .inner
+            .transaction_on_one_with_mode(KEYS::WITHHELD_SESSIONS, IdbTransactionMode::Readonly)?
+            .object_store(KEYS::WITHHELD_SESSIONS)?
+            .get(&key)?
+            .await?
+            .map(|i| i.into_serde())
+            .transpose()?)
+    }
+
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
         let all = self.get_inbound_group_sessions().await?;
         let backed_up = all.iter().filter(|s| s.backed_up()).count();
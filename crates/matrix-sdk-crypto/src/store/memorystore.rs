@@ -29,7 +29,7 @@ use super::{
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{OutboundGroupSession, PrivateCrossSigningIdentity, RoomKeyWithheldEvent},
 };
 
 fn encode_key_info(info: &SecretInfo) -> String {
@@ -53,6 +53,7 @@ pub struct MemoryStore {
     identities: Arc<DashMap<Box<UserId>, ReadOnlyUserIdentities>>,
     outgoing_key_requests: Arc<DashMap<Box<TransactionId>, GossipRequest>>,
     key_requests_by_info: Arc<DashMap<String, Box<TransactionId>>>,
+    withheld_info: Arc<DashMap<Box<RoomId>, DashMap<String, RoomKeyWithheldEvent>>>,
 }
 
 impl Default for MemoryStore {
@@ -67,6 +68,7 @@ impl Default for MemoryStore {
             identities: Default::default(),
             outgoing_key_requests: Default::default(),
             key_requests_by_info: Default::default(),
+            withheld_info: Default::default(),
         }
     }
 }
@@ -144,6 +146,17 @@ impl CryptoStore for MemoryStore {
             self.key_requests_by_info.insert(info_string, id);
         }
 
+        for info in changes.withheld_session_info {
+            if let (Some(room_id), Some(session_id)) =
+                (info.content.room_id.clone(), info.content.session_id.clone())
+            {
+                self.withheld_info
+                    .entry(room_id)
+                    .or_insert_with(DashMap::new)
+                    .insert(session_id, info);
+            }
+        }
+
         Ok(())
     }
 
@@ -199,6 +212,14 @@ impl CryptoStore for MemoryStore {
         Ok(None)
     }
 
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        Ok(self.withheld_info.get(room_id).and_then(|i| i.get(session_id).map(|i| i.clone())))
+    }
+
     fn is_user_tracked(&self, user_id: &UserId) -> bool {
         self.tracked_users.contains(user_id)
     }
@@ -17,9 +17,9 @@
 //! The storage layer for the [`OlmMachine`] can be customized using a trait.
 //! Implementing your own [`CryptoStore`]
 //!
-//! An in-memory only store is provided as well as a Sled based one, depending
-//! on your needs and targets a custom store may be implemented, e.g. for
-//! `wasm-unknown-unknown` an indexeddb store would be needed
+//! An in-memory only store is provided as well as Sled and SQLite based ones,
+//! depending on your needs and targets a custom store may be implemented, e.g.
+//! for `wasm-unknown-unknown` an indexeddb store would be needed
 //!
 //! ```
 //! # use matrix_sdk_crypto::{
@@ -47,9 +47,11 @@ pub mod integration_tests;
 pub(crate) mod indexeddb;
 #[cfg(feature = "sled_cryptostore")]
 pub(crate) mod sled;
+#[cfg(feature = "sqlite_cryptostore")]
+pub(crate) mod sqlite;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
     ops::Deref,
@@ -76,6 +78,8 @@ use zeroize::Zeroize;
 pub use self::indexeddb::IndexeddbStore;
 #[cfg(feature = "sled_cryptostore")]
 pub use self::sled::SledStore;
+#[cfg(feature = "sqlite_cryptostore")]
+pub use self::sqlite::SqliteStore;
 use crate::{
     error::SessionUnpicklingError,
     identities::{
@@ -83,11 +87,11 @@ use crate::{
         Device, ReadOnlyDevice, ReadOnlyUserIdentities, UserDevices,
     },
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        ExportedRoomKey, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, RoomKeyWithheldEvent, Session,
     },
     verification::VerificationMachine,
-    CrossSigningStatus,
+    CrossSigningStatus, RoomKeyImportResult,
 };
 
 /// A `CryptoStore` specific result type.
@@ -125,6 +129,7 @@ pub struct Changes {
     pub key_requests: Vec<GossipRequest>,
     pub identities: IdentityChanges,
     pub devices: DeviceChanges,
+    pub withheld_session_info: Vec<RoomKeyWithheldEvent>,
 }
 
 impl Changes {
@@ -139,6 +144,7 @@ impl Changes {
             && self.key_requests.is_empty()
             && self.identities.is_empty()
             && self.devices.is_empty()
+            && self.withheld_session_info.is_empty()
     }
 }
 
@@ -520,6 +526,95 @@ impl Store {
 
         Ok(())
     }
+
+    /// Import the given room keys into the store.
+    ///
+    /// Room keys will only be imported if we don't already have a better
+    /// version of the same room key, that is a room key with a lower first
+    /// known index.
+    pub async fn import_room_keys(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        #[allow(unused_variables)] from_backup: bool,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        type SessionIdToIndexMap = BTreeMap<Arc<str>, u32>;
+
+        #[derive(Debug)]
+        struct ShallowSessions {
+            inner: BTreeMap<Arc<RoomId>, BTreeMap<Arc<str>, SessionIdToIndexMap>>,
+        }
+
+        impl ShallowSessions {
+            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
+                self.inner
+                    .get(&session.room_id)
+                    .and_then(|m| {
+                        m.get(&session.sender_key).and_then(|m| {
+                            m.get(&session.session_id)
+                                .map(|existing| existing <= &session.first_known_index())
+                        })
+                    })
+                    .unwrap_or(false)
+            }
+        }
+
+        let mut sessions = Vec::new();
+
+        let existing_sessions = ShallowSessions {
+            inner: self.get_inbound_group_sessions().await?.into_iter().fold(
+                BTreeMap::new(),
+                |mut acc, s| {
+                    let index = s.first_known_index();
+
+                    acc.entry(s.room_id)
+                        .or_default()
+                        .entry(s.sender_key)
+                        .or_default()
+                        .insert(s.session_id, index);
+
+                    acc
+                },
+            ),
+        };
+
+        let total_count = exported_keys.len();
+        let mut keys = BTreeMap::new();
+
+        for (i, key) in exported_keys.into_iter().enumerate() {
+            let session = InboundGroupSession::from_export(key)?;
+
+            // Only import the session if we didn't have this session or if it's
+            // a better version of the same session, that is the first known
+            // index is lower.
+            if !existing_sessions.has_better_session(&session) {
+                #[cfg(feature = "backups_v1")]
+                if from_backup {
+                    session.mark_as_backed_up()
+                }
+
+                keys.entry(session.room_id().to_owned())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(session.sender_key().to_owned())
+                    .or_insert_with(BTreeSet::new)
+                    .insert(session.session_id().to_owned());
+
+                sessions.push(session)
+            }
+
+            progress_listener(i, total_count)
+        }
+
+        let imported_count = sessions.len();
+
+        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+
+        self.save_changes(changes).await?;
+
+        info!(total_count, imported_count, room_keys =? keys, "Successfully imported room keys");
+
+        Ok(RoomKeyImportResult::new(imported_count, total_count, keys))
+    }
 }
 
 impl Deref for Store {
@@ -543,6 +638,11 @@ pub enum CryptoStoreError {
     #[error(transparent)]
     Database(#[from] sled::Error),
 
+    /// Error in the internal SQLite database
+    #[cfg(feature = "sqlite_cryptostore")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
     /// Error in the internal database
     #[cfg(feature = "indexeddb_cryptostore")]
     #[error("IndexedDB error: {name} ({code}): {message}")]
@@ -672,6 +772,20 @@ pub trait CryptoStore: AsyncTraitDeps {
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>>;
 
+    /// Get the `m.room_key.withheld` notice we received for the given room key,
+    /// if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the room key.
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>>;
+
     /// Is the given user already tracked.
     fn is_user_tracked(&self, user_id: &UserId) -> bool;
 
@@ -42,7 +42,10 @@ use super::{
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity,
+        RoomKeyWithheldEvent,
+    },
     LocalTrust,
 };
 
@@ -192,6 +195,8 @@ pub struct SledStore {
     identities: Tree,
 
     tracked_users: Tree,
+
+    withheld_info: Tree,
 }
 
 impl std::fmt::Debug for SledStore {
@@ -380,6 +385,8 @@ impl SledStore {
         let unsent_secret_requests = db.open_tree("unsent_secret_requests")?;
         let secret_requests_by_info = db.open_tree("secret_requests_by_info")?;
 
+        let withheld_info = db.open_tree("withheld_info")?;
+
         let session_cache = SessionStore::new();
 
         let pickle_key = if let Some(passphrase) = passphrase {
@@ -409,6 +416,7 @@ impl SledStore {
             tracked_users,
             olm_hashes,
             identities,
+            withheld_info,
         };
 
         database.upgrade()?;
@@ -532,6 +540,7 @@ impl SledStore {
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
         let key_requests = changes.key_requests;
+        let withheld_session_info = changes.withheld_session_info;
         #[cfg(feature = "backups_v1")]
         let backup_version = changes.backup_version;
 
@@ -547,6 +556,7 @@ impl SledStore {
             &self.outgoing_secret_requests,
             &self.unsent_secret_requests,
             &self.secret_requests_by_info,
+            &self.withheld_info,
         )
             .transaction(
                 |(
@@ -561,6 +571,7 @@ impl SledStore {
                     outgoing_secret_requests,
                     unsent_secret_requests,
                     secret_requests_by_info,
+                    withheld_info,
                 )| {
                     if let Some(a) = &account_pickle {
                         account.insert(
@@ -667,6 +678,18 @@ impl SledStore {
                         }
                     }
 
+                    for info in &withheld_session_info {
+                        if let (Some(room_id), Some(session_id)) =
+                            (&info.content.room_id, &info.content.session_id)
+                        {
+                            withheld_info.insert(
+                                (room_id.as_str(), session_id.as_str()).encode(),
+                                serde_json::to_vec(&info)
+                                    .map_err(ConflictableTransactionError::Abort)?,
+                            )?;
+                        }
+                    }
+
                     Ok(())
                 },
             );
@@ -862,6 +885,18 @@ impl CryptoStore for SledStore {
         self.load_outbound_group_session(room_id).await
     }
 
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        Ok(self
+            .withheld_info
+            .get((room_id.as_str(), session_id).encode())?
+            .map(|i| serde_json::from_slice(&i))
+            .transpose()?)
+    }
+
     fn is_user_tracked(&self, user_id: &UserId) -> bool {
         self.tracked_users_cache.contains(user_id)
     }
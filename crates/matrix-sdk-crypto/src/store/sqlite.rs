@@ -0,0 +1,927 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex, RwLock},
+};
+
+use dashmap::DashSet;
+use matrix_sdk_common::{async_trait, locks::Mutex};
+use olm_rs::{account::IdentityKeys, PicklingMode};
+use ruma::{
+    events::{room_key_request::RequestedKeyInfo, secret::request::SecretName},
+    DeviceId, RoomId, TransactionId, UserId,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tracing::debug;
+
+use super::{
+    caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, InboundGroupSession,
+    PickleKey, ReadOnlyAccount, Result, RoomKeyCounts, Session,
+};
+use crate::{
+    gossiping::{GossipRequest, SecretInfo},
+    identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
+    olm::{
+        OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity,
+        RoomKeyWithheldEvent,
+    },
+};
+
+/// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
+/// panic once we try to pickle a Signing object.
+const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
+
+/// The version of the database schema, stored in the `user_version` pragma.
+const DATABASE_VERSION: u32 = 1;
+
+trait EncodeKey {
+    const SEPARATOR: u8 = 0xff;
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl<T: EncodeKey> EncodeKey for &T {
+    fn encode(&self) -> Vec<u8> {
+        T::encode(self)
+    }
+}
+
+impl<T: EncodeKey> EncodeKey for Box<T> {
+    fn encode(&self) -> Vec<u8> {
+        T::encode(self)
+    }
+}
+
+impl EncodeKey for TransactionId {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for SecretName {
+    fn encode(&self) -> Vec<u8> {
+        [self.as_ref().as_bytes(), &[Self::SEPARATOR]].concat()
+    }
+}
+
+impl EncodeKey for SecretInfo {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            SecretInfo::KeyRequest(k) => k.encode(),
+            SecretInfo::SecretRequest(s) => s.encode(),
+        }
+    }
+}
+
+impl EncodeKey for RequestedKeyInfo {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.room_id.as_bytes(),
+            &[Self::SEPARATOR],
+            self.sender_key.as_bytes(),
+            &[Self::SEPARATOR],
+            self.algorithm.as_ref().as_bytes(),
+            &[Self::SEPARATOR],
+            self.session_id.as_bytes(),
+            &[Self::SEPARATOR],
+        ]
+        .concat()
+    }
+}
+
+impl EncodeKey for UserId {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for ReadOnlyDevice {
+    fn encode(&self) -> Vec<u8> {
+        (self.user_id().as_str(), self.device_id().as_str()).encode()
+    }
+}
+
+impl EncodeKey for RoomId {
+    fn encode(&self) -> Vec<u8> {
+        self.as_str().encode()
+    }
+}
+
+impl EncodeKey for str {
+    fn encode(&self) -> Vec<u8> {
+        [self.as_bytes(), &[Self::SEPARATOR]].concat()
+    }
+}
+
+impl EncodeKey for (&str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [self.0.as_bytes(), &[Self::SEPARATOR], self.1.as_bytes(), &[Self::SEPARATOR]].concat()
+    }
+}
+
+impl EncodeKey for (&str, &str, &str) {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.0.as_bytes(),
+            &[Self::SEPARATOR],
+            self.1.as_bytes(),
+            &[Self::SEPARATOR],
+            self.2.as_bytes(),
+            &[Self::SEPARATOR],
+        ]
+        .concat()
+    }
+}
+
+/// A key-value table in the database, keys are compared byte-wise so prefix
+/// scans return values in the same order a sled tree would.
+#[derive(Clone, Copy, Debug)]
+struct Table(&'static str);
+
+type KeyValue = (Vec<u8>, Vec<u8>);
+
+impl Table {
+    fn get(self, conn: &Connection, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        conn.prepare_cached(&format!("SELECT value FROM {} WHERE key = ?1", self.0))?
+            .query_row(params![key], |row| row.get(0))
+            .optional()
+    }
+
+    fn contains_key(self, conn: &Connection, key: &[u8]) -> rusqlite::Result<bool> {
+        Ok(self.get(conn, key)?.is_some())
+    }
+
+    fn insert(self, conn: &Connection, key: &[u8], value: &[u8]) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+            self.0
+        ))?
+        .execute(params![key, value])?;
+
+        Ok(())
+    }
+
+    fn remove(self, conn: &Connection, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let old = self.get(conn, key)?;
+
+        conn.prepare_cached(&format!("DELETE FROM {} WHERE key = ?1", self.0))?
+            .execute(params![key])?;
+
+        Ok(old)
+    }
+
+    fn scan_prefix(self, conn: &Connection, prefix: &[u8]) -> rusqlite::Result<Vec<KeyValue>> {
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT key, value FROM {} WHERE key >= ?1 AND substr(key, 1, length(?1)) = ?1 \
+             ORDER BY key",
+            self.0
+        ))?;
+
+        let values = statement
+            .query_map(params![prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(values)
+    }
+
+    fn iter(self, conn: &Connection) -> rusqlite::Result<Vec<KeyValue>> {
+        let mut statement =
+            conn.prepare_cached(&format!("SELECT key, value FROM {} ORDER BY key", self.0))?;
+
+        let values = statement
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(values)
+    }
+}
+
+const METADATA: Table = Table("metadata");
+const ACCOUNT: Table = Table("account");
+const PRIVATE_IDENTITY: Table = Table("private_identity");
+const OLM_HASHES: Table = Table("olm_hashes");
+const SESSIONS: Table = Table("session");
+const INBOUND_GROUP_SESSIONS: Table = Table("inbound_group_sessions");
+const OUTBOUND_GROUP_SESSIONS: Table = Table("outbound_group_sessions");
+const OUTGOING_SECRET_REQUESTS: Table = Table("outgoing_secret_requests");
+const UNSENT_SECRET_REQUESTS: Table = Table("unsent_secret_requests");
+const SECRET_REQUESTS_BY_INFO: Table = Table("secret_requests_by_info");
+const DEVICES: Table = Table("devices");
+const IDENTITIES: Table = Table("identities");
+const TRACKED_USERS: Table = Table("tracked_users");
+const WITHHELD_INFO: Table = Table("withheld_info");
+
+#[derive(Clone, Debug)]
+struct AccountInfo {
+    user_id: Arc<UserId>,
+    device_id: Arc<DeviceId>,
+    identity_keys: Arc<IdentityKeys>,
+}
+
+/// A [SQLite] based cryptostore.
+///
+/// [SQLite]: https://www.sqlite.org/
+#[derive(Clone)]
+pub struct SqliteStore {
+    account_info: Arc<RwLock<Option<AccountInfo>>>,
+    path: Option<PathBuf>,
+    conn: Arc<StdMutex<Connection>>,
+    pickle_key: Arc<PickleKey>,
+
+    session_cache: SessionStore,
+    tracked_users_cache: Arc<DashSet<Box<UserId>>>,
+    users_for_key_query_cache: Arc<DashSet<Box<UserId>>>,
+}
+
+impl std::fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            f.debug_struct("SqliteStore").field("path", &path).finish()
+        } else {
+            f.debug_struct("SqliteStore").field("path", &"memory store").finish()
+        }
+    }
+}
+
+impl SqliteStore {
+    /// Open the SQLite based cryptostore at the given path using the given
+    /// passphrase to encrypt private data.
+    pub fn open_with_passphrase(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        let path = path.as_ref().join("matrix-sdk-crypto.sqlite3");
+        let conn = Connection::open(&path)?;
+
+        SqliteStore::open_helper(conn, Some(path), passphrase)
+    }
+
+    /// Open a temporary, in-memory, SQLite based cryptostore.
+    /// The given passphrase will be used to encrypt private data.
+    pub fn open_in_memory(passphrase: Option<&str>) -> Result<Self> {
+        SqliteStore::open_helper(Connection::open_in_memory()?, None, passphrase)
+    }
+
+    fn open_helper(
+        mut conn: Connection,
+        path: Option<PathBuf>,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        Self::upgrade(&mut conn)?;
+
+        let pickle_key = if let Some(passphrase) = passphrase {
+            Self::get_or_create_pickle_key(passphrase, &conn)?
+        } else {
+            PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
+                .expect("Can't create default pickle key")
+        };
+
+        Ok(Self {
+            account_info: RwLock::new(None).into(),
+            path,
+            conn: Arc::new(StdMutex::new(conn)),
+            pickle_key: pickle_key.into(),
+            session_cache: SessionStore::new(),
+            tracked_users_cache: DashSet::new().into(),
+            users_for_key_query_cache: DashSet::new().into(),
+        })
+    }
+
+    fn upgrade(conn: &mut Connection) -> Result<()> {
+        let version: u32 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+
+        if version == DATABASE_VERSION {
+            return Ok(());
+        }
+
+        debug!(version, new_version = DATABASE_VERSION, "Upgrading the SQLite crypto store");
+
+        let txn = conn.transaction()?;
+
+        if version < 1 {
+            for table in [
+                METADATA,
+                ACCOUNT,
+                PRIVATE_IDENTITY,
+                OLM_HASHES,
+                SESSIONS,
+                INBOUND_GROUP_SESSIONS,
+                OUTBOUND_GROUP_SESSIONS,
+                OUTGOING_SECRET_REQUESTS,
+                UNSENT_SECRET_REQUESTS,
+                SECRET_REQUESTS_BY_INFO,
+                DEVICES,
+                IDENTITIES,
+                TRACKED_USERS,
+                WITHHELD_INFO,
+            ] {
+                txn.execute_batch(&format!(
+                    "CREATE TABLE {} (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL) \
+                     WITHOUT ROWID;",
+                    table.0
+                ))?;
+            }
+        }
+
+        txn.execute_batch(&format!("PRAGMA user_version = {};", DATABASE_VERSION))?;
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    fn get_or_create_pickle_key(passphrase: &str, conn: &Connection) -> Result<PickleKey> {
+        let key = if let Some(key) =
+            METADATA.get(conn, &"pickle_key".encode())?.map(|v| serde_json::from_slice(&v))
+        {
+            PickleKey::from_encrypted(passphrase, key?)
+                .map_err(|_| CryptoStoreError::UnpicklingError)?
+        } else {
+            let key = PickleKey::new();
+            let encrypted = key.encrypt(passphrase);
+            METADATA.insert(conn, &"pickle_key".encode(), &serde_json::to_vec(&encrypted)?)?;
+            key
+        };
+
+        Ok(key)
+    }
+
+    fn get_account_info(&self) -> Option<AccountInfo> {
+        self.account_info.read().unwrap().clone()
+    }
+
+    fn get_pickle_mode(&self) -> PicklingMode {
+        self.pickle_key.pickle_mode()
+    }
+
+    fn get_pickle_key(&self) -> &[u8] {
+        self.pickle_key.key()
+    }
+
+    /// Run the given closure with the connection.
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let conn = self.conn.lock().unwrap();
+        f(&conn)
+    }
+
+    /// Run the given closure inside of a transaction, the transaction is only
+    /// committed if the closure succeeds.
+    fn transaction<T>(&self, f: impl FnOnce(&Transaction<'_>) -> Result<T>) -> Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction()?;
+        let ret = f(&txn)?;
+        txn.commit()?;
+
+        Ok(ret)
+    }
+
+    fn get_value(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read(|conn| Ok(table.get(conn, key)?))
+    }
+
+    fn get_inbound_group_session_pickles(&self) -> Result<Vec<PickledInboundGroupSession>> {
+        self.read(|conn| {
+            INBOUND_GROUP_SESSIONS
+                .iter(conn)?
+                .into_iter()
+                .map(|(_, p)| serde_json::from_slice(&p).map_err(CryptoStoreError::Serialization))
+                .collect()
+        })
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        self.transaction(|txn| {
+            for (key, pickle) in INBOUND_GROUP_SESSIONS.iter(txn)? {
+                let mut pickle: PickledInboundGroupSession = serde_json::from_slice(&pickle)?;
+                pickle.backed_up = false;
+
+                INBOUND_GROUP_SESSIONS.insert(txn, &key, &serde_json::to_vec(&pickle)?)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn load_tracked_users(&self) -> Result<()> {
+        for (user, dirty) in self.read(|conn| Ok(TRACKED_USERS.iter(conn)?))? {
+            let user = Box::<UserId>::try_from(String::from_utf8_lossy(&user).to_string())?;
+            let dirty = dirty.get(0).map(|d| *d == 1).unwrap_or(true);
+
+            self.tracked_users_cache.insert(user.to_owned());
+
+            if dirty {
+                self.users_for_key_query_cache.insert(user);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        self.get_value(OUTBOUND_GROUP_SESSIONS, &room_id.encode())?
+            .map(|p| serde_json::from_slice(&p).map_err(CryptoStoreError::Serialization))
+            .transpose()?
+            .map(|p| {
+                OutboundGroupSession::from_pickle(
+                    account_info.device_id,
+                    account_info.identity_keys,
+                    p,
+                    self.get_pickle_mode(),
+                )
+                .map_err(CryptoStoreError::OlmGroupSession)
+            })
+            .transpose()
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let account_pickle = if let Some(a) = changes.account {
+            Some(a.pickle(self.get_pickle_mode()).await)
+        } else {
+            None
+        };
+
+        let private_identity_pickle = if let Some(i) = changes.private_identity {
+            Some(i.pickle(self.get_pickle_key()).await?)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "backups_v1")]
+        let recovery_key_pickle = changes.recovery_key.map(|r| r.pickle(self.get_pickle_key()));
+
+        let device_changes = changes.devices;
+        let mut session_changes = HashMap::new();
+
+        for session in changes.sessions {
+            let sender_key = session.sender_key();
+            let session_id = session.session_id();
+
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+            let key = (sender_key, session_id).encode();
+
+            self.session_cache.add(session).await;
+            session_changes.insert(key, pickle);
+        }
+
+        let mut inbound_session_changes = HashMap::new();
+
+        for session in changes.inbound_group_sessions {
+            let room_id = session.room_id();
+            let sender_key = session.sender_key();
+            let session_id = session.session_id();
+            let key = (room_id.as_str(), sender_key, session_id).encode();
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+
+            inbound_session_changes.insert(key, pickle);
+        }
+
+        let mut outbound_session_changes = HashMap::new();
+
+        for session in changes.outbound_group_sessions {
+            let room_id = session.room_id();
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+
+            outbound_session_changes.insert(room_id.to_owned(), pickle);
+        }
+
+        let identity_changes = changes.identities;
+        let olm_hashes = changes.message_hashes;
+        let key_requests = changes.key_requests;
+        let withheld_session_info = changes.withheld_session_info;
+        #[cfg(feature = "backups_v1")]
+        let backup_version = changes.backup_version;
+
+        self.transaction(|txn| {
+            if let Some(a) = &account_pickle {
+                ACCOUNT.insert(txn, &"account".encode(), &serde_json::to_vec(a)?)?;
+            }
+
+            if let Some(i) = &private_identity_pickle {
+                PRIVATE_IDENTITY.insert(txn, &"identity".encode(), &serde_json::to_vec(&i)?)?;
+            }
+
+            #[cfg(feature = "backups_v1")]
+            if let Some(r) = &recovery_key_pickle {
+                ACCOUNT.insert(txn, &"recovery_key_v1".encode(), &serde_json::to_vec(r)?)?;
+            }
+
+            #[cfg(feature = "backups_v1")]
+            if let Some(b) = &backup_version {
+                ACCOUNT.insert(txn, &"backup_version_v1".encode(), &serde_json::to_vec(b)?)?;
+            }
+
+            for device in device_changes.new.iter().chain(&device_changes.changed) {
+                DEVICES.insert(txn, &device.encode(), &serde_json::to_vec(&device)?)?;
+            }
+
+            for device in &device_changes.deleted {
+                DEVICES.remove(txn, &device.encode())?;
+            }
+
+            for identity in identity_changes.changed.iter().chain(&identity_changes.new) {
+                IDENTITIES.insert(
+                    txn,
+                    &identity.user_id().encode(),
+                    &serde_json::to_vec(&identity)?,
+                )?;
+            }
+
+            for (key, session) in &session_changes {
+                SESSIONS.insert(txn, key, &serde_json::to_vec(&session)?)?;
+            }
+
+            for (key, session) in &inbound_session_changes {
+                INBOUND_GROUP_SESSIONS.insert(txn, key, &serde_json::to_vec(&session)?)?;
+            }
+
+            for (key, session) in &outbound_session_changes {
+                OUTBOUND_GROUP_SESSIONS.insert(
+                    txn,
+                    &key.encode(),
+                    &serde_json::to_vec(&session)?,
+                )?;
+            }
+
+            for hash in &olm_hashes {
+                OLM_HASHES.insert(txn, &serde_json::to_vec(&hash)?, &[0])?;
+            }
+
+            for key_request in &key_requests {
+                let key_request_id = key_request.request_id.encode();
+
+                SECRET_REQUESTS_BY_INFO.insert(txn, &key_request.info.encode(), &key_request_id)?;
+
+                if key_request.sent_out {
+                    UNSENT_SECRET_REQUESTS.remove(txn, &key_request_id)?;
+                    OUTGOING_SECRET_REQUESTS.insert(
+                        txn,
+                        &key_request_id,
+                        &serde_json::to_vec(&key_request)?,
+                    )?;
+                } else {
+                    OUTGOING_SECRET_REQUESTS.remove(txn, &key_request_id)?;
+                    UNSENT_SECRET_REQUESTS.insert(
+                        txn,
+                        &key_request_id,
+                        &serde_json::to_vec(&key_request)?,
+                    )?;
+                }
+            }
+
+            for info in &withheld_session_info {
+                if let (Some(room_id), Some(session_id)) =
+                    (&info.content.room_id, &info.content.session_id)
+                {
+                    WITHHELD_INFO.insert(
+                        txn,
+                        &(room_id.as_str(), session_id.as_str()).encode(),
+                        &serde_json::to_vec(&info)?,
+                    )?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_outgoing_key_request_helper(&self, id: &[u8]) -> Result<Option<GossipRequest>> {
+        self.read(|conn| {
+            let request = if let Some(request) = OUTGOING_SECRET_REQUESTS.get(conn, id)? {
+                Some(request)
+            } else {
+                UNSENT_SECRET_REQUESTS.get(conn, id)?
+            };
+
+            Ok(request.map(|r| serde_json::from_slice(&r)).transpose()?)
+        })
+    }
+}
+
+#[async_trait]
+impl CryptoStore for SqliteStore {
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        if let Some(pickle) = self.get_value(ACCOUNT, &"account".encode())? {
+            let pickle = serde_json::from_slice(&pickle)?;
+
+            self.load_tracked_users().await?;
+
+            let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
+
+            let account_info = AccountInfo {
+                user_id: account.user_id.clone(),
+                device_id: account.device_id.clone(),
+                identity_keys: account.identity_keys.clone(),
+            };
+
+            *self.account_info.write().unwrap() = Some(account_info);
+
+            Ok(Some(account))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        let account_info = AccountInfo {
+            user_id: account.user_id.clone(),
+            device_id: account.device_id.clone(),
+            identity_keys: account.identity_keys.clone(),
+        };
+
+        *self.account_info.write().unwrap() = Some(account_info);
+
+        let changes = Changes { account: Some(account), ..Default::default() };
+
+        self.save_changes(changes).await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        if let Some(i) = self.get_value(PRIVATE_IDENTITY, &"identity".encode())? {
+            let pickle = serde_json::from_slice(&i)?;
+            Ok(Some(
+                PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key())
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.save_changes(changes).await
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        if self.session_cache.get(sender_key).is_none() {
+            let sessions: Result<Vec<Session>> = self
+                .read(|conn| Ok(SESSIONS.scan_prefix(conn, &sender_key.encode())?))?
+                .into_iter()
+                .map(|(_, s)| serde_json::from_slice(&s).map_err(CryptoStoreError::Serialization))
+                .map(|p| {
+                    Session::from_pickle(
+                        account_info.user_id.clone(),
+                        account_info.device_id.clone(),
+                        account_info.identity_keys.clone(),
+                        p?,
+                        self.get_pickle_mode(),
+                    )
+                    .map_err(CryptoStoreError::SessionUnpickling)
+                })
+                .collect();
+
+            self.session_cache.set_for_sender(sender_key, sessions?);
+        }
+
+        Ok(self.session_cache.get(sender_key))
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let key = (room_id.as_str(), sender_key, session_id).encode();
+        let pickle =
+            self.get_value(INBOUND_GROUP_SESSIONS, &key)?.map(|p| serde_json::from_slice(&p));
+
+        if let Some(pickle) = pickle {
+            Ok(Some(InboundGroupSession::from_pickle(pickle?, self.get_pickle_mode())?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        Ok(self
+            .get_inbound_group_session_pickles()?
+            .into_iter()
+            .filter_map(|p| InboundGroupSession::from_pickle(p, self.get_pickle_mode()).ok())
+            .collect())
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let pickles = self.get_inbound_group_session_pickles()?;
+
+        let total = pickles.len();
+        let backed_up = pickles.into_iter().filter(|p| p.backed_up).count();
+
+        Ok(RoomKeyCounts { total, backed_up })
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.get_inbound_group_session_pickles()?
+            .into_iter()
+            .filter(|p| !p.backed_up)
+            .take(limit)
+            .map(|p| {
+                InboundGroupSession::from_pickle(p, self.get_pickle_mode())
+                    .map_err(CryptoStoreError::from)
+            })
+            .collect()
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        self.reset_backup_state().await
+    }
+
+    async fn get_outbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.load_outbound_group_session(room_id).await
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        Ok(self
+            .get_value(WITHHELD_INFO, &(room_id.as_str(), session_id).encode())?
+            .map(|i| serde_json::from_slice(&i))
+            .transpose()?)
+    }
+
+    fn is_user_tracked(&self, user_id: &UserId) -> bool {
+        self.tracked_users_cache.contains(user_id)
+    }
+
+    fn has_users_for_key_query(&self) -> bool {
+        !self.users_for_key_query_cache.is_empty()
+    }
+
+    fn users_for_key_query(&self) -> HashSet<Box<UserId>> {
+        self.users_for_key_query_cache.iter().map(|u| u.clone()).collect()
+    }
+
+    fn tracked_users(&self) -> HashSet<Box<UserId>> {
+        self.tracked_users_cache.to_owned().iter().map(|u| u.clone()).collect()
+    }
+
+    async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        let already_added = self.tracked_users_cache.insert(user.to_owned());
+
+        if dirty {
+            self.users_for_key_query_cache.insert(user.to_owned());
+        } else {
+            self.users_for_key_query_cache.remove(user);
+        }
+
+        self.transaction(|txn| {
+            Ok(TRACKED_USERS.insert(txn, user.as_str().as_bytes(), &[dirty as u8])?)
+        })?;
+
+        Ok(already_added)
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        let key = (user_id.as_str(), device_id.as_str()).encode();
+        Ok(self.get_value(DEVICES, &key)?.map(|d| serde_json::from_slice(&d)).transpose()?)
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<Box<DeviceId>, ReadOnlyDevice>> {
+        self.read(|conn| Ok(DEVICES.scan_prefix(conn, &user_id.encode())?))?
+            .into_iter()
+            .map(|(_, d)| {
+                let d: ReadOnlyDevice = serde_json::from_slice(&d)?;
+                Ok((d.device_id().to_owned(), d))
+            })
+            .collect()
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<ReadOnlyUserIdentities>> {
+        Ok(self
+            .get_value(IDENTITIES, &user_id.encode())?
+            .map(|i| serde_json::from_slice(&i))
+            .transpose()?)
+    }
+
+    async fn is_message_known(&self, message_hash: &crate::olm::OlmMessageHash) -> Result<bool> {
+        let key = serde_json::to_vec(message_hash)?;
+        self.read(|conn| Ok(OLM_HASHES.contains_key(conn, &key)?))
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<Option<GossipRequest>> {
+        self.get_outgoing_key_request_helper(&request_id.encode())
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        key_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>> {
+        let id = self.get_value(SECRET_REQUESTS_BY_INFO, &key_info.encode())?;
+
+        if let Some(id) = id {
+            self.get_outgoing_key_request_helper(&id)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        self.read(|conn| Ok(UNSENT_SECRET_REQUESTS.iter(conn)?))?
+            .into_iter()
+            .map(|(_, r)| serde_json::from_slice(&r).map_err(CryptoStoreError::from))
+            .collect()
+    }
+
+    async fn delete_outgoing_secret_requests(&self, request_id: &TransactionId) -> Result<()> {
+        let request_id = request_id.encode();
+
+        self.transaction(|txn| {
+            for request in [
+                OUTGOING_SECRET_REQUESTS.remove(txn, &request_id)?,
+                UNSENT_SECRET_REQUESTS.remove(txn, &request_id)?,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let request: GossipRequest = serde_json::from_slice(&request)?;
+                SECRET_REQUESTS_BY_INFO.remove(txn, &request.info.encode())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        #[cfg(feature = "backups_v1")]
+        let key = {
+            let backup_version = self
+                .get_value(ACCOUNT, &"backup_version_v1".encode())?
+                .map(|v| serde_json::from_slice(&v))
+                .transpose()?;
+
+            let recovery_key = self
+                .get_value(ACCOUNT, &"recovery_key_v1".encode())?
+                .map(|p| serde_json::from_slice(&p))
+                .transpose()?
+                .map(|p| {
+                    crate::backups::RecoveryKey::from_pickle(p, self.get_pickle_key())
+                        .map_err(|_| CryptoStoreError::UnpicklingError)
+                })
+                .transpose()?;
+
+            BackupKeys { backup_version, recovery_key }
+        };
+
+        #[cfg(not(feature = "backups_v1"))]
+        let key = BackupKeys {};
+
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lazy_static::lazy_static;
+    use tempfile::{tempdir, TempDir};
+
+    use super::SqliteStore;
+    lazy_static! {
+        static ref TMP_DIR: TempDir = tempdir().unwrap();
+    }
+
+    async fn get_store(name: String, passphrase: Option<&str>) -> SqliteStore {
+        let tmpdir_path = TMP_DIR.path().join(name);
+        std::fs::create_dir_all(&tmpdir_path).expect("Can't create the store directory");
+
+        SqliteStore::open_with_passphrase(tmpdir_path, passphrase)
+            .expect("Can't create a passphrase protected store")
+    }
+
+    cryptostore_integration_tests! { integration }
+}
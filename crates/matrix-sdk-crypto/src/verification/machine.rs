@@ -304,6 +304,8 @@ impl VerificationMachine {
             return Ok(());
         };
 
+        let flow_id_string = flow_id.as_str().to_owned();
+
         let flow_id_mismatch = || {
             warn!(
                 sender = event.sender().as_str(),
@@ -511,6 +513,16 @@ impl VerificationMachine {
             }
         }
 
+        // Let the listeners of the affected request and verification flow know
+        // if the event moved them into a new state.
+        if let Some(request) = self.get_request(event.sender(), &flow_id_string) {
+            request.notify_changes();
+        }
+
+        if let Some(verification) = self.get_verification(event.sender(), &flow_id_string) {
+            verification.notify_changes();
+        }
+
         Ok(())
     }
 }
@@ -519,6 +531,7 @@ impl VerificationMachine {
 mod test {
     use std::{convert::TryFrom, sync::Arc, time::Duration};
 
+    use futures::StreamExt;
     use matrix_sdk_common::{instant::Instant, locks::Mutex};
     use matrix_sdk_test::async_test;
     use ruma::{device_id, user_id, DeviceId, UserId};
@@ -529,8 +542,8 @@ mod test {
         store::MemoryStore,
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, OutgoingContent},
-            test::wrap_any_to_device_content,
-            VerificationStore,
+            test::{request_to_event, wrap_any_to_device_content},
+            VerificationChange, VerificationStore,
         },
         ReadOnlyAccount, ReadOnlyDevice,
     };
@@ -587,6 +600,26 @@ mod test {
         (machine, bob_sas)
     }
 
+    async fn setup_machine_pair() -> (VerificationMachine, VerificationMachine) {
+        let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
+        let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
+        let alice_store = MemoryStore::new();
+        let bob_store = MemoryStore::new();
+
+        alice_store.save_devices(vec![ReadOnlyDevice::from_account(&bob).await]).await;
+        bob_store.save_devices(vec![ReadOnlyDevice::from_account(&alice).await]).await;
+
+        let alice_identity =
+            Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id().to_owned())));
+        let bob_identity =
+            Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(bob_id().to_owned())));
+
+        (
+            VerificationMachine::new(alice, alice_identity, Arc::new(alice_store)),
+            VerificationMachine::new(bob, bob_identity, Arc::new(bob_store)),
+        )
+    }
+
     #[async_test]
     async fn create() {
         let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
@@ -646,6 +679,193 @@ mod test {
         assert!(bob.is_done());
     }
 
+    #[async_test]
+    async fn sas_changes_from_events() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+        let alice = alice_machine.get_sas(bob.user_id(), bob.flow_id().as_str()).unwrap();
+
+        let mut changes = alice.changes();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Started)));
+
+        let content = OutgoingContent::try_from(alice.accept().unwrap()).unwrap();
+        let content = AcceptContent::try_from(&content).unwrap().into();
+        let content = bob.receive_any_event(alice.user_id(), &content).unwrap();
+
+        alice_machine
+            .receive_any_event(&wrap_any_to_device_content(bob.user_id(), content))
+            .await
+            .unwrap();
+
+        match changes.next().await {
+            Some(VerificationChange::KeysExchanged { emoji, decimals }) => {
+                assert_eq!(emoji, bob.emoji());
+                assert_eq!(decimals, bob.decimals().unwrap());
+            }
+            c => panic!("Expected the keys to be exchanged, got {:?}", c),
+        }
+
+        let request = alice_machine.verifications.outgoing_requests().get(0).cloned().unwrap();
+        alice_machine.mark_request_as_sent(request.request_id());
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = KeyContent::try_from(&content).unwrap().into();
+        bob.receive_any_event(alice.user_id(), &content);
+
+        let request = alice.confirm().await.unwrap().0.pop().unwrap();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Confirmed)));
+
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = MacContent::try_from(&content).unwrap().into();
+        bob.receive_any_event(alice.user_id(), &content);
+
+        let request = bob.confirm().await.unwrap().0.pop().unwrap();
+        alice_machine.receive_any_event(&request_to_event(bob.user_id(), &request)).await.unwrap();
+
+        assert!(matches!(changes.next().await, Some(VerificationChange::Done)));
+        assert!(changes.next().await.is_none());
+    }
+
+    #[async_test]
+    async fn sas_changes_on_cancellation() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+        let alice = alice_machine.get_sas(bob.user_id(), bob.flow_id().as_str()).unwrap();
+
+        let mut changes = alice.changes();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Started)));
+
+        let request = bob.cancel().unwrap();
+        alice_machine.receive_any_event(&request_to_event(bob.user_id(), &request)).await.unwrap();
+
+        match changes.next().await {
+            Some(VerificationChange::Cancelled { info }) => assert!(!info.cancelled_by_us()),
+            c => panic!("Expected the verification to be cancelled, got {:?}", c),
+        }
+        assert!(changes.next().await.is_none());
+    }
+
+    #[async_test]
+    async fn request_changes_from_events() {
+        let (alice_machine, bob_machine) = setup_machine_pair().await;
+
+        let (bob_request, request) = bob_machine
+            .request_to_device_verification(alice_id(), vec![alice_device_id().to_owned()], None)
+            .await;
+
+        let mut bob_changes = bob_request.changes();
+        assert!(matches!(bob_changes.next().await, Some(VerificationChange::Requested)));
+
+        alice_machine.receive_any_event(&request_to_event(bob_id(), &request)).await.unwrap();
+
+        let alice_request =
+            alice_machine.get_request(bob_id(), bob_request.flow_id().as_str()).unwrap();
+        let mut alice_changes = alice_request.changes();
+        assert!(matches!(alice_changes.next().await, Some(VerificationChange::Requested)));
+
+        let request = alice_request.accept().unwrap();
+        assert!(matches!(alice_changes.next().await, Some(VerificationChange::Ready)));
+
+        bob_machine.receive_any_event(&request_to_event(alice_id(), &request)).await.unwrap();
+        assert!(matches!(bob_changes.next().await, Some(VerificationChange::Ready)));
+
+        let (_, request) = bob_request.start_sas().await.unwrap().unwrap();
+        assert!(matches!(bob_changes.next().await, Some(VerificationChange::Started)));
+
+        alice_machine.receive_any_event(&request_to_event(bob_id(), &request)).await.unwrap();
+        assert!(matches!(alice_changes.next().await, Some(VerificationChange::Started)));
+
+        let request = bob_request.cancel().unwrap();
+        match bob_changes.next().await {
+            Some(VerificationChange::Cancelled { info }) => assert!(info.cancelled_by_us()),
+            c => panic!("Expected the request to be cancelled, got {:?}", c),
+        }
+        assert!(bob_changes.next().await.is_none());
+
+        alice_machine.receive_any_event(&request_to_event(bob_id(), &request)).await.unwrap();
+        match alice_changes.next().await {
+            Some(VerificationChange::Cancelled { info }) => assert!(!info.cancelled_by_us()),
+            c => panic!("Expected the request to be cancelled, got {:?}", c),
+        }
+        assert!(alice_changes.next().await.is_none());
+    }
+
+    #[cfg(feature = "qrcode")]
+    #[async_test]
+    async fn qr_changes_from_events() {
+        use matrix_qrcode::QrVerificationData;
+        use ruma::events::key::verification::VerificationMethod;
+
+        use crate::store::{Changes, CryptoStore};
+
+        // A self-verification, the first device has our private cross signing
+        // keys and shows the QR code, the second one scans it.
+        let first = ReadOnlyAccount::new(alice_id(), alice_device_id());
+        let second = ReadOnlyAccount::new(alice_id(), device_id!("ALICE2"));
+
+        let private_identity = PrivateCrossSigningIdentity::new(alice_id().to_owned()).await;
+        let identity = private_identity.to_public_identity().await.unwrap();
+
+        let first_store = MemoryStore::new();
+        let mut changes = Changes::default();
+        changes.identities.new.push(identity.clone().into());
+        changes.devices.new.push(ReadOnlyDevice::from_account(&second).await);
+        first_store.save_changes(changes).await.unwrap();
+
+        let second_store = MemoryStore::new();
+        let mut changes = Changes::default();
+        changes.identities.new.push(identity.into());
+        changes.devices.new.push(ReadOnlyDevice::from_account(&first).await);
+        second_store.save_changes(changes).await.unwrap();
+
+        let first_machine = VerificationMachine::new(
+            first,
+            Arc::new(Mutex::new(private_identity)),
+            Arc::new(first_store),
+        );
+        let second_machine = VerificationMachine::new(
+            second,
+            Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(alice_id().to_owned()))),
+            Arc::new(second_store),
+        );
+
+        let methods = vec![
+            VerificationMethod::QrCodeScanV1,
+            VerificationMethod::QrCodeShowV1,
+            VerificationMethod::ReciprocateV1,
+        ];
+        let (second_request, request) = second_machine
+            .request_to_device_verification(
+                alice_id(),
+                vec![alice_device_id().to_owned()],
+                Some(methods),
+            )
+            .await;
+        first_machine.receive_any_event(&request_to_event(alice_id(), &request)).await.unwrap();
+
+        let first_request =
+            first_machine.get_request(alice_id(), second_request.flow_id().as_str()).unwrap();
+        let request = first_request.accept().unwrap();
+        second_machine.receive_any_event(&request_to_event(alice_id(), &request)).await.unwrap();
+
+        let first_qr = first_request.generate_qr_code().await.unwrap().unwrap();
+        let mut changes = first_qr.changes();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Started)));
+
+        let data = QrVerificationData::from_bytes(first_qr.to_bytes().unwrap()).unwrap();
+        let second_qr = second_request.scan_qr_code(data).await.unwrap().unwrap();
+
+        let request = second_qr.reciprocate().unwrap();
+        first_machine.receive_any_event(&request_to_event(alice_id(), &request)).await.unwrap();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Scanned)));
+
+        let request = second_qr.cancel().unwrap();
+        first_machine.receive_any_event(&request_to_event(alice_id(), &request)).await.unwrap();
+
+        match changes.next().await {
+            Some(VerificationChange::Cancelled { info }) => assert!(!info.cancelled_by_us()),
+            c => panic!("Expected the verification to be cancelled, got {:?}", c),
+        }
+        assert!(changes.next().await.is_none());
+    }
+
     #[cfg(not(target_os = "macos"))]
     #[async_test]
     async fn timing_out() {
@@ -22,10 +22,13 @@ mod sas;
 
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::Arc,
+    mem::discriminant,
+    sync::{Arc, Mutex as StdMutex},
 };
 
 use event_enums::OutgoingContent;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::Stream;
 pub use machine::VerificationMachine;
 use matrix_sdk_common::locks::Mutex;
 #[cfg(feature = "qrcode")]
@@ -219,6 +222,25 @@ impl Verification {
             Verification::QrV1(v) => v.is_self_verification(),
         }
     }
+
+    /// Get a stream of changes of this verification flow.
+    ///
+    /// See [`Sas::changes()`] for more info.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        match self {
+            Verification::SasV1(s) => s.subscribe_changes(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.subscribe_changes(),
+        }
+    }
+
+    pub(crate) fn notify_changes(&self) {
+        match self {
+            Verification::SasV1(s) => s.notify_changes(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.notify_changes(),
+        }
+    }
 }
 
 impl From<Sas> for Verification {
@@ -296,6 +318,103 @@ impl From<Cancelled> for CancelInfo {
     }
 }
 
+/// A change in the state of a verification request or of a verification flow.
+///
+/// Changes are delivered by the `changes()` stream of the
+/// [`VerificationRequest`], [`Sas`] and `QrVerification` objects.
+#[derive(Clone, Debug)]
+pub enum VerificationChange {
+    /// The verification request has been sent or received but it wasn't yet
+    /// accepted.
+    Requested,
+    /// The verification request has been accepted, a verification flow can now
+    /// be started.
+    Ready,
+    /// A verification flow has been started.
+    Started,
+    /// The other side has scanned our QR code, the user now needs to confirm
+    /// that the scan was done using the `confirm_scanning()` method.
+    Scanned,
+    /// The keys have been exchanged and the short auth string can be presented
+    /// to the user.
+    KeysExchanged {
+        /// The emoji version of the short auth string, if the emoji method was
+        /// agreed on.
+        emoji: Option<[Emoji; 7]>,
+        /// The decimal version of the short auth string.
+        decimals: (u16, u16, u16),
+    },
+    /// We have confirmed the verification, we're now waiting for the other
+    /// side to do the same.
+    Confirmed,
+    /// The verification finished successfully.
+    Done,
+    /// The verification has been cancelled.
+    Cancelled {
+        /// Info about the cancellation.
+        info: CancelInfo,
+    },
+}
+
+impl VerificationChange {
+    fn is_final(&self) -> bool {
+        matches!(self, VerificationChange::Done | VerificationChange::Cancelled { .. })
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChangeSubscribers {
+    last_change: Option<VerificationChange>,
+    senders: Vec<UnboundedSender<VerificationChange>>,
+}
+
+impl ChangeSubscribers {
+    fn broadcast(&mut self, change: VerificationChange) {
+        if self.last_change.as_ref().map_or(false, |c| discriminant(c) == discriminant(&change)) {
+            return;
+        }
+
+        self.senders.retain(|s| s.unbounded_send(change.clone()).is_ok());
+
+        // Dropping the senders will terminate the streams, no further changes
+        // are possible once we're done or cancelled.
+        if change.is_final() {
+            self.senders.clear();
+        }
+
+        self.last_change = Some(change);
+    }
+}
+
+/// Helper to deliver `VerificationChange`s to the streams that were handed out
+/// by a verification object.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChangeNotifier {
+    inner: Arc<StdMutex<ChangeSubscribers>>,
+}
+
+impl ChangeNotifier {
+    /// Send out the given change if it differs from the last one we sent out.
+    pub fn notify(&self, change: VerificationChange) {
+        self.inner.lock().unwrap().broadcast(change)
+    }
+
+    /// Create a new stream of changes, the stream will immediately yield the
+    /// given current state of the verification object.
+    pub fn subscribe(&self, current: VerificationChange) -> UnboundedReceiver<VerificationChange> {
+        let mut inner = self.inner.lock().unwrap();
+        let (sender, receiver) = unbounded();
+
+        inner.broadcast(current.clone());
+
+        if sender.unbounded_send(current.clone()).is_ok() && !current.is_final() {
+            inner.senders.push(sender);
+        }
+
+        receiver
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Cancelled {
     cancelled_by_us: bool,
@@ -740,6 +859,9 @@ pub(crate) mod test {
             AnyToDeviceEventContent::KeyVerificationDone(c) => {
                 AnyToDeviceEvent::KeyVerificationDone(ToDeviceEvent { sender, content: c })
             }
+            AnyToDeviceEventContent::KeyVerificationCancel(c) => {
+                AnyToDeviceEvent::KeyVerificationCancel(ToDeviceEvent { sender, content: c })
+            }
 
             _ => unreachable!(),
         }
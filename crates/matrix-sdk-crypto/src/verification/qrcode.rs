@@ -14,6 +14,8 @@
 
 use std::sync::{Arc, Mutex};
 
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::Stream;
 use matrix_qrcode::{
     qrcode::QrCode, EncodingError, QrVerificationData, SelfVerificationData,
     SelfVerificationNoMasterKey, VerificationData,
@@ -41,8 +43,8 @@ use tracing::trace;
 use super::{
     event_enums::{CancelContent, DoneContent, OutgoingContent, OwnedStartContent, StartContent},
     requests::RequestHandle,
-    CancelInfo, Cancelled, Done, FlowId, IdentitiesBeingVerified, VerificationResult,
-    VerificationStore,
+    CancelInfo, Cancelled, ChangeNotifier, Done, FlowId, IdentitiesBeingVerified,
+    VerificationChange, VerificationResult, VerificationStore,
 };
 use crate::{
     olm::PrivateCrossSigningIdentity, CryptoStoreError, OutgoingVerificationRequest,
@@ -95,6 +97,7 @@ pub struct QrVerification {
     identities: IdentitiesBeingVerified,
     request_handle: Option<RequestHandle>,
     we_started: bool,
+    changes: ChangeNotifier,
 }
 
 impl std::fmt::Debug for QrVerification {
@@ -233,7 +236,7 @@ impl QrVerification {
         let new_state = QrState::<Cancelled>::new(true, code);
         let content = new_state.as_content(self.flow_id());
 
-        match &*state {
+        let request = match &*state {
             InnerState::Confirmed(_)
             | InnerState::Created(_)
             | InnerState::Scanned(_)
@@ -243,7 +246,12 @@ impl QrVerification {
                 Some(self.content_to_request(content))
             }
             InnerState::Cancelled(_) => None,
-        }
+        };
+
+        drop(state);
+        self.notify_changes();
+
+        request
     }
 
     /// Notify the other side that we have successfully scanned the QR code and
@@ -268,7 +276,7 @@ impl QrVerification {
     pub fn confirm_scanning(&self) -> Option<OutgoingVerificationRequest> {
         let mut state = self.state.lock().unwrap();
 
-        match &*state {
+        let request = match &*state {
             InnerState::Scanned(s) => {
                 let new_state = s.clone().confirm_scanning();
                 let content = new_state.as_content(&self.flow_id);
@@ -281,6 +289,41 @@ impl QrVerification {
             | InnerState::Confirmed(_)
             | InnerState::Reciprocated(_)
             | InnerState::Done(_) => None,
+        };
+
+        drop(state);
+        self.notify_changes();
+
+        request
+    }
+
+    /// Get a stream of changes of this QR code verification flow.
+    ///
+    /// The stream will first yield the current state of the verification flow,
+    /// after that a new item is yielded every time the flow moves on, e.g.
+    /// once the other side has scanned our QR code. The stream terminates once
+    /// the verification is done or has been cancelled.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.subscribe_changes()
+    }
+
+    pub(crate) fn subscribe_changes(&self) -> UnboundedReceiver<VerificationChange> {
+        self.changes.subscribe(self.current_change())
+    }
+
+    pub(crate) fn notify_changes(&self) {
+        self.changes.notify(self.current_change())
+    }
+
+    fn current_change(&self) -> VerificationChange {
+        match &*self.state.lock().unwrap() {
+            InnerState::Created(_) | InnerState::Reciprocated(_) => VerificationChange::Started,
+            InnerState::Scanned(_) => VerificationChange::Scanned,
+            InnerState::Confirmed(_) => VerificationChange::Confirmed,
+            InnerState::Done(_) => VerificationChange::Done,
+            InnerState::Cancelled(c) => {
+                VerificationChange::Cancelled { info: c.state.clone().into() }
+            }
         }
     }
 
@@ -601,6 +644,7 @@ impl QrVerification {
             identities,
             we_started,
             request_handle,
+            changes: ChangeNotifier::default(),
         })
     }
 
@@ -620,6 +664,7 @@ impl QrVerification {
             identities,
             we_started,
             request_handle,
+            changes: ChangeNotifier::default(),
         }
     }
 }
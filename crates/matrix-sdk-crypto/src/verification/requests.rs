@@ -17,6 +17,8 @@ use std::{
     time::Duration,
 };
 
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::Stream;
 #[cfg(feature = "qrcode")]
 use matrix_qrcode::QrVerificationData;
 use matrix_sdk_common::{instant::Instant, util::milli_seconds_since_unix_epoch};
@@ -44,7 +46,8 @@ use super::{
     event_enums::{
         CancelContent, DoneContent, OutgoingContent, ReadyContent, RequestContent, StartContent,
     },
-    CancelInfo, Cancelled, FlowId, Verification, VerificationStore,
+    CancelInfo, Cancelled, ChangeNotifier, FlowId, Verification, VerificationChange,
+    VerificationStore,
 };
 #[cfg(feature = "qrcode")]
 use super::{
@@ -83,6 +86,7 @@ pub struct VerificationRequest {
     creation_time: Arc<Instant>,
     we_started: bool,
     recipient_devices: Arc<Vec<Box<DeviceId>>>,
+    changes: ChangeNotifier,
 }
 
 /// A handle to a request so child verification flows can cancel the request.
@@ -96,17 +100,17 @@ pub struct VerificationRequest {
 #[derive(Clone, Debug)]
 pub(crate) struct RequestHandle {
     inner: Arc<Mutex<InnerRequest>>,
+    changes: ChangeNotifier,
 }
 
 impl RequestHandle {
     pub fn cancel_with_code(&self, cancel_code: &CancelCode) {
-        self.inner.lock().unwrap().cancel(true, cancel_code)
-    }
-}
+        let mut inner = self.inner.lock().unwrap();
+        inner.cancel(true, cancel_code);
 
-impl From<Arc<Mutex<InnerRequest>>> for RequestHandle {
-    fn from(inner: Arc<Mutex<InnerRequest>>) -> Self {
-        Self { inner }
+        if let InnerRequest::Cancelled(c) = &*inner {
+            self.changes.notify(VerificationChange::Cancelled { info: c.state.clone().into() });
+        }
     }
 }
 
@@ -141,9 +145,14 @@ impl VerificationRequest {
             creation_time: Instant::now().into(),
             we_started: true,
             recipient_devices: recipient_devices.into(),
+            changes: ChangeNotifier::default(),
         }
     }
 
+    fn request_handle(&self) -> RequestHandle {
+        RequestHandle { inner: self.inner.clone(), changes: self.changes.clone() }
+    }
+
     /// Create an event content that can be sent as a to-device event to request
     /// verification from the other side. This should be used only for
     /// self-verifications and it should be sent to the specific device that we
@@ -313,7 +322,7 @@ impl VerificationRequest {
     pub async fn generate_qr_code(&self) -> Result<Option<QrVerification>, CryptoStoreError> {
         let inner = self.inner.lock().unwrap().clone();
 
-        inner.generate_qr_code(self.we_started, self.inner.clone().into()).await
+        inner.generate_qr_code(self.we_started, self.request_handle()).await
     }
 
     /// Start a QR code verification by providing a scanned QR code for this
@@ -337,7 +346,7 @@ impl VerificationRequest {
                 r.flow_id.as_ref().to_owned(),
                 data,
                 self.we_started,
-                Some(self.inner.clone().into()),
+                Some(self.request_handle()),
             ))
         } else {
             None
@@ -346,6 +355,7 @@ impl VerificationRequest {
         if let Some(future) = fut {
             let qr_verification = future.await?;
             self.verification_cache.insert_qr(qr_verification.clone());
+            self.notify_changes();
 
             Ok(Some(qr_verification))
         } else {
@@ -379,6 +389,44 @@ impl VerificationRequest {
             we_started: false,
             creation_time: Instant::now().into(),
             recipient_devices: vec![].into(),
+            changes: ChangeNotifier::default(),
+        }
+    }
+
+    /// Get a stream of changes of this verification request.
+    ///
+    /// The stream will first yield the current state of the request, after
+    /// that a new item is yielded every time the request moves on, e.g. once
+    /// the other side accepts the request or once a verification flow has been
+    /// started. The stream terminates once the request is done or has been
+    /// cancelled.
+    ///
+    /// The verification flow that was started from this request, available
+    /// using the `VerificationMachine`, offers its own, more detailed,
+    /// stream of changes.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.changes.subscribe(self.current_change())
+    }
+
+    pub(crate) fn notify_changes(&self) {
+        self.changes.notify(self.current_change())
+    }
+
+    fn current_change(&self) -> VerificationChange {
+        match &*self.inner.lock().unwrap() {
+            InnerRequest::Created(_) | InnerRequest::Requested(_) => VerificationChange::Requested,
+            InnerRequest::Ready(_) | InnerRequest::Passive(_) => {
+                if self.verification_cache.get(self.other_user(), self.flow_id().as_str()).is_some()
+                {
+                    VerificationChange::Started
+                } else {
+                    VerificationChange::Ready
+                }
+            }
+            InnerRequest::Done(_) => VerificationChange::Done,
+            InnerRequest::Cancelled(c) => {
+                VerificationChange::Cancelled { info: c.state.clone().into() }
+            }
         }
     }
 
@@ -394,14 +442,19 @@ impl VerificationRequest {
     ) -> Option<OutgoingVerificationRequest> {
         let mut inner = self.inner.lock().unwrap();
 
-        inner.accept(methods).map(|c| match c {
+        let request = inner.accept(methods).map(|c| match c {
             OutgoingContent::ToDevice(content) => {
                 ToDeviceRequest::new(self.other_user(), inner.other_device_id(), content).into()
             }
             OutgoingContent::Room(room_id, content) => {
                 RoomMessageRequest { room_id, txn_id: TransactionId::new(), content }.into()
             }
-        })
+        });
+
+        drop(inner);
+        self.notify_changes();
+
+        request
     }
 
     /// Accept the verification request.
@@ -470,6 +523,8 @@ impl VerificationRequest {
             };
         }
 
+        self.notify_changes();
+
         request
     }
 
@@ -583,7 +638,7 @@ impl VerificationRequest {
         let inner = self.inner.lock().unwrap().clone();
 
         if let InnerRequest::Ready(s) = inner {
-            s.receive_start(sender, content, self.we_started, self.inner.clone().into()).await?;
+            s.receive_start(sender, content, self.we_started, self.request_handle()).await?;
         } else {
             warn!(
                 sender = sender.as_str(),
@@ -642,11 +697,12 @@ impl VerificationRequest {
                         s.store.clone(),
                         s.private_cross_signing_identity.clone(),
                         self.we_started,
-                        self.inner.clone().into(),
+                        self.request_handle(),
                     )
                     .await?
                 {
                     self.verification_cache.insert_sas(sas.clone());
+                    self.notify_changes();
 
                     let request = match content {
                         OutgoingContent::ToDevice(content) => ToDeviceRequest::new(
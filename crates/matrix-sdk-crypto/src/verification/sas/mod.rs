@@ -18,6 +18,8 @@ mod sas_state;
 
 use std::sync::{Arc, Mutex};
 
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::Stream;
 use inner_sas::InnerSas;
 #[cfg(test)]
 use matrix_sdk_common::instant::Instant;
@@ -34,7 +36,8 @@ use tracing::trace;
 use super::{
     event_enums::{AnyVerificationContent, OutgoingContent, OwnedAcceptContent, StartContent},
     requests::RequestHandle,
-    CancelInfo, FlowId, IdentitiesBeingVerified, VerificationResult, VerificationStore,
+    CancelInfo, ChangeNotifier, FlowId, IdentitiesBeingVerified, VerificationChange,
+    VerificationResult, VerificationStore,
 };
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
@@ -53,6 +56,7 @@ pub struct Sas {
     flow_id: Arc<FlowId>,
     we_started: bool,
     request_handle: Option<RequestHandle>,
+    changes: ChangeNotifier,
 }
 
 impl Sas {
@@ -169,6 +173,7 @@ impl Sas {
             flow_id,
             we_started,
             request_handle,
+            changes: ChangeNotifier::default(),
         }
     }
 
@@ -384,17 +389,21 @@ impl Sas {
             )
         }
 
-        if done {
+        let result = if done {
             match self.mark_as_done().await? {
                 VerificationResult::Cancel(c) => {
-                    Ok((self.cancel_with_code(c).into_iter().collect(), None))
+                    (self.cancel_with_code(c).into_iter().collect(), None)
                 }
-                VerificationResult::Ok => Ok((mac_requests, None)),
-                VerificationResult::SignatureUpload(r) => Ok((mac_requests, Some(r))),
+                VerificationResult::Ok => (mac_requests, None),
+                VerificationResult::SignatureUpload(r) => (mac_requests, Some(r)),
             }
         } else {
-            Ok((mac_requests, None))
-        }
+            (mac_requests, None)
+        };
+
+        self.notify_changes();
+
+        Ok(result)
     }
 
     pub(crate) async fn mark_as_done(&self) -> Result<VerificationResult, CryptoStoreError> {
@@ -436,6 +445,10 @@ impl Sas {
         let sas: InnerSas = (*guard).clone();
         let (sas, content) = sas.cancel(true, code);
         *guard = sas;
+        drop(guard);
+
+        self.notify_changes();
+
         content.map(|c| match c {
             OutgoingContent::Room(room_id, content) => {
                 RoomMessageRequest { room_id, txn_id: TransactionId::new(), content }.into()
@@ -501,6 +514,56 @@ impl Sas {
         self.inner.lock().unwrap().decimals()
     }
 
+    /// Get a stream of changes of this SAS verification flow.
+    ///
+    /// The stream will first yield the current state of the verification flow,
+    /// after that a new item is yielded every time the flow moves on, e.g.
+    /// once the short auth string can be presented to the user. The stream
+    /// terminates once the verification is done or has been cancelled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::stream::StreamExt;
+    /// # use matrix_sdk_crypto::{Sas, VerificationChange};
+    /// # async fn example(sas: Sas) {
+    /// let mut changes = sas.changes();
+    ///
+    /// while let Some(change) = changes.next().await {
+    ///     if let VerificationChange::KeysExchanged { emoji, decimals } = change {
+    ///         println!("Do the emoji {:?} or decimals {:?} match?", emoji, decimals);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.subscribe_changes()
+    }
+
+    pub(crate) fn subscribe_changes(&self) -> UnboundedReceiver<VerificationChange> {
+        self.changes.subscribe(self.current_change())
+    }
+
+    pub(crate) fn notify_changes(&self) {
+        self.changes.notify(self.current_change())
+    }
+
+    fn current_change(&self) -> VerificationChange {
+        let inner = self.inner.lock().unwrap();
+
+        if let InnerSas::Cancelled(c) = &*inner {
+            VerificationChange::Cancelled { info: c.state.as_ref().clone().into() }
+        } else if inner.is_done() {
+            VerificationChange::Done
+        } else if inner.have_we_confirmed() {
+            VerificationChange::Confirmed
+        } else if let Some(decimals) = inner.decimals() {
+            VerificationChange::KeysExchanged { emoji: inner.emoji(), decimals }
+        } else {
+            VerificationChange::Started
+        }
+    }
+
     pub(crate) fn receive_any_event(
         &self,
         sender: &UserId,
@@ -560,6 +623,7 @@ impl AcceptSettings {
 mod test {
     use std::{convert::TryFrom, sync::Arc};
 
+    use futures::StreamExt;
     use matrix_sdk_test::async_test;
     use ruma::{device_id, user_id, DeviceId, UserId};
 
@@ -569,7 +633,7 @@ mod test {
         store::MemoryStore,
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, OutgoingContent, StartContent},
-            VerificationStore,
+            VerificationChange, VerificationStore,
         },
         ReadOnlyAccount, ReadOnlyDevice,
     };
@@ -671,4 +735,93 @@ mod test {
         assert!(alice.verified_devices().unwrap().contains(alice.other_device()));
         assert!(bob.verified_devices().unwrap().contains(bob.other_device()));
     }
+
+    #[async_test]
+    async fn sas_changes() {
+        let alice = ReadOnlyAccount::new(alice_id(), alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let bob = ReadOnlyAccount::new(bob_id(), bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+
+        let alice_store =
+            VerificationStore { account: alice.clone(), inner: Arc::new(MemoryStore::new()) };
+
+        let bob_store = MemoryStore::new();
+        bob_store.save_devices(vec![alice_device.clone()]).await;
+
+        let bob_store = VerificationStore { account: bob.clone(), inner: Arc::new(bob_store) };
+
+        let (alice, content) = Sas::start(
+            PrivateCrossSigningIdentity::empty(alice_id().to_owned()),
+            bob_device,
+            alice_store,
+            None,
+            None,
+            None,
+            true,
+            None,
+        );
+
+        let mut changes = alice.changes();
+        assert!(matches!(changes.next().await, Some(VerificationChange::Started)));
+
+        let flow_id = alice.flow_id().to_owned();
+        let content = StartContent::try_from(&content).unwrap();
+
+        let bob = Sas::from_start_event(
+            flow_id,
+            &content,
+            bob_store,
+            PrivateCrossSigningIdentity::empty(bob_id().to_owned()),
+            alice_device,
+            None,
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+
+        let request = bob.accept().unwrap();
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = AcceptContent::try_from(&content).unwrap();
+
+        // The verification machine notifies the listeners after every event,
+        // we need to do so manually here.
+        let content = alice.receive_any_event(bob.user_id(), &content.into()).unwrap();
+        alice.notify_changes();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        let content = bob.receive_any_event(alice.user_id(), &content.into()).unwrap();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        alice.receive_any_event(bob.user_id(), &content.into());
+        alice.notify_changes();
+
+        match changes.next().await {
+            Some(VerificationChange::KeysExchanged { emoji, decimals }) => {
+                assert_eq!(emoji, bob.emoji());
+                assert_eq!(decimals, bob.decimals().unwrap());
+            }
+            c => panic!("Expected the keys to be exchanged, got {:?}", c),
+        }
+
+        let mut requests = alice.confirm().await.unwrap().0;
+        assert!(matches!(changes.next().await, Some(VerificationChange::Confirmed)));
+
+        let request = requests.pop().unwrap();
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = MacContent::try_from(&content).unwrap();
+        bob.receive_any_event(alice.user_id(), &content.into());
+
+        let mut requests = bob.confirm().await.unwrap().0;
+        let request = requests.pop().unwrap();
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = MacContent::try_from(&content).unwrap();
+        alice.receive_any_event(bob.user_id(), &content.into());
+        alice.notify_changes();
+
+        assert!(matches!(changes.next().await, Some(VerificationChange::Done)));
+        assert!(changes.next().await.is_none());
+    }
 }
@@ -7,10 +7,11 @@ use std::{
     },
 };
 
+use futures::StreamExt;
 use matrix_sdk::{
     self,
     config::SyncSettings,
-    encryption::verification::{SasVerification, Verification},
+    encryption::verification::{Emoji, SasVerification, Verification, VerificationChange},
     ruma::{
         events::{
             room::message::MessageType, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
@@ -21,22 +22,42 @@ use matrix_sdk::{
 };
 use url::Url;
 
-async fn wait_for_confirmation(client: Client, sas: SasVerification) {
-    println!("Does the emoji match: {:?}", sas.emoji());
+async fn wait_for_confirmation(sas: SasVerification, emoji: Option<[Emoji; 7]>) {
+    println!("Does the emoji match: {:?}", emoji);
 
     let mut input = String::new();
     io::stdin().read_line(&mut input).expect("error: unable to read user input");
 
     match input.trim().to_lowercase().as_ref() {
-        "yes" | "true" | "ok" => {
-            sas.confirm().await.unwrap();
+        "yes" | "true" | "ok" => sas.confirm().await.unwrap(),
+        _ => sas.cancel().await.unwrap(),
+    }
+}
 
-            if sas.is_done() {
+async fn sas_verification_handler(client: Client, sas: SasVerification) {
+    println!(
+        "Starting verification with {} {}",
+        &sas.other_device().user_id(),
+        &sas.other_device().device_id()
+    );
+    print_devices(sas.other_device().user_id(), &client).await;
+
+    let mut changes = sas.changes();
+
+    while let Some(change) = changes.next().await {
+        match change {
+            VerificationChange::KeysExchanged { emoji, .. } => {
+                tokio::spawn(wait_for_confirmation(sas.clone(), emoji));
+            }
+            VerificationChange::Done => {
                 print_result(&sas);
                 print_devices(sas.other_device().user_id(), &client).await;
             }
+            VerificationChange::Cancelled { info } => {
+                println!("The verification has been cancelled: {}", info.reason());
+            }
+            _ => (),
         }
-        _ => sas.cancel().await.unwrap(),
     }
 }
 
@@ -90,37 +111,11 @@ async fn login(
                             .get_verification(&e.sender, e.content.transaction_id.as_str())
                             .await
                         {
-                            println!(
-                                "Starting verification with {} {}",
-                                &sas.other_device().user_id(),
-                                &sas.other_device().device_id()
-                            );
-                            print_devices(&e.sender, client).await;
+                            tokio::spawn(sas_verification_handler((*client).clone(), sas.clone()));
                             sas.accept().await.unwrap();
                         }
                     }
 
-                    AnyToDeviceEvent::KeyVerificationKey(e) => {
-                        if let Some(Verification::SasV1(sas)) = client
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
-                            .await
-                        {
-                            tokio::spawn(wait_for_confirmation((*client).clone(), sas));
-                        }
-                    }
-
-                    AnyToDeviceEvent::KeyVerificationMac(e) => {
-                        if let Some(Verification::SasV1(sas)) = client
-                            .get_verification(&e.sender, e.content.transaction_id.as_str())
-                            .await
-                        {
-                            if sas.is_done() {
-                                print_result(&sas);
-                                print_devices(&e.sender, client).await;
-                            }
-                        }
-                    }
-
                     _ => (),
                 }
             }
@@ -146,18 +141,7 @@ async fn login(
                                             .expect("Can't accept verification request");
                                     }
                                 }
-                                AnySyncMessageEvent::KeyVerificationKey(e) => {
-                                    if let Some(Verification::SasV1(sas)) = client
-                                        .get_verification(
-                                            &e.sender,
-                                            e.content.relates_to.event_id.as_str(),
-                                        )
-                                        .await
-                                    {
-                                        tokio::spawn(wait_for_confirmation((*client).clone(), sas));
-                                    }
-                                }
-                                AnySyncMessageEvent::KeyVerificationMac(e) => {
+                                AnySyncMessageEvent::KeyVerificationStart(e) => {
                                     if let Some(Verification::SasV1(sas)) = client
                                         .get_verification(
                                             &e.sender,
@@ -165,10 +149,10 @@ async fn login(
                                         )
                                         .await
                                     {
-                                        if sas.is_done() {
-                                            print_result(&sas);
-                                            print_devices(&e.sender, client).await;
-                                        }
+                                        tokio::spawn(sas_verification_handler(
+                                            (*client).clone(),
+                                            sas,
+                                        ));
                                     }
                                 }
                                 _ => (),
@@ -0,0 +1,653 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types to configure how attachments are sent with
+//! [`Joined::send_attachment()`].
+//!
+//! If the `image` feature is enabled, the dimensions and the [BlurHash] of
+//! image attachments are computed and a thumbnail is generated and sent
+//! alongside of them, unless this was disabled in the [`AttachmentConfig`].
+//!
+//! [`Joined::send_attachment()`]: crate::room::Joined::send_attachment
+//! [BlurHash]: https://blurha.sh
+
+use std::{convert::TryFrom, time::Duration};
+
+use mime::Mime;
+use ruma::{
+    assign,
+    events::{
+        room::{
+            message::{
+                AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+                ImageMessageEventContent, InReplyTo, MessageType, Relation,
+                RoomMessageEventContent, VideoInfo, VideoMessageEventContent,
+            },
+            EncryptedFile, ImageInfo, ThumbnailInfo,
+        },
+        sticker::StickerEventContent,
+        AnyMessageEventContent,
+    },
+    EventId, MxcUri, TransactionId, UInt,
+};
+
+/// The default maximum width of generated thumbnails.
+#[cfg(feature = "image")]
+const DEFAULT_THUMBNAIL_WIDTH: u32 = 800;
+/// The default maximum height of generated thumbnails.
+#[cfg(feature = "image")]
+const DEFAULT_THUMBNAIL_HEIGHT: u32 = 600;
+
+/// Base metadata about an image.
+#[derive(Clone, Debug, Default)]
+pub struct BaseImageInfo {
+    /// The height of the image in pixels.
+    pub height: Option<UInt>,
+    /// The width of the image in pixels.
+    pub width: Option<UInt>,
+    /// The file size of the image in bytes.
+    pub size: Option<UInt>,
+    /// The [BlurHash](https://blurha.sh) of the image.
+    pub blurhash: Option<String>,
+}
+
+impl BaseImageInfo {
+    /// Fill the fields that aren't set yet with the values of `other`.
+    #[cfg(feature = "image")]
+    fn merge(self, other: BaseImageInfo) -> Self {
+        Self {
+            height: self.height.or(other.height),
+            width: self.width.or(other.width),
+            size: self.size.or(other.size),
+            blurhash: self.blurhash.or(other.blurhash),
+        }
+    }
+}
+
+/// Base metadata about a video.
+#[derive(Clone, Debug, Default)]
+pub struct BaseVideoInfo {
+    /// The duration of the video.
+    pub duration: Option<Duration>,
+    /// The height of the video in pixels.
+    pub height: Option<UInt>,
+    /// The width of the video in pixels.
+    pub width: Option<UInt>,
+    /// The file size of the video in bytes.
+    pub size: Option<UInt>,
+    /// The [BlurHash](https://blurha.sh) of the video.
+    pub blurhash: Option<String>,
+}
+
+/// Base metadata about an audio clip.
+#[derive(Clone, Debug, Default)]
+pub struct BaseAudioInfo {
+    /// The duration of the audio clip.
+    pub duration: Option<Duration>,
+    /// The file size of the audio clip in bytes.
+    pub size: Option<UInt>,
+}
+
+/// Base metadata about a file.
+#[derive(Clone, Debug, Default)]
+pub struct BaseFileInfo {
+    /// The size of the file in bytes.
+    pub size: Option<UInt>,
+}
+
+/// The metadata of an attachment.
+#[derive(Clone, Debug)]
+pub enum AttachmentInfo {
+    /// The metadata of an image or a sticker.
+    Image(BaseImageInfo),
+    /// The metadata of a video.
+    Video(BaseVideoInfo),
+    /// The metadata of an audio clip.
+    Audio(BaseAudioInfo),
+    /// The metadata of a file.
+    File(BaseFileInfo),
+}
+
+/// The kind of event an attachment is sent as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentType {
+    /// An `m.image` message.
+    Image,
+    /// An `m.video` message.
+    Video,
+    /// An `m.audio` message.
+    Audio,
+    /// An `m.file` message.
+    File,
+    /// An `m.sticker` event.
+    ///
+    /// The media of stickers is never encrypted, even in encrypted rooms.
+    Sticker,
+}
+
+impl AttachmentType {
+    /// Get the type of attachment that fits the given `content_type` best.
+    pub fn from_content_type(content_type: &Mime) -> Self {
+        match content_type.type_() {
+            mime::IMAGE => Self::Image,
+            mime::VIDEO => Self::Video,
+            mime::AUDIO => Self::Audio,
+            _ => Self::File,
+        }
+    }
+}
+
+/// Base metadata about a thumbnail.
+#[derive(Clone, Debug, Default)]
+pub struct BaseThumbnailInfo {
+    /// The height of the thumbnail in pixels.
+    pub height: Option<UInt>,
+    /// The width of the thumbnail in pixels.
+    pub width: Option<UInt>,
+    /// The file size of the thumbnail in bytes.
+    pub size: Option<UInt>,
+}
+
+/// A thumbnail to upload and send with an attachment.
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    /// The raw bytes of the thumbnail.
+    pub data: Vec<u8>,
+    /// The type of the thumbnail, this will be used as the content-type
+    /// header.
+    pub content_type: Mime,
+    /// The metadata of the thumbnail.
+    pub info: Option<BaseThumbnailInfo>,
+}
+
+impl Thumbnail {
+    /// Get the `ThumbnailInfo` of this thumbnail.
+    pub(crate) fn thumbnail_info(&self) -> ThumbnailInfo {
+        let info = self.info.clone().unwrap_or_default();
+
+        assign!(ThumbnailInfo::new(), {
+            height: info.height,
+            width: info.width,
+            mimetype: Some(self.content_type.essence_str().to_owned()),
+            size: info.size.or_else(|| UInt::new(self.data.len() as u64)),
+        })
+    }
+}
+
+/// Where the uploaded media of an attachment can be found.
+#[derive(Debug)]
+pub(crate) enum AttachmentSource {
+    /// The media was uploaded unencrypted.
+    Plain(Box<MxcUri>),
+    /// The media was encrypted before it was uploaded.
+    Encrypted(Box<EncryptedFile>),
+}
+
+/// Configuration for sending an attachment.
+///
+/// # Examples
+///
+/// ```
+/// use matrix_sdk::{
+///     attachment::{AttachmentConfig, AttachmentInfo, BaseVideoInfo},
+///     ruma::{event_id, uint, TransactionId},
+/// };
+/// use std::time::Duration;
+///
+/// // Generate the transaction id up front, to show a local echo of the
+/// // message while the video is uploaded.
+/// let txn_id = TransactionId::new();
+///
+/// let config = AttachmentConfig::new()
+///     .txn_id(&txn_id)
+///     .info(AttachmentInfo::Video(BaseVideoInfo {
+///         duration: Some(Duration::from_secs(42)),
+///         width: Some(uint!(1280)),
+///         height: Some(uint!(720)),
+///         ..Default::default()
+///     }))
+///     .reply(event_id!("$h29iv0s8:example.com"));
+/// ```
+#[derive(Debug)]
+pub struct AttachmentConfig {
+    pub(crate) txn_id: Option<Box<TransactionId>>,
+    pub(crate) info: Option<AttachmentInfo>,
+    pub(crate) thumbnail: Option<Thumbnail>,
+    pub(crate) attachment_type: Option<AttachmentType>,
+    pub(crate) reply: Option<Box<EventId>>,
+    #[cfg(feature = "image")]
+    pub(crate) generate_thumbnail: bool,
+    #[cfg(feature = "image")]
+    pub(crate) thumbnail_size: (u32, u32),
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            txn_id: None,
+            info: None,
+            thumbnail: None,
+            attachment_type: None,
+            reply: None,
+            #[cfg(feature = "image")]
+            generate_thumbnail: true,
+            #[cfg(feature = "image")]
+            thumbnail_size: (DEFAULT_THUMBNAIL_WIDTH, DEFAULT_THUMBNAIL_HEIGHT),
+        }
+    }
+}
+
+impl AttachmentConfig {
+    /// Create a new default `AttachmentConfig`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the transaction id of the event.
+    ///
+    /// A unique ID that can be attached to a `MessageEvent` held in its
+    /// unsigned field as `transaction_id`. If not given one is created for the
+    /// message. Setting it up front allows to show a local echo of the event
+    /// before the media is uploaded.
+    #[must_use]
+    pub fn txn_id(mut self, txn_id: &TransactionId) -> Self {
+        self.txn_id = Some(txn_id.to_owned());
+        self
+    }
+
+    /// Set the metadata of the attachment.
+    ///
+    /// Metadata that doesn't fit the type of the attachment is ignored. For
+    /// images, the fields that are set here take precedence over the ones that
+    /// are computed from the image.
+    #[must_use]
+    pub fn info(mut self, info: AttachmentInfo) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    /// Set the thumbnail to send with the attachment.
+    ///
+    /// No thumbnail is generated if one is given here. Audio attachments
+    /// don't support thumbnails.
+    #[must_use]
+    pub fn thumbnail(mut self, thumbnail: Thumbnail) -> Self {
+        self.thumbnail = Some(thumbnail);
+        self
+    }
+
+    /// Set the kind of event the attachment is sent as.
+    ///
+    /// By default it's picked based on the content type of the attachment.
+    #[must_use]
+    pub fn attachment_type(mut self, attachment_type: AttachmentType) -> Self {
+        self.attachment_type = Some(attachment_type);
+        self
+    }
+
+    /// Send the attachment as a reply to the event with the given id.
+    ///
+    /// Stickers can't be sent as replies, this is ignored for them.
+    #[must_use]
+    pub fn reply(mut self, event_id: &EventId) -> Self {
+        self.reply = Some(event_id.to_owned());
+        self
+    }
+
+    /// Should the metadata and a thumbnail be generated for image
+    /// attachments, defaults to `true`.
+    #[cfg(feature = "image")]
+    #[must_use]
+    pub fn generate_thumbnail(mut self, generate: bool) -> Self {
+        self.generate_thumbnail = generate;
+        self
+    }
+
+    /// Set the maximum size of generated thumbnails, defaults to 800x600.
+    ///
+    /// The aspect ratio of the image is preserved, images that are smaller
+    /// than the maximum size are sent without a thumbnail.
+    #[cfg(feature = "image")]
+    #[must_use]
+    pub fn thumbnail_size(mut self, width: u32, height: u32) -> Self {
+        self.thumbnail_size = (width, height);
+        self
+    }
+
+    /// Get the kind of event the attachment with the given `content_type` is
+    /// sent as.
+    pub(crate) fn get_attachment_type(&self, content_type: &Mime) -> AttachmentType {
+        self.attachment_type.unwrap_or_else(|| AttachmentType::from_content_type(content_type))
+    }
+
+    /// Compute the metadata and the thumbnail of the image `data`, if the
+    /// config doesn't already contain them.
+    #[cfg(feature = "image")]
+    pub(crate) fn generate_image_info(&mut self, data: &[u8]) -> Result<(), image_rs::ImageError> {
+        let (info, thumbnail) = generate_image_info(data, self.thumbnail_size)?;
+
+        self.info = Some(AttachmentInfo::Image(match self.info.take() {
+            Some(AttachmentInfo::Image(user_info)) => user_info.merge(info),
+            _ => info,
+        }));
+
+        if self.thumbnail.is_none() {
+            self.thumbnail = thumbnail;
+        }
+
+        Ok(())
+    }
+
+    /// Construct the content of the event for the attachment that was
+    /// uploaded to `source`.
+    pub(crate) fn into_content(
+        self,
+        body: &str,
+        content_type: &Mime,
+        source: AttachmentSource,
+        thumbnail: Option<(AttachmentSource, ThumbnailInfo)>,
+    ) -> AnyMessageEventContent {
+        let attachment_type = self.get_attachment_type(content_type);
+        let body = body.to_owned();
+        let mimetype = Some(content_type.essence_str().to_owned());
+
+        let (thumbnail_source, thumbnail_info) = match thumbnail {
+            Some((source, info)) => (Some(source), Some(Box::new(info))),
+            None => (None, None),
+        };
+        let (thumbnail_url, thumbnail_file) = match thumbnail_source {
+            Some(AttachmentSource::Plain(url)) => (Some(url), None),
+            Some(AttachmentSource::Encrypted(file)) => (None, Some(file)),
+            None => (None, None),
+        };
+
+        let msgtype = match attachment_type {
+            AttachmentType::Image | AttachmentType::Sticker => {
+                let base = match self.info {
+                    Some(AttachmentInfo::Image(info)) => info,
+                    _ => Default::default(),
+                };
+                let info = assign!(ImageInfo::new(), {
+                    height: base.height,
+                    width: base.width,
+                    mimetype,
+                    size: base.size,
+                    blurhash: base.blurhash,
+                    thumbnail_info,
+                    thumbnail_url,
+                    thumbnail_file,
+                });
+
+                match source {
+                    AttachmentSource::Plain(url) if attachment_type == AttachmentType::Sticker => {
+                        return AnyMessageEventContent::Sticker(StickerEventContent::new(
+                            body, info, url,
+                        ));
+                    }
+                    AttachmentSource::Plain(url) => MessageType::Image(
+                        ImageMessageEventContent::plain(body, url, Some(Box::new(info))),
+                    ),
+                    AttachmentSource::Encrypted(file) => MessageType::Image(assign!(
+                        ImageMessageEventContent::encrypted(body, *file),
+                        { info: Some(Box::new(info)) }
+                    )),
+                }
+            }
+            AttachmentType::Video => {
+                let base = match self.info {
+                    Some(AttachmentInfo::Video(info)) => info,
+                    _ => Default::default(),
+                };
+                let info = Box::new(assign!(VideoInfo::new(), {
+                    duration: base.duration.and_then(|d| UInt::try_from(d.as_millis()).ok()),
+                    height: base.height,
+                    width: base.width,
+                    mimetype,
+                    size: base.size,
+                    blurhash: base.blurhash,
+                    thumbnail_info,
+                    thumbnail_url,
+                    thumbnail_file,
+                }));
+
+                MessageType::Video(match source {
+                    AttachmentSource::Plain(url) => {
+                        VideoMessageEventContent::plain(body, url, Some(info))
+                    }
+                    AttachmentSource::Encrypted(file) => assign!(
+                        VideoMessageEventContent::encrypted(body, *file),
+                        { info: Some(info) }
+                    ),
+                })
+            }
+            AttachmentType::Audio => {
+                let base = match self.info {
+                    Some(AttachmentInfo::Audio(info)) => info,
+                    _ => Default::default(),
+                };
+                let info = Box::new(assign!(AudioInfo::new(), {
+                    duration: base.duration.and_then(|d| UInt::try_from(d.as_millis()).ok()),
+                    mimetype,
+                    size: base.size,
+                }));
+
+                MessageType::Audio(match source {
+                    AttachmentSource::Plain(url) => {
+                        AudioMessageEventContent::plain(body, url, Some(info))
+                    }
+                    AttachmentSource::Encrypted(file) => assign!(
+                        AudioMessageEventContent::encrypted(body, *file),
+                        { info: Some(info) }
+                    ),
+                })
+            }
+            AttachmentType::File => {
+                let base = match self.info {
+                    Some(AttachmentInfo::File(info)) => info,
+                    _ => Default::default(),
+                };
+                let info = Box::new(assign!(FileInfo::new(), {
+                    mimetype,
+                    size: base.size,
+                    thumbnail_info,
+                    thumbnail_url,
+                    thumbnail_file,
+                }));
+
+                MessageType::File(match source {
+                    AttachmentSource::Plain(url) => {
+                        FileMessageEventContent::plain(body, url, Some(info))
+                    }
+                    AttachmentSource::Encrypted(file) => assign!(
+                        FileMessageEventContent::encrypted(body, *file),
+                        { info: Some(info) }
+                    ),
+                })
+            }
+        };
+
+        let mut content = RoomMessageEventContent::new(msgtype);
+
+        if let Some(event_id) = self.reply {
+            content.relates_to = Some(Relation::Reply { in_reply_to: InReplyTo::new(event_id) });
+        }
+
+        AnyMessageEventContent::RoomMessage(content)
+    }
+}
+
+/// Compute the metadata of the image `data` and generate a thumbnail that fits
+/// into `max_size`.
+///
+/// No thumbnail is generated if the image already fits into `max_size`.
+#[cfg(feature = "image")]
+fn generate_image_info(
+    data: &[u8],
+    max_size: (u32, u32),
+) -> Result<(BaseImageInfo, Option<Thumbnail>), image_rs::ImageError> {
+    use std::io::Cursor;
+
+    use image_rs::{DynamicImage, GenericImageView, ImageOutputFormat};
+
+    let image = image_rs::load_from_memory(data)?;
+    let (width, height) = image.dimensions();
+    let (max_width, max_height) = max_size;
+
+    let small = image.thumbnail(max_width, max_height);
+    // Computing the BlurHash of the full image would be needlessly slow, the
+    // result is the same for the thumbnail.
+    let blurhash =
+        blurhash::encode(4, 3, small.width(), small.height(), &small.to_rgba8().into_raw());
+
+    let info = BaseImageInfo {
+        height: Some(height.into()),
+        width: Some(width.into()),
+        size: UInt::new(data.len() as u64),
+        blurhash: Some(blurhash),
+    };
+
+    if width <= max_width && height <= max_height {
+        return Ok((info, None));
+    }
+
+    // JPEG doesn't support transparency, use PNG for images that need it.
+    let (small, format, content_type) = if small.color().has_alpha() {
+        (DynamicImage::ImageRgba8(small.to_rgba8()), ImageOutputFormat::Png, mime::IMAGE_PNG)
+    } else {
+        (DynamicImage::ImageRgb8(small.to_rgb8()), ImageOutputFormat::Jpeg(80), mime::IMAGE_JPEG)
+    };
+
+    let mut thumbnail_data = Cursor::new(Vec::new());
+    small.write_to(&mut thumbnail_data, format)?;
+    let thumbnail_data = thumbnail_data.into_inner();
+
+    let thumbnail = Thumbnail {
+        info: Some(BaseThumbnailInfo {
+            height: Some(small.height().into()),
+            width: Some(small.width().into()),
+            size: UInt::new(thumbnail_data.len() as u64),
+        }),
+        data: thumbnail_data,
+        content_type,
+    };
+
+    Ok((info, Some(thumbnail)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use matches::assert_matches;
+    use ruma::{
+        event_id,
+        events::{
+            room::message::{MessageType, Relation},
+            AnyMessageEventContent,
+        },
+        mxc_uri, uint,
+    };
+
+    #[cfg(feature = "image")]
+    use super::{generate_image_info, BaseImageInfo};
+    use super::{
+        AttachmentConfig, AttachmentInfo, AttachmentSource, AttachmentType, BaseVideoInfo,
+    };
+
+    #[test]
+    fn attachment_content() {
+        let config = AttachmentConfig::new()
+            .info(AttachmentInfo::Video(BaseVideoInfo {
+                duration: Some(Duration::from_secs(2)),
+                width: Some(uint!(1280)),
+                ..Default::default()
+            }))
+            .reply(event_id!("$h29iv0s8:example.com"));
+
+        let source = AttachmentSource::Plain(mxc_uri!("mxc://localhost/video").to_owned());
+        let content = config.into_content("cat.mp4", &mime::VIDEO_MP4, source, None);
+
+        let content = match content {
+            AnyMessageEventContent::RoomMessage(content) => content,
+            _ => panic!("The video should be sent as a room message"),
+        };
+        assert_matches!(
+            content.relates_to,
+            Some(Relation::Reply { in_reply_to }) if in_reply_to.event_id == "$h29iv0s8:example.com"
+        );
+
+        let info = match content.msgtype {
+            MessageType::Video(content) => content.info.unwrap(),
+            _ => panic!("The attachment should be sent as a video"),
+        };
+        assert_eq!(info.duration, Some(uint!(2000)));
+        assert_eq!(info.width, Some(uint!(1280)));
+        assert_eq!(info.mimetype.as_deref(), Some("video/mp4"));
+
+        let config = AttachmentConfig::new().attachment_type(AttachmentType::Sticker);
+        let source = AttachmentSource::Plain(mxc_uri!("mxc://localhost/sticker").to_owned());
+        let content = config.into_content("cat", &mime::IMAGE_PNG, source, None);
+
+        assert_matches!(content, AnyMessageEventContent::Sticker(_));
+    }
+
+    #[cfg(feature = "image")]
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        use image_rs::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+
+        let image = RgbImage::from_pixel(width, height, Rgb([200, 100, 50]));
+        let mut data = std::io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut data, ImageOutputFormat::Jpeg(80)).unwrap();
+
+        data.into_inner()
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn thumbnail_generation() {
+        let (info, thumbnail) = generate_image_info(&jpeg(1600, 900), (800, 600)).unwrap();
+
+        assert_eq!(info.width, Some(uint!(1600)));
+        assert_eq!(info.height, Some(uint!(900)));
+        assert!(info.blurhash.is_some());
+
+        let thumbnail = thumbnail.unwrap();
+        let thumbnail_info = thumbnail.info.unwrap();
+        assert_eq!(thumbnail.content_type, mime::IMAGE_JPEG);
+        assert_eq!(thumbnail_info.width, Some(uint!(800)));
+        assert_eq!(thumbnail_info.height, Some(uint!(450)));
+
+        let (_, thumbnail) = generate_image_info(&jpeg(400, 300), (800, 600)).unwrap();
+        assert!(thumbnail.is_none());
+
+        assert!(generate_image_info(b"Not an image", (800, 600)).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn image_info_overrides() {
+        let mut config = AttachmentConfig::new().info(AttachmentInfo::Image(BaseImageInfo {
+            width: Some(uint!(10)),
+            ..Default::default()
+        }));
+        config.generate_image_info(&jpeg(1600, 900)).unwrap();
+
+        let info = match config.info {
+            Some(AttachmentInfo::Image(info)) => info,
+            _ => panic!("The config should contain image info"),
+        };
+        assert_eq!(info.width, Some(uint!(10)));
+        assert_eq!(info.height, Some(uint!(900)));
+    }
+}
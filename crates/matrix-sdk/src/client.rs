@@ -17,19 +17,27 @@ use std::{
     collections::BTreeMap,
     fmt::{self, Debug},
     future::Future,
-    io::Read,
+    io::{Error as IoError, ErrorKind, Read},
     pin::Pin,
     result::Result as StdResult,
-    sync::{Arc, RwLock as StdRwLock},
+    sync::{Arc, RwLock as StdRwLock, Weak},
 };
 
 use anymap2::any::CloneAnySendSync;
+use bytes::Bytes;
 use dashmap::DashMap;
 use futures_core::stream::Stream;
+use futures_util::{
+    io::AsyncRead,
+    stream::{self, StreamExt},
+};
 use matrix_sdk_base::{
     deserialized_responses::SyncResponse,
-    media::{MediaEventContent, MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
-    BaseClient, Session, Store,
+    media::{
+        MediaCacheConfig, MediaCacheStats, MediaEventContent, MediaFormat, MediaRequest,
+        MediaThumbnailSize, MediaType,
+    },
+    BaseClient, Session, StateChanges, Store, StoreError,
 };
 use matrix_sdk_common::{
     instant::{Duration, Instant},
@@ -38,6 +46,13 @@ use matrix_sdk_common::{
 use mime::{self, Mime};
 #[cfg(feature = "encryption")]
 use ruma::TransactionId;
+#[cfg(all(feature = "appservice", feature = "encryption"))]
+use ruma::{
+    api::client::r0::sync::sync_events::{DeviceLists, JoinedRoom},
+    events::{AnyRoomEvent, AnyToDeviceEvent},
+    serde::Raw,
+    DeviceKeyAlgorithm,
+};
 use ruma::{
     api::{
         client::{
@@ -72,8 +87,10 @@ use crate::{
     config::{ClientConfig, RequestConfig},
     error::{HttpError, HttpResult},
     event_handler::{EventHandler, EventHandlerData, EventHandlerResult, EventKind, SyncEvent},
-    http_client::{client_with_config, HttpClient},
-    room, Error, Result,
+    http_client::{client_with_config, ByteStream, HttpClient, RateLimitInfo},
+    room,
+    transfer::{IoStream, MediaStream, ProgressStream, ReaderStream, TransferConfig},
+    Error, Result,
 };
 
 /// A conservative upload speed of 1Mbps
@@ -142,12 +159,21 @@ pub(crate) struct ClientInner {
     /// Whether the client should update its homeserver URL with the discovery
     /// information present in the login response.
     use_discovery_response: bool,
+    /// The limits of the media cache, see `ClientConfig::media_cache_config`.
+    media_cache_config: Option<MediaCacheConfig>,
     /// An event that can be listened on to wait for a successful sync. The
     /// event will only be fired if a sync loop is running. Can be used for
     /// synchronization, e.g. if we send out a request to create a room, we can
     /// wait for the sync to get the data to fetch a room object from the state
     /// store.
     pub(crate) sync_beat: event_listener::Event,
+    /// The state of the server-side backup of room keys.
+    #[cfg(feature = "backups_v1")]
+    pub(crate) backup_state: crate::encryption::backups::BackupClientState,
+    /// The state of the queue of outgoing events.
+    pub(crate) send_queue: crate::send_queue::SendQueueState,
+    /// The live timelines of the rooms. See `room::Common::timeline`.
+    pub(crate) timelines: DashMap<Box<RoomId>, Weak<crate::room::TimelineInner>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -206,7 +232,12 @@ impl Client {
             notification_handlers: Default::default(),
             appservice_mode: config.appservice_mode,
             use_discovery_response: config.use_discovery_response,
+            media_cache_config: config.media_cache_config,
             sync_beat: event_listener::Event::new(),
+            #[cfg(feature = "backups_v1")]
+            backup_state: Default::default(),
+            send_queue: Default::default(),
+            timelines: Default::default(),
         });
 
         Ok(Self { inner })
@@ -348,6 +379,11 @@ impl Client {
 
     /// Process a [transaction] received from the homeserver
     ///
+    /// The transaction is processed even if it was already received, so that
+    /// the homeserver can retry a transaction whose processing was
+    /// interrupted. Deduplicating transactions is up to the caller, the
+    /// `AppService` of the `matrix-sdk-appservice` crate does that.
+    ///
     /// # Arguments
     ///
     /// * `incoming_transaction` - The incoming transaction received from the
@@ -361,7 +397,125 @@ impl Client {
     ) -> Result<()> {
         let txn_id = incoming_transaction.txn_id.clone();
         let response = incoming_transaction.try_into_sync_response(txn_id)?;
-        self.process_sync(response).await?;
+        self.replay_sync(response).await?;
+
+        Ok(())
+    }
+
+    /// Create a new device for a user of an application service.
+    ///
+    /// The device is created by logging in as the user with the
+    /// `m.login.application_service` login type of [MSC2778], authenticated
+    /// with the `as_token` of the application service. Unlike the other login
+    /// methods this doesn't log the client in, the device ID of the response
+    /// should be used together with the `as_token` to [restore the login] of
+    /// the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `as_token` - The access token of the application service.
+    ///
+    /// * `user_id` - The user the device should be created for, it needs to be
+    ///   registered and covered by the user namespaces of the application
+    ///   service.
+    ///
+    /// * `device_id` - The ID the device should have, if this is `None` the
+    ///   homeserver chooses a random one.
+    ///
+    /// [MSC2778]: https://github.com/matrix-org/matrix-doc/pull/2778
+    /// [restore the login]: #method.restore_login
+    #[cfg(feature = "appservice")]
+    pub async fn create_appservice_device(
+        &self,
+        as_token: &str,
+        user_id: &UserId,
+        device_id: Option<&DeviceId>,
+    ) -> HttpResult<login::Response> {
+        let login_info = login::LoginInfo::ApplicationService(login::ApplicationService::new(
+            UserIdentifier::MatrixId(user_id.as_str()),
+        ));
+        let request = assign!(login::Request::new(login_info), { device_id });
+
+        self.inner.http_client.send_with_access_token(request, as_token, None).await
+    }
+
+    /// Process the events of a [transaction] received from the homeserver,
+    /// together with the end-to-end encryption related data that was pushed
+    /// to the application service for the device of this client.
+    ///
+    /// Homeservers push to-device events, device list changes and one-time
+    /// key counts to application services as described in [MSC2409] and
+    /// [MSC3202]. This makes it possible for the application service to
+    /// encrypt and decrypt messages on behalf of its users without syncing.
+    ///
+    /// Any end-to-end encryption requests that need to be sent out after the
+    /// transaction has been processed, e.g. uploading one-time keys, are sent
+    /// out before this method returns.
+    ///
+    /// Like [`Client::receive_transaction()`], this processes the transaction
+    /// even if it was already received.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The ID of the transaction.
+    ///
+    /// * `events` - The room events of the transaction.
+    ///
+    /// * `to_device` - The to-device events that were sent to the device of
+    ///   this client.
+    ///
+    /// * `device_lists` - The users whose devices changed.
+    ///
+    /// * `one_time_keys_count` - The number of unclaimed one-time keys the
+    ///   device of this client has on the server.
+    ///
+    /// * `unused_fallback_key_types` - The types of the fallback keys of the
+    ///   device of this client that weren't used yet, if known.
+    ///
+    /// [transaction]: https://matrix.org/docs/spec/application_service/r0.1.2#put-matrix-app-v1-transactions-txnid
+    /// [MSC2409]: https://github.com/matrix-org/matrix-doc/pull/2409
+    /// [MSC3202]: https://github.com/matrix-org/matrix-doc/pull/3202
+    #[cfg(all(feature = "appservice", feature = "encryption"))]
+    pub async fn receive_transaction_with_encryption(
+        &self,
+        txn_id: &str,
+        events: Vec<Raw<AnyRoomEvent>>,
+        to_device: Vec<Raw<AnyToDeviceEvent>>,
+        device_lists: DeviceLists,
+        one_time_keys_count: BTreeMap<DeviceKeyAlgorithm, UInt>,
+        unused_fallback_key_types: Option<Vec<DeviceKeyAlgorithm>>,
+    ) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct EventRoomId {
+            room_id: Box<RoomId>,
+        }
+
+        let mut response = sync_events::Response::new(txn_id.to_owned());
+
+        for event in events {
+            match event.deserialize_as::<EventRoomId>() {
+                Ok(EventRoomId { room_id }) => response
+                    .rooms
+                    .join
+                    .entry(room_id)
+                    .or_insert_with(JoinedRoom::new)
+                    .timeline
+                    .events
+                    .push(Raw::from_json(event.into_json())),
+                Err(e) => warn!(error =? e, "Ignoring a transaction event without a room ID"),
+            }
+        }
+
+        response.to_device.events = to_device;
+        response.device_lists = device_lists;
+        response.device_one_time_keys_count = one_time_keys_count;
+        response.device_unused_fallback_key_types = unused_fallback_key_types;
+
+        self.replay_sync(response).await?;
+
+        if let Err(e) = self.send_outgoing_requests().await {
+            error!(error =? e, "Error while sending outgoing E2EE requests");
+        }
 
         Ok(())
     }
@@ -760,6 +914,83 @@ impl Client {
         self
     }
 
+    /// Register a hook that can add its own changes to the changes of every
+    /// sync response.
+    ///
+    /// Event handlers are called after the changes of a sync response were
+    /// saved, the changes a hook adds are instead saved atomically together
+    /// with the state of the sync response. This makes sure that the state a
+    /// bot keeps in a [`KeyValueStore`] never diverges from the state of the
+    /// rooms. If the hook returns an error, the sync response isn't saved.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # block_on(async {
+    /// use matrix_sdk::Client;
+    ///
+    /// let client = Client::new(homeserver).await?;
+    /// let seen = client.store().key_value_store::<String, u64>("bot.seen_events");
+    ///
+    /// client
+    ///     .register_sync_hook(move |response, changes| {
+    ///         for (room_id, room) in &response.rooms.join {
+    ///             let count = room.timeline.events.len() as u64;
+    ///             seen.set_in_changes(changes, &room_id.to_string(), &count)?;
+    ///         }
+    ///
+    ///         Ok(())
+    ///     })
+    ///     .await;
+    /// # matrix_sdk::Result::<()>::Ok(()) });
+    /// ```
+    ///
+    /// [`KeyValueStore`]: crate::KeyValueStore
+    pub async fn register_sync_hook<H>(&self, hook: H) -> &Self
+    where
+        H: Fn(&SyncResponse, &mut StateChanges) -> Result<(), StoreError> + Send + Sync + 'static,
+    {
+        self.base_client().register_sync_hook(hook).await;
+        self
+    }
+
+    /// Register a handler that is called every time the homeserver rate
+    /// limits one of our requests.
+    ///
+    /// The handler is called before the request is retried, it can be used to
+    /// observe how often and for how long the client is being throttled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # block_on(async {
+    /// use matrix_sdk::Client;
+    ///
+    /// let client = Client::new(homeserver).await?;
+    ///
+    /// client.register_rate_limit_handler(|info| {
+    ///     println!(
+    ///         "Request to {} was rate limited, retrying after {:?}",
+    ///         info.path, info.retry_after
+    ///     );
+    /// });
+    /// # matrix_sdk::Result::<()>::Ok(()) });
+    /// ```
+    pub fn register_rate_limit_handler<H>(&self, handler: H) -> &Self
+    where
+        H: Fn(&RateLimitInfo) + Send + Sync + 'static,
+    {
+        self.inner.http_client.rate_limit_handlers.write().unwrap().push(Box::new(handler));
+
+        self
+    }
+
     pub(crate) async fn notification_handlers(
         &self,
     ) -> RwLockReadGuard<'_, Vec<NotificationHandlerFn>> {
@@ -1640,6 +1871,124 @@ impl Client {
         Ok(self.inner.http_client.upload(request, Some(request_config)).await?)
     }
 
+    /// Upload some media to the server by streaming it from `reader`.
+    ///
+    /// Unlike [`upload()`](#method.upload) the media is never held in memory
+    /// as a whole, which makes this method suitable for big files. Streamed
+    /// uploads aren't retried if they fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `reader` - An `AsyncRead` the raw bytes of the media are streamed
+    /// from.
+    ///
+    /// * `size` - The size of the media in bytes, if known. It's used to report
+    /// the progress and to pick a request timeout, if it's unknown the default
+    /// request timeout is used.
+    ///
+    /// * `config` - The progress callback and cancellation token of the upload.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, transfer::TransferConfig};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use futures_util::io::AllowStdIo;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// let video = std::fs::File::open("/home/example/my-cat.mp4")?;
+    /// let size = video.metadata()?.len();
+    ///
+    /// let config = TransferConfig::new()
+    ///     .progress(|p| println!("Uploaded {} of {:?} bytes", p.transferred, p.total));
+    ///
+    /// let response = client
+    ///     .upload_stream(&mime::VIDEO_MP4, AllowStdIo::new(video), Some(size), config)
+    ///     .await?;
+    ///
+    /// println!("Cat URI: {}", response.content_uri);
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub async fn upload_stream(
+        &self,
+        content_type: &Mime,
+        reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+        size: Option<u64>,
+        config: TransferConfig,
+    ) -> Result<create_content::Response> {
+        self.upload_io_stream(content_type, Box::pin(ReaderStream::new(reader)), size, config).await
+    }
+
+    /// Encrypt and upload the media streamed from `reader`.
+    ///
+    /// Returns the [`EncryptedFile`] that contains the URI of the uploaded
+    /// media and the keys to decrypt it, the arguments are the same as for
+    /// [`upload_stream()`](#method.upload_stream).
+    ///
+    /// [`EncryptedFile`]: ruma::events::room::EncryptedFile
+    #[cfg(feature = "encryption")]
+    pub async fn upload_encrypted_stream(
+        &self,
+        content_type: &Mime,
+        reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+        size: Option<u64>,
+        config: TransferConfig,
+    ) -> Result<ruma::events::room::EncryptedFile> {
+        let encryptor = matrix_sdk_base::crypto::AsyncAttachmentEncryptor::new(reader);
+        let (stream, encryptor) = ReaderStream::with_finished(encryptor);
+
+        let response = self.upload_io_stream(content_type, Box::pin(stream), size, config).await?;
+
+        let keys = encryptor
+            .await
+            .map_err(|_| IoError::new(ErrorKind::Other, "The encrypted upload didn't finish"))?
+            .finish();
+
+        Ok(ruma::events::room::EncryptedFileInit {
+            url: response.content_uri,
+            key: keys.web_key,
+            iv: keys.iv,
+            hashes: keys.hashes,
+            v: keys.version,
+        }
+        .into())
+    }
+
+    async fn upload_io_stream(
+        &self,
+        content_type: &Mime,
+        stream: IoStream,
+        size: Option<u64>,
+        config: TransferConfig,
+    ) -> Result<create_content::Response> {
+        let body: ByteStream = Box::pin(
+            ProgressStream::new(stream, config.clone(), size).map(|c| c.map_err(HttpError::Io)),
+        );
+
+        let request_config = match size {
+            Some(size) => self.inner.http_client.request_config.timeout(std::cmp::max(
+                Duration::from_secs(size / DEFAULT_UPLOAD_SPEED),
+                MIN_UPLOAD_REQUEST_TIMEOUT,
+            )),
+            None => self.inner.http_client.request_config,
+        };
+
+        let upload = async {
+            Ok(self
+                .inner
+                .http_client
+                .upload_stream(content_type.essence_str(), body, size, Some(request_config))
+                .await?)
+        };
+
+        config.run(upload).await
+    }
+
     /// Send an arbitrary request to the server, without updating client state.
     ///
     /// **Warning:** Because this method *does not* update the client state, it
@@ -2174,13 +2523,155 @@ impl Client {
             };
 
             if use_cache {
-                self.inner.base_client.store().add_media_content(request, content.clone()).await?;
+                self.cache_media_content(request, content.clone()).await?;
             }
 
             Ok(content)
         }
     }
 
+    /// Add the given media content to the media cache and evict the content
+    /// that exceeds the limits of the cache.
+    async fn cache_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
+        let store = self.inner.base_client.store();
+
+        if let Some(config) = &self.inner.media_cache_config {
+            store.cache_media_content(request, content, config).await?;
+        } else {
+            store.add_media_content(request, content).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stream a media file's content.
+    ///
+    /// Unlike [`get_media_content()`](#method.get_media_content) the content
+    /// is never held in memory as a whole, which makes this method suitable for
+    /// big files. If the content is encrypted and encryption is enabled, the
+    /// content will be decrypted while it's streamed, note that the integrity
+    /// of encrypted content is only checked at the end of the stream.
+    ///
+    /// The media cache is only used if a
+    /// [`cache_limit`](TransferConfig::cache_limit) was configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the content.
+    ///
+    /// * `config` - The progress callback, cancellation token and cache limit
+    /// of the download.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, transfer::TransferConfig, media::MediaRequest};
+    /// # use url::Url;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// # let request: MediaRequest = todo!();
+    /// let mut stream = client.get_media_content_stream(&request, TransferConfig::new()).await?;
+    ///
+    /// while let Some(chunk) = stream.next().await {
+    ///     let chunk = chunk?;
+    ///     // Write the chunk to a file.
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub async fn get_media_content_stream(
+        &self,
+        request: &MediaRequest,
+        config: TransferConfig,
+    ) -> Result<MediaStream> {
+        config.run(self.fetch_media_content_stream(request, config.clone())).await
+    }
+
+    async fn fetch_media_content_stream(
+        &self,
+        request: &MediaRequest,
+        config: TransferConfig,
+    ) -> Result<MediaStream> {
+        fn single_chunk(content: Vec<u8>) -> MediaStream {
+            Box::pin(stream::iter(Some(Ok::<_, Error>(Bytes::from(content)))))
+        }
+
+        if config.cache_limit.is_some() {
+            if let Some(content) = self.store().get_media_content(request).await? {
+                return Ok(single_chunk(content));
+            }
+        }
+
+        let response = match &request.media_type {
+            MediaType::Encrypted(file) => {
+                self.inner
+                    .http_client
+                    .download(get_content::Request::from_url(&file.url)?, None)
+                    .await?
+            }
+            MediaType::Uri(uri) => {
+                if let MediaFormat::Thumbnail(size) = &request.format {
+                    self.inner
+                        .http_client
+                        .download(
+                            get_content_thumbnail::Request::from_url(uri, size.width, size.height)?,
+                            None,
+                        )
+                        .await?
+                } else {
+                    self.inner
+                        .http_client
+                        .download(get_content::Request::from_url(uri)?, None)
+                        .await?
+                }
+            }
+        };
+
+        let total = response
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u64>().ok());
+
+        let body: IoStream = Box::pin(
+            response.into_body().map(|c| c.map_err(|e| IoError::new(ErrorKind::Other, e))),
+        );
+
+        let body: IoStream = match &request.media_type {
+            #[cfg(feature = "encryption")]
+            MediaType::Encrypted(file) => {
+                use futures_util::TryStreamExt;
+
+                let decryptor = matrix_sdk_base::crypto::AsyncAttachmentDecryptor::new(
+                    body.into_async_read(),
+                    file.as_ref().clone().into(),
+                )?;
+
+                Box::pin(ReaderStream::new(decryptor))
+            }
+            _ => body,
+        };
+
+        let mut body = ProgressStream::new(body, config.clone(), total);
+
+        if let (Some(limit), Some(total)) = (config.cache_limit, total) {
+            if total <= limit {
+                let mut content = Vec::new();
+
+                while let Some(chunk) = body.next().await {
+                    content.extend_from_slice(&chunk.map_err(|e| config.to_error(e))?);
+                }
+
+                self.cache_media_content(request, content.clone()).await?;
+
+                return Ok(single_chunk(content));
+            }
+        }
+
+        Ok(Box::pin(body.map(move |c| c.map_err(|e| config.to_error(e)))))
+    }
+
     /// Remove a media file's content from the store.
     ///
     /// # Arguments
@@ -2200,6 +2691,16 @@ impl Client {
         Ok(self.inner.base_client.store().remove_media_content_for_uri(uri).await?)
     }
 
+    /// Get statistics about the media content stored in the media cache.
+    pub async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        Ok(self.inner.base_client.store().media_cache_stats().await?)
+    }
+
+    /// Delete all the media content from the media cache.
+    pub async fn clear_media_cache(&self) -> Result<()> {
+        Ok(self.inner.base_client.store().clear_media_cache().await?)
+    }
+
     /// Get the file of the given media event content.
     ///
     /// If the content is encrypted and encryption is enabled, the content will
@@ -2319,47 +2820,8 @@ impl Client {
         let request = whoami::Request::new();
         self.send(request, None).await
     }
-
-    /// Upload the file to be read from `reader` and construct an attachment
-    /// message with `body` and the specified `content_type`.
-    pub(crate) async fn prepare_attachment_message<R: Read>(
-        &self,
-        body: &str,
-        content_type: &Mime,
-        reader: &mut R,
-    ) -> Result<ruma::events::room::message::MessageType> {
-        let response = self.upload(content_type, reader).await?;
-
-        let url = response.content_uri;
-
-        use ruma::events::room::message;
-        Ok(match content_type.type_() {
-            mime::IMAGE => {
-                // TODO create a thumbnail using the image crate?.
-                message::MessageType::Image(message::ImageMessageEventContent::plain(
-                    body.to_owned(),
-                    url,
-                    None,
-                ))
-            }
-            mime::AUDIO => message::MessageType::Audio(message::AudioMessageEventContent::plain(
-                body.to_owned(),
-                url,
-                None,
-            )),
-            mime::VIDEO => message::MessageType::Video(message::VideoMessageEventContent::plain(
-                body.to_owned(),
-                url,
-                None,
-            )),
-            _ => message::MessageType::File(message::FileMessageEventContent::plain(
-                body.to_owned(),
-                url,
-                None,
-            )),
-        })
-    }
 }
+
 // mockito (the http mocking library) is not supported for wasm32
 #[cfg(all(test, not(target_arch = "wasm32")))]
 pub(crate) mod test {
@@ -2372,9 +2834,12 @@ pub(crate) mod test {
         convert::{TryFrom, TryInto},
         io::Cursor,
         str::FromStr,
+        sync::{Arc, RwLock as StdRwLock},
         time::Duration,
     };
 
+    use bytes::Bytes;
+    use futures_util::TryStreamExt;
     use matrix_sdk_base::media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType};
     use matrix_sdk_test::{test_json, EventBuilder, EventsJson};
     use mockito::{mock, Matcher};
@@ -2406,14 +2871,16 @@ pub(crate) mod test {
             },
             AnySyncStateEvent, EventType,
         },
-        mxc_uri, room_id, thirdparty, uint, user_id, TransactionId, UserId,
+        mxc_uri, room_id, thirdparty, uint, user_id, RoomId, TransactionId, UserId,
     };
     use serde_json::json;
 
-    use super::{Client, Session, Url};
+    use super::{Client, Session, StoreError, Url};
     use crate::{
+        attachment::AttachmentConfig,
         config::{ClientConfig, RequestConfig, SyncSettings},
-        HttpError, RoomMember,
+        transfer::{CancellationToken, TransferConfig, TransferProgress},
+        Error, HttpError, RoomMember,
     };
 
     pub(crate) async fn logged_in_client() -> Client {
@@ -3222,8 +3689,10 @@ pub(crate) mod test {
 
         let mut media = Cursor::new("Hello world");
 
-        let response =
-            room.send_attachment("image", &mime::IMAGE_JPEG, &mut media, None).await.unwrap();
+        let response = room
+            .send_attachment("image", &mime::IMAGE_JPEG, &mut media, AttachmentConfig::new())
+            .await
+            .unwrap();
 
         assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
     }
@@ -3361,6 +3830,49 @@ pub(crate) mod test {
         assert!(client.sync_token().await.is_some());
     }
 
+    #[async_test]
+    async fn sync_hook() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let event_counts = client.store().key_value_store::<Box<RoomId>, usize>("event_counts");
+
+        client
+            .register_sync_hook({
+                let event_counts = event_counts.clone();
+                move |response, changes| {
+                    for (room_id, room) in &response.rooms.join {
+                        event_counts.set_in_changes(
+                            changes,
+                            room_id,
+                            &room.timeline.events.len(),
+                        )?;
+                    }
+
+                    Ok(())
+                }
+            })
+            .await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let response = client.sync_once(SyncSettings::new()).await.unwrap();
+        let event_count = response.rooms.join[room_id].timeline.events.len();
+
+        assert_eq!(event_counts.get(&room_id.to_owned()).await.unwrap(), Some(event_count));
+
+        // A failing hook prevents the sync response from being saved.
+        let client = logged_in_client().await;
+        client.register_sync_hook(|_, _| Err(StoreError::Codec("Failing hook".to_owned()))).await;
+
+        client.sync_once(SyncSettings::new()).await.unwrap_err();
+        assert!(client.sync_token().await.is_none());
+        assert!(client.store().get_sync_token().await.unwrap().is_none());
+    }
+
     #[async_test]
     async fn room_names() {
         let client = logged_in_client().await;
@@ -3465,6 +3977,83 @@ pub(crate) mod test {
         }
     }
 
+    #[async_test]
+    async fn rate_limited_http_requests() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::default().request_config(RequestConfig::new().retry_limit(2));
+        let client = Client::new_with_config(homeserver, config).await.unwrap();
+
+        let rate_limits = Arc::new(StdRwLock::new(Vec::new()));
+        let rate_limits_clone = rate_limits.clone();
+
+        client.register_rate_limit_handler(move |info| {
+            rate_limits_clone.write().unwrap().push(info.clone());
+        });
+
+        let m = mock("POST", "/_matrix/client/r0/login")
+            .with_status(429)
+            .with_body(
+                json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                    "retry_after_ms": 10,
+                })
+                .to_string(),
+            )
+            .expect(2)
+            .create();
+
+        if client.login("example", "wordpass", None, None).await.is_err() {
+            m.assert();
+        } else {
+            panic!("this request should return an `Err` variant")
+        }
+
+        let rate_limits = rate_limits.read().unwrap();
+
+        assert_eq!(rate_limits.len(), 2);
+        assert!(rate_limits[0].will_retry);
+        assert!(!rate_limits[1].will_retry);
+
+        for info in rate_limits.iter() {
+            assert_eq!(info.path, "/_matrix/client/r0/login");
+            assert_eq!(info.retry_after, Some(Duration::from_millis(10)));
+        }
+    }
+
+    #[async_test]
+    async fn rate_limited_http_requests_with_huge_retry_after() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::default()
+            .request_config(RequestConfig::new().retry_timeout(Duration::from_secs(5)));
+        let client = Client::new_with_config(homeserver, config).await.unwrap();
+
+        let rate_limits = Arc::new(StdRwLock::new(Vec::new()));
+        let rate_limits_clone = rate_limits.clone();
+
+        client.register_rate_limit_handler(move |info| {
+            rate_limits_clone.write().unwrap().push(info.clone());
+        });
+
+        let m = mock("POST", "/_matrix/client/r0/login")
+            .with_status(429)
+            .with_header("Retry-After", &u64::MAX.to_string())
+            .expect(1)
+            .create();
+
+        if client.login("example", "wordpass", None, None).await.is_err() {
+            m.assert();
+        } else {
+            panic!("this request should return an `Err` variant")
+        }
+
+        let rate_limits = rate_limits.read().unwrap();
+
+        assert_eq!(rate_limits.len(), 1);
+        assert!(!rate_limits[0].will_retry);
+        assert_eq!(rate_limits[0].retry_after, Some(Duration::from_secs(u64::MAX)));
+    }
+
     #[async_test]
     async fn retry_timeout_http_requests() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
@@ -3525,6 +4114,124 @@ pub(crate) mod test {
         m.assert();
     }
 
+    #[async_test]
+    async fn media_cache() {
+        let client = logged_in_client().await;
+
+        let request = MediaRequest {
+            media_type: MediaType::Uri(mxc_uri!("mxc://localhost/textfile").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/download/localhost/textfile\?.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body("Some very interesting text.")
+        .create();
+
+        client.get_media_content(&request, true).await.unwrap();
+
+        let stats = client.media_cache_stats().await.unwrap();
+        assert_eq!(stats.file_count, 1);
+        assert_eq!(stats.file_size, "Some very interesting text.".len() as u64);
+        assert_eq!(stats.thumbnail_count, 0);
+
+        client.clear_media_cache().await.unwrap();
+        assert_eq!(client.media_cache_stats().await.unwrap().total_size(), 0);
+    }
+
+    #[async_test]
+    async fn upload_stream() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()))
+            .with_status(200)
+            .match_header("content-type", "text/plain")
+            .match_header("content-length", "11")
+            .match_body("Hello world")
+            .with_body(json!({ "content_uri": "mxc://localhost/textfile" }).to_string())
+            .create();
+
+        let progress = Arc::new(StdRwLock::new(Vec::new()));
+        let config = {
+            let progress = progress.clone();
+            TransferConfig::new().progress(move |p| progress.write().unwrap().push(p))
+        };
+
+        let media = futures_util::io::Cursor::new(b"Hello world".to_vec());
+        let response =
+            client.upload_stream(&mime::TEXT_PLAIN, media, Some(11), config).await.unwrap();
+
+        assert_eq!(response.content_uri, mxc_uri!("mxc://localhost/textfile"));
+        assert_eq!(
+            progress.read().unwrap().last(),
+            Some(&TransferProgress { transferred: 11, total: Some(11) })
+        );
+
+        let token = CancellationToken::new();
+        token.cancel();
+
+        let media = futures_util::io::Cursor::new(b"Hello world".to_vec());
+        let config = TransferConfig::new().cancellation(token);
+
+        matches::assert_matches!(
+            client.upload_stream(&mime::TEXT_PLAIN, media, Some(11), config).await,
+            Err(Error::TransferCancelled)
+        );
+
+        // Cancelling wakes up an upload that is waiting for its body.
+        let token = CancellationToken::new();
+        let media = futures_util::stream::pending::<std::io::Result<Vec<u8>>>().into_async_read();
+        let config = TransferConfig::new().cancellation(token.clone());
+
+        let (response, ()) = futures_util::future::join(
+            client.upload_stream(&mime::TEXT_PLAIN, media, Some(11), config),
+            async { token.cancel() },
+        )
+        .await;
+
+        matches::assert_matches!(response, Err(Error::TransferCancelled));
+    }
+
+    #[async_test]
+    async fn get_media_content_stream() {
+        let client = logged_in_client().await;
+
+        let request = MediaRequest {
+            media_type: MediaType::Uri(mxc_uri!("mxc://localhost/textfile").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/download/localhost/textfile\?.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body("Some very interesting text.")
+        .expect(2)
+        .create();
+
+        let stream =
+            client.get_media_content_stream(&request, TransferConfig::new()).await.unwrap();
+        let content: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"Some very interesting text.");
+        assert_eq!(client.media_cache_stats().await.unwrap().file_count, 0);
+
+        let config = TransferConfig::new().cache_limit(1024);
+        let stream = client.get_media_content_stream(&request, config.clone()).await.unwrap();
+        let content: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"Some very interesting text.");
+        assert_eq!(client.media_cache_stats().await.unwrap().file_count, 1);
+
+        // The content is now served from the cache.
+        let stream = client.get_media_content_stream(&request, config).await.unwrap();
+        let content: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"Some very interesting text.");
+        m.assert();
+    }
+
     #[async_test]
     async fn get_media_file() {
         let client = logged_in_client().await;
@@ -20,7 +20,7 @@ use std::{
 };
 
 use http::{header::InvalidHeaderValue, HeaderValue};
-use matrix_sdk_base::BaseClientConfig;
+use matrix_sdk_base::{media::MediaCacheConfig, BaseClientConfig};
 
 use crate::{config::RequestConfig, HttpSend, Result};
 
@@ -51,6 +51,7 @@ pub struct ClientConfig {
     pub(crate) client: Option<Arc<dyn HttpSend>>,
     pub(crate) appservice_mode: bool,
     pub(crate) use_discovery_response: bool,
+    pub(crate) media_cache_config: Option<MediaCacheConfig>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -64,6 +65,7 @@ impl Debug for ClientConfig {
         res.field("user_agent", &self.user_agent)
             .field("disable_ssl_verification", &self.disable_ssl_verification)
             .field("request_config", &self.request_config)
+            .field("media_cache_config", &self.media_cache_config)
             .finish()
     }
 }
@@ -129,8 +131,9 @@ impl ClientConfig {
     /// callers responsibility to make sure that the path exists.
     ///
     /// In the default configuration, and if the corresponding features
-    /// (`sled_state_store` and `sled_cryptostore`) are enabled, the client will
-    /// open default implementations for the crypto store and the state store.
+    /// (`sled_state_store` and `sled_cryptostore`, or `sqlite_state_store` and
+    /// `sqlite_cryptostore`) are enabled, the client will open default
+    /// implementations for the crypto store and the state store.
     /// It will use the given path to open the stores. If no path is provided an
     /// in-memory store will be opened.
     #[must_use]
@@ -206,4 +209,29 @@ impl ClientConfig {
         self.use_discovery_response = true;
         self
     }
+
+    /// Set the limits of the media cache.
+    ///
+    /// Media content that exceeds the limits is evicted from the state store
+    /// every time new content is added to the cache. By default the cache
+    /// grows without bounds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use matrix_sdk::{config::ClientConfig, media::MediaCacheConfig};
+    ///
+    /// let client_config = ClientConfig::new().media_cache_config(
+    ///     MediaCacheConfig::new()
+    ///         .max_size(500 * 1024 * 1024)
+    ///         .max_age(Duration::from_secs(60 * 60 * 24 * 7)),
+    /// );
+    /// ```
+    #[must_use]
+    pub fn media_cache_config(mut self, config: MediaCacheConfig) -> Self {
+        self.media_cache_config = Some(config);
+        self
+    }
 }
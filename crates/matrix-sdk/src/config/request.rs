@@ -18,6 +18,10 @@ use std::{
 };
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRY_INITIAL_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_RETRY_MULTIPLIER: f64 = 1.5;
+const DEFAULT_RETRY_RANDOMIZATION_FACTOR: f64 = 0.5;
 
 /// Configuration for requests the `Client` makes.
 ///
@@ -26,6 +30,11 @@ const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 ///
 /// By default requests are retried indefinitely and use no timeout.
 ///
+/// Failed requests are retried using a jittered exponential backoff. If the
+/// homeserver rate limits a request, the delay the homeserver asks for, either
+/// using the `retry_after_ms` field of a `M_LIMIT_EXCEEDED` error or the
+/// `Retry-After` header, is used instead.
+///
 /// # Example
 ///
 /// ```
@@ -42,8 +51,13 @@ pub struct RequestConfig {
     pub(crate) timeout: Duration,
     pub(crate) retry_limit: Option<u64>,
     pub(crate) retry_timeout: Option<Duration>,
+    pub(crate) retry_initial_interval: Duration,
+    pub(crate) retry_max_interval: Duration,
+    pub(crate) retry_multiplier: f64,
+    pub(crate) retry_randomization_factor: f64,
     pub(crate) force_auth: bool,
     pub(crate) assert_identity: bool,
+    pub(crate) assert_device: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -54,6 +68,10 @@ impl Debug for RequestConfig {
         res.field("timeout", &self.timeout)
             .field("retry_limit", &self.retry_limit)
             .field("retry_timeout", &self.retry_timeout)
+            .field("retry_initial_interval", &self.retry_initial_interval)
+            .field("retry_max_interval", &self.retry_max_interval)
+            .field("retry_multiplier", &self.retry_multiplier)
+            .field("retry_randomization_factor", &self.retry_randomization_factor)
             .finish()
     }
 }
@@ -64,8 +82,13 @@ impl Default for RequestConfig {
             timeout: DEFAULT_REQUEST_TIMEOUT,
             retry_limit: Default::default(),
             retry_timeout: Default::default(),
+            retry_initial_interval: DEFAULT_RETRY_INITIAL_INTERVAL,
+            retry_max_interval: DEFAULT_RETRY_MAX_INTERVAL,
+            retry_multiplier: DEFAULT_RETRY_MULTIPLIER,
+            retry_randomization_factor: DEFAULT_RETRY_RANDOMIZATION_FACTOR,
             force_auth: false,
             assert_identity: false,
+            assert_device: false,
         }
     }
 }
@@ -107,6 +130,42 @@ impl RequestConfig {
         self
     }
 
+    /// Set how long to wait before the first retry of a failed request. The
+    /// default is 500 milliseconds.
+    #[must_use]
+    pub fn retry_initial_interval(mut self, interval: Duration) -> Self {
+        self.retry_initial_interval = interval;
+        self
+    }
+
+    /// Set the upper bound for the delay between two retries of a failed
+    /// request. The default is 60 seconds.
+    ///
+    /// This doesn't limit the delay the homeserver asks for if it rate limits
+    /// a request.
+    #[must_use]
+    pub fn retry_max_interval(mut self, interval: Duration) -> Self {
+        self.retry_max_interval = interval;
+        self
+    }
+
+    /// Set the factor the delay between retries is multiplied with after each
+    /// failed attempt. The default is `1.5`.
+    #[must_use]
+    pub fn retry_multiplier(mut self, multiplier: f64) -> Self {
+        self.retry_multiplier = multiplier;
+        self
+    }
+
+    /// Set how much the delay between retries should be randomized, a factor
+    /// of `0.5` means that the delay is picked randomly between 50% and 150% of
+    /// the calculated delay. The default is `0.5`, `0.0` disables the jitter.
+    #[must_use]
+    pub fn retry_randomization_factor(mut self, randomization_factor: f64) -> Self {
+        self.retry_randomization_factor = randomization_factor;
+        self
+    }
+
     /// Force sending authorization even if the endpoint does not require it.
     /// Default is only sending authorization if it is required.
     #[must_use]
@@ -127,4 +186,34 @@ impl RequestConfig {
         self.assert_identity = true;
         self
     }
+
+    /// All outgoing http requests that assert the identity of the user will
+    /// additionally have the `device_id` from the `Session` appended as a GET
+    /// query value. This lets an application service act as a specific device
+    /// of the user, e.g. to upload its end-to-end encryption keys, see
+    /// [MSC3202].
+    ///
+    /// This only has an effect if [`RequestConfig::assert_identity()`] is
+    /// used as well.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-doc/pull/3202
+    #[cfg(feature = "appservice")]
+    #[must_use]
+    pub fn assert_device(mut self) -> Self {
+        self.assert_device = true;
+        self
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn backoff(&self) -> backoff::ExponentialBackoff {
+        backoff::ExponentialBackoff {
+            initial_interval: self.retry_initial_interval,
+            current_interval: self.retry_initial_interval,
+            max_interval: self.retry_max_interval,
+            multiplier: self.retry_multiplier,
+            randomization_factor: self.retry_randomization_factor,
+            max_elapsed_time: self.retry_timeout,
+            ..Default::default()
+        }
+    }
 }
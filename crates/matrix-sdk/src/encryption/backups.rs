@@ -0,0 +1,542 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server-side backups of room keys.
+//!
+//! Room keys can be backed up to the homeserver, encrypted using a backup key.
+//! This allows other devices, or this device after a logout, to download the
+//! room keys and decrypt the room history.
+//!
+//! The [`Backups`] object is the entry point for managing such a backup, it can
+//! be retrieved using the [`Client::backups()`] method.
+//!
+//! Once a backup has been enabled, room keys are uploaded automatically while
+//! the client syncs, no further action is needed.
+
+use std::sync::RwLock as StdRwLock;
+
+use futures_core::stream::Stream;
+use matrix_sdk_base::crypto::{backups::MegolmV1BackupKey, OlmMachine};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::backup::{create_backup_version, delete_backup_version, get_latest_backup_info},
+    },
+    serde::{CanonicalJsonError, CanonicalJsonValue, Raw},
+};
+use serde_json::json;
+use tracing::{error, info, instrument, warn};
+
+pub use crate::encryption::RecoveryKey;
+use crate::{error::HttpError, Client, Error, Result};
+
+/// The error code the homeserver uses to tell us that a newer backup version
+/// has been created, i.e. our backup is stale.
+const WRONG_ROOM_KEYS_VERSION: &str = "M_WRONG_ROOM_KEYS_VERSION";
+
+/// Error type for the failures that can happen while creating a new backup
+/// version.
+#[derive(Debug, thiserror::Error)]
+pub enum BackupCreationError {
+    /// Not enough randomness could be gathered to create a new recovery key.
+    #[error("can't gather enough randomness to create a recovery key: {0}")]
+    Random(Box<dyn std::error::Error + Send + Sync>),
+
+    /// The auth data of the backup couldn't be converted to canonical JSON, to
+    /// be signed.
+    #[error(transparent)]
+    CanonicalJson(#[from] CanonicalJsonError),
+}
+
+/// The state of the server-side backup of room keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackupState {
+    /// No backup is enabled, room keys won't be uploaded to the server.
+    Disabled,
+    /// A new backup version is being created on the server.
+    Creating,
+    /// The backup is enabled, new room keys will be uploaded while syncing.
+    Enabled,
+    /// Room keys are being uploaded to the server.
+    Uploading,
+    /// The last attempt to upload room keys failed, the upload will be retried
+    /// on the next sync.
+    Error,
+}
+
+/// The backup state, as it is stored inside of the `Client`.
+#[derive(Debug)]
+pub(crate) struct BackupClientState {
+    state: StdRwLock<BackupState>,
+    changed: event_listener::Event,
+}
+
+impl Default for BackupClientState {
+    fn default() -> Self {
+        Self { state: StdRwLock::new(BackupState::Disabled), changed: event_listener::Event::new() }
+    }
+}
+
+impl BackupClientState {
+    fn get(&self) -> BackupState {
+        self.state.read().unwrap().clone()
+    }
+
+    fn set(&self, state: BackupState) {
+        let mut current = self.state.write().unwrap();
+
+        if *current != state {
+            *current = state;
+            self.changed.notify(usize::MAX);
+        }
+    }
+}
+
+/// A handle to manage the server-side backup of room keys.
+///
+/// This can be retrieved using the [`Client::backups()`] method.
+#[derive(Debug, Clone)]
+pub struct Backups {
+    client: Client,
+}
+
+impl Client {
+    /// Get a handle to manage the server-side backup of room keys.
+    pub fn backups(&self) -> Backups {
+        Backups { client: self.clone() }
+    }
+
+    pub(crate) fn set_backup_state(&self, state: BackupState) {
+        self.inner.backup_state.set(state)
+    }
+
+    /// Update the backup state after a request to upload room keys failed.
+    ///
+    /// If the backup version was replaced by another device the backup gets
+    /// disabled, otherwise the upload will be retried on the next sync.
+    pub(crate) async fn handle_backup_upload_error(&self, error: &HttpError) {
+        let wrong_version = error
+            .client_api_error_kind()
+            .map(|kind| kind.to_string() == WRONG_ROOM_KEYS_VERSION)
+            .unwrap_or(false);
+
+        if wrong_version {
+            warn!("The backup version was changed by another device, disabling the backup");
+
+            if let Err(e) = self.backups().disable().await {
+                error!(error =? e, "Couldn't disable the backup");
+                self.set_backup_state(BackupState::Error);
+            }
+        } else {
+            self.set_backup_state(BackupState::Error);
+        }
+    }
+
+    /// Synchronize our view of the backup state with the state of the
+    /// `OlmMachine`, a backup might have been re-enabled from the store.
+    pub(crate) async fn sync_backup_state(&self) {
+        if let Some(olm) = self.olm_machine().await {
+            if olm.backup_machine().enabled().await
+                && self.backups().state() == BackupState::Disabled
+            {
+                self.set_backup_state(BackupState::Enabled);
+            }
+        }
+    }
+}
+
+impl Backups {
+    async fn olm_machine(&self) -> Result<OlmMachine> {
+        self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)
+    }
+
+    /// Create a new backup version on the server and enable it.
+    ///
+    /// A new backup key is generated, the public part of it is uploaded to the
+    /// server, signed by our own device and, if available, our cross signing
+    /// master key. Room keys will be uploaded to the new backup version while
+    /// the client syncs.
+    ///
+    /// Returns the private part of the backup key. It should be presented to
+    /// the user, since it is needed to restore the backup on another device.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let recovery_key = client.backups().create().await?;
+    ///
+    /// println!("Your recovery key is {}", recovery_key.to_base58());
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[instrument(skip(self))]
+    pub async fn create(&self) -> Result<RecoveryKey> {
+        let olm = self.olm_machine().await?;
+
+        self.client.set_backup_state(BackupState::Creating);
+
+        let result = self.create_helper(&olm).await;
+
+        if result.is_err() {
+            // The previous backup, if there was one, stays active.
+            let state = if olm.backup_machine().enabled().await {
+                BackupState::Enabled
+            } else {
+                BackupState::Disabled
+            };
+
+            self.client.set_backup_state(state);
+        }
+
+        result
+    }
+
+    async fn create_helper(&self, olm: &OlmMachine) -> Result<RecoveryKey> {
+        let recovery_key =
+            RecoveryKey::new().map_err(|e| BackupCreationError::Random(Box::new(e)))?;
+        let backup_key = recovery_key.megolm_v1_public_key();
+
+        let mut auth_data = json!({ "public_key": backup_key.to_base64() });
+        let canonical_json: CanonicalJsonValue =
+            auth_data.clone().try_into().map_err(BackupCreationError::from)?;
+        auth_data["signatures"] =
+            serde_json::to_value(olm.sign(&canonical_json.to_string()).await)?;
+
+        let algorithm = Raw::from_json(serde_json::value::to_raw_value(&json!({
+            "algorithm": backup_key.backup_algorithm(),
+            "auth_data": auth_data,
+        }))?);
+
+        let request = create_backup_version::Request::new(algorithm);
+        let response = self.client.send(request, None).await?;
+
+        info!(version = response.version.as_str(), "Created a new backup version");
+
+        self.enable_helper(olm, &recovery_key, backup_key, response.version).await?;
+
+        Ok(recovery_key)
+    }
+
+    /// Enable the latest backup version that exists on the server.
+    ///
+    /// This is useful to continue backing up room keys to a backup that was
+    /// created by another device, the given recovery key needs to match the
+    /// public key of the latest backup version.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The private part of the backup key, usually entered
+    /// by the user as a base58 encoded string.
+    #[instrument(skip(self, recovery_key))]
+    pub async fn enable(&self, recovery_key: &RecoveryKey) -> Result<()> {
+        let olm = self.olm_machine().await?;
+        let version = self.client.backup_version_for_recovery_key(recovery_key).await?;
+        let backup_key = recovery_key.megolm_v1_public_key();
+
+        self.enable_helper(&olm, recovery_key, backup_key, version).await
+    }
+
+    async fn enable_helper(
+        &self,
+        olm: &OlmMachine,
+        recovery_key: &RecoveryKey,
+        backup_key: MegolmV1BackupKey,
+        version: String,
+    ) -> Result<()> {
+        backup_key.set_version(version.clone());
+
+        let backup_machine = olm.backup_machine();
+        // Room keys that were backed up to a previous version need to be
+        // uploaded again.
+        backup_machine.disable_backup().await?;
+        backup_machine.enable_backup_v1(backup_key).await?;
+        backup_machine.save_recovery_key(Some(recovery_key.clone()), Some(version)).await?;
+
+        self.client.set_backup_state(BackupState::Enabled);
+
+        Ok(())
+    }
+
+    /// Disable the backup of room keys.
+    ///
+    /// Room keys won't be uploaded anymore, the backup itself stays on the
+    /// server. Use [`Backups::delete_version()`] to remove it as well.
+    #[instrument(skip(self))]
+    pub async fn disable(&self) -> Result<()> {
+        let olm = self.olm_machine().await?;
+
+        let backup_machine = olm.backup_machine();
+        backup_machine.disable_backup().await?;
+        backup_machine.save_recovery_key(None, None).await?;
+
+        self.client.set_backup_state(BackupState::Disabled);
+
+        Ok(())
+    }
+
+    /// Delete the given backup version from the server.
+    ///
+    /// If the version is the one we are currently backing up to, the backup
+    /// will be disabled as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The backup version that should be deleted.
+    #[instrument(skip(self))]
+    pub async fn delete_version(&self, version: &str) -> Result<()> {
+        let olm = self.olm_machine().await?;
+
+        self.client.send(delete_backup_version::Request::new(version), None).await?;
+
+        let current_version = olm.backup_machine().get_backup_keys().await?.backup_version;
+
+        if current_version.as_deref() == Some(version) {
+            self.disable().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Are room keys currently being backed up to the server?
+    pub async fn are_enabled(&self) -> bool {
+        if let Some(olm) = self.client.olm_machine().await {
+            olm.backup_machine().enabled().await
+        } else {
+            false
+        }
+    }
+
+    /// Check that the backup version we are using is still the latest backup
+    /// version on the server.
+    ///
+    /// Another device might have created a new backup version or deleted our
+    /// backup, in that case the backup gets disabled. The caller can then
+    /// decide to [enable] the new version or to [create] a new one.
+    ///
+    /// Returns `true` if our backup is still the latest backup version, `false`
+    /// if the backup is, or has just been, disabled.
+    ///
+    /// [enable]: Backups::enable
+    /// [create]: Backups::create
+    #[instrument(skip(self))]
+    pub async fn check_version(&self) -> Result<bool> {
+        let olm = self.olm_machine().await?;
+
+        let current_version = match olm.backup_machine().get_backup_keys().await?.backup_version {
+            Some(v) if olm.backup_machine().enabled().await => v,
+            _ => return Ok(false),
+        };
+
+        let latest_version =
+            match self.client.send(get_latest_backup_info::Request::new(), None).await {
+                Ok(response) => Some(response.version),
+                Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+
+        if latest_version.as_deref() == Some(current_version.as_str()) {
+            Ok(true)
+        } else {
+            warn!(
+                current_version = current_version.as_str(),
+                latest_version =? latest_version,
+                "The backup version was changed by another device, disabling the backup"
+            );
+
+            self.disable().await?;
+
+            Ok(false)
+        }
+    }
+
+    /// Get the current state of the backup.
+    pub fn state(&self) -> BackupState {
+        self.client.inner.backup_state.get()
+    }
+
+    /// Get a stream of updates to the state of the backup.
+    ///
+    /// The stream will first yield the current state and afterwards every
+    /// time the state changes.
+    pub fn state_stream(&self) -> impl Stream<Item = BackupState> {
+        let client = self.client.clone();
+
+        async_stream::stream! {
+            let mut last_state = None;
+
+            loop {
+                let listener = client.inner.backup_state.changed.listen();
+                let state = client.inner.backup_state.get();
+
+                if last_state.as_ref() != Some(&state) {
+                    last_state = Some(state.clone());
+                    yield state;
+                }
+
+                listener.await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use matrix_sdk_base::crypto::KeysBackupRequest;
+    use matrix_sdk_test::async_test;
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    use super::BackupState;
+    use crate::client::test::logged_in_client;
+
+    #[async_test]
+    async fn create_and_disable() {
+        let client = logged_in_client().await;
+        let backups = client.backups();
+
+        assert_eq!(backups.state(), BackupState::Disabled);
+
+        let _create = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(json!({ "version": "1" }).to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        backups.create().await.unwrap();
+
+        assert_eq!(backups.state(), BackupState::Enabled);
+        assert!(backups.are_enabled().await);
+
+        backups.disable().await.unwrap();
+
+        assert_eq!(backups.state(), BackupState::Disabled);
+        assert!(!backups.are_enabled().await);
+    }
+
+    #[async_test]
+    async fn failed_creation_keeps_previous_state() {
+        let client = logged_in_client().await;
+        let backups = client.backups();
+
+        let _create = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(500)
+            .with_body(json!({ "errcode": "M_UNKNOWN", "error": "Oops" }).to_string())
+            .create();
+
+        backups.create().await.unwrap_err();
+
+        assert_eq!(backups.state(), BackupState::Disabled);
+        assert!(!backups.are_enabled().await);
+    }
+
+    #[async_test]
+    async fn check_version_disables_stale_backup() {
+        let client = logged_in_client().await;
+        let backups = client.backups();
+
+        let _create = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(json!({ "version": "1" }).to_string())
+            .create();
+
+        backups.create().await.unwrap();
+
+        let _latest = mock("GET", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                    "auth_data": { "public_key": "abcdefg", "signatures": {} },
+                    "count": 0,
+                    "etag": "1",
+                    "version": "2",
+                })
+                .to_string(),
+            )
+            .create();
+
+        assert!(!backups.check_version().await.unwrap());
+        assert_eq!(backups.state(), BackupState::Disabled);
+        assert!(!backups.are_enabled().await);
+    }
+
+    #[async_test]
+    async fn wrong_backup_version_disables_backup() {
+        let client = logged_in_client().await;
+        let backups = client.backups();
+
+        let _create = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(json!({ "version": "1" }).to_string())
+            .create();
+
+        backups.create().await.unwrap();
+
+        let _upload =
+            mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/room_keys/keys\?.*$".to_owned()))
+                .with_status(403)
+                .with_body(
+                    json!({
+                        "errcode": "M_WRONG_ROOM_KEYS_VERSION",
+                        "error": "Wrong backup version.",
+                        "current_version": "2",
+                    })
+                    .to_string(),
+                )
+                .create();
+
+        let request = KeysBackupRequest { version: "1".to_owned(), rooms: BTreeMap::new() };
+        let error = client.send_backup_request(&request).await.unwrap_err();
+
+        client.handle_backup_upload_error(&error).await;
+
+        assert_eq!(backups.state(), BackupState::Disabled);
+        assert!(!backups.are_enabled().await);
+    }
+
+    #[async_test]
+    async fn failed_upload_is_retried() {
+        let client = logged_in_client().await;
+        let backups = client.backups();
+
+        let _create = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(json!({ "version": "1" }).to_string())
+            .create();
+
+        backups.create().await.unwrap();
+
+        let _upload =
+            mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/room_keys/keys\?.*$".to_owned()))
+                .with_status(500)
+                .with_body(json!({ "errcode": "M_UNKNOWN", "error": "Oops" }).to_string())
+                .create();
+
+        let request = KeysBackupRequest { version: "1".to_owned(), rooms: BTreeMap::new() };
+        let error = client.send_backup_request(&request).await.unwrap_err();
+
+        client.handle_backup_upload_error(&error).await;
+
+        // The backup stays enabled, the upload is retried on the next sync.
+        assert_eq!(backups.state(), BackupState::Error);
+        assert!(backups.are_enabled().await);
+    }
+}
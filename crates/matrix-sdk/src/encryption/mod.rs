@@ -246,7 +246,10 @@
 //! [device keys]: https://spec.matrix.org/unstable/client-server-api/#device-keys
 
 #![cfg_attr(target_arch = "wasm32", allow(unused_imports))]
+#[cfg(feature = "backups_v1")]
+pub mod backups;
 pub mod identities;
+pub mod secret_storage;
 pub mod verification;
 use std::{
     collections::{BTreeMap, HashSet},
@@ -257,6 +260,8 @@ use std::{
 
 use futures_util::stream::{self, StreamExt};
 pub use matrix_sdk_base::crypto::{MediaEncryptionInfo, LocalTrust, RoomKeyImportResult};
+#[cfg(feature = "backups_v1")]
+pub use matrix_sdk_base::crypto::backups::RecoveryKey;
 use matrix_sdk_base::{
     crypto::{
         store::CryptoStoreError, CrossSigningStatus, OutgoingRequest, RoomMessageRequest,
@@ -280,11 +285,12 @@ use ruma::{
     serde::Raw,
     DeviceId, TransactionId, UserId,
 };
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, info, instrument, trace, warn};
 
 use crate::{
     encryption::{
         identities::{Device, UserDevices},
+        secret_storage::SecretStore,
         verification::{SasVerification, Verification, VerificationRequest},
     },
     error::{HttpError, HttpResult, RoomKeyImportError},
@@ -487,6 +493,10 @@ impl Client {
 
     /// Create and upload a new cross signing identity.
     ///
+    /// The private parts of the cross signing keys will only be available on
+    /// this device. Use [`SecretStore::export_secrets()`] to put them into the
+    /// secret storage, so other devices of the user can import them.
+    ///
     /// # Arguments
     ///
     /// * `auth_data` - This request requires user interactive auth, the first
@@ -525,6 +535,8 @@ impl Client {
     ///     }
     /// }
     /// # anyhow::Result::<()>::Ok(()) });
+    ///
+    /// [`SecretStore::export_secrets()`]: crate::encryption::secret_storage::SecretStore::export_secrets
     #[cfg(feature = "encryption")]
     pub async fn bootstrap_cross_signing(&self, auth_data: Option<AuthData<'_>>) -> Result<()> {
         use serde_json::value::to_raw_value;
@@ -682,6 +694,240 @@ impl Client {
         Ok(olm.import_keys(import, false, |_, _| {}).await?)
     }
 
+    /// Restore room keys from the latest version of the server-side key
+    /// backup.
+    ///
+    /// This will download all the room keys that are part of the backup,
+    /// decrypt them using the given `RecoveryKey` and import them into our
+    /// store. Room keys that are imported this way are considered to be
+    /// already backed up.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The private part of the backup key, usually entered
+    /// by the user as a base58 encoded string.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were processed so far and the total number of room
+    /// keys that are going to be imported.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, encryption::RecoveryKey};
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let recovery_key = RecoveryKey::from_base58(
+    ///     "EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d",
+    /// )?;
+    ///
+    /// let result = client
+    ///     .restore_backup(&recovery_key, |processed, total| {
+    ///         println!("Processed {} out of {} room keys", processed, total)
+    ///     })
+    ///     .await?;
+    ///
+    /// println!(
+    ///     "Restored {} room keys out of {}",
+    ///     result.imported_count, result.total_count
+    /// );
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[cfg(feature = "backups_v1")]
+    pub async fn restore_backup(
+        &self,
+        recovery_key: &RecoveryKey,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        use ruma::api::client::r0::backup::get_backup_keys;
+
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let version = self.backup_version_for_recovery_key(recovery_key).await?;
+
+        let response = self.send(get_backup_keys::Request::new(&version), None).await?;
+
+        Ok(olm
+            .backup_machine()
+            .import_backed_up_room_keys(recovery_key, response.rooms, progress_listener)
+            .await?)
+    }
+
+    /// Restore the room keys of a single room from the latest version of the
+    /// server-side key backup.
+    ///
+    /// This works the same way as [`Client::restore_backup()`] but only
+    /// downloads and imports the room keys that belong to the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the room keys should be
+    /// restored.
+    ///
+    /// * `recovery_key` - The private part of the backup key, usually entered
+    /// by the user as a base58 encoded string.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were processed so far and the total number of room
+    /// keys that are going to be imported.
+    #[cfg(feature = "backups_v1")]
+    pub async fn restore_room_backup(
+        &self,
+        room_id: &ruma::RoomId,
+        recovery_key: &RecoveryKey,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        use ruma::api::client::r0::backup::{get_backup_keys_for_room, RoomKeyBackup};
+
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let version = self.backup_version_for_recovery_key(recovery_key).await?;
+
+        let request = get_backup_keys_for_room::Request::new(&version, room_id);
+        let response = self.send(request, None).await?;
+
+        let rooms = BTreeMap::from([(room_id.to_owned(), RoomKeyBackup::new(response.sessions))]);
+
+        Ok(olm
+            .backup_machine()
+            .import_backed_up_room_keys(recovery_key, rooms, progress_listener)
+            .await?)
+    }
+
+    /// Get the version of the latest server-side key backup, making sure that
+    /// the given recovery key can decrypt the room keys of the backup.
+    #[cfg(feature = "backups_v1")]
+    async fn backup_version_for_recovery_key(&self, recovery_key: &RecoveryKey) -> Result<String> {
+        use ruma::api::client::r0::backup::get_latest_backup_info;
+
+        let response = self.send(get_latest_backup_info::Request::new(), None).await?;
+
+        // Look at the raw JSON, the backup might use an algorithm that Ruma
+        // doesn't know about.
+        let algorithm: serde_json::Value = response.algorithm.deserialize_as()?;
+        let public_key = algorithm
+            .get("auth_data")
+            .and_then(|a| a.get("public_key"))
+            .and_then(|k| k.as_str());
+
+        if public_key == Some(recovery_key.megolm_v1_public_key().to_base64().as_str()) {
+            Ok(response.version)
+        } else {
+            warn!(
+                version = response.version.as_str(),
+                "The recovery key doesn't match the public key of the latest key backup"
+            );
+            Err(Error::MismatchedRecoveryKey)
+        }
+    }
+
+    /// Create a new dehydrated device and upload it to the server.
+    ///
+    /// The dehydrated device is encrypted with a key derived from the key of
+    /// the given secret store. Other devices will send room keys to it, which allows us
+    /// to receive room keys while none of our devices are online. An already
+    /// uploaded dehydrated device gets replaced.
+    ///
+    /// Returns the device ID of the new dehydrated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_store` - The opened secret store, its key is used to encrypt
+    /// the dehydrated device.
+    ///
+    /// * `display_name` - The display name the dehydrated device should have.
+    #[instrument(skip(self, secret_store))]
+    pub async fn dehydrate_device(
+        &self,
+        secret_store: &SecretStore,
+        display_name: Option<&str>,
+    ) -> Result<Box<DeviceId>> {
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let request = olm.dehydrated_devices().create(secret_store.key(), display_name).await;
+        let response = self.send(request, None).await?;
+
+        info!(device_id = response.device_id.as_str(), "Uploaded a new dehydrated device");
+
+        Ok(response.device_id)
+    }
+
+    /// Rehydrate our dehydrated device and import the room keys that were
+    /// sent to it while we were offline.
+    ///
+    /// Returns `None` if we don't have a dehydrated device. Once rehydrated,
+    /// the dehydrated device won't receive any more room keys, so a new one
+    /// should be created using the [`Client::dehydrate_device()`] method.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_store` - The opened secret store whose key was used to
+    /// create the dehydrated device.
+    #[instrument(skip(self, secret_store))]
+    pub async fn rehydrate_device(
+        &self,
+        secret_store: &SecretStore,
+    ) -> Result<Option<RoomKeyImportResult>> {
+        use matrix_sdk_base::crypto::dehydration::{get_dehydrated_device, get_events};
+        use ruma::api::client::error::ErrorKind;
+
+        let olm = self.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let response = match self.send(get_dehydrated_device::Request::new(), None).await {
+            Ok(r) => r,
+            Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let device = olm.dehydrated_devices().rehydrate(
+            secret_store.key(),
+            &response.device_id,
+            response.device_data,
+        )?;
+
+        let mut result =
+            RoomKeyImportResult { imported_count: 0, total_count: 0, keys: Default::default() };
+        let mut next_batch = None;
+
+        loop {
+            let mut request = get_events::Request::new(device.device_id());
+            request.next_batch = next_batch.as_deref();
+
+            let response = self.send(request, None).await?;
+
+            if response.events.is_empty() {
+                break;
+            }
+
+            let imported = device.receive_events(response.events).await?;
+
+            result.imported_count += imported.imported_count;
+            result.total_count += imported.total_count;
+
+            for (room_id, room_keys) in imported.keys {
+                let entry = result.keys.entry(room_id).or_default();
+
+                for (sender_key, sessions) in room_keys {
+                    entry.entry(sender_key).or_default().extend(sessions);
+                }
+            }
+
+            match response.next_batch {
+                Some(token) => next_batch = Some(token),
+                None => break,
+            }
+        }
+
+        info!(
+            device_id = device.device_id().as_str(),
+            imported_count = result.imported_count,
+            "Rehydrated our dehydrated device"
+        );
+
+        Ok(Some(result))
+    }
+
     /// Tries to decrypt a `AnyRoomEvent`. Returns undecrypted room event when
     /// decryption fails.
     #[cfg(feature = "encryption")]
@@ -729,44 +975,26 @@ impl Client {
         Ok(response)
     }
 
-    /// Encrypt and upload the file to be read from `reader` and construct an
-    /// attachment message with `body` and the specified `content_type`.
+    /// Encrypt and upload the file to be read from `reader`.
     #[cfg(feature = "encryption")]
-    pub(crate) async fn prepare_encrypted_attachment_message<R: Read>(
+    pub(crate) async fn upload_encrypted_file<R: Read>(
         &self,
-        body: &str,
         content_type: &mime::Mime,
         reader: &mut R,
-    ) -> Result<ruma::events::room::message::MessageType> {
+    ) -> Result<ruma::events::room::EncryptedFile> {
         let mut reader = matrix_sdk_base::crypto::AttachmentEncryptor::new(reader);
 
         let response = self.upload(content_type, &mut reader).await?;
 
-        let file: ruma::events::room::EncryptedFile = {
-            let keys = reader.finish();
-            ruma::events::room::EncryptedFileInit {
-                url: response.content_uri,
-                key: keys.web_key,
-                iv: keys.iv,
-                hashes: keys.hashes,
-                v: keys.version,
-            }
-            .into()
-        };
-
-        use ruma::events::room::message;
-        Ok(match content_type.type_() {
-            mime::IMAGE => {
-                message::MessageType::Image(message::ImageMessageEventContent::encrypted(body.to_owned(), file))
-            }
-            mime::AUDIO => {
-                message::MessageType::Audio(message::AudioMessageEventContent::encrypted(body.to_owned(), file))
-            }
-            mime::VIDEO => {
-                message::MessageType::Video(message::VideoMessageEventContent::encrypted(body.to_owned(), file))
-            }
-            _ => message::MessageType::File(message::FileMessageEventContent::encrypted(body.to_owned(), file)),
-        })
+        let keys = reader.finish();
+        Ok(ruma::events::room::EncryptedFileInit {
+            url: response.content_uri,
+            key: keys.web_key,
+            iv: keys.iv,
+            hashes: keys.hashes,
+            v: keys.version,
+        }
+        .into())
     }
 
     #[cfg(feature = "encryption")]
@@ -787,6 +1015,48 @@ impl Client {
         Ok(self.send(request, None).await?)
     }
 
+    /// Get the content of the global account data event with the given type
+    /// from the server.
+    ///
+    /// Returns `None` if no such event exists.
+    pub(crate) async fn account_data_content<T: serde::de::DeserializeOwned>(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<T>> {
+        use ruma::api::client::{error::ErrorKind, r0::config::get_global_account_data};
+
+        let own_user =
+            self.user_id().await.ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
+
+        let request = get_global_account_data::Request::new(&own_user, event_type);
+
+        match self.send(request, None).await {
+            Ok(response) => Ok(Some(response.account_data.deserialize_as()?)),
+            Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Put the given content into the global account data event with the
+    /// given type.
+    pub(crate) async fn set_account_data_content(
+        &self,
+        event_type: &str,
+        content: &impl serde::Serialize,
+    ) -> Result<ruma::api::client::r0::config::set_global_account_data::Response> {
+        let own_user =
+            self.user_id().await.ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
+        let data = serde_json::value::to_raw_value(content)?;
+
+        let request = ruma::api::client::r0::config::set_global_account_data::Request::new(
+            &data,
+            event_type,
+            &own_user,
+        );
+
+        Ok(self.send(request, None).await?)
+    }
+
     #[cfg(feature = "encryption")]
     pub(crate) async fn create_dm_room(&self, user_id: Box<UserId>) -> Result<Option<room::Joined>> {
         use ruma::{
@@ -981,8 +1251,23 @@ impl Client {
                 self.mark_request_as_sent(r.request_id(), &response).await?;
             }
             OutgoingRequests::KeysBackup(request) => {
-                let response = self.send_backup_request(request).await?;
+                #[cfg(feature = "backups_v1")]
+                self.set_backup_state(backups::BackupState::Uploading);
+
+                let response = match self.send_backup_request(request).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        #[cfg(feature = "backups_v1")]
+                        self.handle_backup_upload_error(&e).await;
+
+                        return Err(e.into());
+                    }
+                };
+
                 self.mark_request_as_sent(r.request_id(), &response).await?;
+
+                #[cfg(feature = "backups_v1")]
+                self.set_backup_state(backups::BackupState::Enabled);
             }
         }
 
@@ -992,13 +1277,13 @@ impl Client {
     async fn send_backup_request(
         &self,
         request: &matrix_sdk_base::crypto::KeysBackupRequest,
-    ) -> Result<KeysBackupResponse> {
+    ) -> HttpResult<KeysBackupResponse> {
         let request = ruma::api::client::r0::backup::add_backup_keys::Request::new(
             &request.version,
             request.rooms.to_owned(),
         );
 
-        Ok(self.send(request, None).await?)
+        self.send(request, None).await
     }
 
     pub(crate) async fn send_outgoing_requests(&self) -> Result<()> {
@@ -1010,6 +1295,9 @@ impl Client {
             warn!("Error while claiming one-time keys {:?}", e);
         }
 
+        #[cfg(feature = "backups_v1")]
+        self.sync_backup_state().await;
+
         let outgoing_requests = stream::iter(self.base_client().outgoing_requests().await?)
             .map(|r| self.send_outgoing_request(r));
 
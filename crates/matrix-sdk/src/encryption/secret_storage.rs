@@ -0,0 +1,329 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Secret storage, also known as SSSS.
+//!
+//! Secret storage allows secrets, like the private parts of the cross signing
+//! keys or the backup recovery key, to be stored encrypted in the global
+//! account data of the user. A new device can download and decrypt them, as
+//! long as the user remembers the secret storage key or the passphrase it was
+//! derived from.
+//!
+//! The [`SecretStorage`] object is the entry point to create or open a
+//! [`SecretStore`], it can be retrieved using the [`Client::secret_storage()`]
+//! method.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use matrix_sdk::Client;
+//! # use futures::executor::block_on;
+//! # use url::Url;
+//! # block_on(async {
+//! # let homeserver = Url::parse("http://localhost:8080")?;
+//! # let client = Client::new(homeserver).await?;
+//! // On the device that bootstrapped cross signing.
+//! let secret_store = client.secret_storage().create_secret_store(None).await?;
+//! secret_store.export_secrets().await?;
+//!
+//! println!("Your secret storage key is {}", secret_store.secret_storage_key());
+//!
+//! // On a new device, after the key has been entered by the user.
+//! # let key = "";
+//! let secret_store = client.secret_storage().open_secret_store(key).await?;
+//! secret_store.import_secrets().await?;
+//! # anyhow::Result::<()>::Ok(()) });
+//! ```
+
+use matrix_sdk_base::crypto::{
+    secret_storage::{
+        DefaultKeyContent, EncryptedSecretContent, SecretStorageError, SecretStorageKey,
+        SecretStorageKeyDescription, DEFAULT_KEY_EVENT_TYPE, KEY_EVENT_TYPE_PREFIX,
+    },
+    CrossSigningKeyExport,
+};
+use ruma::events::secret::request::SecretName;
+use tracing::{info, instrument, warn};
+use zeroize::Zeroizing;
+
+use crate::{Client, Error, Result};
+
+/// A handle to create or open a [`SecretStore`].
+///
+/// This can be retrieved using the [`Client::secret_storage()`] method.
+#[derive(Debug, Clone)]
+pub struct SecretStorage {
+    client: Client,
+}
+
+impl Client {
+    /// Get a handle to the secret storage of our own user.
+    pub fn secret_storage(&self) -> SecretStorage {
+        SecretStorage { client: self.clone() }
+    }
+}
+
+impl SecretStorage {
+    /// Has a default secret storage key been set up for our user?
+    pub async fn is_enabled(&self) -> Result<bool> {
+        Ok(self
+            .client
+            .account_data_content::<DefaultKeyContent>(DEFAULT_KEY_EVENT_TYPE)
+            .await?
+            .is_some())
+    }
+
+    /// Create a new secret storage key and make it the default key.
+    ///
+    /// The description of the key is uploaded to the global account data,
+    /// secrets can then be stored using the returned [`SecretStore`].
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - An optional passphrase the key should be derived from.
+    /// If no passphrase is given, a random key is generated.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create the key.
+    #[instrument(skip(self, passphrase))]
+    pub async fn create_secret_store(&self, passphrase: Option<&str>) -> Result<SecretStore> {
+        let key = if let Some(passphrase) = passphrase {
+            let passphrase = Zeroizing::new(passphrase.to_owned());
+            run_blocking(move || SecretStorageKey::new_from_passphrase(&passphrase)).await
+        } else {
+            SecretStorageKey::new()
+        };
+
+        self.client.set_account_data_content(&key.event_type(), key.description()).await?;
+        self.client
+            .set_account_data_content(
+                DEFAULT_KEY_EVENT_TYPE,
+                &DefaultKeyContent { key: key.key_id().to_owned() },
+            )
+            .await?;
+
+        info!(key_id = key.key_id(), "Created a new default secret storage key");
+
+        Ok(SecretStore { client: self.client.clone(), key })
+    }
+
+    /// Open the secret store using the default secret storage key.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_storage_key` - Either the base58 encoded secret storage key,
+    /// or the passphrase the key was derived from.
+    #[instrument(skip(self, secret_storage_key))]
+    pub async fn open_secret_store(&self, secret_storage_key: &str) -> Result<SecretStore> {
+        let key_id = self
+            .client
+            .account_data_content::<DefaultKeyContent>(DEFAULT_KEY_EVENT_TYPE)
+            .await?
+            .ok_or(SecretStorageError::MissingDefaultKey)?
+            .key;
+
+        let description: SecretStorageKeyDescription = self
+            .client
+            .account_data_content(&format!("{}{}", KEY_EVENT_TYPE_PREFIX, key_id))
+            .await?
+            .ok_or_else(|| SecretStorageError::MissingKeyDescription(key_id.clone()))?;
+
+        let key = match SecretStorageKey::from_recovery_key(
+            &key_id,
+            description.clone(),
+            secret_storage_key,
+        ) {
+            Ok(key) => key,
+            // The input was a valid key, it just isn't the right one.
+            Err(SecretStorageError::InvalidMac) => {
+                return Err(SecretStorageError::InvalidMac.into())
+            }
+            Err(_) if description.passphrase.is_some() => {
+                let passphrase = Zeroizing::new(secret_storage_key.to_owned());
+                run_blocking(move || {
+                    SecretStorageKey::from_passphrase(&key_id, description, &passphrase)
+                })
+                .await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(SecretStore { client: self.client.clone(), key })
+    }
+}
+
+/// An opened secret store, allows secrets to be stored in and retrieved from
+/// the global account data.
+#[derive(Debug)]
+pub struct SecretStore {
+    client: Client,
+    key: SecretStorageKey,
+}
+
+impl SecretStore {
+    /// Get the ID of the secret storage key.
+    pub fn key_id(&self) -> &str {
+        self.key.key_id()
+    }
+
+    /// Get the secret storage key as a base58 encoded string.
+    ///
+    /// This should be presented to the user, it's needed to open the secret
+    /// store on another device.
+    pub fn secret_storage_key(&self) -> String {
+        self.key.to_base58()
+    }
+
+    /// Get the secret storage key of this secret store.
+    pub(crate) fn key(&self) -> &SecretStorageKey {
+        &self.key
+    }
+
+    /// Get and decrypt the secret with the given name.
+    ///
+    /// Returns `None` if the secret isn't stored in the global account data.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, e.g. `m.cross_signing.master`.
+    pub async fn get_secret(&self, secret_name: &str) -> Result<Option<Zeroizing<String>>> {
+        let content: Option<EncryptedSecretContent> =
+            self.client.account_data_content(secret_name).await?;
+
+        if let Some(content) = content {
+            let encrypted = content.get(self.key.key_id())?;
+            Ok(Some(self.key.decrypt(secret_name, &encrypted)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Encrypt the given secret and put it into the global account data.
+    ///
+    /// Secrets that are encrypted with other secret storage keys are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, e.g. `m.cross_signing.master`.
+    ///
+    /// * `secret` - The secret that should be stored.
+    pub async fn put_secret(&self, secret_name: &str, secret: &str) -> Result<()> {
+        let mut content: EncryptedSecretContent =
+            self.client.account_data_content(secret_name).await?.unwrap_or_default();
+
+        content.insert(self.key.key_id(), self.key.encrypt(secret_name, secret));
+
+        self.client.set_account_data_content(secret_name, &content).await?;
+
+        Ok(())
+    }
+
+    /// Put all the secrets we know about into the secret store.
+    ///
+    /// This stores the private parts of our cross signing keys and, if
+    /// backups are enabled, the backup recovery key.
+    #[instrument(skip(self))]
+    pub async fn export_secrets(&self) -> Result<()> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        if let Some(export) = olm.export_cross_signing_keys().await {
+            let keys = [
+                (SecretName::CrossSigningMasterKey, export.master_key.as_deref()),
+                (SecretName::CrossSigningSelfSigningKey, export.self_signing_key.as_deref()),
+                (SecretName::CrossSigningUserSigningKey, export.user_signing_key.as_deref()),
+            ];
+
+            for (secret_name, secret) in keys {
+                if let Some(secret) = secret {
+                    self.put_secret(secret_name.as_ref(), secret).await?;
+                }
+            }
+        }
+
+        #[cfg(feature = "backups_v1")]
+        if let Some(recovery_key) = olm.backup_machine().get_backup_keys().await?.recovery_key {
+            let secret = Zeroizing::new(recovery_key.to_base64());
+            self.put_secret(SecretName::RecoveryKey.as_ref(), &secret).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get all the secrets we know about from the secret store and import them.
+    ///
+    /// The private cross signing keys are only imported if they match the
+    /// public cross signing keys of our own user identity, so the identity
+    /// needs to be known, i.e. a keys query for our own user needs to have
+    /// happened. If backups are supported the backup recovery key will be used
+    /// to enable the latest backup version.
+    #[instrument(skip(self))]
+    pub async fn import_secrets(&self) -> Result<()> {
+        let olm = self.client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let master_key = self.get_secret(SecretName::CrossSigningMasterKey.as_ref()).await?;
+        let self_signing_key =
+            self.get_secret(SecretName::CrossSigningSelfSigningKey.as_ref()).await?;
+        let user_signing_key =
+            self.get_secret(SecretName::CrossSigningUserSigningKey.as_ref()).await?;
+
+        if master_key.is_some() || self_signing_key.is_some() || user_signing_key.is_some() {
+            let export = CrossSigningKeyExport {
+                master_key: master_key.map(|k| k.to_string()),
+                self_signing_key: self_signing_key.map(|k| k.to_string()),
+                user_signing_key: user_signing_key.map(|k| k.to_string()),
+            };
+
+            let status = olm.import_cross_signing_keys(export).await?;
+
+            info!(status =? status, "Imported the private cross signing keys from the secret store");
+        }
+
+        #[cfg(feature = "backups_v1")]
+        if let Some(secret) = self.get_secret(SecretName::RecoveryKey.as_ref()).await? {
+            use crate::encryption::RecoveryKey;
+
+            match RecoveryKey::from_base64(&secret) {
+                Ok(recovery_key) => {
+                    match self.client.backups().enable(&recovery_key).await {
+                        Ok(()) => {
+                            info!("Enabled the backup using the recovery key from the secret store")
+                        }
+                        Err(Error::MismatchedRecoveryKey) => {
+                            warn!("The recovery key in the secret store doesn't match the latest backup")
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(e) => warn!(error =? e, "The recovery key in the secret store isn't valid"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run a CPU heavy closure, e.g. a passphrase key derivation, without blocking
+/// the async runtime if possible.
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::task::spawn_blocking(f).await.expect("Task join error")
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        f()
+    }
+}
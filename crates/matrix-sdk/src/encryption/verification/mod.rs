@@ -29,15 +29,21 @@
 //!   authentication
 //! string.
 //! * [`QrVerification`] - Interactive verification using QR codes.
+//!
+//! Requests and verification flows offer a `changes()` stream yielding a
+//! [`VerificationChange`] every time they move into a new state, this can be
+//! used to drive a verification UI without polling the objects.
 
 #[cfg(feature = "qrcode")]
 mod qrcode;
 mod requests;
 mod sas;
 
+use futures_core::stream::Stream;
+use matrix_sdk_base::crypto::Verification as BaseVerification;
 #[cfg(feature = "qrcode")]
 pub use matrix_sdk_base::crypto::{matrix_qrcode::QrVerificationData, ScanError};
-pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, Emoji};
+pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, Emoji, VerificationChange};
 #[cfg(feature = "qrcode")]
 pub use qrcode::QrVerification;
 pub use requests::VerificationRequest;
@@ -138,6 +144,19 @@ impl Verification {
             Verification::QrV1(q) => q.we_started(),
         }
     }
+
+    /// Get a stream of changes of this verification flow.
+    ///
+    /// See [`SasVerification::changes()`] for more info.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        let verification: BaseVerification = match self {
+            Verification::SasV1(s) => s.inner.clone().into(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(q) => q.inner.clone().into(),
+        };
+
+        verification.changes()
+    }
 }
 
 impl From<SasVerification> for Verification {
@@ -12,9 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures_core::stream::Stream;
 use matrix_sdk_base::crypto::{
     matrix_qrcode::{qrcode::QrCode, EncodingError},
-    CancelInfo, QrVerification as BaseQrVerification,
+    CancelInfo, QrVerification as BaseQrVerification, VerificationChange,
 };
 use ruma::UserId;
 
@@ -54,6 +55,16 @@ impl QrVerification {
         self.inner.cancel_info()
     }
 
+    /// Get a stream of changes of this verification flow.
+    ///
+    /// The stream yields the current state of the flow first and a new
+    /// [`VerificationChange`] every time the flow moves on, e.g. once the other
+    /// side has scanned our QR code. It terminates once the verification is
+    /// done or has been cancelled.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.inner.changes()
+    }
+
     /// Get the user id of the other user participating in this verification
     /// flow.
     pub fn other_user_id(&self) -> &UserId {
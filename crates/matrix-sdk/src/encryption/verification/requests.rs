@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::{CancelInfo, VerificationRequest as BaseVerificationRequest};
+use futures_core::stream::Stream;
+use matrix_sdk_base::crypto::{
+    CancelInfo, VerificationChange, VerificationRequest as BaseVerificationRequest,
+};
 use ruma::events::key::verification::VerificationMethod;
 
 use super::SasVerification;
@@ -49,6 +52,17 @@ impl VerificationRequest {
         self.inner.cancel_info()
     }
 
+    /// Get a stream of changes of this verification request.
+    ///
+    /// The stream yields the current state of the request first and a new
+    /// [`VerificationChange`] every time the request moves on, e.g. once it
+    /// has been accepted by the other side or once a verification flow has
+    /// been started. It terminates once the request is done or has been
+    /// cancelled.
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.inner.changes()
+    }
+
     /// Get our own user id.
     pub fn own_user_id(&self) -> &ruma::UserId {
         self.inner.own_user_id()
@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas};
+use futures_core::stream::Stream;
+use matrix_sdk_base::crypto::{
+    AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas, VerificationChange,
+};
 use ruma::{events::key::verification::cancel::CancelCode, UserId};
 
 use crate::{error::Result, Client};
@@ -189,6 +192,41 @@ impl SasVerification {
         self.inner.is_cancelled()
     }
 
+    /// Get a stream of changes of this verification flow.
+    ///
+    /// The stream yields the current state of the flow first and a new
+    /// [`VerificationChange`] every time the flow moves on, it terminates once
+    /// the verification is done or has been cancelled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::StreamExt;
+    /// use matrix_sdk::encryption::verification::{SasVerification, VerificationChange};
+    ///
+    /// # async fn example(sas: SasVerification) -> matrix_sdk::Result<()> {
+    /// let mut changes = sas.changes();
+    ///
+    /// while let Some(change) = changes.next().await {
+    ///     match change {
+    ///         VerificationChange::KeysExchanged { emoji, .. } => {
+    ///             println!("Do the emoji match? {:?}", emoji);
+    ///             sas.confirm().await?;
+    ///         }
+    ///         VerificationChange::Done => println!("Successfully verified the device"),
+    ///         VerificationChange::Cancelled { info } => {
+    ///             println!("The verification has been cancelled: {}", info.reason())
+    ///         }
+    ///         _ => (),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn changes(&self) -> impl Stream<Item = VerificationChange> {
+        self.inner.changes()
+    }
+
     /// Get the other users device that we're verifying.
     pub fn other_device(&self) -> &ReadOnlyDevice {
         self.inner.other_device()
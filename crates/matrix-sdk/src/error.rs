@@ -21,13 +21,15 @@ use http::StatusCode;
 use matrix_sdk_base::crypto::ScanError;
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{
-    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError,
+    dehydration::DehydrationError, secret_storage::SecretStorageError, CryptoStoreError,
+    DecryptorError, KeyExportError, MegolmError, OlmError, SecretImportError,
 };
 use matrix_sdk_base::{Error as SdkBaseError, StoreError};
 use reqwest::Error as ReqwestError;
 use ruma::{
     api::{
         client::{
+            error::ErrorKind,
             r0::uiaa::{UiaaInfo, UiaaResponse as UiaaError},
             Error as RumaClientApiError,
         },
@@ -35,11 +37,15 @@ use ruma::{
     },
     events::tag::InvalidUserTagName,
     identifiers::Error as IdentifierError,
+    RoomId,
 };
 use serde_json::Error as JsonError;
 use thiserror::Error;
 use url::ParseError as UrlParseError;
 
+#[cfg(feature = "backups_v1")]
+use crate::encryption::backups::BackupCreationError;
+
 /// Result type of the matrix-sdk.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -100,6 +106,10 @@ pub enum HttpError {
     /// Tried to send a request without `user_id` in the `Session`
     #[error("missing user_id in session")]
     UserIdRequired,
+
+    /// Reading the body of a streamed request failed.
+    #[error(transparent)]
+    Io(#[from] IoError),
 }
 
 /// Internal representation of errors.
@@ -142,6 +152,21 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while using the secret storage.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    SecretStorage(#[from] SecretStorageError),
+
+    /// An error occurred while importing a secret.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    SecretImport(#[from] SecretImportError),
+
+    /// An error occurred while creating or rehydrating a dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    Dehydration(#[from] DehydrationError),
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -159,9 +184,29 @@ pub enum Error {
     #[error(transparent)]
     QrCodeScanError(#[from] ScanError),
 
+    /// The room isn't in the joined state, events can't be sent to it.
+    #[error("the room {0} isn't in the joined state")]
+    RoomNotJoined(Box<RoomId>),
+
     /// An error encountered when trying to parse a user tag name.
     #[error(transparent)]
     UserTagName(#[from] InvalidUserTagName),
+
+    /// The recovery key doesn't match the public key of the server-side key
+    /// backup.
+    #[cfg(feature = "backups_v1")]
+    #[error("the recovery key doesn't match the public key of the server-side key backup")]
+    MismatchedRecoveryKey,
+
+    /// An error occurred while creating a new server-side key backup version.
+    #[cfg(feature = "backups_v1")]
+    #[error(transparent)]
+    BackupCreation(#[from] BackupCreationError),
+
+    /// A media transfer was cancelled using its
+    /// [`CancellationToken`](crate::transfer::CancellationToken).
+    #[error("the media transfer was cancelled")]
+    TransferCancelled,
 }
 
 /// Error for the room key importing functionality.
@@ -214,6 +259,22 @@ impl HttpError {
             None
         }
     }
+
+    /// Try to destructure the error into the kind of a known client-server API
+    /// error that the homeserver returned.
+    ///
+    /// This includes errors of endpoints that use user-interactive
+    /// authentication, if the homeserver didn't ask for further
+    /// authentication.
+    pub fn client_api_error_kind(&self) -> Option<&ErrorKind> {
+        match self {
+            HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e)))
+            | HttpError::UiaaError(FromHttpResponseError::Http(ServerError::Known(
+                UiaaError::MatrixError(e),
+            ))) => Some(&e.kind),
+            _ => None,
+        }
+    }
 }
 
 impl Error {
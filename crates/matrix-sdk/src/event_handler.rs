@@ -53,6 +53,7 @@ pub enum EventKind {
     InitialState,
     ToDevice,
     Presence,
+    LocalEcho,
 }
 
 /// A statically-known event kind/type that can be retrieved from an event sync.
@@ -315,6 +316,20 @@ impl Client {
         .await
     }
 
+    pub(crate) async fn handle_local_echo_events<T>(
+        &self,
+        room: &Option<room::Room>,
+        local_echoes: &[Raw<T>],
+    ) -> serde_json::Result<()> {
+        self.handle_sync_events_wrapped_with(
+            room,
+            local_echoes,
+            |ev| (ev, None),
+            |_| Ok((EventKind::LocalEcho, Cow::Borrowed(crate::send_queue::LocalEcho::TYPE))),
+        )
+        .await
+    }
+
     async fn handle_sync_events_wrapped_with<'a, T: 'a, U: 'a>(
         &self,
         room: &Option<room::Room>,
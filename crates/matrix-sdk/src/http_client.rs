@@ -12,17 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{convert::TryFrom, fmt::Debug, sync::Arc};
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    pin::Pin,
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
 
 use bytes::{Bytes, BytesMut};
-use http::Response as HttpResponse;
+use futures_core::stream::Stream;
+use futures_util::{stream, StreamExt};
+use http::{
+    header::{CONTENT_LENGTH, RETRY_AFTER},
+    Method, Response as HttpResponse, StatusCode,
+};
 use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
 use reqwest::{Client, Response};
-use ruma::api::{
-    client::r0::media::create_content, error::FromHttpResponseError, AuthScheme, IncomingResponse,
-    OutgoingRequest, OutgoingRequestAppserviceExt, SendAccessToken,
+use ruma::{
+    api::{
+        client::r0::media::create_content, error::FromHttpResponseError, AuthScheme,
+        IncomingResponse, OutgoingRequest, OutgoingRequestAppserviceExt, SendAccessToken,
+    },
+    assign,
 };
-use tracing::trace;
+use tracing::{trace, warn};
 use url::Url;
 
 use crate::{
@@ -31,6 +45,14 @@ use crate::{
     Session,
 };
 
+/// A stream of bytes, the body of a streamed request or response.
+#[cfg(not(target_arch = "wasm32"))]
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, HttpError>> + Send + Sync>>;
+
+/// A stream of bytes, the body of a streamed request or response.
+#[cfg(target_arch = "wasm32")]
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, HttpError>>>>;
+
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -42,6 +64,11 @@ pub trait HttpSend: AsyncTraitDeps {
     /// This is called by the client every time it wants to send anything to a
     /// homeserver.
     ///
+    /// Implementors don't need to retry failed requests, the client retries
+    /// requests that failed with a [`HttpError::Reqwest`] error or returned a
+    /// server error or `429 Too Many Requests` status code, as configured by
+    /// the [`RequestConfig`].
+    ///
     /// # Arguments
     ///
     /// * `request` - The http request that has been converted from a ruma
@@ -90,14 +117,106 @@ pub trait HttpSend: AsyncTraitDeps {
         request: http::Request<Bytes>,
         config: RequestConfig,
     ) -> Result<http::Response<Bytes>, HttpError>;
+
+    /// Send a request with a streamed body, used to upload media.
+    ///
+    /// Streamed requests are never retried. The default implementation
+    /// collects the whole body and sends it using
+    /// [`send_request()`](#tymethod.send_request).
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The http request with a streamed body.
+    ///
+    /// * `request_config` - The config used for this request.
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<ByteStream>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let (parts, body) = request.into_parts();
+        let body = collect_byte_stream(body).await?;
+
+        self.send_request(http::Request::from_parts(parts, body), config).await
+    }
+
+    /// Send a request and stream the body of the response, used to download
+    /// media.
+    ///
+    /// The default implementation sends the request using
+    /// [`send_request()`](#tymethod.send_request) and returns the whole body
+    /// as a single chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The http request that has been converted from a ruma
+    ///   `Request`.
+    ///
+    /// * `request_config` - The config used for this request.
+    async fn send_request_streaming_response(
+        &self,
+        request: http::Request<Bytes>,
+        config: RequestConfig,
+    ) -> Result<http::Response<ByteStream>, HttpError> {
+        let response = self.send_request(request, config).await?;
+
+        Ok(response.map(|body| Box::pin(stream::once(async move { Ok(body) })) as ByteStream))
+    }
+}
+
+/// Collect all the chunks of the given stream.
+pub(crate) async fn collect_byte_stream(mut stream: ByteStream) -> Result<Bytes, HttpError> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+    }
+
+    Ok(body.freeze())
 }
 
+/// Information about a request that was rate limited by the homeserver.
+///
+/// This is passed to the handlers registered using
+/// [`Client::register_rate_limit_handler()`].
+///
+/// [`Client::register_rate_limit_handler()`]: crate::Client::register_rate_limit_handler
 #[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RateLimitInfo {
+    /// The HTTP method of the rate limited request.
+    pub method: Method,
+    /// The path of the rate limited request.
+    pub path: String,
+    /// The number of the attempt that was rate limited, starting at `1`.
+    pub attempt: u64,
+    /// How long the homeserver asked us to wait before retrying the request.
+    pub retry_after: Option<Duration>,
+    /// Will the request be retried, `false` if the retry limit or the retry
+    /// timeout of the [`RequestConfig`] has been reached.
+    pub will_retry: bool,
+}
+
+pub(crate) type RateLimitHandlerFn = Box<dyn Fn(&RateLimitInfo) + Send + Sync>;
+
+#[derive(Clone)]
 pub(crate) struct HttpClient {
     pub(crate) inner: Arc<dyn HttpSend>,
     pub(crate) homeserver: Arc<RwLock<Url>>,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
     pub(crate) request_config: RequestConfig,
+    pub(crate) rate_limit_handlers: Arc<StdRwLock<Vec<RateLimitHandlerFn>>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl Debug for HttpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpClient")
+            .field("inner", &self.inner)
+            .field("homeserver", &self.homeserver)
+            .field("request_config", &self.request_config)
+            .finish()
+    }
 }
 
 impl HttpClient {
@@ -107,7 +226,140 @@ impl HttpClient {
         session: Arc<RwLock<Option<Session>>>,
         request_config: RequestConfig,
     ) -> Self {
-        HttpClient { inner, homeserver, session, request_config }
+        HttpClient {
+            inner,
+            homeserver,
+            session,
+            request_config,
+            rate_limit_handlers: Default::default(),
+        }
+    }
+
+    fn notify_rate_limited(&self, info: &RateLimitInfo) {
+        warn!(
+            method = %info.method,
+            path = info.path.as_str(),
+            attempt = info.attempt,
+            retry_after = ?info.retry_after,
+            will_retry = info.will_retry,
+            "The request was rate limited by the homeserver"
+        );
+
+        for handler in self.rate_limit_handlers.read().unwrap().iter() {
+            handler(info);
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn send_with_retry(
+        &self,
+        request: http::Request<Bytes>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+
+        let response = self.inner.send_request(request, config).await?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            self.notify_rate_limited(&RateLimitInfo {
+                method,
+                path,
+                attempt: 1,
+                retry_after: retry_after(&response),
+                will_retry: false,
+            });
+        }
+
+        Ok(response)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_with_retry(
+        &self,
+        request: http::Request<Bytes>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        use std::{
+            sync::atomic::{AtomicU64, Ordering},
+            time::Instant,
+        };
+
+        use backoff::{future::retry, Error as RetryError};
+
+        let backoff = config.backoff();
+        let retry_limit = config.retry_limit;
+        let retry_count = AtomicU64::new(1);
+        let start = Instant::now();
+
+        let request = &request;
+        let retry_count = &retry_count;
+
+        let send = || async move {
+            let attempt = retry_count.fetch_add(1, Ordering::Relaxed);
+
+            let stop =
+                if let Some(retry_limit) = retry_limit { attempt >= retry_limit } else { false };
+
+            let response =
+                self.inner.send_request(clone_request(request), config).await.map_err(|e| {
+                    match e {
+                        // Connection errors and timeouts are worth retrying.
+                        HttpError::Reqwest(_) if !stop => {
+                            RetryError::Transient { err: e, retry_after: None }
+                        }
+                        e => RetryError::Permanent(e),
+                    }
+                })?;
+
+            let status_code = response.status();
+
+            let retry_after = if status_code == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = retry_after(&response);
+
+                // The backoff doesn't check the retry timeout if we tell it how
+                // long to wait, so we need to do it ourselves.
+                let exceeds_timeout = match (config.retry_timeout, retry_after) {
+                    // The server controls the value, an absurdly large one
+                    // would overflow the addition.
+                    (Some(timeout), Some(retry_after)) => {
+                        start.elapsed().checked_add(retry_after).map_or(true, |t| t > timeout)
+                    }
+                    _ => false,
+                };
+
+                let stop = stop || exceeds_timeout;
+
+                self.notify_rate_limited(&RateLimitInfo {
+                    method: request.method().clone(),
+                    path: request.uri().path().to_owned(),
+                    attempt,
+                    retry_after,
+                    will_retry: !stop,
+                });
+
+                if stop {
+                    return Ok(response);
+                }
+
+                retry_after
+            } else {
+                None
+            };
+
+            if !stop
+                && (status_code.is_server_error() || status_code == StatusCode::TOO_MANY_REQUESTS)
+            {
+                return Err(RetryError::Transient {
+                    err: HttpError::Server(status_code),
+                    retry_after,
+                });
+            }
+
+            Ok(response)
+        };
+
+        retry(backoff, send).await
     }
 
     async fn send_request<Request: OutgoingRequest>(
@@ -121,13 +373,22 @@ impl HttpClient {
             None => self.request_config,
         };
 
-        let request = if !self.request_config.assert_identity {
-            self.try_into_http_request(request, session, config).await?
-        } else {
-            self.try_into_http_request_with_identity_assertion(request, session, config).await?
-        };
+        let request = self.build_http_request(request, session, config).await?;
 
-        self.inner.send_request(request, config).await
+        self.send_with_retry(request, config).await
+    }
+
+    async fn build_http_request<Request: OutgoingRequest>(
+        &self,
+        request: Request,
+        session: Arc<RwLock<Option<Session>>>,
+        config: RequestConfig,
+    ) -> Result<http::Request<Bytes>, HttpError> {
+        if !self.request_config.assert_identity {
+            self.try_into_http_request(request, session, config).await
+        } else {
+            self.try_into_http_request_with_identity_assertion(request, session, config).await
+        }
     }
 
     async fn try_into_http_request<Request: OutgoingRequest>(
@@ -183,13 +444,13 @@ impl HttpClient {
             return Err(HttpError::AuthenticationRequired);
         };
 
-        let user_id = if let Some(session) = read_guard.as_ref() {
-            session.user_id.clone()
+        let (user_id, device_id) = if let Some(session) = read_guard.as_ref() {
+            (session.user_id.clone(), session.device_id.clone())
         } else {
             return Err(HttpError::UserIdRequired);
         };
 
-        let http_request = request
+        let mut http_request = request
             .try_into_http_request_with_user_id::<BytesMut>(
                 &self.homeserver.read().await.to_string(),
                 access_token,
@@ -197,6 +458,26 @@ impl HttpClient {
             )?
             .map(|body| body.freeze());
 
+        if self.request_config.assert_device {
+            // The query already contains the `user_id`, so we can just append
+            // the device ID to it, see MSC3202.
+            let device_id: String =
+                url::form_urlencoded::byte_serialize(device_id.as_bytes()).collect();
+            let uri = http_request.uri();
+            let path_and_query = format!(
+                "{}?{}&org.matrix.msc3202.device_id={}",
+                uri.path(),
+                uri.query().unwrap_or_default(),
+                device_id
+            );
+
+            let mut parts = uri.clone().into_parts();
+            parts.path_and_query =
+                Some(path_and_query.parse().expect("Can't parse the device ID query"));
+            *http_request.uri_mut() =
+                http::Uri::from_parts(parts).expect("Can't build an URI with the device ID");
+        }
+
         Ok(http_request)
     }
 
@@ -209,6 +490,72 @@ impl HttpClient {
         Ok(create_content::Response::try_from_http_response(response)?)
     }
 
+    /// Upload the media of the given stream.
+    ///
+    /// The request is built like any other `create_content` request, including
+    /// the identity assertion of appservices, only its body is replaced by the
+    /// stream. Unlike other requests, streamed uploads are never retried since
+    /// the stream can only be consumed once.
+    pub async fn upload_stream(
+        &self,
+        content_type: &str,
+        body: ByteStream,
+        size: Option<u64>,
+        config: Option<RequestConfig>,
+    ) -> Result<create_content::Response, HttpError> {
+        let config = config.unwrap_or(self.request_config);
+
+        let request = assign!(create_content::Request::new(&[]), {
+            content_type: Some(content_type),
+        });
+        let (mut parts, _) =
+            self.build_http_request(request, self.session.clone(), config).await?.into_parts();
+
+        if let Some(size) = size {
+            parts.headers.insert(CONTENT_LENGTH, size.into());
+        }
+
+        let request = http::Request::from_parts(parts, body);
+        let response = self.inner.send_streaming_request(request, config).await?;
+
+        trace!("Got response: {:?}", response);
+
+        Ok(create_content::Response::try_from_http_response(response)?)
+    }
+
+    /// Send the given request and stream the body of a successful response.
+    pub async fn download<Request>(
+        &self,
+        request: Request,
+        config: Option<RequestConfig>,
+    ) -> Result<http::Response<ByteStream>, HttpError>
+    where
+        Request: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let config = config.unwrap_or(self.request_config);
+
+        let request = self.build_http_request(request, self.session.clone(), config).await?;
+        let response = self.inner.send_request_streaming_response(request, config).await?;
+
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        // Let ruma parse the error, error responses are small so collecting
+        // them is fine.
+        let status = response.status();
+        let (parts, body) = response.into_parts();
+        let response = http::Response::from_parts(parts, collect_byte_stream(body).await?);
+
+        trace!("Got response: {:?}", response);
+
+        match Request::IncomingResponse::try_from_http_response(response) {
+            Ok(_) => Err(HttpError::Server(status)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn send<Request>(
         &self,
         request: Request,
@@ -226,6 +573,96 @@ impl HttpClient {
 
         Ok(response)
     }
+
+    /// Send the given request authenticated with the given access token
+    /// instead of the one of the session, without asserting an identity.
+    #[cfg(feature = "appservice")]
+    pub async fn send_with_access_token<Request>(
+        &self,
+        request: Request,
+        access_token: &str,
+        config: Option<RequestConfig>,
+    ) -> Result<Request::IncomingResponse, HttpError>
+    where
+        Request: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let config = config.unwrap_or(self.request_config);
+
+        let request = request
+            .try_into_http_request::<BytesMut>(
+                &self.homeserver.read().await.to_string(),
+                SendAccessToken::Always(access_token),
+            )?
+            .map(|body| body.freeze());
+        let response = self.send_with_retry(request, config).await?;
+
+        trace!("Got response: {:?}", response);
+
+        let response = Request::IncomingResponse::try_from_http_response(response)?;
+
+        Ok(response)
+    }
+
+    /// Send a request to an endpoint that has no ruma definition, e.g. an
+    /// unstable endpoint, using a JSON body and expecting a JSON response.
+    #[cfg(feature = "sliding_sync")]
+    pub async fn send_json<Body, Response>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: &Body,
+        config: Option<RequestConfig>,
+    ) -> Result<Response, HttpError>
+    where
+        Body: serde::Serialize,
+        Response: serde::de::DeserializeOwned,
+    {
+        use http::header::{AUTHORIZATION, CONTENT_TYPE};
+        use ruma::api::{
+            client::Error as RumaClientApiError,
+            error::{IntoHttpError, ServerError},
+            EndpointError,
+        };
+
+        let config = config.unwrap_or(self.request_config);
+
+        let access_token = match self.session.read().await.as_ref() {
+            Some(session) => session.access_token.clone(),
+            None => return Err(HttpError::AuthenticationRequired),
+        };
+
+        let mut url =
+            self.homeserver.read().await.join(path).expect("The path should be a valid URL path");
+        url.query_pairs_mut().extend_pairs(query);
+
+        let body = serde_json::to_vec(body).map_err(IntoHttpError::from)?;
+
+        let request = http::Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Bytes::from(body))
+            .map_err(IntoHttpError::from)?;
+
+        let response = self.send_with_retry(request, config).await?;
+
+        trace!("Got response: {:?}", response);
+
+        if response.status().is_success() {
+            serde_json::from_slice(response.body())
+                .map_err(|e| HttpError::ClientApi(FromHttpResponseError::Deserialization(e.into())))
+        } else {
+            let error = match RumaClientApiError::try_from_http_response(response) {
+                Ok(e) => ServerError::Known(e),
+                Err(e) => ServerError::Unknown(e),
+            };
+
+            Err(HttpError::ClientApi(FromHttpResponseError::Http(error)))
+        }
+    }
 }
 
 /// Build a client with the specified configuration.
@@ -304,61 +741,51 @@ async fn send_request(
     request: http::Request<Bytes>,
     config: RequestConfig,
 ) -> Result<http::Response<Bytes>, HttpError> {
-    use std::sync::atomic::{AtomicU64, Ordering};
-
-    use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
-    use http::StatusCode;
-
-    let mut backoff = ExponentialBackoff::default();
     let mut request = reqwest::Request::try_from(request)?;
-    let retry_limit = config.retry_limit;
-    let retry_count = AtomicU64::new(1);
-
     *request.timeout_mut() = Some(config.timeout);
 
-    backoff.max_elapsed_time = config.retry_timeout;
-
-    let request = &request;
-    let retry_count = &retry_count;
-
-    let request = || async move {
-        let stop = if let Some(retry_limit) = retry_limit {
-            retry_count.fetch_add(1, Ordering::Relaxed) >= retry_limit
-        } else {
-            false
-        };
-
-        // Turn errors into permanent errors when the retry limit is reached
-        let error_type = if stop {
-            RetryError::Permanent
-        } else {
-            |err| RetryError::Transient { err, retry_after: None }
-        };
-
-        let request = request.try_clone().ok_or(HttpError::UnableToCloneRequest)?;
-
-        let response =
-            client.execute(request).await.map_err(|e| error_type(HttpError::Reqwest(e)))?;
-
-        let status_code = response.status();
-        // TODO TOO_MANY_REQUESTS will have a retry timeout which we should
-        // use.
-        if !stop
-            && (status_code.is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS)
-        {
-            return Err(error_type(HttpError::Server(status_code)));
-        }
+    let response = client.execute(request).await?;
 
-        let response = response_to_http_response(response)
-            .await
-            .map_err(|e| RetryError::Permanent(HttpError::Reqwest(e)))?;
+    Ok(response_to_http_response(response).await?)
+}
 
-        Ok(response)
-    };
+#[cfg(not(target_arch = "wasm32"))]
+/// Clone a HTTP request so it can be sent again, the extensions of the request
+/// aren't cloned.
+fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
+    let mut builder = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version());
+
+    if let Some(headers) = builder.headers_mut() {
+        *headers = request.headers().clone();
+    }
 
-    let response = retry(backoff, request).await?;
+    builder.body(request.body().clone()).expect("Can't clone a valid HTTP request")
+}
 
-    Ok(response)
+/// Get the delay a rate limited response asks us to wait before retrying the
+/// request.
+///
+/// The `retry_after_ms` field of a `M_LIMIT_EXCEEDED` error takes precedence,
+/// otherwise the `Retry-After` header is used, only the delay-seconds form of
+/// the header is supported.
+fn retry_after(response: &http::Response<Bytes>) -> Option<Duration> {
+    let from_body = serde_json::from_slice::<serde_json::Value>(response.body())
+        .ok()
+        .filter(|e| e["errcode"] == "M_LIMIT_EXCEEDED")
+        .and_then(|e| e["retry_after_ms"].as_u64())
+        .map(Duration::from_millis);
+
+    from_body.or_else(|| {
+        response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+    })
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -371,4 +798,45 @@ impl HttpSend for Client {
     ) -> Result<http::Response<Bytes>, HttpError> {
         send_request(self, request, config).await
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<ByteStream>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let request = request.map(reqwest::Body::wrap_stream);
+        let mut request = reqwest::Request::try_from(request)?;
+        *request.timeout_mut() = Some(config.timeout);
+
+        let response = self.execute(request).await?;
+
+        Ok(response_to_http_response(response).await?)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_streaming_response(
+        &self,
+        request: http::Request<Bytes>,
+        config: RequestConfig,
+    ) -> Result<http::Response<ByteStream>, HttpError> {
+        let mut request = reqwest::Request::try_from(request)?;
+        *request.timeout_mut() = Some(config.timeout);
+
+        let mut response = self.execute(request).await?;
+
+        let mut http_builder = HttpResponse::builder().status(response.status());
+        let headers = http_builder.headers_mut().expect("Can't get the response builder headers");
+
+        for (k, v) in response.headers_mut().drain() {
+            if let Some(key) = k {
+                headers.insert(key, v);
+            }
+        }
+
+        let body: ByteStream =
+            Box::pin(response.bytes_stream().map(|c| c.map_err(HttpError::Reqwest)));
+
+        Ok(http_builder.body(body).expect("Can't construct a response using the given body"))
+    }
 }
@@ -38,14 +38,15 @@ compile_error!("'sso_login' cannot be enabled on 'wasm32' arch");
 
 pub use bytes;
 pub use matrix_sdk_base::{
-    media, Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomType, Session,
-    StateChanges, StoreError,
+    media, KeyValueStore, Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomType,
+    Session, StateChanges, StoreError,
 };
 pub use matrix_sdk_common::*;
 pub use reqwest;
 #[doc(no_inline)]
 pub use ruma;
 
+pub mod attachment;
 mod client;
 pub mod config;
 mod error;
@@ -54,14 +55,18 @@ mod http_client;
 /// High-level room API
 pub mod room;
 mod room_member;
+pub mod send_queue;
+#[cfg(feature = "sliding_sync")]
+pub mod sliding_sync;
 mod sync;
+pub mod transfer;
 
 #[cfg(feature = "encryption")]
 pub mod encryption;
 
 pub use client::{Client, LoopCtrl};
 pub use error::{Error, HttpError, HttpResult, Result};
-pub use http_client::HttpSend;
+pub use http_client::{ByteStream, HttpSend, RateLimitInfo};
 pub use room_member::RoomMember;
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -1,6 +1,10 @@
-use std::{ops::Deref, sync::Arc};
+use std::{collections::BTreeMap, ops::Deref, sync::Arc};
 
-use matrix_sdk_base::deserialized_responses::{MembersResponse, RoomEvent};
+use dashmap::mapref::entry::Entry;
+use matrix_sdk_base::{
+    deserialized_responses::{MembersResponse, RoomEvent, SyncRoomEvent},
+    StateChanges, TimelineChunk,
+};
 use matrix_sdk_common::locks::Mutex;
 use ruma::{
     api::client::r0::{
@@ -19,10 +23,11 @@ use ruma::{
     serde::Raw,
     uint, EventId, RoomId, UInt, UserId,
 };
+use serde_json::{value::to_raw_value, Value as JsonValue};
 
 use crate::{
     media::{MediaFormat, MediaRequest, MediaType},
-    room::RoomType,
+    room::{RoomType, Timeline},
     BaseRoom, Client, HttpError, HttpResult, Result, RoomMember,
 };
 
@@ -164,6 +169,9 @@ impl Common {
     /// # });
     /// ```
     pub async fn messages(&self, options: MessagesOptions<'_>) -> Result<Messages> {
+        let backward = matches!(options.dir, Direction::Backward);
+        // Filtered responses don't contain all the events of the timeline.
+        let cacheable = options.filter.is_none();
         let request = options.into_request(self.inner.room_id());
         let http_response = self.client.send(request, None).await?;
 
@@ -191,11 +199,93 @@ impl Common {
             response.chunk.push(event);
         }
 
+        if cacheable && !response.chunk.is_empty() {
+            self.cache_messages(&response, backward).await?;
+        }
+
         Ok(response)
     }
 
+    async fn cache_messages(&self, messages: &Messages, backward: bool) -> Result<()> {
+        let events = messages.chunk.iter().cloned().map(SyncRoomEvent::from);
+
+        let chunk = if backward {
+            TimelineChunk {
+                room_id: self.room_id().to_owned(),
+                events: events.rev().collect(),
+                prev_batch: messages.end.clone(),
+                next_batch: messages.start.clone(),
+            }
+        } else if let Some(end) = &messages.end {
+            TimelineChunk {
+                room_id: self.room_id().to_owned(),
+                events: events.collect(),
+                prev_batch: Some(messages.start.clone()),
+                next_batch: end.clone(),
+            }
+        } else {
+            return Ok(());
+        };
+
+        let mut changes = StateChanges::default();
+        changes.add_timeline_chunk(chunk);
+
+        Ok(self.client.store().save_changes(&changes).await?)
+    }
+
+    /// Get the [`Timeline`] of this room.
+    ///
+    /// The timeline is kept up to date with the events received over the sync
+    /// API as long as a copy of it is alive. Calling this method multiple
+    /// times returns timelines that share the same state.
+    pub async fn timeline(&self) -> Timeline {
+        if let Some(inner) =
+            self.client.inner.timelines.get(self.room_id()).and_then(|t| t.upgrade())
+        {
+            return Timeline::from_inner(inner);
+        }
+
+        let back_token = match self.client.sync_token().await {
+            Some(token) => Some(token),
+            None => self.last_prev_batch(),
+        };
+
+        match self.client.inner.timelines.entry(self.room_id().to_owned()) {
+            Entry::Occupied(mut entry) => match entry.get().upgrade() {
+                Some(inner) => Timeline::from_inner(inner),
+                None => {
+                    let timeline = Timeline::new(self.clone(), back_token);
+                    entry.insert(Arc::downgrade(timeline.inner()));
+                    timeline
+                }
+            },
+            Entry::Vacant(entry) => {
+                let timeline = Timeline::new(self.clone(), back_token);
+                entry.insert(Arc::downgrade(timeline.inner()));
+                timeline
+            }
+        }
+    }
+
     /// Fetch the event with the given `EventId` in this room.
+    ///
+    /// The event is looked up in the timeline cache of the store first, the
+    /// homeserver is only asked if the event isn't cached. Events that were
+    /// redacted by a cached event are always fetched from the homeserver, to
+    /// get their redacted form.
     pub async fn event(&self, event_id: &EventId) -> Result<RoomEvent> {
+        if let Some(event) =
+            self.client.store().get_timeline_event(self.room_id(), event_id).await?
+        {
+            let mut json: BTreeMap<String, JsonValue> = event.event.deserialize_as()?;
+            json.insert("room_id".to_owned(), self.room_id().as_str().into());
+
+            return Ok(RoomEvent {
+                event: Raw::from_json(to_raw_value(&json)?),
+                encryption_info: event.encryption_info,
+            });
+        }
+
         let request = get_room_event::Request::new(self.room_id(), event_id);
         let event = self.client.send(request, None).await?.event.deserialize()?;
 
@@ -2,6 +2,7 @@
 use std::sync::Arc;
 use std::{io::Read, ops::Deref};
 
+use futures_util::io::AsyncRead;
 use matrix_sdk_common::instant::{Duration, Instant};
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::locks::Mutex;
@@ -21,7 +22,7 @@ use ruma::{
         typing::create_typing_event::{Request as TypingRequest, Typing},
     },
     assign,
-    events::{room::message::RoomMessageEventContent, MessageEventContent, StateEventContent},
+    events::{room::ThumbnailInfo, AnyMessageEventContent, MessageEventContent, StateEventContent},
     receipt::ReceiptType,
     serde::Raw,
     EventId, TransactionId, UserId,
@@ -30,8 +31,16 @@ use serde_json::Value;
 use tracing::debug;
 #[cfg(feature = "encryption")]
 use tracing::instrument;
+#[cfg(feature = "image")]
+use tracing::warn;
 
-use crate::{error::HttpResult, room::Common, BaseRoom, Client, Result, RoomType};
+use crate::{
+    attachment::{AttachmentConfig, AttachmentSource, AttachmentType},
+    error::HttpResult,
+    room::Common,
+    transfer::TransferConfig,
+    BaseRoom, Client, Result, RoomType,
+};
 
 const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
 const TYPING_NOTICE_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
@@ -597,15 +606,15 @@ impl Joined {
     /// * `reader` - A `Reader` that will be used to fetch the raw bytes of the
     /// media.
     ///
-    /// * `txn_id` - A unique ID that can be attached to a `MessageEvent`
-    /// held in its unsigned field as `transaction_id`. If not given one is
-    /// created for the message.
+    /// * `config` - Metadata and configuration for the attachment, if the
+    /// `image` feature is enabled a thumbnail is generated for image
+    /// attachments unless this is disabled here.
     ///
     /// # Examples
     ///
     /// ```no_run
     /// # use std::{path::PathBuf, fs::File, io::Read};
-    /// # use matrix_sdk::{Client, ruma::room_id};
+    /// # use matrix_sdk::{Client, attachment::AttachmentConfig, ruma::room_id};
     /// # use url::Url;
     /// # use mime;
     /// # use futures::executor::block_on;
@@ -621,7 +630,7 @@ impl Joined {
     ///         "My favorite cat",
     ///         &mime::IMAGE_JPEG,
     ///         &mut image,
-    ///         None,
+    ///         AttachmentConfig::new(),
     ///     ).await?;
     /// }
     /// # Result::<_, matrix_sdk::Error>::Ok(()) });
@@ -631,19 +640,294 @@ impl Joined {
         body: &str,
         content_type: &Mime,
         reader: &mut R,
-        txn_id: Option<&TransactionId>,
+        config: AttachmentConfig,
+    ) -> Result<send_message_event::Response> {
+        let txn_id = config.txn_id.clone();
+        let content = self.prepare_attachment(body, content_type, reader, config).await?;
+
+        self.send(content, txn_id.as_deref()).await
+    }
+
+    /// Upload an attachment and its thumbnail and construct the event content
+    /// referencing them.
+    async fn prepare_attachment<R: Read>(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        reader: &mut R,
+        #[allow(unused_mut)] mut config: AttachmentConfig,
+    ) -> Result<AnyMessageEventContent> {
+        #[cfg(feature = "image")]
+        if config.generate_thumbnail
+            && matches!(
+                config.get_attachment_type(content_type),
+                AttachmentType::Image | AttachmentType::Sticker
+            )
+        {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+
+            let generate = move || {
+                // Images we can't decode are still sent, just without the metadata.
+                if let Err(e) = config.generate_image_info(&data) {
+                    warn!(error =? e, "Couldn't generate a thumbnail for an image attachment");
+                }
+
+                (data, config)
+            };
+
+            // Decoding and resizing the image is CPU heavy, don't block the
+            // async runtime with it if possible.
+            #[cfg(not(target_arch = "wasm32"))]
+            let (data, config) =
+                tokio::task::spawn_blocking(generate).await.expect("Task join error");
+            #[cfg(target_arch = "wasm32")]
+            let (data, config) = generate();
+
+            return self
+                .upload_attachment(body, content_type, &mut std::io::Cursor::new(data), config)
+                .await;
+        }
+
+        self.upload_attachment(body, content_type, reader, config).await
+    }
+
+    async fn upload_attachment<R: Read>(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        reader: &mut R,
+        mut config: AttachmentConfig,
+    ) -> Result<AnyMessageEventContent> {
+        let encrypt = self.should_encrypt_attachment(&config, content_type);
+        let thumbnail = self.upload_thumbnail(&mut config, content_type, encrypt).await?;
+        let source = self.upload_media(content_type, reader, encrypt).await?;
+
+        Ok(config.into_content(body, content_type, source, thumbnail))
+    }
+
+    /// Should the media of the attachment be encrypted before it's uploaded.
+    fn should_encrypt_attachment(&self, config: &AttachmentConfig, content_type: &Mime) -> bool {
+        #[cfg(feature = "encryption")]
+        let encrypted = self.is_encrypted();
+        #[cfg(not(feature = "encryption"))]
+        let encrypted = false;
+
+        // Stickers reference their media with a plain URL.
+        encrypted && config.get_attachment_type(content_type) != AttachmentType::Sticker
+    }
+
+    /// Upload the thumbnail of the attachment, if there is one and the type of
+    /// the attachment supports thumbnails.
+    async fn upload_thumbnail(
+        &self,
+        config: &mut AttachmentConfig,
+        content_type: &Mime,
+        encrypt: bool,
+    ) -> Result<Option<(AttachmentSource, ThumbnailInfo)>> {
+        if config.get_attachment_type(content_type) == AttachmentType::Audio {
+            return Ok(None);
+        }
+
+        match config.thumbnail.take() {
+            Some(thumbnail) => {
+                let info = thumbnail.thumbnail_info();
+                let mut reader = std::io::Cursor::new(thumbnail.data);
+                let source =
+                    self.upload_media(&thumbnail.content_type, &mut reader, encrypt).await?;
+
+                Ok(Some((source, info)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Upload the media read from `reader`, encrypting it if `encrypt` is
+    /// set.
+    async fn upload_media<R: Read>(
+        &self,
+        content_type: &Mime,
+        reader: &mut R,
+        encrypt: bool,
+    ) -> Result<AttachmentSource> {
+        #[cfg(feature = "encryption")]
+        if encrypt {
+            let file = self.client.upload_encrypted_file(content_type, reader).await?;
+            return Ok(AttachmentSource::Encrypted(Box::new(file)));
+        }
+
+        #[cfg(not(feature = "encryption"))]
+        let _ = encrypt;
+
+        let response = self.client.upload(content_type, reader).await?;
+        Ok(AttachmentSource::Plain(response.content_uri))
+    }
+
+    /// Send an attachment to this room, streaming the media from `reader`.
+    ///
+    /// Unlike [`Joined::send_attachment()`] the media is never held in memory
+    /// as a whole, which makes this method suitable for big files. If the
+    /// room is encrypted, the media is encrypted while it's uploaded. No
+    /// thumbnail is generated for streamed attachments, but one can be given in
+    /// the `config`.
+    ///
+    /// Returns the parsed response from the server.
+    ///
+    /// # Arguments
+    /// * `body` - A textual representation of the media that is going to be
+    /// uploaded. Usually the file name.
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `reader` - An `AsyncRead` the raw bytes of the media are streamed
+    /// from.
+    ///
+    /// * `size` - The size of the media in bytes, if known.
+    ///
+    /// * `transfer` - The progress callback and cancellation token of the
+    /// upload.
+    ///
+    /// * `config` - Metadata and configuration for the attachment.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{
+    /// #     attachment::AttachmentConfig, ruma::room_id, transfer::TransferConfig, Client,
+    /// # };
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use futures_util::io::AllowStdIo;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// # let room_id = room_id!("!test:localhost");
+    /// let video = std::fs::File::open("/home/example/my-cat.mp4")?;
+    /// let size = video.metadata()?.len();
+    ///
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     room.send_attachment_stream(
+    ///         "My favorite cat",
+    ///         &mime::VIDEO_MP4,
+    ///         AllowStdIo::new(video),
+    ///         Some(size),
+    ///         TransferConfig::new(),
+    ///         AttachmentConfig::new(),
+    ///     ).await?;
+    /// }
+    /// # Result::<_, matrix_sdk::Error>::Ok(()) });
+    /// ```
+    pub async fn send_attachment_stream(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+        size: Option<u64>,
+        transfer: TransferConfig,
+        mut config: AttachmentConfig,
     ) -> Result<send_message_event::Response> {
+        let encrypt = self.should_encrypt_attachment(&config, content_type);
+        let thumbnail = self.upload_thumbnail(&mut config, content_type, encrypt).await?;
+
         #[cfg(feature = "encryption")]
-        let content = if self.is_encrypted() {
-            self.client.prepare_encrypted_attachment_message(body, content_type, reader).await?
+        let source = if encrypt {
+            let file =
+                self.client.upload_encrypted_stream(content_type, reader, size, transfer).await?;
+            AttachmentSource::Encrypted(Box::new(file))
         } else {
-            self.client.prepare_attachment_message(body, content_type, reader).await?
+            let response = self.client.upload_stream(content_type, reader, size, transfer).await?;
+            AttachmentSource::Plain(response.content_uri)
         };
 
         #[cfg(not(feature = "encryption"))]
-        let content = self.client.prepare_attachment_message(body, content_type, reader).await?;
+        let source = {
+            let response = self.client.upload_stream(content_type, reader, size, transfer).await?;
+            AttachmentSource::Plain(response.content_uri)
+        };
+
+        let txn_id = config.txn_id.clone();
+        let content = config.into_content(body, content_type, source, thumbnail);
+
+        self.send(content, txn_id.as_deref()).await
+    }
+
+    /// Queue a room message to be sent to this room.
+    ///
+    /// Unlike [`Joined::send()`], this method doesn't wait for the homeserver
+    /// to accept the event. The event is stored in the [`SendQueue`] and is
+    /// sent in the background, it will be retried if the network is
+    /// unavailable, even across restarts of the client. The progress is
+    /// reported as a [`LocalEcho`] to the registered event handlers.
+    ///
+    /// Returns the transaction id of the queued event.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// * `txn_id` - A locally-unique ID describing a message transaction with
+    ///   the homeserver, if `None` a new one will be created.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = url::Url::parse("http://localhost:8080")?;
+    /// # let mut client = matrix_sdk::Client::new(homeserver).await?;
+    /// # let room_id = matrix_sdk::ruma::room_id!("!test:localhost");
+    /// use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+    ///
+    /// let content = RoomMessageEventContent::text_plain("Hello world");
+    ///
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     let txn_id = room.send_queued(content, None).await?;
+    /// }
+    /// # Result::<_, matrix_sdk::Error>::Ok(()) });
+    /// ```
+    ///
+    /// [`SendQueue`]: crate::send_queue::SendQueue
+    /// [`LocalEcho`]: crate::send_queue::LocalEcho
+    pub async fn send_queued(
+        &self,
+        content: impl MessageEventContent,
+        txn_id: Option<&TransactionId>,
+    ) -> Result<Box<TransactionId>> {
+        self.client.send_queue().send(self.room_id(), content, txn_id).await
+    }
+
+    /// Upload an attachment and queue a message referencing it to be sent to
+    /// this room.
+    ///
+    /// The media is uploaded before this method returns, only the message
+    /// event is queued, see [`Joined::send_queued()`] for the details.
+    ///
+    /// Returns the transaction id of the queued event.
+    ///
+    /// # Arguments
+    /// * `body` - A textual representation of the media that is going to be
+    /// uploaded. Usually the file name.
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `reader` - A `Reader` that will be used to fetch the raw bytes of the
+    /// media.
+    ///
+    /// * `config` - Metadata and configuration for the attachment, the
+    /// transaction id of the queued event can be set here.
+    pub async fn send_attachment_queued<R: Read>(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        reader: &mut R,
+        config: AttachmentConfig,
+    ) -> Result<Box<TransactionId>> {
+        let txn_id = config.txn_id.clone();
+        let content = self.prepare_attachment(body, content_type, reader, config).await?;
 
-        self.send(RoomMessageEventContent::new(content), txn_id).await
+        self.send_queued(content, txn_id.as_deref()).await
     }
 
     /// Send a room state event to the homeserver.
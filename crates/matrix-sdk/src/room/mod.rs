@@ -6,12 +6,15 @@ mod common;
 mod invited;
 mod joined;
 mod left;
+mod timeline;
 
+pub(crate) use self::timeline::TimelineInner;
 pub use self::{
     common::{Common, Messages, MessagesOptions},
     invited::Invited,
     joined::Joined,
     left::Left,
+    timeline::{Timeline, TimelineDiff, TimelineItem, TimelineItemContent},
 };
 
 /// An enum that abstracts over the different states a room can be in.
@@ -0,0 +1,789 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use futures_core::stream::Stream;
+use matrix_sdk_base::deserialized_responses::{
+    EncryptionInfo, SyncResponse, SyncRoomEvent, Timeline as SyncTimeline,
+};
+use matrix_sdk_common::locks::Mutex;
+use ruma::{
+    events::{room::message::RoomMessageEventContent, AnySyncRoomEvent},
+    serde::Raw,
+    EventId, MilliSecondsSinceUnixEpoch, UInt, UserId,
+};
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::value::RawValue as RawJsonValue;
+use tracing::warn;
+
+use crate::{
+    room::{Common, MessagesOptions},
+    Client, Result,
+};
+
+/// A live view over the events of a room.
+///
+/// The timeline combines the events received over the sync API with events
+/// that were loaded using [`paginate_backwards()`][Self::paginate_backwards].
+/// Events are deduplicated, decrypted if possible, and relations like edits,
+/// reactions and redactions are applied to the events they relate to instead
+/// of being shown as separate items.
+///
+/// A timeline can be obtained using [`Common::timeline()`], all timeline
+/// objects for a single room share the same state.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    inner: Arc<TimelineInner>,
+}
+
+/// A single item of a [`Timeline`].
+#[derive(Debug, Clone)]
+pub struct TimelineItem {
+    /// The unique ID of the event.
+    pub event_id: Box<EventId>,
+    /// The user that sent the event.
+    pub sender: Box<UserId>,
+    /// The time the event was sent, according to the homeserver of the sender.
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    /// The content of the event, with the latest edit applied.
+    pub content: TimelineItemContent,
+    /// Has the content of this item been replaced by an edit.
+    pub edited: bool,
+    /// The reactions to this item, grouped by the reaction key.
+    ///
+    /// The inner map contains the event ID of the reaction and the user that
+    /// sent the reaction.
+    pub reactions: BTreeMap<String, BTreeMap<Box<EventId>, Box<UserId>>>,
+    /// Information about the encryption of the event, `None` if the event
+    /// wasn't encrypted.
+    pub encryption_info: Option<EncryptionInfo>,
+    /// The raw event this item was created from.
+    pub event: Raw<AnySyncRoomEvent>,
+    last_edit_ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// The content of a [`TimelineItem`].
+#[derive(Debug, Clone)]
+pub enum TimelineItemContent {
+    /// An `m.room.message` event.
+    Message(RoomMessageEventContent),
+    /// An event that has been redacted.
+    Redacted,
+    /// An encrypted event that couldn't be decrypted yet.
+    UnableToDecrypt,
+    /// Any other event, the content can be found in the raw event.
+    Other,
+}
+
+/// A change to the list of items of a [`Timeline`].
+#[derive(Debug, Clone)]
+pub enum TimelineDiff {
+    /// An item was added to the start of the timeline.
+    PushFront(TimelineItem),
+    /// An item was added to the end of the timeline.
+    PushBack(TimelineItem),
+    /// The item at the given index was replaced.
+    Set {
+        /// The index of the item that was replaced.
+        index: usize,
+        /// The new item.
+        item: TimelineItem,
+    },
+    /// The item at the given index was removed.
+    Remove {
+        /// The index of the item that was removed.
+        index: usize,
+    },
+    /// All items were removed from the timeline.
+    Clear,
+}
+
+#[derive(Debug)]
+pub(crate) struct TimelineInner {
+    room: Common,
+    state: StdRwLock<TimelineState>,
+    /// Lock making sure we only have one pagination request in flight.
+    pagination_lock: Mutex<()>,
+}
+
+impl Timeline {
+    pub(crate) fn from_inner(inner: Arc<TimelineInner>) -> Self {
+        Self { inner }
+    }
+
+    pub(crate) fn new(room: Common, back_token: Option<String>) -> Self {
+        let state = TimelineState { back_token, ..Default::default() };
+
+        Self {
+            inner: Arc::new(TimelineInner {
+                room,
+                state: StdRwLock::new(state),
+                pagination_lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub(crate) fn inner(&self) -> &Arc<TimelineInner> {
+        &self.inner
+    }
+
+    /// Get a copy of the current items of the timeline.
+    pub fn items(&self) -> Vec<TimelineItem> {
+        self.inner.state.read().unwrap().items.clone()
+    }
+
+    /// Get the current items of the timeline and a stream of changes to them.
+    ///
+    /// Applying the changes in the stream to the returned items in order will
+    /// keep them in sync with the timeline. The stream ends once the
+    /// timeline is dropped.
+    pub fn subscribe(&self) -> (Vec<TimelineItem>, impl Stream<Item = TimelineDiff>) {
+        let (sender, receiver) = unbounded();
+        let mut state = self.inner.state.write().unwrap();
+        state.subscribers.push(sender);
+
+        (state.items.clone(), receiver)
+    }
+
+    /// Load older events of the room and add them to the start of the
+    /// timeline.
+    ///
+    /// Returns `true` if there are more events that can be loaded, `false` if
+    /// the start of the room was reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of events that should be requested from
+    ///   the homeserver.
+    pub async fn paginate_backwards(&self, limit: UInt) -> Result<bool> {
+        let _guard = self.inner.pagination_lock.lock().await;
+
+        let (token, generation) = {
+            let state = self.inner.state.read().unwrap();
+            (state.back_token.clone(), state.generation)
+        };
+
+        let token = match token {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+
+        let mut options = MessagesOptions::backward(&token);
+        options.limit = limit;
+        let messages = self.inner.room.messages(options).await?;
+
+        let mut state = self.inner.state.write().unwrap();
+
+        // The timeline got reset by a limited sync while the request was in
+        // flight, the events don't connect to the timeline anymore.
+        if state.generation != generation {
+            return Ok(state.back_token.is_some());
+        }
+
+        // The chunk is ordered from the newest to the oldest event, each event
+        // is older than the current first item.
+        for event in messages.chunk {
+            state.handle_event(event.into(), Position::Start);
+        }
+
+        state.back_token = messages.end.filter(|end| *end != messages.start);
+
+        Ok(state.back_token.is_some())
+    }
+
+    /// Try to decrypt the events of the timeline that couldn't be decrypted
+    /// so far.
+    ///
+    /// This is done automatically after every sync, so calling this is only
+    /// needed if room keys were imported manually.
+    #[cfg(feature = "encryption")]
+    pub async fn retry_decryption(&self) {
+        self.inner.retry_decryption().await
+    }
+}
+
+impl TimelineInner {
+    pub(crate) fn handle_sync_timeline(&self, timeline: &SyncTimeline) {
+        let mut state = self.state.write().unwrap();
+
+        if timeline.limited {
+            state.clear();
+            state.back_token = timeline.prev_batch.clone();
+        }
+
+        for event in &timeline.events {
+            state.handle_event(event.clone(), Position::End);
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn retry_decryption(&self) {
+        use ruma::events::AnySyncMessageEvent;
+
+        let undecrypted: Vec<_> = self
+            .state
+            .read()
+            .unwrap()
+            .items
+            .iter()
+            .filter(|i| matches!(i.content, TimelineItemContent::UnableToDecrypt))
+            .map(|i| (i.event_id.clone(), i.event.clone()))
+            .collect();
+
+        if undecrypted.is_empty() {
+            return;
+        }
+
+        let machine = match self.room.client.olm_machine().await {
+            Some(m) => m,
+            None => return,
+        };
+
+        for (event_id, event) in undecrypted {
+            let event = match event.deserialize() {
+                Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(e))) => e,
+                _ => continue,
+            };
+
+            if let Ok(decrypted) = machine.decrypt_room_event(&event, self.room.room_id()).await {
+                self.state.write().unwrap().replace_undecrypted(&event_id, decrypted.into());
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Forward the timelines of a sync response to the live [`Timeline`]s of
+    /// the rooms.
+    pub(crate) async fn update_timelines(&self, response: &SyncResponse) {
+        let rooms = &response.rooms;
+        let timelines = rooms
+            .join
+            .iter()
+            .map(|(room_id, room)| (room_id, &room.timeline))
+            .chain(rooms.leave.iter().map(|(room_id, room)| (room_id, &room.timeline)));
+
+        for (room_id, timeline) in timelines {
+            let inner = self.inner.timelines.get(room_id).and_then(|t| t.upgrade());
+
+            if let Some(inner) = inner {
+                inner.handle_sync_timeline(timeline);
+            }
+        }
+
+        self.inner.timelines.retain(|_, t| t.strong_count() > 0);
+
+        // New room keys might have been received, try to decrypt the events
+        // that we couldn't decrypt before.
+        #[cfg(feature = "encryption")]
+        {
+            let timelines: Vec<_> =
+                self.inner.timelines.iter().filter_map(|t| t.value().upgrade()).collect();
+
+            for timeline in timelines {
+                timeline.retry_decryption().await;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Start,
+    End,
+}
+
+#[derive(Debug)]
+enum Aggregation {
+    Edit { sender: Box<UserId>, ts: MilliSecondsSinceUnixEpoch, content: RoomMessageEventContent },
+    Reaction { event_id: Box<EventId>, sender: Box<UserId>, key: String },
+    Redaction,
+}
+
+#[derive(Debug)]
+enum ParsedEvent {
+    Item(TimelineItem),
+    Aggregation { target: Box<EventId>, aggregation: Aggregation },
+}
+
+#[derive(Debug, Default)]
+struct TimelineState {
+    items: Vec<TimelineItem>,
+    /// Aggregations whose target event isn't part of the timeline yet, keyed
+    /// by the event ID of the target.
+    pending: BTreeMap<Box<EventId>, Vec<Aggregation>>,
+    /// The reactions that were applied to items, maps the event ID of the
+    /// reaction to the event ID of the target and the reaction key.
+    reactions: BTreeMap<Box<EventId>, (Box<EventId>, String)>,
+    /// The token to load older events with, `None` if the start of the room
+    /// was reached.
+    back_token: Option<String>,
+    /// Incremented every time the timeline gets cleared.
+    generation: u64,
+    subscribers: Vec<UnboundedSender<TimelineDiff>>,
+}
+
+impl TimelineState {
+    fn notify(&mut self, diff: TimelineDiff) {
+        self.subscribers.retain(|s| s.unbounded_send(diff.clone()).is_ok());
+    }
+
+    fn index_of(&self, event_id: &EventId) -> Option<usize> {
+        self.items.iter().position(|i| *i.event_id == *event_id)
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+        self.pending.clear();
+        self.reactions.clear();
+        self.generation += 1;
+        self.notify(TimelineDiff::Clear);
+    }
+
+    fn handle_event(&mut self, event: SyncRoomEvent, position: Position) {
+        match parse_event(event) {
+            Some(ParsedEvent::Item(item)) => self.add_item(item, position),
+            Some(ParsedEvent::Aggregation { target, aggregation }) => {
+                self.aggregate(target, aggregation)
+            }
+            None => {}
+        }
+    }
+
+    fn add_item(&mut self, item: TimelineItem, position: Position) {
+        if self.index_of(&item.event_id).is_some() {
+            return;
+        }
+
+        let event_id = item.event_id.clone();
+
+        match position {
+            Position::Start => {
+                self.items.insert(0, item.clone());
+                self.notify(TimelineDiff::PushFront(item));
+            }
+            Position::End => {
+                self.items.push(item.clone());
+                self.notify(TimelineDiff::PushBack(item));
+            }
+        }
+
+        self.apply_pending(&event_id);
+    }
+
+    /// Replace an undecryptable item with its decrypted version.
+    #[cfg(feature = "encryption")]
+    fn replace_undecrypted(&mut self, event_id: &EventId, event: SyncRoomEvent) {
+        let index = match self.index_of(event_id) {
+            Some(i) if matches!(self.items[i].content, TimelineItemContent::UnableToDecrypt) => i,
+            _ => return,
+        };
+
+        match parse_event(event) {
+            Some(ParsedEvent::Item(mut item)) => {
+                item.reactions = std::mem::take(&mut self.items[index].reactions);
+                self.items[index] = item.clone();
+                self.notify(TimelineDiff::Set { index, item });
+                self.apply_pending(event_id);
+            }
+            Some(ParsedEvent::Aggregation { target, aggregation }) => {
+                self.items.remove(index);
+                self.notify(TimelineDiff::Remove { index });
+                self.aggregate(target, aggregation);
+            }
+            None => {}
+        }
+    }
+
+    fn apply_pending(&mut self, event_id: &EventId) {
+        if let Some(aggregations) = self.pending.remove(event_id) {
+            for aggregation in aggregations {
+                self.aggregate(event_id.to_owned(), aggregation);
+            }
+        }
+    }
+
+    fn aggregate(&mut self, target: Box<EventId>, aggregation: Aggregation) {
+        if !self.try_aggregate(&target, &aggregation) {
+            self.pending.entry(target).or_default().push(aggregation);
+        }
+    }
+
+    /// Apply the aggregation to its target, returns `false` if the target
+    /// isn't ready for it yet.
+    fn try_aggregate(&mut self, target: &EventId, aggregation: &Aggregation) -> bool {
+        match aggregation {
+            Aggregation::Redaction => {
+                if let Some((item_id, key)) = self.reactions.remove(target) {
+                    if let Some(index) = self.index_of(&item_id) {
+                        let item = &mut self.items[index];
+
+                        if let Some(senders) = item.reactions.get_mut(&key) {
+                            senders.remove(target);
+
+                            if senders.is_empty() {
+                                item.reactions.remove(&key);
+                            }
+                        }
+
+                        let item = item.clone();
+                        self.notify(TimelineDiff::Set { index, item });
+                    }
+
+                    true
+                } else if let Some(index) = self.index_of(target) {
+                    let item = &mut self.items[index];
+                    item.content = TimelineItemContent::Redacted;
+                    item.edited = false;
+                    item.reactions.clear();
+
+                    let item = item.clone();
+                    self.reactions.retain(|_, (item_id, _)| **item_id != *target);
+                    self.notify(TimelineDiff::Set { index, item });
+
+                    true
+                } else {
+                    false
+                }
+            }
+            Aggregation::Reaction { event_id, sender, key } => {
+                let index = match self.index_of(target) {
+                    Some(i) => i,
+                    None => return false,
+                };
+
+                if self.reactions.contains_key(event_id)
+                    || matches!(self.items[index].content, TimelineItemContent::Redacted)
+                {
+                    return true;
+                }
+
+                let item = &mut self.items[index];
+                item.reactions
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(event_id.clone(), sender.clone());
+
+                let item = item.clone();
+                self.reactions.insert(event_id.clone(), (target.to_owned(), key.clone()));
+                self.notify(TimelineDiff::Set { index, item });
+
+                // The redaction of the reaction might have arrived before the
+                // reaction itself.
+                self.apply_pending(event_id);
+
+                true
+            }
+            Aggregation::Edit { sender, ts, content } => {
+                let index = match self.index_of(target) {
+                    Some(i) => i,
+                    None => return false,
+                };
+
+                let item = &mut self.items[index];
+
+                match item.content {
+                    TimelineItemContent::Message(_) => {}
+                    // Keep the edit around until the item gets decrypted.
+                    TimelineItemContent::UnableToDecrypt => return false,
+                    _ => return true,
+                }
+
+                if item.sender != *sender || item.last_edit_ts.map_or(false, |t| t >= *ts) {
+                    return true;
+                }
+
+                item.content = TimelineItemContent::Message(content.clone());
+                item.edited = true;
+                item.last_edit_ts = Some(*ts);
+
+                let item = item.clone();
+                self.notify(TimelineDiff::Set { index, item });
+
+                true
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EventDetails {
+    #[serde(rename = "type")]
+    event_type: String,
+    event_id: Box<EventId>,
+    sender: Box<UserId>,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    redacts: Option<Box<EventId>>,
+    unsigned: Option<UnsignedDetails>,
+    content: Option<Box<RawJsonValue>>,
+}
+
+#[derive(Deserialize)]
+struct UnsignedDetails {
+    redacted_because: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct RelationContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Option<RelatesTo>,
+    #[serde(rename = "m.new_content")]
+    new_content: Option<Box<RawJsonValue>>,
+}
+
+#[derive(Deserialize)]
+struct RelatesTo {
+    rel_type: Option<String>,
+    event_id: Option<Box<EventId>>,
+    key: Option<String>,
+}
+
+fn parse_event(event: SyncRoomEvent) -> Option<ParsedEvent> {
+    let details: EventDetails = match event.event.deserialize_as() {
+        Ok(d) => d,
+        Err(e) => {
+            warn!(error = ?e, "Couldn't parse a timeline event");
+            return None;
+        }
+    };
+
+    let redacted = details.unsigned.map_or(false, |u| u.redacted_because.is_some());
+
+    let relation = details
+        .content
+        .as_ref()
+        .and_then(|c| serde_json::from_str::<RelationContent>(c.get()).ok())
+        .filter(|_| !redacted);
+
+    if let Some(RelationContent { relates_to: Some(relates_to), new_content }) = relation {
+        let RelatesTo { rel_type, event_id, key } = relates_to;
+
+        match (details.event_type.as_str(), rel_type.as_deref(), event_id) {
+            ("m.reaction", Some("m.annotation"), Some(target)) => {
+                if let Some(key) = key {
+                    return Some(ParsedEvent::Aggregation {
+                        target,
+                        aggregation: Aggregation::Reaction {
+                            event_id: details.event_id,
+                            sender: details.sender,
+                            key,
+                        },
+                    });
+                }
+            }
+            ("m.room.message", Some("m.replace"), Some(target)) => {
+                if let Some(content) = new_content.and_then(|c| serde_json::from_str(c.get()).ok())
+                {
+                    return Some(ParsedEvent::Aggregation {
+                        target,
+                        aggregation: Aggregation::Edit {
+                            sender: details.sender,
+                            ts: details.origin_server_ts,
+                            content,
+                        },
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if details.event_type == "m.room.redaction" && !redacted {
+        if let Some(target) = details.redacts {
+            return Some(ParsedEvent::Aggregation { target, aggregation: Aggregation::Redaction });
+        }
+    }
+
+    let content = if redacted {
+        TimelineItemContent::Redacted
+    } else {
+        match details.event_type.as_str() {
+            "m.room.encrypted" => TimelineItemContent::UnableToDecrypt,
+            "m.room.message" => details
+                .content
+                .and_then(|c| serde_json::from_str(c.get()).ok())
+                .map_or(TimelineItemContent::Other, TimelineItemContent::Message),
+            _ => TimelineItemContent::Other,
+        }
+    };
+
+    Some(ParsedEvent::Item(TimelineItem {
+        event_id: details.event_id,
+        sender: details.sender,
+        origin_server_ts: details.origin_server_ts,
+        content,
+        edited: false,
+        reactions: BTreeMap::new(),
+        encryption_info: event.encryption_info,
+        event: event.event,
+        last_edit_ts: None,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::StreamExt;
+    use matrix_sdk_base::deserialized_responses::SyncRoomEvent;
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        event_id,
+        events::room::message::{MessageType, RoomMessageEventContent},
+        serde::Raw,
+    };
+    use serde_json::{json, value::to_raw_value, Value as JsonValue};
+
+    use super::{Position, TimelineDiff, TimelineItemContent, TimelineState};
+
+    fn event(json: JsonValue) -> SyncRoomEvent {
+        SyncRoomEvent::from(Raw::from_json(to_raw_value(&json).unwrap()))
+    }
+
+    fn message(event_id: &str, body: &str, ts: u64) -> SyncRoomEvent {
+        event(json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": ts,
+            "content": { "msgtype": "m.text", "body": body },
+        }))
+    }
+
+    fn edit(event_id: &str, target: &str, body: &str, ts: u64) -> SyncRoomEvent {
+        event(json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": ts,
+            "content": {
+                "msgtype": "m.text",
+                "body": format!("* {}", body),
+                "m.new_content": { "msgtype": "m.text", "body": body },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": target },
+            },
+        }))
+    }
+
+    fn reaction(event_id: &str, target: &str, key: &str) -> SyncRoomEvent {
+        event(json!({
+            "type": "m.reaction",
+            "event_id": event_id,
+            "sender": "@bob:example.org",
+            "origin_server_ts": 10,
+            "content": {
+                "m.relates_to": { "rel_type": "m.annotation", "event_id": target, "key": key },
+            },
+        }))
+    }
+
+    fn redaction(event_id: &str, target: &str) -> SyncRoomEvent {
+        event(json!({
+            "type": "m.room.redaction",
+            "event_id": event_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": 20,
+            "redacts": target,
+            "content": {},
+        }))
+    }
+
+    fn body(content: &TimelineItemContent) -> &str {
+        match content {
+            TimelineItemContent::Message(RoomMessageEventContent {
+                msgtype: MessageType::Text(c),
+                ..
+            }) => &c.body,
+            _ => panic!("Not a message: {:?}", content),
+        }
+    }
+
+    #[test]
+    fn events_are_deduplicated() {
+        let mut state = TimelineState::default();
+
+        state.handle_event(message("$a", "hello", 1), Position::End);
+        state.handle_event(message("$b", "world", 2), Position::End);
+        state.handle_event(message("$a", "hello", 1), Position::Start);
+
+        assert_eq!(state.items.len(), 2);
+        assert_eq!(body(&state.items[0].content), "hello");
+        assert_eq!(body(&state.items[1].content), "world");
+    }
+
+    #[test]
+    fn relations_are_aggregated() {
+        let mut state = TimelineState::default();
+
+        state.handle_event(message("$a", "hello", 1), Position::End);
+        state.handle_event(edit("$b", "$a", "hi", 2), Position::End);
+        state.handle_event(edit("$c", "$a", "outdated", 1), Position::End);
+        state.handle_event(reaction("$d", "$a", "👍"), Position::End);
+
+        assert_eq!(state.items.len(), 1);
+
+        let item = &state.items[0];
+        assert!(item.edited);
+        assert_eq!(body(&item.content), "hi");
+        assert_eq!(item.reactions["👍"].len(), 1);
+
+        state.handle_event(redaction("$e", "$d"), Position::End);
+        assert!(state.items[0].reactions.is_empty());
+
+        state.handle_event(redaction("$f", "$a"), Position::End);
+        assert!(matches!(state.items[0].content, TimelineItemContent::Redacted));
+    }
+
+    #[test]
+    fn relations_before_target_are_applied_later() {
+        let mut state = TimelineState::default();
+
+        // Back-pagination delivers the events from the newest to the oldest.
+        state.handle_event(redaction("$e", "$d"), Position::Start);
+        state.handle_event(reaction("$d", "$a", "👍"), Position::Start);
+        state.handle_event(reaction("$c", "$a", "🎉"), Position::Start);
+        state.handle_event(edit("$b", "$a", "hi", 2), Position::Start);
+        assert!(state.items.is_empty());
+
+        state.handle_event(message("$a", "hello", 1), Position::Start);
+
+        assert_eq!(state.items.len(), 1);
+        assert!(state.pending.is_empty());
+
+        let item = &state.items[0];
+        assert_eq!(body(&item.content), "hi");
+        assert_eq!(item.reactions.len(), 1);
+        assert!(item.reactions["🎉"].contains_key(event_id!("$c")));
+    }
+
+    #[async_test]
+    async fn subscribers_receive_diffs() {
+        let mut state = TimelineState::default();
+        let (sender, mut receiver) = futures_channel::mpsc::unbounded();
+        state.subscribers.push(sender);
+
+        state.handle_event(message("$a", "hello", 1), Position::End);
+        state.handle_event(message("$b", "older", 0), Position::Start);
+        state.handle_event(reaction("$c", "$a", "👍"), Position::End);
+        state.clear();
+
+        assert!(matches!(receiver.next().await, Some(TimelineDiff::PushBack(_))));
+        assert!(matches!(receiver.next().await, Some(TimelineDiff::PushFront(_))));
+        assert!(matches!(receiver.next().await, Some(TimelineDiff::Set { index: 1, .. })));
+        assert!(matches!(receiver.next().await, Some(TimelineDiff::Clear)));
+    }
+}
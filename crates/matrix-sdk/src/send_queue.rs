@@ -0,0 +1,614 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A persistent queue for events that should be sent to rooms.
+//!
+//! Events that are sent using the [`SendQueue`] are first stored in the
+//! [`StateStore`] and get a transaction id assigned, only after that the
+//! events are sent to the homeserver in the background. Events that couldn't
+//! be sent because of a network failure stay in the store, they will be
+//! retried after the next successful sync or once [`SendQueue::resume()`] is
+//! called, e.g. after the client was restarted.
+//!
+//! Events for a single room are always sent in the order they were queued in.
+//!
+//! The progress of a queued event is reported as a [`LocalEcho`], which can be
+//! received by registering an event handler:
+//!
+//! ```no_run
+//! # use futures::executor::block_on;
+//! # use url::Url;
+//! # let homeserver = Url::parse("http://localhost:8080").unwrap();
+//! # block_on(async {
+//! use matrix_sdk::{
+//!     send_queue::{LocalEcho, LocalEchoState},
+//!     Client,
+//! };
+//!
+//! let client = Client::new(homeserver).await?;
+//!
+//! client
+//!     .register_event_handler(|echo: LocalEcho| async move {
+//!         match echo.state {
+//!             LocalEchoState::Sending => println!("Sending {}", echo.transaction_id),
+//!             LocalEchoState::Sent { event_id } => println!("Sent {}", event_id),
+//!             LocalEchoState::Failed { error, .. } => println!("Failed to send: {}", error),
+//!         }
+//!     })
+//!     .await;
+//! # matrix_sdk::Result::<()>::Ok(()) });
+//! ```
+//!
+//! [`StateStore`]: matrix_sdk_base::StateStore
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use dashmap::{DashMap, DashSet};
+use http::StatusCode;
+pub use matrix_sdk_base::PendingEvent;
+use matrix_sdk_common::{executor::spawn, locks::Mutex};
+use ruma::{
+    api::{
+        client::r0::message::send_message_event,
+        error::{FromHttpResponseError, ServerError},
+    },
+    events::{AnyMessageEventContent, MessageEventContent},
+    serde::Raw,
+    EventId, RoomId, TransactionId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{
+    error::HttpError,
+    event_handler::{EventKind, SyncEvent},
+    Client, Error, Result,
+};
+
+/// The state of an event that was queued to be sent to a room.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LocalEchoState {
+    /// The event was queued and will be sent to the homeserver.
+    Sending,
+    /// The homeserver accepted the event.
+    Sent {
+        /// The event id the homeserver assigned to the event.
+        event_id: Box<EventId>,
+    },
+    /// The event couldn't be sent.
+    Failed {
+        /// A description of the error that prevented the event from being
+        /// sent.
+        error: String,
+        /// Will the event be retried, if `false` the event was removed from
+        /// the queue.
+        will_retry: bool,
+    },
+}
+
+/// A local echo of an event that was queued to be sent to a room.
+///
+/// Local echoes are delivered to event handlers every time the state of a
+/// queued event changes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LocalEcho {
+    /// The id of the room the event is sent to.
+    pub room_id: Box<RoomId>,
+    /// The transaction id of the event, the event will contain the same
+    /// transaction id in its unsigned field once it comes down the sync.
+    pub transaction_id: Box<TransactionId>,
+    /// The type of the event.
+    pub event_type: String,
+    /// The unencrypted content of the event.
+    pub content: Raw<AnyMessageEventContent>,
+    /// The current state of the event.
+    pub state: LocalEchoState,
+}
+
+impl LocalEcho {
+    /// The type that is used to register event handlers for local echoes.
+    pub const TYPE: &'static str = "org.matrix.sdk.local_echo";
+
+    fn new(event: &PendingEvent, state: LocalEchoState) -> Self {
+        Self {
+            room_id: event.room_id.clone(),
+            transaction_id: event.transaction_id.clone(),
+            event_type: event.event_type.clone(),
+            content: event.content.clone(),
+            state,
+        }
+    }
+}
+
+impl SyncEvent for LocalEcho {
+    const ID: (EventKind, &'static str) = (EventKind::LocalEcho, Self::TYPE);
+}
+
+/// The send queue state, as it is stored inside of the `Client`.
+#[derive(Debug, Default)]
+pub(crate) struct SendQueueState {
+    /// The sequence number the next queued event will get, `None` if it
+    /// wasn't yet loaded from the store.
+    next_sequence: Mutex<Option<u64>>,
+    /// Locks making sure that we send only one event per room at a time.
+    room_locks: DashMap<Box<RoomId>, Arc<Mutex<()>>>,
+    /// Rooms for which sending stopped because of a transient error.
+    stalled_rooms: DashSet<Box<RoomId>>,
+}
+
+/// A handle to the persistent queue of events that should be sent to rooms.
+///
+/// This can be retrieved using the [`Client::send_queue()`] method.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    client: Client,
+}
+
+impl Client {
+    /// Get a handle to the persistent queue of outgoing events.
+    pub fn send_queue(&self) -> SendQueue {
+        SendQueue { client: self.clone() }
+    }
+
+    async fn handle_local_echo(&self, echo: &LocalEcho) {
+        let room = self.get_room(&echo.room_id);
+
+        let raw: Raw<LocalEcho> = match serde_json::value::to_raw_value(echo) {
+            Ok(r) => Raw::from_json(r),
+            Err(e) => {
+                warn!(error =? e, "Failed to serialize a local echo");
+                return;
+            }
+        };
+
+        if let Err(e) = self.handle_local_echo_events(&room, &[raw]).await {
+            warn!(error =? e, "Failed to handle a local echo");
+        }
+    }
+}
+
+impl SendQueue {
+    /// Queue a message event to be sent to the given room.
+    ///
+    /// The event is stored before this method returns and is sent in the
+    /// background, the progress is reported as a [`LocalEcho`] to the
+    /// registered event handlers.
+    ///
+    /// Returns the transaction id of the queued event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event should be sent to.
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// * `txn_id` - The transaction id the event should use, if `None` a new
+    ///   one will be created.
+    pub async fn send(
+        &self,
+        room_id: &RoomId,
+        content: impl MessageEventContent,
+        txn_id: Option<&TransactionId>,
+    ) -> Result<Box<TransactionId>> {
+        let event_type = content.event_type().to_owned();
+        let content = serde_json::to_value(&content)?;
+
+        self.send_raw(room_id, content, &event_type, txn_id).await
+    }
+
+    /// Queue a message event, given as a json `Value`, to be sent to the given
+    /// room.
+    ///
+    /// This method is equivalent to the [`SendQueue::send()`] method but allows
+    /// sending custom JSON payloads.
+    ///
+    /// Returns the transaction id of the queued event.
+    pub async fn send_raw(
+        &self,
+        room_id: &RoomId,
+        content: Value,
+        event_type: &str,
+        txn_id: Option<&TransactionId>,
+    ) -> Result<Box<TransactionId>> {
+        let transaction_id = txn_id.map_or_else(TransactionId::new, ToOwned::to_owned);
+        let content = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+
+        let event = {
+            // Hold the lock until the event is stored, otherwise an event with
+            // a bigger sequence number might be sent first.
+            let mut next_sequence = self.client.inner.send_queue.next_sequence.lock().await;
+
+            let sequence = match *next_sequence {
+                Some(s) => s,
+                None => self
+                    .client
+                    .store()
+                    .get_pending_events()
+                    .await?
+                    .iter()
+                    .map(|e| e.sequence + 1)
+                    .max()
+                    .unwrap_or_default(),
+            };
+
+            let event = PendingEvent {
+                room_id: room_id.to_owned(),
+                transaction_id: transaction_id.clone(),
+                event_type: event_type.to_owned(),
+                content,
+                sequence,
+            };
+
+            self.client.store().save_pending_event(&event).await?;
+            *next_sequence = Some(sequence + 1);
+
+            event
+        };
+
+        self.client.handle_local_echo(&LocalEcho::new(&event, LocalEchoState::Sending)).await;
+        self.spawn_flush(room_id.to_owned());
+
+        Ok(transaction_id)
+    }
+
+    /// Get the events that are waiting to be sent to the given room, in the
+    /// order they will be sent in.
+    pub async fn pending_events(&self, room_id: &RoomId) -> Result<Vec<PendingEvent>> {
+        Ok(self
+            .client
+            .store()
+            .get_pending_events()
+            .await?
+            .into_iter()
+            .filter(|e| e.room_id == room_id)
+            .collect())
+    }
+
+    /// Remove an event from the queue.
+    ///
+    /// If the event is currently being sent, this waits for the request to
+    /// finish.
+    ///
+    /// Returns `true` if the event was removed, `false` if the event isn't in
+    /// the queue anymore, e.g. because it was already sent.
+    pub async fn cancel(&self, room_id: &RoomId, txn_id: &TransactionId) -> Result<bool> {
+        let lock = self.room_lock(room_id);
+        let _guard = lock.lock().await;
+
+        let is_pending = self
+            .pending_events(room_id)
+            .await?
+            .iter()
+            .any(|e| e.transaction_id.as_str() == txn_id.as_str());
+
+        if is_pending {
+            self.client.store().remove_pending_event(room_id, txn_id).await?;
+        }
+
+        Ok(is_pending)
+    }
+
+    /// Start sending all the events that are in the queue.
+    ///
+    /// This should be called after a previous session was restored, to send
+    /// out the events that were queued but not sent before the client was
+    /// stopped.
+    pub async fn resume(&self) -> Result<()> {
+        let room_ids: BTreeSet<Box<RoomId>> = self
+            .client
+            .store()
+            .get_pending_events()
+            .await?
+            .into_iter()
+            .map(|e| e.room_id)
+            .collect();
+
+        for room_id in room_ids {
+            self.spawn_flush(room_id);
+        }
+
+        Ok(())
+    }
+
+    /// Retry sending events to rooms for which sending stopped because of a
+    /// transient error.
+    pub(crate) fn retry_stalled_rooms(&self) {
+        let stalled_rooms = &self.client.inner.send_queue.stalled_rooms;
+
+        if stalled_rooms.is_empty() {
+            return;
+        }
+
+        let room_ids: Vec<Box<RoomId>> = stalled_rooms.iter().map(|r| r.key().clone()).collect();
+
+        for room_id in room_ids {
+            stalled_rooms.remove(&room_id);
+            self.spawn_flush(room_id);
+        }
+    }
+
+    fn room_lock(&self, room_id: &RoomId) -> Arc<Mutex<()>> {
+        self.client
+            .inner
+            .send_queue
+            .room_locks
+            .entry(room_id.to_owned())
+            .or_insert_with(Default::default)
+            .clone()
+    }
+
+    fn spawn_flush(&self, room_id: Box<RoomId>) {
+        let queue = self.clone();
+
+        spawn(async move {
+            queue.flush_room(&room_id).await;
+        });
+    }
+
+    /// Send the pending events of a room, one by one, until the queue of the
+    /// room is empty or an event couldn't be sent because of a transient
+    /// error.
+    async fn flush_room(&self, room_id: &RoomId) {
+        loop {
+            let (event, result) = {
+                let lock = self.room_lock(room_id);
+                let _guard = lock.lock().await;
+
+                let event = match self.pending_events(room_id).await {
+                    Ok(events) => match events.into_iter().next() {
+                        Some(e) => e,
+                        None => break,
+                    },
+                    Err(e) => {
+                        warn!(
+                            room_id = room_id.as_str(),
+                            error =? e,
+                            "Failed to load the pending events of a room"
+                        );
+                        break;
+                    }
+                };
+
+                let result = self.send_pending_event(&event).await;
+
+                let remove = match &result {
+                    Ok(_) => true,
+                    Err(e) => !is_transient(e),
+                };
+
+                if remove {
+                    if let Err(e) = self
+                        .client
+                        .store()
+                        .remove_pending_event(&event.room_id, &event.transaction_id)
+                        .await
+                    {
+                        warn!(
+                            room_id = room_id.as_str(),
+                            transaction_id = event.transaction_id.as_str(),
+                            error =? e,
+                            "Failed to remove a pending event from the store"
+                        );
+                    }
+                }
+
+                (event, result)
+            };
+
+            match result {
+                Ok(response) => {
+                    debug!(
+                        room_id = room_id.as_str(),
+                        transaction_id = event.transaction_id.as_str(),
+                        event_id = response.event_id.as_str(),
+                        "Sent a queued event"
+                    );
+
+                    let state = LocalEchoState::Sent { event_id: response.event_id };
+                    self.client.handle_local_echo(&LocalEcho::new(&event, state)).await;
+                }
+                Err(e) => {
+                    let will_retry = is_transient(&e);
+
+                    warn!(
+                        room_id = room_id.as_str(),
+                        transaction_id = event.transaction_id.as_str(),
+                        error =? e,
+                        will_retry,
+                        "Failed to send a queued event"
+                    );
+
+                    let state = LocalEchoState::Failed { error: e.to_string(), will_retry };
+                    self.client.handle_local_echo(&LocalEcho::new(&event, state)).await;
+
+                    if will_retry {
+                        // Stop here so the events of this room don't get
+                        // reordered, we'll try again after the next sync.
+                        self.client.inner.send_queue.stalled_rooms.insert(room_id.to_owned());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_pending_event(
+        &self,
+        event: &PendingEvent,
+    ) -> Result<send_message_event::Response> {
+        let room = self
+            .client
+            .get_joined_room(&event.room_id)
+            .ok_or_else(|| Error::RoomNotJoined(event.room_id.clone()))?;
+
+        let content: Value = event.content.deserialize_as()?;
+
+        room.send_raw(content, &event.event_type, Some(&event.transaction_id)).await
+    }
+}
+
+/// Is the error one that might go away if we retry sending the event later.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Http(HttpError::Reqwest(_)) | Error::Http(HttpError::Server(_)) => true,
+        Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e)))) => {
+            e.status_code == StatusCode::TOO_MANY_REQUESTS || e.status_code.is_server_error()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use std::{
+        sync::{Arc, Mutex as StdMutex},
+        time::Duration,
+    };
+
+    use matrix_sdk_test::{async_test, test_json};
+    use mockito::{mock, Matcher};
+    use ruma::{events::room::message::RoomMessageEventContent, room_id};
+    use serde_json::json;
+
+    use super::{LocalEcho, LocalEchoState};
+    use crate::{client::test::logged_in_client, config::SyncSettings};
+
+    #[async_test]
+    async fn queued_events_are_sent_in_order() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let _sync = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_owned()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let _send = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.message/.*".to_owned()),
+        )
+        .with_status(200)
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let echoes = Arc::new(StdMutex::new(Vec::new()));
+
+        client
+            .register_event_handler({
+                let echoes = echoes.clone();
+                move |echo: LocalEcho| {
+                    let echoes = echoes.clone();
+                    async move { echoes.lock().unwrap().push(echo) }
+                }
+            })
+            .await;
+
+        let queue = client.send_queue();
+
+        let first =
+            queue.send(room_id, RoomMessageEventContent::text_plain("Hello"), None).await.unwrap();
+        let second =
+            queue.send(room_id, RoomMessageEventContent::text_plain("world"), None).await.unwrap();
+
+        for _ in 0..50 {
+            if queue.pending_events(room_id).await.unwrap().is_empty() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(queue.pending_events(room_id).await.unwrap().is_empty());
+
+        let sent: Vec<_> = echoes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| matches!(e.state, LocalEchoState::Sent { .. }))
+            .map(|e| e.transaction_id.clone())
+            .collect();
+
+        assert_eq!(sent, vec![first, second]);
+    }
+
+    #[async_test]
+    async fn transient_failures_stay_queued() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let _sync = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_owned()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let _send = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.message/.*".to_owned()),
+        )
+        .with_status(429)
+        .with_body(
+            json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "Too many requests",
+            })
+            .to_string(),
+        )
+        .create();
+
+        let failed = Arc::new(StdMutex::new(None));
+
+        client
+            .register_event_handler({
+                let failed = failed.clone();
+                move |echo: LocalEcho| {
+                    let failed = failed.clone();
+                    async move {
+                        if let LocalEchoState::Failed { will_retry, .. } = echo.state {
+                            *failed.lock().unwrap() = Some(will_retry);
+                        }
+                    }
+                }
+            })
+            .await;
+
+        let queue = client.send_queue();
+        let txn_id =
+            queue.send(room_id, RoomMessageEventContent::text_plain("Hello"), None).await.unwrap();
+
+        for _ in 0..500 {
+            if failed.lock().unwrap().is_some() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(*failed.lock().unwrap(), Some(true));
+
+        let pending = queue.pending_events(room_id).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].transaction_id, txn_id);
+
+        assert!(queue.cancel(room_id, &txn_id).await.unwrap());
+        assert!(queue.pending_events(room_id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,863 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the sliding sync protocol ([MSC3575]).
+//!
+//! Instead of syncing every room of the account, sliding sync lets the client
+//! define lists of rooms which are sorted and filtered by the server. Only the
+//! rooms inside the requested ranges of a list are sent down to the client,
+//! which keeps the response small even for accounts with thousands of rooms.
+//!
+//! The received rooms, account data and to-device events are fed into the
+//! client the same way a response of the regular sync is, so
+//! [`Client::rooms()`] and the registered event handlers keep working.
+//!
+//! ```no_run
+//! # use futures::executor::block_on;
+//! # use url::Url;
+//! # let homeserver = Url::parse("http://localhost:8080").unwrap();
+//! # block_on(async {
+//! use futures::StreamExt;
+//! use matrix_sdk::{
+//!     ruma::{events::EventType, uint},
+//!     sliding_sync::SlidingSyncList,
+//!     Client,
+//! };
+//!
+//! let client = Client::new(homeserver).await?;
+//! client.login("example", "wordpass", None, None).await?;
+//!
+//! let sliding_sync = client
+//!     .sliding_sync()
+//!     .add_list(
+//!         SlidingSyncList::new("all_rooms")
+//!             .ranges(vec![(uint!(0), uint!(19))])
+//!             .sort(vec!["by_recency".to_owned(), "by_name".to_owned()])
+//!             .required_state(vec![(EventType::RoomAvatar, "".to_owned())])
+//!             .timeline_limit(uint!(1)),
+//!     )
+//!     .with_to_device_extension()
+//!     .with_e2ee_extension()
+//!     .build();
+//!
+//! let mut stream = Box::pin(sliding_sync.stream());
+//!
+//! while let Some(Ok(_)) = stream.next().await {
+//!     println!("Visible rooms: {:?}", sliding_sync.list_rooms("all_rooms"));
+//! }
+//! # matrix_sdk::Result::<()>::Ok(()) });
+//! ```
+//!
+//! [MSC3575]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
+
+use futures_core::stream::Stream;
+use futures_timer::Delay;
+use http::Method;
+use matrix_sdk_base::deserialized_responses::SyncResponse;
+use matrix_sdk_common::locks::Mutex;
+use ruma::{
+    api::{client::r0::sync::sync_events, IncomingResponse},
+    events::EventType,
+    RoomId, UInt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+use tracing::{error, warn};
+
+use crate::{Client, Result};
+
+const SLIDING_SYNC_PATH: &str = "/_matrix/client/unstable/org.matrix.msc3575/sync";
+/// The maximum number of rooms we keep track of per list, the counts and
+/// indices the server sends us are clamped to this so a misbehaving server
+/// can't make us allocate arbitrary amounts of memory.
+const MAX_LIST_LENGTH: usize = 100_000;
+
+/// A list of rooms that should be synced using sliding sync.
+///
+/// The server sorts and filters all the rooms of the account according to the
+/// configuration of the list, only the rooms inside the ranges of the list
+/// are sent to the client.
+#[derive(Clone, Debug, Serialize)]
+pub struct SlidingSyncList {
+    #[serde(skip)]
+    name: String,
+    ranges: Vec<(UInt, UInt)>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sort: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    required_state: Vec<(EventType, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeline_limit: Option<UInt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filters: Option<SlidingSyncFilters>,
+}
+
+impl SlidingSyncList {
+    /// Create a new list with the given name.
+    ///
+    /// The name is only used locally to refer to the list, e.g. in
+    /// [`SlidingSync::list_rooms()`].
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ranges: Vec::new(),
+            sort: Vec::new(),
+            required_state: Vec::new(),
+            timeline_limit: None,
+            filters: None,
+        }
+    }
+
+    /// The name of the list.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set the ranges of the sorted list that should be synced, both ends of a
+    /// range are inclusive.
+    pub fn ranges(mut self, ranges: Vec<(UInt, UInt)>) -> Self {
+        self.ranges = ranges;
+        self
+    }
+
+    /// Set the sort order of the list, e.g. `by_recency` or `by_name`.
+    ///
+    /// Later entries are used to break ties of earlier ones.
+    pub fn sort(mut self, sort: Vec<String>) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Set the state events that should be sent for every room of the list.
+    ///
+    /// A state key of `*` matches all the state keys of the event type.
+    pub fn required_state(mut self, required_state: Vec<(EventType, String)>) -> Self {
+        self.required_state = required_state;
+        self
+    }
+
+    /// Set the maximum number of timeline events that should be sent for every
+    /// room of the list.
+    pub fn timeline_limit(mut self, limit: UInt) -> Self {
+        self.timeline_limit = Some(limit);
+        self
+    }
+
+    /// Only include the rooms matching the given filters in the list.
+    pub fn filters(mut self, filters: SlidingSyncFilters) -> Self {
+        self.filters = Some(filters);
+        self
+    }
+}
+
+/// Filters deciding which rooms are part of a [`SlidingSyncList`].
+///
+/// Filters that aren't set don't restrict the list.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SlidingSyncFilters {
+    /// Only include direct message rooms, or only non-DM rooms if `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_dm: Option<bool>,
+    /// Only include rooms that are part of one of the given spaces.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub spaces: Vec<Box<RoomId>>,
+    /// Only include encrypted rooms, or only unencrypted rooms if `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_encrypted: Option<bool>,
+    /// Only include rooms the user is invited to, or only rooms the user is
+    /// not invited to if `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_invite: Option<bool>,
+    /// Only include rooms whose name contains the given string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_name_like: Option<String>,
+    /// Only include rooms that have one of the given tags.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Exclude the rooms that have one of the given tags.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub not_tags: Vec<String>,
+}
+
+/// A subscription to a single room, independent of the lists the room is
+/// part of.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RoomSubscription {
+    /// The state events that should be sent for the room.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub required_state: Vec<(EventType, String)>,
+    /// The maximum number of timeline events that should be sent for the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeline_limit: Option<UInt>,
+}
+
+/// Builder for a [`SlidingSync`] session, created using
+/// [`Client::sliding_sync()`].
+#[derive(Debug, Clone)]
+pub struct SlidingSyncBuilder {
+    client: Client,
+    lists: Vec<SlidingSyncList>,
+    subscriptions: BTreeMap<Box<RoomId>, RoomSubscription>,
+    extensions: ExtensionsConfig,
+    timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ExtensionsConfig {
+    to_device: bool,
+    e2ee: bool,
+    account_data: bool,
+}
+
+impl SlidingSyncBuilder {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            lists: Vec::new(),
+            subscriptions: BTreeMap::new(),
+            extensions: ExtensionsConfig::default(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Add a list of rooms that should be synced.
+    pub fn add_list(mut self, list: SlidingSyncList) -> Self {
+        self.lists.push(list);
+        self
+    }
+
+    /// Subscribe to a single room.
+    pub fn subscribe(mut self, room_id: &RoomId, subscription: RoomSubscription) -> Self {
+        self.subscriptions.insert(room_id.to_owned(), subscription);
+        self
+    }
+
+    /// Receive to-device events, this is required for end-to-end encryption.
+    pub fn with_to_device_extension(mut self) -> Self {
+        self.extensions.to_device = true;
+        self
+    }
+
+    /// Receive device list changes and one-time key counts, this is required
+    /// for end-to-end encryption.
+    pub fn with_e2ee_extension(mut self) -> Self {
+        self.extensions.e2ee = true;
+        self
+    }
+
+    /// Receive global and room account data.
+    pub fn with_account_data_extension(mut self) -> Self {
+        self.extensions.account_data = true;
+        self
+    }
+
+    /// Set the maximum time the server should wait for new data before it
+    /// responds. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Create the [`SlidingSync`] session.
+    pub fn build(self) -> SlidingSync {
+        let lists = self
+            .lists
+            .into_iter()
+            .map(|config| ListState { config, count: 0, rooms: Vec::new() })
+            .collect();
+
+        SlidingSync {
+            client: self.client,
+            inner: Arc::new(SlidingSyncInner {
+                lists: StdRwLock::new(lists),
+                subscriptions: StdRwLock::new(self.subscriptions),
+                unsubscribed: StdRwLock::new(Vec::new()),
+                extensions: self.extensions,
+                timeout: self.timeout,
+                pos: Mutex::new(None),
+                to_device_since: StdRwLock::new(None),
+            }),
+        }
+    }
+}
+
+/// A sliding sync session.
+///
+/// All the clones of a session share the same state.
+#[derive(Debug, Clone)]
+pub struct SlidingSync {
+    client: Client,
+    inner: Arc<SlidingSyncInner>,
+}
+
+#[derive(Debug)]
+struct SlidingSyncInner {
+    lists: StdRwLock<Vec<ListState>>,
+    subscriptions: StdRwLock<BTreeMap<Box<RoomId>, RoomSubscription>>,
+    unsubscribed: StdRwLock<Vec<Box<RoomId>>>,
+    extensions: ExtensionsConfig,
+    timeout: Duration,
+    /// The position in the stream of the session, also makes sure only a
+    /// single request is in flight.
+    pos: Mutex<Option<String>>,
+    to_device_since: StdRwLock<Option<String>>,
+}
+
+#[derive(Debug)]
+struct ListState {
+    config: SlidingSyncList,
+    count: usize,
+    rooms: Vec<Option<Box<RoomId>>>,
+}
+
+impl Client {
+    /// Create a new sliding sync session for this client.
+    ///
+    /// Sliding sync and the regular sync share the store of the client, they
+    /// shouldn't be used together with the same store.
+    pub fn sliding_sync(&self) -> SlidingSyncBuilder {
+        SlidingSyncBuilder::new(self.clone())
+    }
+}
+
+impl SlidingSync {
+    /// Get the total number of rooms of the list with the given name, as
+    /// reported by the server.
+    pub fn list_count(&self, name: &str) -> Option<usize> {
+        self.inner.lists.read().unwrap().iter().find(|l| l.config.name == name).map(|l| l.count)
+    }
+
+    /// Get the rooms of the list with the given name.
+    ///
+    /// The returned vector has the length of the whole list, capped at 100 000
+    /// rooms, entries outside of the synced ranges are `None`.
+    pub fn list_rooms(&self, name: &str) -> Option<Vec<Option<Box<RoomId>>>> {
+        self.inner
+            .lists
+            .read()
+            .unwrap()
+            .iter()
+            .find(|l| l.config.name == name)
+            .map(|l| l.rooms.clone())
+    }
+
+    /// Change the ranges of the list with the given name, e.g. because the
+    /// user scrolled through the list.
+    ///
+    /// The new ranges will be used by the next request. Returns `false` if no
+    /// list with the given name exists.
+    pub fn set_ranges(&self, name: &str, ranges: Vec<(UInt, UInt)>) -> bool {
+        match self.inner.lists.write().unwrap().iter_mut().find(|l| l.config.name == name) {
+            Some(list) => {
+                list.config.ranges = ranges;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribe to a single room, independent of the lists the room is part
+    /// of.
+    pub fn subscribe(&self, room_id: &RoomId, subscription: RoomSubscription) {
+        self.inner.unsubscribed.write().unwrap().retain(|r| **r != *room_id);
+        self.inner.subscriptions.write().unwrap().insert(room_id.to_owned(), subscription);
+    }
+
+    /// Remove the subscription to the given room.
+    pub fn unsubscribe(&self, room_id: &RoomId) {
+        if self.inner.subscriptions.write().unwrap().remove(room_id).is_some() {
+            self.inner.unsubscribed.write().unwrap().push(room_id.to_owned());
+        }
+    }
+
+    fn build_request(&self) -> JsonValue {
+        let lists: Vec<_> =
+            self.inner.lists.read().unwrap().iter().map(|l| l.config.clone()).collect();
+
+        let mut extensions = JsonMap::new();
+
+        if self.inner.extensions.to_device {
+            let since = self.inner.to_device_since.read().unwrap().clone();
+            extensions.insert("to_device".to_owned(), json!({ "enabled": true, "since": since }));
+        }
+
+        if self.inner.extensions.e2ee {
+            extensions.insert("e2ee".to_owned(), json!({ "enabled": true }));
+        }
+
+        if self.inner.extensions.account_data {
+            extensions.insert("account_data".to_owned(), json!({ "enabled": true }));
+        }
+
+        json!({
+            "lists": lists,
+            "room_subscriptions": *self.inner.subscriptions.read().unwrap(),
+            "unsubscribe_rooms": *self.inner.unsubscribed.read().unwrap(),
+            "extensions": extensions,
+        })
+    }
+
+    /// Send a single sliding sync request and process the response.
+    ///
+    /// The response is converted into a [`SyncResponse`] and handled by the
+    /// client, the same way [`Client::sync_once()`] handles it.
+    pub async fn sync_once(&self) -> Result<SyncResponse> {
+        let mut pos = self.inner.pos.lock().await;
+
+        #[cfg(feature = "encryption")]
+        if let Err(e) = self.client.send_outgoing_requests().await {
+            error!(error =? e, "Error while sending outgoing E2EE requests");
+        };
+
+        let request = self.build_request();
+        let unsubscribed = self.inner.unsubscribed.read().unwrap().len();
+
+        let timeout = self.inner.timeout.as_millis().to_string();
+        let mut query = vec![("timeout", timeout.as_str())];
+        if let Some(pos) = pos.as_deref() {
+            query.push(("pos", pos));
+        }
+
+        let http_client = &self.client.inner.http_client;
+        let request_config = http_client
+            .request_config
+            .timeout(self.inner.timeout + http_client.request_config.timeout);
+
+        let mut response: SlidingSyncResponse = http_client
+            .send_json(Method::POST, SLIDING_SYNC_PATH, &query, &request, Some(request_config))
+            .await?;
+
+        // The state of the session is only updated once the response was
+        // processed successfully, otherwise the response is requested again
+        // and e.g. to-device events would be lost or list ops applied twice.
+        let next_pos = response.pos.clone();
+        let lists = std::mem::take(&mut response.lists);
+        let to_device_since = response.extensions.to_device.as_ref().map(|t| t.next_batch.clone());
+
+        let sync_response = response.into_sync_response();
+        let body = serde_json::to_vec(&sync_response)?;
+        let sync_response =
+            sync_events::Response::try_from_http_response(http::Response::new(body))
+                .map_err(crate::HttpError::from)?;
+
+        let response = self.client.process_sync(sync_response).await?;
+
+        // The server knows about the removed subscriptions now.
+        self.inner.unsubscribed.write().unwrap().drain(..unsubscribed);
+
+        self.apply_list_ops(&lists);
+
+        if let Some(to_device_since) = to_device_since {
+            *self.inner.to_device_since.write().unwrap() = Some(to_device_since);
+        }
+
+        *pos = Some(next_pos);
+
+        #[cfg(feature = "encryption")]
+        if let Err(e) = self.client.send_outgoing_requests().await {
+            error!(error =? e, "Error while sending outgoing E2EE requests");
+        };
+
+        self.client.inner.sync_beat.notify(usize::MAX);
+
+        Ok(response)
+    }
+
+    /// Keep syncing, yielding the result of every sliding sync request.
+    ///
+    /// Failed requests are yielded as errors and retried after a short delay.
+    pub fn stream(&self) -> impl Stream<Item = Result<SyncResponse>> + '_ {
+        async_stream::stream! {
+            loop {
+                let response = self.sync_once().await;
+
+                if let Err(e) = &response {
+                    error!("Received an invalid sliding sync response: {}", e);
+                    Delay::new(Duration::from_secs(1)).await;
+                }
+
+                yield response;
+            }
+        }
+    }
+
+    fn apply_list_ops(&self, responses: &[ListResponse]) {
+        let mut lists = self.inner.lists.write().unwrap();
+
+        for (list, response) in lists.iter_mut().zip(responses) {
+            list.apply_ops(response);
+        }
+    }
+}
+
+impl ListState {
+    fn apply_ops(&mut self, response: &ListResponse) {
+        let count = usize::try_from(response.count).unwrap_or(usize::MAX);
+        let length = count.min(MAX_LIST_LENGTH);
+
+        for op in &response.ops {
+            match op {
+                ListOp::Sync { range: (start, end), room_ids } => {
+                    let needed =
+                        end.saturating_add(1).max(start.saturating_add(room_ids.len())).min(length);
+                    if self.rooms.len() < needed {
+                        self.rooms.resize(needed, None);
+                    }
+
+                    for (index, room_id) in (*start..needed).zip(room_ids) {
+                        self.rooms[index] = Some(room_id.clone());
+                    }
+                }
+                ListOp::Invalidate { range: (start, end) } => {
+                    let end = end.saturating_add(1).min(self.rooms.len());
+
+                    for room in self.rooms.iter_mut().take(end).skip(*start) {
+                        *room = None;
+                    }
+                }
+                ListOp::Delete { index } => {
+                    if *index < self.rooms.len() {
+                        self.rooms.remove(*index);
+                    }
+                }
+                ListOp::Insert { index, room_id } => {
+                    let index = (*index).min(self.rooms.len());
+                    self.rooms.insert(index, Some(room_id.clone()));
+                }
+                ListOp::Update { index, room_id } => {
+                    if *index >= length {
+                        continue;
+                    }
+
+                    if *index >= self.rooms.len() {
+                        self.rooms.resize(index + 1, None);
+                    }
+
+                    self.rooms[*index] = Some(room_id.clone());
+                }
+                ListOp::Unknown => warn!("Received an unknown sliding sync list operation"),
+            }
+        }
+
+        self.count = count;
+        self.rooms.resize(length, None);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SlidingSyncResponse {
+    pos: String,
+    #[serde(default)]
+    lists: Vec<ListResponse>,
+    #[serde(default)]
+    rooms: BTreeMap<Box<RoomId>, RoomResponse>,
+    #[serde(default)]
+    extensions: ExtensionsResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListResponse {
+    #[serde(default)]
+    count: u64,
+    #[serde(default)]
+    ops: Vec<ListOp>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "SCREAMING_SNAKE_CASE")]
+enum ListOp {
+    Sync {
+        range: (usize, usize),
+        #[serde(default)]
+        room_ids: Vec<Box<RoomId>>,
+    },
+    Invalidate {
+        range: (usize, usize),
+    },
+    Delete {
+        index: usize,
+    },
+    Insert {
+        index: usize,
+        room_id: Box<RoomId>,
+    },
+    Update {
+        index: usize,
+        room_id: Box<RoomId>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomResponse {
+    #[serde(default)]
+    required_state: Vec<JsonValue>,
+    #[serde(default)]
+    timeline: Vec<JsonValue>,
+    invite_state: Option<Vec<JsonValue>>,
+    notification_count: Option<UInt>,
+    highlight_count: Option<UInt>,
+    #[serde(default)]
+    limited: bool,
+    prev_batch: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExtensionsResponse {
+    to_device: Option<ToDeviceResponse>,
+    e2ee: Option<E2eeResponse>,
+    account_data: Option<AccountDataResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToDeviceResponse {
+    next_batch: String,
+    #[serde(default)]
+    events: Vec<JsonValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct E2eeResponse {
+    device_lists: Option<JsonValue>,
+    device_one_time_keys_count: Option<JsonValue>,
+    device_unused_fallback_key_types: Option<JsonValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountDataResponse {
+    #[serde(default)]
+    global: Vec<JsonValue>,
+    #[serde(default)]
+    rooms: BTreeMap<Box<RoomId>, Vec<JsonValue>>,
+}
+
+impl SlidingSyncResponse {
+    /// Convert the response into the body of a response of the regular sync
+    /// endpoint.
+    fn into_sync_response(self) -> JsonValue {
+        let ExtensionsResponse { to_device, e2ee, account_data } = self.extensions;
+        let AccountDataResponse { global: global_account_data, rooms: mut room_account_data } =
+            account_data
+                .unwrap_or(AccountDataResponse { global: Vec::new(), rooms: BTreeMap::new() });
+
+        let mut join = JsonMap::new();
+        let mut invite = JsonMap::new();
+
+        for (room_id, room) in self.rooms {
+            if let Some(invite_state) = room.invite_state {
+                invite.insert(
+                    room_id.to_string(),
+                    json!({ "invite_state": { "events": invite_state } }),
+                );
+                continue;
+            }
+
+            let account_data = room_account_data.remove(&room_id).unwrap_or_default();
+
+            join.insert(
+                room_id.to_string(),
+                json!({
+                    "state": { "events": room.required_state },
+                    "timeline": {
+                        "events": room.timeline,
+                        "limited": room.limited,
+                        "prev_batch": room.prev_batch,
+                    },
+                    "unread_notifications": {
+                        "notification_count": room.notification_count,
+                        "highlight_count": room.highlight_count,
+                    },
+                    "account_data": { "events": account_data },
+                }),
+            );
+        }
+
+        // Account data can be sent for rooms that aren't otherwise part of the
+        // response.
+        for (room_id, account_data) in room_account_data {
+            join.insert(room_id.to_string(), json!({ "account_data": { "events": account_data } }));
+        }
+
+        let mut response = json!({
+            "next_batch": self.pos,
+            "rooms": { "join": join, "invite": invite },
+            "account_data": { "events": global_account_data },
+            "to_device": { "events": to_device.map(|t| t.events).unwrap_or_default() },
+        });
+
+        if let Some(e2ee) = e2ee {
+            let response = response.as_object_mut().expect("The sync response is an object");
+
+            if let Some(device_lists) = e2ee.device_lists {
+                response.insert("device_lists".to_owned(), device_lists);
+            }
+
+            if let Some(counts) = e2ee.device_one_time_keys_count {
+                response.insert("device_one_time_keys_count".to_owned(), counts);
+            }
+
+            if let Some(key_types) = e2ee.device_unused_fallback_key_types {
+                response.insert("device_unused_fallback_key_types".to_owned(), key_types);
+            }
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_test::{async_test, test_json};
+    use mockito::{mock, Matcher};
+    use ruma::{room_id, uint};
+    use serde_json::json;
+
+    use super::SlidingSyncList;
+    use crate::client::test::logged_in_client;
+
+    #[async_test]
+    async fn sliding_sync_rooms_are_processed() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let other_room_id = room_id!("!other:localhost");
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/unstable/org.matrix.msc3575/sync".to_owned()),
+        )
+        .match_body(Matcher::PartialJson(json!({
+            "lists": [{ "ranges": [[0, 9]], "timeline_limit": 5 }],
+            "extensions": { "to_device": { "enabled": true } },
+        })))
+        .with_status(200)
+        .with_body(
+            json!({
+                "pos": "1",
+                "lists": [{
+                    "count": 12,
+                    "ops": [{
+                        "op": "SYNC",
+                        "range": [0, 9],
+                        "room_ids": [room_id, other_room_id],
+                    }],
+                }],
+                "rooms": {
+                    room_id.as_str(): {
+                        "required_state": [test_json::NAME.clone()],
+                        "timeline": [test_json::MESSAGE_TEXT.clone()],
+                        "notification_count": 1,
+                        "limited": true,
+                        "prev_batch": "t392-516_47314_0_7_1_1_1_11444_1",
+                    },
+                },
+                "extensions": { "to_device": { "next_batch": "td1", "events": [] } },
+            })
+            .to_string(),
+        )
+        .create();
+
+        let sliding_sync = client
+            .sliding_sync()
+            .add_list(
+                SlidingSyncList::new("rooms")
+                    .ranges(vec![(uint!(0), uint!(9))])
+                    .timeline_limit(uint!(5)),
+            )
+            .with_to_device_extension()
+            .build();
+
+        let response = sliding_sync.sync_once().await.unwrap();
+
+        assert_eq!(response.next_batch, "1");
+        assert_eq!(response.rooms.join[room_id].timeline.events.len(), 1);
+        assert_eq!(*sliding_sync.inner.to_device_since.read().unwrap(), Some("td1".to_owned()));
+
+        let room = client.get_joined_room(room_id).unwrap();
+        assert_eq!(room.name().as_deref(), Some("room name"));
+
+        let rooms = sliding_sync.list_rooms("rooms").unwrap();
+        assert_eq!(sliding_sync.list_count("rooms"), Some(12));
+        assert_eq!(rooms.len(), 12);
+        assert_eq!(rooms[0].as_deref(), Some(room_id));
+        assert_eq!(rooms[1].as_deref(), Some(other_room_id));
+        assert!(rooms[2].is_none());
+    }
+
+    #[test]
+    fn list_operations_are_applied() {
+        use super::{ListResponse, ListState};
+
+        let mut list =
+            ListState { config: SlidingSyncList::new("rooms"), count: 0, rooms: Vec::new() };
+
+        let response: ListResponse = serde_json::from_value(json!({
+            "count": 4,
+            "ops": [
+                { "op": "SYNC", "range": [0, 3], "room_ids": ["!a:l", "!b:l", "!c:l", "!d:l"] },
+                { "op": "DELETE", "index": 2 },
+                { "op": "INSERT", "index": 0, "room_id": "!c:l" },
+                { "op": "SOMETHING_NEW" },
+            ],
+        }))
+        .unwrap();
+        list.apply_ops(&response);
+
+        let rooms: Vec<_> = list.rooms.iter().map(|r| r.as_ref().unwrap().as_str()).collect();
+        assert_eq!(rooms, ["!c:l", "!a:l", "!b:l", "!d:l"]);
+
+        let response: ListResponse = serde_json::from_value(json!({
+            "count": 5,
+            "ops": [{ "op": "INVALIDATE", "range": [1, 2] }],
+        }))
+        .unwrap();
+        list.apply_ops(&response);
+
+        assert_eq!(list.rooms.len(), 5);
+        assert!(list.rooms[0].is_some());
+        assert!(list.rooms[1].is_none() && list.rooms[2].is_none());
+        assert!(list.rooms[3].is_some());
+        assert!(list.rooms[4].is_none());
+    }
+
+    #[test]
+    fn huge_list_counts_and_indices_are_clamped() {
+        use super::{ListResponse, ListState, MAX_LIST_LENGTH};
+
+        let mut list =
+            ListState { config: SlidingSyncList::new("rooms"), count: 0, rooms: Vec::new() };
+
+        let response: ListResponse = serde_json::from_value(json!({
+            "count": u64::MAX,
+            "ops": [
+                { "op": "SYNC", "range": [0, usize::MAX], "room_ids": ["!a:l"] },
+                { "op": "INVALIDATE", "range": [usize::MAX, usize::MAX] },
+                { "op": "UPDATE", "index": usize::MAX, "room_id": "!b:l" },
+            ],
+        }))
+        .unwrap();
+        list.apply_ops(&response);
+
+        assert_eq!(list.count, usize::MAX);
+        assert_eq!(list.rooms.len(), MAX_LIST_LENGTH);
+        assert_eq!(list.rooms[0].as_ref().unwrap().as_str(), "!a:l");
+        assert!(list.rooms[1..].iter().all(Option::is_none));
+    }
+}
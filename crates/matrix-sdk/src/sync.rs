@@ -18,6 +18,21 @@ impl Client {
         response: sync_events::Response,
     ) -> Result<SyncResponse> {
         let response = self.base_client().receive_sync_response(response).await?;
+        self.handle_sync_response(response).await
+    }
+
+    /// Process a sync response again, even if it was already received, and
+    /// call the event handlers for its events.
+    #[cfg(feature = "appservice")]
+    pub(crate) async fn replay_sync(
+        &self,
+        response: sync_events::Response,
+    ) -> Result<SyncResponse> {
+        let response = self.base_client().replay_sync_response(response).await?;
+        self.handle_sync_response(response).await
+    }
+
+    async fn handle_sync_response(&self, response: SyncResponse) -> Result<SyncResponse> {
         let SyncResponse {
             next_batch: _,
             rooms,
@@ -81,6 +96,8 @@ impl Client {
             .await?;
         }
 
+        self.update_timelines(&response).await;
+
         // Construct notification event handler futures
         let mut futures = Vec::new();
         for handler in &*self.notification_handlers().await {
@@ -117,6 +134,9 @@ impl Client {
         match response {
             Ok(r) => {
                 sync_settings.token = Some(r.next_batch.clone());
+                // The homeserver is reachable again, retry sending the events
+                // that failed because of a transient error.
+                self.send_queue().retry_stalled_rooms();
                 Ok(r)
             }
             Err(e) => {
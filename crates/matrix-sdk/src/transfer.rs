@@ -0,0 +1,395 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types to stream media content to and from the homeserver.
+//!
+//! Media can be uploaded with [`Client::upload_stream()`] and downloaded with
+//! [`Client::get_media_content_stream()`] without ever holding the whole file
+//! in memory. A [`TransferConfig`] can be used to follow the progress of a
+//! transfer and to cancel it.
+//!
+//! ```no_run
+//! # use futures::executor::block_on;
+//! # use url::Url;
+//! # let homeserver = Url::parse("http://localhost:8080").unwrap();
+//! # block_on(async {
+//! use matrix_sdk::{
+//!     transfer::{CancellationToken, TransferConfig},
+//!     Client,
+//! };
+//!
+//! let client = Client::new(homeserver).await?;
+//!
+//! let token = CancellationToken::new();
+//! let config = TransferConfig::new()
+//!     .progress(|p| println!("Uploaded {} of {:?} bytes", p.transferred, p.total))
+//!     .cancellation(token.clone());
+//!
+//! let file = futures_util::io::Cursor::new(b"Hello world".to_vec());
+//! let response = client.upload_stream(&mime::TEXT_PLAIN, file, Some(11), config).await?;
+//! # matrix_sdk::Result::<()>::Ok(()) });
+//! ```
+//!
+//! [`Client::upload_stream()`]: crate::Client::upload_stream
+//! [`Client::get_media_content_stream()`]: crate::Client::get_media_content_stream
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    io::{Error as IoError, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use bytes::Bytes;
+use futures_channel::oneshot;
+use futures_core::stream::Stream;
+use futures_util::{
+    future::{self, Either},
+    io::AsyncRead,
+    pin_mut, ready,
+};
+
+use crate::{Error, HttpError};
+
+/// The size of the chunks that are read from an `AsyncRead` while streaming.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A stream of media content.
+#[cfg(not(target_arch = "wasm32"))]
+pub type MediaStream = Pin<Box<dyn Stream<Item = crate::Result<Bytes>> + Send + Sync>>;
+
+/// A stream of media content.
+#[cfg(target_arch = "wasm32")]
+pub type MediaStream = Pin<Box<dyn Stream<Item = crate::Result<Bytes>>>>;
+
+/// A stream of chunks of a media transfer that is processed internally.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type IoStream = Pin<Box<dyn Stream<Item = Result<Bytes, IoError>> + Send + Sync>>;
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) type IoStream = Pin<Box<dyn Stream<Item = Result<Bytes, IoError>>>>;
+
+/// The progress of a media transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// The number of bytes that were transferred so far.
+    pub transferred: u64,
+
+    /// The total number of bytes of the transfer, if known.
+    pub total: Option<u64>,
+}
+
+/// A token to cancel a media transfer.
+///
+/// The token can be cloned, cancelling any of the clones cancels the
+/// transfers the token was given to. Cancelling a transfer aborts it right
+/// away, even if it's waiting for the homeserver.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<CancellationState>);
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    next_waiter_id: AtomicU64,
+    /// The wakers of the [`Cancelled`] futures that are waiting for this
+    /// token, by their ID.
+    waiters: StdMutex<BTreeMap<u64, Waker>>,
+}
+
+impl CancellationToken {
+    /// Create a new `CancellationToken`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Cancel the transfers using this token.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+
+        let waiters = std::mem::take(&mut *self.0.waiters.lock().unwrap());
+        for waker in waiters.into_values() {
+            waker.wake();
+        }
+    }
+
+    /// Has this token been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Get a future that resolves once this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled { token: self.clone(), id: self.0.next_waiter_id.fetch_add(1, Ordering::SeqCst) }
+    }
+}
+
+/// A future that resolves once a [`CancellationToken`] is cancelled.
+///
+/// Created with [`CancellationToken::cancelled()`].
+#[derive(Debug)]
+pub struct Cancelled {
+    token: CancellationToken,
+    id: u64,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        let mut waiters = self.token.0.waiters.lock().unwrap();
+
+        // The token might have been cancelled while we were waiting for the
+        // lock, in that case nobody would wake us up.
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        waiters.insert(self.id, cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        self.token.0.waiters.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The error a stream of a cancelled transfer fails with.
+#[derive(Debug, thiserror::Error)]
+#[error("the media transfer was cancelled")]
+pub(crate) struct TransferCancelledError;
+
+type ProgressFn = Arc<dyn Fn(TransferProgress) + Send + Sync>;
+
+/// Settings for a streamed media transfer.
+#[derive(Clone, Default)]
+pub struct TransferConfig {
+    pub(crate) progress: Option<ProgressFn>,
+    pub(crate) cancellation: Option<CancellationToken>,
+    pub(crate) cache_limit: Option<u64>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for TransferConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransferConfig")
+            .field("cancellation", &self.cancellation)
+            .field("cache_limit", &self.cache_limit)
+            .finish()
+    }
+}
+
+impl TransferConfig {
+    /// Create a new default `TransferConfig`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set a callback that is called every time a chunk of the media was
+    /// transferred.
+    #[must_use]
+    pub fn progress(mut self, progress: impl Fn(TransferProgress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Set a token that can be used to cancel the transfer.
+    ///
+    /// A cancelled transfer fails with [`Error::TransferCancelled`].
+    #[must_use]
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Use the media cache of the state store for downloads that aren't
+    /// larger than `size` bytes.
+    ///
+    /// Media that fits into the cache is fully downloaded before it's
+    /// returned, bigger media is streamed and never cached. By default streamed
+    /// downloads don't use the cache.
+    #[must_use]
+    pub fn cache_limit(mut self, size: u64) -> Self {
+        self.cache_limit = Some(size);
+        self
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().map_or(false, |t| t.is_cancelled())
+    }
+
+    /// Run the given future of a transfer using this config, it's aborted as
+    /// soon as the transfer is cancelled.
+    pub(crate) async fn run<T>(
+        &self,
+        transfer: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let token = match &self.cancellation {
+            Some(token) => token,
+            None => return transfer.await,
+        };
+
+        let cancelled = token.cancelled();
+        pin_mut!(transfer, cancelled);
+
+        match future::select(transfer, cancelled).await {
+            Either::Left((Err(_), _)) if self.is_cancelled() => Err(Error::TransferCancelled),
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::TransferCancelled),
+        }
+    }
+
+    /// Convert an error of a transfer using this config to an [`Error`].
+    pub(crate) fn to_error(&self, error: IoError) -> Error {
+        if self.is_cancelled() {
+            return Error::TransferCancelled;
+        }
+
+        // Errors of the HTTP layer are wrapped in an I/O error while they are
+        // streamed, unwrap them again.
+        let kind = error.kind();
+
+        match error.into_inner() {
+            Some(e) if e.is::<TransferCancelledError>() => Error::TransferCancelled,
+            Some(e) => match e.downcast::<HttpError>() {
+                Ok(e) => Error::Http(*e),
+                Err(e) => Error::Io(IoError::new(kind, e)),
+            },
+            None => Error::Io(kind.into()),
+        }
+    }
+}
+
+/// A stream that reads an `AsyncRead` in chunks.
+///
+/// Once the reader is exhausted it's sent to the optional `finished` channel,
+/// this allows to retrieve state from the reader after it was streamed, e.g.
+/// the encryption info of an encrypted attachment.
+pub(crate) struct ReaderStream<R> {
+    reader: Option<R>,
+    buffer: Vec<u8>,
+    finished: Option<oneshot::Sender<R>>,
+}
+
+impl<R: AsyncRead + Unpin> ReaderStream<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self { reader: Some(reader), buffer: vec![0; CHUNK_SIZE], finished: None }
+    }
+
+    pub(crate) fn with_finished(reader: R) -> (Self, oneshot::Receiver<R>) {
+        let (sender, receiver) = oneshot::channel();
+        let mut stream = Self::new(reader);
+        stream.finished = Some(sender);
+
+        (stream, receiver)
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for ReaderStream<R> {
+    type Item = Result<Bytes, IoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        let reader = match this.reader.as_mut() {
+            Some(r) => r,
+            None => return Poll::Ready(None),
+        };
+
+        match ready!(Pin::new(reader).poll_read(cx, &mut this.buffer)) {
+            Ok(0) => {
+                if let (Some(reader), Some(finished)) = (this.reader.take(), this.finished.take()) {
+                    // The receiver might not be interested in the reader
+                    // anymore.
+                    let _ = finished.send(reader);
+                }
+
+                Poll::Ready(None)
+            }
+            Ok(n) => Poll::Ready(Some(Ok(Bytes::copy_from_slice(&this.buffer[..n])))),
+            Err(e) => {
+                this.reader = None;
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
+
+impl<R> Drop for ReaderStream<R> {
+    fn drop(&mut self) {
+        // The consumer of the stream might stop polling once it got all the
+        // data it expected, without waiting for the end of the stream.
+        if let (Some(reader), Some(finished)) = (self.reader.take(), self.finished.take()) {
+            let _ = finished.send(reader);
+        }
+    }
+}
+
+/// A stream that reports the progress of the wrapped stream and stops it once
+/// the transfer was cancelled.
+pub(crate) struct ProgressStream<S> {
+    inner: S,
+    config: TransferConfig,
+    cancelled: Option<Cancelled>,
+    transferred: u64,
+    total: Option<u64>,
+}
+
+impl<S> ProgressStream<S> {
+    pub(crate) fn new(inner: S, config: TransferConfig, total: Option<u64>) -> Self {
+        let cancelled = config.cancellation.as_ref().map(CancellationToken::cancelled);
+        Self { inner, config, cancelled, transferred: 0, total }
+    }
+}
+
+impl<S: Stream<Item = Result<Bytes, IoError>> + Unpin> Stream for ProgressStream<S> {
+    type Item = Result<Bytes, IoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Polling the cancellation makes sure we're woken up if the transfer
+        // is cancelled while we're waiting for the next chunk.
+        if let Some(cancelled) = self.cancelled.as_mut() {
+            if Pin::new(cancelled).poll(cx).is_ready() {
+                return Poll::Ready(Some(Err(IoError::new(
+                    ErrorKind::Other,
+                    TransferCancelledError,
+                ))));
+            }
+        }
+
+        let chunk = ready!(Pin::new(&mut self.inner).poll_next(cx));
+
+        if let Some(Ok(chunk)) = &chunk {
+            self.transferred += chunk.len() as u64;
+
+            if let Some(progress) = &self.config.progress {
+                progress(TransferProgress { transferred: self.transferred, total: self.total });
+            }
+        }
+
+        Poll::Ready(chunk)
+    }
+}